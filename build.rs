@@ -0,0 +1,11 @@
+//! Generates the gRPC service and messages from proto/, with a protobuf compiler written in Rust
+//! so building needs no `protoc`
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto");
+    let descriptors = protox::compile(["qrlink/v1/links.proto"], ["proto"])?;
+    tonic_build::configure()
+        .build_transport(false)
+        .compile_fds(descriptors)?;
+    Ok(())
+}
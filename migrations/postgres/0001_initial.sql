@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS urls (
+    id BIGSERIAL PRIMARY KEY,
+    external_id TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    deleted_at TIMESTAMPTZ DEFAULT NULL,
+    expires_at TIMESTAMPTZ DEFAULT NULL,
+    slug TEXT DEFAULT NULL UNIQUE,
+    code TEXT DEFAULT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS stats (
+    url_id BIGINT NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+    ip_addr TEXT NOT NULL,
+    clicked_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS url_history (
+    id BIGSERIAL PRIMARY KEY,
+    url_id BIGINT NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+    external_id TEXT NOT NULL,
+    replaced_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,3 @@
+ALTER TABLE stats ADD COLUMN visitor TEXT DEFAULT NULL;
+
+CREATE INDEX IF NOT EXISTS stats_url_clicked ON stats (url_id, clicked_at);
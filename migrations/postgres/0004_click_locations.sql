@@ -0,0 +1,2 @@
+ALTER TABLE stats ADD COLUMN country TEXT DEFAULT NULL;
+ALTER TABLE stats ADD COLUMN city TEXT DEFAULT NULL;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS link_language_targets (
+    url_id BIGINT NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    lang TEXT NOT NULL,
+    url TEXT NOT NULL,
+    PRIMARY KEY (url_id, position)
+);
@@ -0,0 +1,4 @@
+ALTER TABLE urls ADD COLUMN title TEXT DEFAULT NULL;
+ALTER TABLE urls ADD COLUMN notes TEXT DEFAULT NULL;
+ALTER TABLE urls ADD COLUMN page_title TEXT DEFAULT NULL;
+ALTER TABLE urls ADD COLUMN page_description TEXT DEFAULT NULL;
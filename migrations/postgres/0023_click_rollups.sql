@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS click_rollups (
+    url_id BIGINT NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+    day DATE NOT NULL,
+    source TEXT NOT NULL,
+    clicks BIGINT NOT NULL,
+    PRIMARY KEY (url_id, day, source)
+);
+CREATE INDEX IF NOT EXISTS stats_clicked_at ON stats(clicked_at);
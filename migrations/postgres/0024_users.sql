@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS users (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+ALTER TABLE urls ADD COLUMN owner_id BIGINT DEFAULT NULL REFERENCES users(id);
+ALTER TABLE api_keys ADD COLUMN user_id BIGINT DEFAULT NULL REFERENCES users(id);
+CREATE INDEX IF NOT EXISTS urls_owner_id ON urls(owner_id);
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN oidc_subject TEXT DEFAULT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS users_oidc_subject ON users(oidc_subject);
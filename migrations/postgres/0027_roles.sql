@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'editor';
+ALTER TABLE api_keys ADD COLUMN role TEXT NOT NULL DEFAULT 'editor';
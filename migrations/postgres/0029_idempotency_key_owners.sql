@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS urls_idempotency_key;
+CREATE UNIQUE INDEX IF NOT EXISTS urls_owner_idempotency_key ON urls(owner_id, idempotency_key);
+CREATE UNIQUE INDEX IF NOT EXISTS urls_unowned_idempotency_key ON urls(idempotency_key)
+    WHERE owner_id IS NULL;
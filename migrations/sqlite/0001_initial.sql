@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS urls (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    external_id TEXT NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    deleted_at DATETIME DEFAULT NULL,
+    expires_at DATETIME DEFAULT NULL,
+    slug TEXT DEFAULT NULL,
+    code TEXT DEFAULT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS urls_slug ON urls (slug);
+CREATE UNIQUE INDEX IF NOT EXISTS urls_code ON urls (code);
+
+CREATE TABLE IF NOT EXISTS stats (
+    url_id INTEGER NOT NULL,
+    ip_addr TEXT NOT NULL,
+    clicked_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (url_id) REFERENCES urls(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS url_history (
+    url_id INTEGER NOT NULL,
+    external_id TEXT NOT NULL,
+    replaced_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (url_id) REFERENCES urls(id) ON DELETE CASCADE
+);
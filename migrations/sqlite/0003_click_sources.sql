@@ -0,0 +1,4 @@
+ALTER TABLE stats ADD COLUMN referrer TEXT DEFAULT NULL;
+ALTER TABLE stats ADD COLUMN user_agent TEXT DEFAULT NULL;
+ALTER TABLE stats ADD COLUMN browser TEXT DEFAULT NULL;
+ALTER TABLE stats ADD COLUMN os TEXT DEFAULT NULL;
@@ -0,0 +1 @@
+ALTER TABLE stats ADD COLUMN source TEXT NOT NULL DEFAULT 'link';
@@ -0,0 +1 @@
+ALTER TABLE urls ADD COLUMN activate_at DATETIME DEFAULT NULL;
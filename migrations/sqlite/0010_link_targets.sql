@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS link_targets (
+    url_id INTEGER NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+    platform TEXT NOT NULL,
+    url TEXT NOT NULL,
+    PRIMARY KEY (url_id, platform)
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS link_variants (
+    url_id INTEGER NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    weight INTEGER NOT NULL,
+    PRIMARY KEY (url_id, position)
+);
+
+ALTER TABLE stats ADD COLUMN variant TEXT DEFAULT NULL;
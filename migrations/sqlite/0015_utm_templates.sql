@@ -0,0 +1 @@
+ALTER TABLE urls ADD COLUMN utm_template TEXT DEFAULT NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE urls ADD COLUMN url_hash TEXT DEFAULT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS urls_url_hash ON urls(url_hash) WHERE deleted_at IS NULL;
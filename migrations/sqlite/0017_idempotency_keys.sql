@@ -0,0 +1,2 @@
+ALTER TABLE urls ADD COLUMN idempotency_key TEXT DEFAULT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS urls_idempotency_key ON urls(idempotency_key);
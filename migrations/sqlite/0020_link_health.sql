@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS link_health (
+    url_id INTEGER PRIMARY KEY REFERENCES urls(id) ON DELETE CASCADE,
+    checked_at DATETIME NOT NULL,
+    status INTEGER DEFAULT NULL,
+    latency_ms INTEGER NOT NULL,
+    error TEXT DEFAULT NULL,
+    broken BOOLEAN NOT NULL
+);
+CREATE INDEX IF NOT EXISTS link_health_checked_at ON link_health(checked_at);
@@ -0,0 +1,4 @@
+ALTER TABLE urls ADD COLUMN threat TEXT DEFAULT NULL;
+ALTER TABLE urls ADD COLUMN quarantined_at DATETIME DEFAULT NULL;
+ALTER TABLE urls ADD COLUMN safety_checked_at DATETIME DEFAULT NULL;
+CREATE INDEX IF NOT EXISTS urls_safety_checked_at ON urls(safety_checked_at);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS visitor_salts (
+    day TEXT PRIMARY KEY,
+    salt TEXT NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS click_rollups (
+    url_id INTEGER NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+    day TEXT NOT NULL,
+    source TEXT NOT NULL,
+    clicks INTEGER NOT NULL,
+    PRIMARY KEY (url_id, day, source)
+);
+CREATE INDEX IF NOT EXISTS stats_clicked_at ON stats(clicked_at);
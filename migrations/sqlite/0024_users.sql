@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+ALTER TABLE urls ADD COLUMN owner_id INTEGER DEFAULT NULL REFERENCES users(id);
+ALTER TABLE api_keys ADD COLUMN user_id INTEGER DEFAULT NULL REFERENCES users(id);
+CREATE INDEX IF NOT EXISTS urls_owner_id ON urls(owner_id);
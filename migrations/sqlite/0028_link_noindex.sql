@@ -0,0 +1 @@
+ALTER TABLE urls ADD COLUMN noindex BOOLEAN NOT NULL DEFAULT 0;
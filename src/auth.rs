@@ -0,0 +1,279 @@
+//! Bearer-key authentication for the endpoints that change links or list them; the dashboard
+//! has sessions of its own, see [crate::session]. Keys issued to a user only reach that user's
+//! links, and keys issued to no one only the links of no one, while the admin token and keys
+//! with the admin role reach every link.
+//!
+//! What a caller may do is up to their [Role]: handlers declare the [Permission] they need by
+//! taking an [Authorized] caller, which refuses the others with 403 Forbidden.
+
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use axum::extract::{FromRequestParts, OptionalFromRequestParts, Request, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, Method, header};
+use axum::middleware::Next;
+use axum::response::Response;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::AppState;
+use crate::code::{BASE62, CodeGenerator};
+use crate::error::{Error, QrLinkResult};
+use crate::store::{ApiKey, Role, Scope, User};
+
+/// Marks issued keys, so they are easy to recognise, e.g. by secret scanners
+pub static KEY_PREFIX: &str = "qrl_";
+
+/// Random characters in a key after [KEY_PREFIX]
+pub const KEY_LENGTH: usize = 40;
+
+/// How much of a key is kept in the clear to tell keys apart
+pub const VISIBLE_LENGTH: usize = 8;
+
+/// A fresh key, and the hash and prefix it is stored under
+pub fn generate_key() -> (String, String, String) {
+    let codes = CodeGenerator::new(BASE62, KEY_LENGTH).expect("base62 is a valid alphabet");
+    let key = format!("{KEY_PREFIX}{}", codes.generate());
+    let prefix = key[..VISIBLE_LENGTH].to_owned();
+    (hash(&key), prefix, key)
+}
+
+/// The hex SHA-256 of a key, which is what gets stored and compared
+pub fn hash(key: &str) -> String {
+    Sha256::digest(key)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The longest `Idempotency-Key` header accepted
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+/// The token of an `Authorization: Bearer <token>` header
+fn bearer_token(headers: &HeaderMap) -> QrLinkResult<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| Error::Unauthorized("missing bearer token".into()))
+}
+
+/// Whether `token` is the configured admin token. Hashes are compared rather than the tokens
+/// themselves, so how long the comparison takes says nothing about the token.
+pub fn is_admin_token(app_state: &AppState, token: &str) -> bool {
+    app_state
+        .config
+        .admin_token
+        .as_deref()
+        .is_some_and(|admin_token| hash(admin_token) == hash(token))
+}
+
+/// Who sent a request: the holder of a bearer token, or someone signed in to the dashboard
+#[derive(Clone, Debug)]
+pub enum Caller {
+    Admin,
+    Key(ApiKey),
+    User(User),
+}
+
+impl Caller {
+    pub fn role(&self) -> Role {
+        match self {
+            Caller::Admin => Role::Admin,
+            Caller::Key(key) => key.role,
+            Caller::User(user) => user.role,
+        }
+    }
+
+    /// The links the caller sees and changes
+    pub fn scope(&self) -> Scope {
+        if self.role() == Role::Admin {
+            Scope::All
+        } else {
+            Scope::Owner(self.owner_id())
+        }
+    }
+
+    /// The user the links the caller creates belong to
+    pub fn owner_id(&self) -> Option<u64> {
+        match self {
+            Caller::Admin => None,
+            Caller::Key(key) => key.user_id,
+            Caller::User(user) => Some(user.id),
+        }
+    }
+
+    /// The links a request from `caller`, if it has a bearer token at all, sees. Without one
+    /// only the links of no user are public.
+    pub fn scope_of(caller: Option<&Caller>) -> Scope {
+        caller.map_or(Scope::Owner(None), Caller::scope)
+    }
+}
+
+/// The caller the bearer token of a request stands for
+pub(crate) async fn authenticate(
+    app_state: &AppState,
+    headers: &HeaderMap,
+) -> QrLinkResult<Caller> {
+    let token = bearer_token(headers)?;
+    if is_admin_token(app_state, token) {
+        return Ok(Caller::Admin);
+    }
+    app_state
+        .store
+        .find_api_key(&hash(token))
+        .await?
+        .map(Caller::Key)
+        .ok_or_else(|| Error::Unauthorized("unknown or revoked API key".into()))
+}
+
+/// Found by [require_api_key] on the routes behind it, and looked up on any others
+impl FromRequestParts<AppState> for Caller {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, app_state: &AppState) -> QrLinkResult<Self> {
+        match parts.extensions.get::<Caller>() {
+            Some(caller) => Ok(caller.clone()),
+            None => authenticate(app_state, &parts.headers).await,
+        }
+    }
+}
+
+/// `None` for requests without a bearer token, which public routes answer too
+impl OptionalFromRequestParts<AppState> for Caller {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        app_state: &AppState,
+    ) -> QrLinkResult<Option<Self>> {
+        if bearer_token(&parts.headers).is_err() {
+            return Ok(None);
+        }
+        <Caller as FromRequestParts<AppState>>::from_request_parts(parts, app_state)
+            .await
+            .map(Some)
+    }
+}
+
+/// What a handler needs the role of its caller to allow
+pub trait Permission {
+    const ROLE: Role;
+}
+
+/// Reading links and their stats
+pub struct Read;
+
+/// Creating, changing and deleting links
+pub struct Edit;
+
+/// Managing users, keys and settings
+pub struct Manage;
+
+impl Permission for Read {
+    const ROLE: Role = Role::Viewer;
+}
+
+impl Permission for Edit {
+    const ROLE: Role = Role::Editor;
+}
+
+impl Permission for Manage {
+    const ROLE: Role = Role::Admin;
+}
+
+/// A [Caller] whose role allows `P`, which handlers take to declare what they need
+pub struct Authorized<P> {
+    caller: Caller,
+    permission: PhantomData<P>,
+}
+
+impl<P> Deref for Authorized<P> {
+    type Target = Caller;
+
+    fn deref(&self) -> &Caller {
+        &self.caller
+    }
+}
+
+impl<P: Permission> FromRequestParts<AppState> for Authorized<P> {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, app_state: &AppState) -> QrLinkResult<Self> {
+        let caller =
+            <Caller as FromRequestParts<AppState>>::from_request_parts(parts, app_state).await?;
+        if caller.role() < P::ROLE {
+            return Err(Error::Forbidden(format!(
+                "the {} role is needed for this",
+                P::ROLE.as_str()
+            )));
+        }
+        Ok(Authorized {
+            caller,
+            permission: PhantomData,
+        })
+    }
+}
+
+/// Middleware letting a request through only with an unrevoked API key or the admin token,
+/// which handlers then take as a [Caller]. `OPTIONS` requests, which no handler takes and which
+/// only learn the methods a route allows, are let through without.
+pub async fn require_api_key(
+    State(app_state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> QrLinkResult<Response> {
+    if request.method() == Method::OPTIONS {
+        return Ok(next.run(request).await);
+    }
+    let caller = authenticate(&app_state, request.headers()).await?;
+    request.extensions_mut().insert(caller);
+    Ok(next.run(request).await)
+}
+
+/// What the `Idempotency-Key` header of a request is kept as: a hash of it and the caller's
+/// bearer token, so one caller's keys never find another caller's links
+pub fn idempotency_key(headers: &HeaderMap) -> QrLinkResult<Option<String>> {
+    let Some(value) = headers.get("idempotency-key") else {
+        return Ok(None);
+    };
+    let key = value
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|key| (1..=MAX_IDEMPOTENCY_KEY_LENGTH).contains(&key.len()))
+        .ok_or_else(|| {
+            Error::Validation(format!(
+                "Idempotency-Key must be 1 to {MAX_IDEMPOTENCY_KEY_LENGTH} visible characters"
+            ))
+        })?;
+    Ok(Some(hash(&format!("{}\n{key}", bearer_token(headers)?))))
+}
+
+/// Whether a request was sent from another site's page. Older browsers send session cookies
+/// along with any request to us, so a form elsewhere could otherwise change links.
+pub fn is_cross_site(request: &Request) -> bool {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    if let Some(site) = header(header::HeaderName::from_static("sec-fetch-site")) {
+        return !matches!(site, "same-origin" | "none");
+    }
+    let origin = header(header::ORIGIN).and_then(|origin| Url::parse(origin).ok());
+    match (origin, header(header::HOST)) {
+        (Some(origin), Some(host)) => {
+            let authority = match origin.port() {
+                Some(port) => format!("{}:{port}", origin.host_str().unwrap_or_default()),
+                None => origin.host_str().unwrap_or_default().to_owned(),
+            };
+            !authority.eq_ignore_ascii_case(host)
+        }
+        _ => false,
+    }
+}
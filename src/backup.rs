@@ -0,0 +1,280 @@
+//! Backups of a SQLite database: a snapshot is copied into `backups.directory` every
+//! `backups.interval_secs`, and whenever the admin asks for one, while the database stays in
+//! use. Snapshots are named after when they were taken, and only the newest `backups.keep` of
+//! them are kept.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use chrono::{DateTime, NaiveDateTime, SubsecRound, Utc};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use utoipa::ToSchema;
+
+use crate::AppState;
+use crate::auth::{Authorized, Manage};
+use crate::error::{Error, ErrorBody, QrLinkResult};
+use crate::store::LinkStore;
+
+/// Snapshots are named `qrlink-<when>.db`, e.g. `qrlink-20250101T030000Z.db`
+const FILE_PREFIX: &str = "qrlink-";
+const FILE_EXTENSION: &str = ".db";
+const TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Bytes read at a time while a snapshot is downloaded
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    /// Where snapshots are written; without it, no backups are made
+    pub directory: Option<PathBuf>,
+    /// Seconds between two scheduled snapshots; 0 only takes them when asked to
+    pub interval_secs: u64,
+    /// Snapshots kept, the oldest being deleted as new ones are taken
+    pub keep: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            directory: None,
+            interval_secs: 86400,
+            keep: 7,
+        }
+    }
+}
+
+/// A snapshot in the backup directory
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub file_name: String,
+    pub taken_at: DateTime<Utc>,
+    pub bytes: u64,
+}
+
+/// Takes snapshots of the database into the backup directory and rotates them
+pub struct Backups {
+    directory: PathBuf,
+    interval: Option<Duration>,
+    keep: usize,
+    /// Held while a snapshot is taken, so scheduled and requested ones don't overlap
+    taking: tokio::sync::Mutex<()>,
+}
+
+impl Backups {
+    /// Backups as `config` has them, unless it has no directory for them
+    pub fn new(config: &BackupConfig) -> Option<Self> {
+        let directory = config.directory.clone()?;
+        Some(Backups {
+            directory,
+            interval: (config.interval_secs > 0).then(|| Duration::from_secs(config.interval_secs)),
+            keep: config.keep,
+            taking: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Keeps taking snapshots of `store` once per interval, if there is one, until the process
+    /// exits. The first is taken an interval after starting, so restarts don't rotate out the
+    /// snapshots from before.
+    pub fn start(self: Arc<Self>, store: Arc<dyn LinkStore>) {
+        let Some(period) = self.interval else {
+            return;
+        };
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                match self.take(&*store).await {
+                    Ok(snapshot) => tracing::info!(
+                        file = snapshot.file_name,
+                        bytes = snapshot.bytes,
+                        "database backed up"
+                    ),
+                    Err(error) => tracing::warn!(%error, "database could not be backed up"),
+                }
+            }
+        });
+    }
+
+    /// Copies the database in `store` into a new snapshot, then deletes the oldest snapshots
+    /// beyond those kept
+    pub async fn take(&self, store: &dyn LinkStore) -> QrLinkResult<Snapshot> {
+        let _taking = self.taking.lock().await;
+        tokio::fs::create_dir_all(&self.directory)
+            .await
+            .map_err(Error::Io)?;
+        // To the second, as in the snapshot's name
+        let taken_at = Utc::now().trunc_subsecs(0);
+        let file_name = format!(
+            "{FILE_PREFIX}{}{FILE_EXTENSION}",
+            taken_at.format(TIME_FORMAT)
+        );
+        let path = self.directory.join(&file_name);
+        // Copied under another name first, so every snapshot by its own name is complete
+        let partial = self.directory.join(format!("{file_name}.partial"));
+        let _ = tokio::fs::remove_file(&partial).await;
+        if let Err(error) = store.backup(&partial).await {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(error);
+        }
+        tokio::fs::rename(&partial, &path)
+            .await
+            .map_err(Error::Io)?;
+        let bytes = tokio::fs::metadata(&path).await.map_err(Error::Io)?.len();
+
+        let snapshots = self.snapshots().await?;
+        let outdated = snapshots.len().saturating_sub(self.keep);
+        for snapshot in &snapshots[..outdated] {
+            tokio::fs::remove_file(&snapshot.path)
+                .await
+                .map_err(Error::Io)?;
+        }
+        Ok(Snapshot {
+            path,
+            file_name,
+            taken_at,
+            bytes,
+        })
+    }
+
+    /// The newest snapshot, if any has been taken
+    pub async fn latest(&self) -> QrLinkResult<Option<Snapshot>> {
+        Ok(self.snapshots().await?.pop())
+    }
+
+    /// The snapshots in the backup directory, oldest first, leaving out any other files
+    async fn snapshots(&self) -> QrLinkResult<Vec<Snapshot>> {
+        let mut entries = match tokio::fs::read_dir(&self.directory).await {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(Error::Io(error)),
+        };
+        let mut snapshots = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            let Ok(file_name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let taken_at = file_name
+                .strip_prefix(FILE_PREFIX)
+                .and_then(|name| name.strip_suffix(FILE_EXTENSION))
+                .and_then(|time| NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok());
+            let Some(taken_at) = taken_at else {
+                continue;
+            };
+            snapshots.push(Snapshot {
+                path: entry.path(),
+                file_name,
+                taken_at: taken_at.and_utc(),
+                bytes: entry.metadata().await.map_err(Error::Io)?.len(),
+            });
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.taken_at);
+        Ok(snapshots)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BackupInfo {
+    /// The snapshot's name in the backup directory
+    file_name: String,
+    taken_at: DateTime<Utc>,
+    bytes: u64,
+}
+
+impl From<Snapshot> for BackupInfo {
+    fn from(snapshot: Snapshot) -> Self {
+        BackupInfo {
+            file_name: snapshot.file_name,
+            taken_at: snapshot.taken_at,
+            bytes: snapshot.bytes,
+        }
+    }
+}
+
+/// POST /api/v1/admin/backup takes a snapshot of the database right away, rotating out the oldest
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/backup",
+    summary = "Back the database up (admin)",
+    responses(
+        (status = 201, body = BackupInfo),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "operations"
+)]
+pub(crate) async fn take_backup(
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+) -> QrLinkResult<(StatusCode, axum::Json<BackupInfo>)> {
+    let backups = app_state.backups.as_ref().ok_or(Error::NoBackup)?;
+    let snapshot = backups.take(&*app_state.store).await?;
+    tracing::info!(
+        file = snapshot.file_name,
+        bytes = snapshot.bytes,
+        "database backed up"
+    );
+    Ok((StatusCode::CREATED, axum::Json(snapshot.into())))
+}
+
+/// GET /api/v1/admin/backup downloads the newest snapshot of the database
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/backup",
+    summary = "Download the latest backup (admin)",
+    responses(
+        (status = 200, description = "The SQLite database file", body = Vec<u8>,
+            content_type = "application/vnd.sqlite3"),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+        (status = 404, description = "No backup has been made yet", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "operations"
+)]
+pub(crate) async fn download_backup(
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+) -> QrLinkResult<impl IntoResponse> {
+    let backups = app_state.backups.as_ref().ok_or(Error::NoBackup)?;
+    let snapshot = backups.latest().await?.ok_or(Error::NoBackup)?;
+    let file = tokio::fs::File::open(&snapshot.path)
+        .await
+        .map_err(Error::Io)?;
+    let chunks = stream::try_unfold(file, |mut file| async move {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let read = file.read(&mut chunk).await?;
+        chunk.truncate(read);
+        Ok::<_, std::io::Error>((read > 0).then(|| (Bytes::from(chunk), file)))
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/vnd.sqlite3".to_owned()),
+            (header::CONTENT_LENGTH, snapshot.bytes.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", snapshot.file_name),
+            ),
+        ],
+        Body::from_stream(chunks),
+    ))
+}
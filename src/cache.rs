@@ -0,0 +1,178 @@
+//! In-process caches in front of work that gives the same answer until a link changes
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use hashlink::LruCache;
+use sha2::{Digest, Sha256};
+
+use crate::destination;
+use crate::store::{Link, LinkTargets};
+
+/// A rendered QR code and the strong ETag its bytes hash to
+#[derive(Clone, Debug)]
+pub struct CachedQr {
+    pub body: Bytes,
+    pub etag: Arc<str>,
+}
+
+impl CachedQr {
+    pub fn new(body: Vec<u8>) -> Self {
+        let digest: String = Sha256::digest(&body)
+            .iter()
+            .take(16)
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        CachedQr {
+            body: body.into(),
+            etag: format!("\"{digest}\"").into(),
+        }
+    }
+
+    /// Whether an `If-None-Match` header names this code's ETag, so the client's copy is current
+    pub fn matches(&self, if_none_match: Option<&str>) -> bool {
+        if_none_match.is_some_and(|tags| {
+            tags.split(',')
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == &*self.etag)
+        })
+    }
+}
+
+/// The most recently requested QR codes, by link and by everything that went into drawing them
+pub struct QrCache {
+    entries: Mutex<LruCache<(u64, String), CachedQr>>,
+}
+
+impl QrCache {
+    /// A cache of up to `capacity` codes
+    pub fn new(capacity: usize) -> Self {
+        QrCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// The code drawn for `link_id` from `key`, if it is still cached
+    pub fn get(&self, link_id: u64, key: &str) -> Option<CachedQr> {
+        self.lock().get(&(link_id, key.to_owned())).cloned()
+    }
+
+    pub fn insert(&self, link_id: u64, key: String, qr: CachedQr) {
+        self.lock().insert((link_id, key), qr);
+    }
+
+    /// Drops every code drawn for a link, once it points elsewhere or is gone
+    pub fn invalidate(&self, link_id: u64) {
+        let mut entries = self.lock();
+        let stale: Vec<_> = entries
+            .iter()
+            .filter(|((id, _), _)| *id == link_id)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            entries.remove(&key);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LruCache<(u64, String), CachedQr>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Where an active link leads, as of when it was cached
+#[derive(Clone, Debug)]
+pub struct CachedRedirect {
+    pub link_id: u64,
+    pub owner_id: Option<u64>,
+    pub url: Arc<str>,
+    pub targets: Arc<LinkTargets>,
+    pub forward_query: bool,
+    pub forward_path: bool,
+    pub noindex: bool,
+    /// The link's UTM template, filled in
+    pub utm_query: Option<Arc<str>>,
+    /// Whether the link has a `max_clicks` every click counts toward
+    pub has_click_limit: bool,
+    expires_at: Option<DateTime<Utc>>,
+    cached_at: Instant,
+}
+
+impl CachedRedirect {
+    pub fn new(link: &Link, targets: LinkTargets) -> Self {
+        CachedRedirect {
+            link_id: link.id,
+            owner_id: link.owner_id,
+            url: link.url.as_str().into(),
+            targets: Arc::new(targets),
+            forward_query: link.forward_query,
+            forward_path: link.forward_path,
+            noindex: link.noindex,
+            utm_query: link
+                .utm_template
+                .as_deref()
+                .map(|template| destination::utm_query(template, link).into()),
+            has_click_limit: link.max_clicks.is_some(),
+            expires_at: link.expires_at,
+            cached_at: Instant::now(),
+        }
+    }
+}
+
+/// The destinations of recently followed links, by the code, slug or id they were followed by
+pub struct RedirectCache {
+    entries: Mutex<LruCache<String, CachedRedirect>>,
+    ttl: Duration,
+}
+
+impl RedirectCache {
+    /// A cache of up to `capacity` links, each trusted for `ttl` before it is looked up again
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        RedirectCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+        }
+    }
+
+    /// Where `external_id` leads, unless it isn't cached, has been for longer than the TTL or
+    /// has expired since
+    pub fn get(&self, external_id: &str) -> Option<CachedRedirect> {
+        let mut entries = self.lock();
+        let redirect = entries.get(external_id)?;
+        let expired = redirect
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now());
+        if expired || redirect.cached_at.elapsed() > self.ttl {
+            entries.remove(external_id);
+            return None;
+        }
+        Some(redirect.clone())
+    }
+
+    /// Remembers where a link, followed as `external_id`, leads; it should be active
+    pub fn insert(&self, external_id: &str, redirect: CachedRedirect) {
+        self.lock().insert(external_id.to_owned(), redirect);
+    }
+
+    /// Forgets a link under every id it was followed by, once it points elsewhere or is gone
+    pub fn invalidate(&self, link_id: u64) {
+        let mut entries = self.lock();
+        let stale: Vec<_> = entries
+            .iter()
+            .filter(|(_, redirect)| redirect.link_id == link_id)
+            .map(|(external_id, _)| external_id.clone())
+            .collect();
+        for external_id in stale {
+            entries.remove(&external_id);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LruCache<String, CachedRedirect>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -0,0 +1,289 @@
+//! Social cards: the 1200×630 image chat apps and social networks show for a link shared in
+//! them, with its QR code, its short link and its title on the colors `card` configures.
+//! Preview pages name it as their `og:image`.
+
+use std::path::PathBuf;
+use std::time::Instant;
+
+use ab_glyph::FontArc;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use image::{Rgba, RgbaImage, imageops};
+use serde::Deserialize;
+
+use crate::cache::CachedQr;
+use crate::error::{Error, ErrorBody, QrLinkResult};
+use crate::extract::Path;
+use crate::qr::{self, Color, ErrorCorrection, Format};
+use crate::{AppState, QrTarget, metrics, text};
+
+/// The size Open Graph asks images to be shared at
+pub const WIDTH: u32 = 1200;
+pub const HEIGHT: u32 = 630;
+
+/// Space kept clear around the edges and between the text and the code
+const MARGIN: u32 = 60;
+
+/// The white square the code is drawn on, as high as the card allows
+const PANEL: u32 = HEIGHT - 2 * MARGIN;
+const PANEL_RADIUS: u32 = 24;
+
+/// How wide the text beside the panel may run
+const TEXT_WIDTH: f32 = (WIDTH - PANEL - 3 * MARGIN) as f32;
+
+const TITLE_SIZE: f32 = 56.0;
+const TITLE_LINES: usize = 4;
+const SHORT_URL_SIZE: f32 = 44.0;
+const MIN_SHORT_URL_SIZE: f32 = 24.0;
+
+/// The bundled DejaVu Sans Bold, drawn without a `card.font`
+static DEFAULT_FONT: &[u8] = include_bytes!("../assets/DejaVuSans-Bold.ttf");
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CardConfig {
+    /// TrueType or OpenType font for the text, and the labels of framed PNG codes, instead of
+    /// the bundled DejaVu Sans Bold
+    pub font: Option<PathBuf>,
+    pub background: Color,
+    pub text: Color,
+}
+
+impl Default for CardConfig {
+    fn default() -> Self {
+        CardConfig {
+            font: None,
+            background: Color::rgb(0x09, 0x69, 0xda),
+            text: Color::WHITE,
+        }
+    }
+}
+
+/// Draws cards as `card` configures them, with its font read at start
+#[derive(Clone, Debug)]
+pub struct Cards {
+    font: FontArc,
+    background: Color,
+    text: Color,
+}
+
+impl Cards {
+    pub fn new(config: &CardConfig) -> QrLinkResult<Self> {
+        let font = match &config.font {
+            Some(path) => std::fs::read(path)
+                .map_err(|error| error.to_string())
+                .and_then(|font| FontArc::try_from_vec(font).map_err(|error| error.to_string()))
+                .map_err(|error| Error::Config(format!("card.font {}: {error}", path.display())))?,
+            None => FontArc::try_from_slice(DEFAULT_FONT).expect("the bundled font parses"),
+        };
+        Ok(Cards {
+            font,
+            background: config.background,
+            text: config.text,
+        })
+    }
+
+    /// The card of a link: `title` at the top left, if it has one, `short_url` at the bottom
+    /// left, and a code for `data` on the right, encoded as a PNG
+    pub fn render(
+        &self,
+        data: &str,
+        short_url: &str,
+        title: Option<&str>,
+    ) -> QrLinkResult<Vec<u8>> {
+        let code = qr::encode(data, ErrorCorrection::M, None)?;
+        let mut card = RgbaImage::from_pixel(WIDTH, HEIGHT, self.background.rgba());
+
+        let panel_x = WIDTH - MARGIN - PANEL;
+        draw_panel(&mut card, panel_x, MARGIN);
+        // The quiet zone is white like the panel, so modules are as large as it lets them be
+        let module = PANEL / (code.width() as u32 + 8);
+        let qr = code
+            .render::<Rgba<u8>>()
+            .module_dimensions(module, module)
+            .dark_color(Color::BLACK.rgba())
+            .light_color(Color::WHITE.rgba())
+            .build();
+        let offset = |start: u32, side: u32| i64::from(start + (PANEL - side) / 2);
+        imageops::overlay(
+            &mut card,
+            &qr,
+            offset(panel_x, qr.width()),
+            offset(MARGIN, qr.height()),
+        );
+
+        if let Some(title) = title {
+            let line_height = TITLE_SIZE * 1.25;
+            for (index, line) in self.wrap(title).iter().enumerate() {
+                let baseline = MARGIN as f32 + TITLE_SIZE + line_height * index as f32;
+                self.draw_text(&mut card, line, TITLE_SIZE, baseline);
+            }
+        }
+        let shown = short_url
+            .strip_prefix("https://")
+            .or_else(|| short_url.strip_prefix("http://"))
+            .unwrap_or(short_url);
+        let mut size = SHORT_URL_SIZE;
+        while size > MIN_SHORT_URL_SIZE && self.text_width(shown, size) > TEXT_WIDTH {
+            size -= 2.0;
+        }
+        let shown = self.fit(shown, size);
+        self.draw_text(&mut card, &shown, size, (HEIGHT - MARGIN) as f32);
+
+        qr::encode_raster(card, Format::Png, qr::DEFAULT_QUALITY)
+    }
+
+    /// `title` broken into lines that fit beside the panel, words that don't fit on a line of
+    /// their own broken anywhere, and the last line cut short with an ellipsis if there are more
+    fn wrap(&self, title: &str) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        let mut line = String::new();
+        for word in title.split_whitespace() {
+            let candidate = if line.is_empty() {
+                word.to_owned()
+            } else {
+                format!("{line} {word}")
+            };
+            if self.text_width(&candidate, TITLE_SIZE) <= TEXT_WIDTH {
+                line = candidate;
+                continue;
+            }
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            for ch in word.chars() {
+                line.push(ch);
+                if self.text_width(&line, TITLE_SIZE) > TEXT_WIDTH {
+                    line.pop();
+                    lines.push(std::mem::replace(&mut line, ch.to_string()));
+                }
+            }
+        }
+        if !line.is_empty() {
+            lines.push(line);
+        }
+        if lines.len() > TITLE_LINES {
+            lines.truncate(TITLE_LINES);
+            let last = format!("{}…", lines[TITLE_LINES - 1]);
+            lines[TITLE_LINES - 1] = self.fit(&last, TITLE_SIZE);
+        }
+        lines
+    }
+
+    fn text_width(&self, text: &str, size: f32) -> f32 {
+        text::width(&self.font, text, size)
+    }
+
+    /// `text`, or as much of it as fits beside the panel followed by an ellipsis
+    fn fit(&self, text: &str, size: f32) -> String {
+        text::fit(&self.font, text, size, TEXT_WIDTH)
+    }
+
+    /// Draws `text` from the left margin, sitting on `baseline`
+    fn draw_text(&self, card: &mut RgbaImage, text: &str, size: f32, baseline: f32) {
+        let origin = (MARGIN as f32, baseline);
+        text::draw(card, &self.font, text, size, origin, self.text);
+    }
+
+    /// The font text is drawn in, on cards and on the labels of framed QR codes
+    pub fn font(&self) -> &FontArc {
+        &self.font
+    }
+}
+
+/// Fills the white panel the code goes on, a `PANEL` square with rounded corners
+fn draw_panel(card: &mut RgbaImage, left: u32, top: u32) {
+    let radius = PANEL_RADIUS as f32;
+    for y in 0..PANEL {
+        for x in 0..PANEL {
+            // Distance into a corner's rounding, if the pixel is in one
+            let corner = |position: u32| {
+                let position = position as f32 + 0.5;
+                if position < radius {
+                    radius - position
+                } else if position > PANEL as f32 - radius {
+                    position - (PANEL as f32 - radius)
+                } else {
+                    0.0
+                }
+            };
+            let distance = corner(x).hypot(corner(y));
+            let coverage = (radius + 0.5 - distance).clamp(0.0, 1.0);
+            qr::blend(
+                card.get_pixel_mut(left + x, top + y),
+                Color::WHITE,
+                coverage,
+            );
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/{external_id}/card.png",
+    summary = "Return the social card of a link",
+    description = "A 1200×630 PNG with the link's QR code, its short link and its title, or \
+        that of the page it leads to, on the colors `card` configures: the `og:image` of its \
+        preview page, which chat apps and social networks show where the link is shared.",
+    params(("external_id" = String, Path, description = "Code or slug")),
+    responses(
+        (status = 200, description = "The card", content((Vec<u8> = "image/png"))),
+        (status = 304, description = "The card named in `If-None-Match` is still current"),
+        (status = 404, description = "No such link", body = ErrorBody),
+        (status = 422, description = "The link doesn't fit in a QR code", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody),
+    ),
+    tag = "qr"
+)]
+pub async fn get_card(
+    Path(external_id): Path<String>,
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+) -> QrLinkResult<Response> {
+    let link = app_state.store.resolve_active(&external_id).await?;
+    let data = crate::qr_payload(&app_state, &headers, &link, QrTarget::Short, false);
+    let short_url = crate::short_url(&app_state, &headers, &link.public_id());
+    let title = link.title.as_ref().or(link.page_title.as_ref());
+
+    // Cached with the link's codes, so that changing the link drops its card too
+    let key = format!("card {title:?} {data}");
+    let cached = app_state
+        .qr_cache
+        .as_ref()
+        .and_then(|cache| cache.get(link.id, &key));
+    let card = match cached {
+        Some(card) => card,
+        None => {
+            let cards = app_state.cards.clone();
+            let title = title.cloned();
+            let started = Instant::now();
+            let card = tokio::task::spawn_blocking(move || {
+                cards.render(&data, &short_url, title.as_deref())
+            })
+            .await
+            .map_err(Error::Task)??;
+            let card = CachedQr::new(card);
+            metrics::record_qr_render("card", started);
+            if let Some(cache) = &app_state.qr_cache {
+                cache.insert(link.id, key, card.clone());
+            }
+            card
+        }
+    };
+
+    let caching = [
+        (header::ETAG, card.etag.to_string()),
+        (
+            header::CACHE_CONTROL,
+            format!("public, max-age={}", app_state.config.qr.max_age_secs),
+        ),
+    ];
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
+    if card.matches(if_none_match) {
+        return Ok((StatusCode::NOT_MODIFIED, caching).into_response());
+    }
+    Ok(([(header::CONTENT_TYPE, "image/png")], caching, card.body).into_response())
+}
@@ -0,0 +1,281 @@
+//! Commands for managing links from a shell on the server, e.g. over SSH, rather than through the
+//! HTTP API. They open the configured database themselves, so they work whether or not the
+//! service is running, and act with the admin's rights on every link. Webhook events they cause
+//! are queued for the running service to deliver.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+use clap::Subcommand;
+use futures_util::StreamExt;
+
+use crate::config::Config;
+use crate::error::{Error, QrLinkResult};
+use crate::export::{self, ExportFormat, Include};
+use crate::qr::Format;
+use crate::sheet::{self, Layout};
+use crate::store::{Granularity, LinkFilter, Scope};
+use crate::{AppState, CreateUrlParams, QrQuery, QrTarget, SheetLink};
+
+/// Links `list` shows unless `--limit` says otherwise
+const DEFAULT_LIST_LIMIT: u32 = 50;
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Serve the service, which is also what happens without a command
+    Serve,
+    /// Apply pending database migrations and exit
+    Migrate,
+    /// Create a short link and print it
+    Create {
+        /// Where the link leads
+        url: String,
+        /// Custom slug to publish it under instead of a random code
+        #[arg(long)]
+        slug: Option<String>,
+        /// What the link is, for listings
+        #[arg(long)]
+        title: Option<String>,
+        /// When it stops redirecting, e.g. 2030-01-01T00:00:00Z
+        #[arg(long)]
+        expires_at: Option<DateTime<Utc>>,
+        /// Tag to organize it by; may be given more than once
+        #[arg(long)]
+        tag: Vec<String>,
+    },
+    /// List links, newest first, with their click counts
+    List {
+        #[arg(long, default_value_t = DEFAULT_LIST_LIMIT)]
+        limit: u32,
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+        /// Case-insensitive substring of the destination
+        #[arg(long)]
+        search: Option<String>,
+        /// Only links with this tag
+        #[arg(long)]
+        tag: Option<String>,
+        /// Only deleted links
+        #[arg(long)]
+        deleted: bool,
+    },
+    /// Delete a link by its code or slug; it can be restored through the API
+    Delete { id: String },
+    /// Write every link out, oldest first
+    Export {
+        #[arg(long, value_enum, default_value_t)]
+        format: ExportFormat,
+        /// Which columns to include: links, stats or both, comma-separated
+        #[arg(long, default_value = "links")]
+        include: Include,
+        /// File to write to instead of standard output
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Draw the QR code of a link into a file, in the format its extension names
+    Qr {
+        id: String,
+        /// e.g. code.png, code.svg, code.pdf or code.txt
+        #[arg(long)]
+        out: PathBuf,
+        /// Smallest width and height in pixels
+        #[arg(long)]
+        size: Option<u32>,
+        /// Draw the smallest code there is, for printing very small, like `compact=true`
+        #[arg(long)]
+        compact: bool,
+    },
+    /// Lay out the QR codes of many links on PDF pages for printing on sticker sheets, each
+    /// captioned with its slug or code
+    QrSheet {
+        /// Codes or slugs, each optionally followed by `=` and a caption to print instead
+        ids: Vec<String>,
+        /// Include every link with this tag, after those named
+        #[arg(long)]
+        tag: Option<String>,
+        /// e.g. stickers.pdf
+        #[arg(long)]
+        out: PathBuf,
+        #[command(flatten)]
+        layout: Layout,
+    },
+    /// Print the daily click statistics of a link as JSON
+    Stats { id: String },
+}
+
+/// Carries out `command` on the database `config` names
+pub async fn run(command: Command, config: Config) -> QrLinkResult<()> {
+    match command {
+        Command::Serve => return crate::run(config).await,
+        Command::Migrate => return crate::open_store(&config).await.map(drop),
+        _ => {}
+    }
+    let store = crate::open_store(&config).await?;
+    let app_state = AppState::new(config, store.clone())?;
+    app_state.webhooks.reload(&*store).await?;
+    // Without a request, short links can only be built from `base_url`
+    let headers = HeaderMap::new();
+    match command {
+        Command::Serve | Command::Migrate => unreachable!("handled above"),
+        Command::Create {
+            url,
+            slug,
+            title,
+            expires_at,
+            tag,
+        } => {
+            let params = CreateUrlParams {
+                url,
+                slug,
+                title,
+                expires_at,
+                tags: (!tag.is_empty()).then_some(tag),
+                ..CreateUrlParams::default()
+            };
+            let link = crate::create_link(&app_state, &headers, params, None, None).await?;
+            println!(
+                "{}",
+                crate::short_url(&app_state, &headers, &link.public_id())
+            );
+        }
+        Command::List {
+            limit,
+            offset,
+            search,
+            tag,
+            deleted,
+        } => {
+            let page = app_state
+                .store
+                .list(LinkFilter {
+                    deleted: Some(deleted),
+                    search,
+                    tag,
+                    scope: Scope::All,
+                    limit,
+                    offset,
+                    ..LinkFilter::default()
+                })
+                .await?;
+            let shown = page.links.len();
+            for summary in page.links {
+                let link = summary.link;
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    link.public_id(),
+                    summary.clicks,
+                    link.created_at.format("%Y-%m-%d"),
+                    link.url
+                );
+            }
+            eprintln!("{shown} of {} links", page.total);
+        }
+        Command::Delete { id } => crate::delete_link(&app_state, Scope::All, &id).await?,
+        Command::Export {
+            format,
+            include,
+            out,
+        } => {
+            let filter = LinkFilter {
+                scope: Scope::All,
+                ..LinkFilter::default()
+            };
+            let mut output: Box<dyn Write> = match &out {
+                Some(path) => Box::new(std::fs::File::create(path).map_err(Error::Io)?),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            let mut chunks =
+                export::stream(app_state.store.clone(), filter, format, include).into_data_stream();
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk.map_err(|error| Error::Io(std::io::Error::other(error)))?;
+                output.write_all(&chunk).map_err(Error::Io)?;
+            }
+            output.flush().map_err(Error::Io)?;
+        }
+        Command::Qr {
+            id,
+            out,
+            size,
+            compact,
+        } => {
+            let link = app_state.store.resolve_active(&id).await?;
+            let format = out
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .and_then(Format::from_extension)
+                .ok_or_else(|| {
+                    Error::Validation(format!(
+                        "{} should end in .png, .svg, .pdf, .txt, .jpg, .webp or .bmp",
+                        out.display()
+                    ))
+                })?;
+            let params = QrQuery {
+                size,
+                format: Some(format),
+                compact: Some(compact),
+                ..QrQuery::default()
+            };
+            let rendering = crate::qr_rendering(&app_state, &params, format)?;
+            let url = crate::qr_payload(
+                &app_state,
+                &headers,
+                &link,
+                QrTarget::Short,
+                rendering.compact,
+            );
+            std::fs::write(&out, rendering.render(&url)?).map_err(Error::Io)?;
+        }
+        Command::QrSheet {
+            ids,
+            tag,
+            out,
+            layout,
+        } => {
+            let mut links: Vec<SheetLink> = ids
+                .into_iter()
+                .map(|id| match id.split_once('=') {
+                    Some((id, label)) => SheetLink {
+                        id: id.to_owned(),
+                        label: Some(label.to_owned()),
+                    },
+                    None => SheetLink { id, label: None },
+                })
+                .collect();
+            if let Some(tag) = tag {
+                let page = app_state
+                    .store
+                    .list(LinkFilter {
+                        deleted: Some(false),
+                        tag: Some(tag),
+                        scope: Scope::All,
+                        limit: sheet::MAX_STICKERS as u32,
+                        ..LinkFilter::default()
+                    })
+                    .await?;
+                links.extend(page.links.into_iter().map(|summary| SheetLink {
+                    id: summary.link.public_id(),
+                    label: None,
+                }));
+            }
+            let pdf = crate::qr_sheet(
+                &app_state,
+                &headers,
+                Scope::All,
+                &QrQuery::default(),
+                links,
+                layout,
+            )
+            .await?;
+            std::fs::write(&out, pdf).map_err(Error::Io)?;
+        }
+        Command::Stats { id } => {
+            let link = app_state.store.resolve_active(&id).await?;
+            let stats = crate::link_statistics(&app_state, &link, Granularity::Day).await?;
+            let json = serde_json::to_string_pretty(&stats).expect("statistics serialize");
+            println!("{json}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+//! Buffered click logging: with `click_log.buffered` on, redirects hand their clicks to a
+//! queue instead of waiting for them to be written, and a background task writes them in
+//! batches, one transaction per batch. Clicks are then counted up to `flush_interval_ms` late,
+//! and stamped with when they were written rather than made.
+//!
+//! Clicks on links with a click limit are still written as they happen, since the redirect
+//! depends on how many there were. So are clicks that find the queue full, which
+//! `click_log_overflows_total` counts. Whatever is queued is written before the process exits.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::error::QrLinkResult;
+use crate::metrics;
+use crate::store::{Click, LinkStore};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClickLogConfig {
+    /// Whether clicks are queued and written in batches rather than one by one
+    pub buffered: bool,
+    /// Clicks the queue holds before redirects write their own
+    pub capacity: usize,
+    /// Most clicks written in one transaction
+    pub batch_size: usize,
+    /// Longest a click waits in the queue, in milliseconds
+    pub flush_interval_ms: u64,
+}
+
+impl Default for ClickLogConfig {
+    fn default() -> Self {
+        ClickLogConfig {
+            buffered: false,
+            capacity: 10_000,
+            batch_size: 500,
+            flush_interval_ms: 1000,
+        }
+    }
+}
+
+enum Message {
+    /// Boxed, so flushes and a queue of clicks take no more room than they need
+    Click(u64, Box<Click>),
+    /// Answered once every click queued before it is written
+    Flush(oneshot::Sender<()>),
+}
+
+/// The queue clicks wait in on their way to the database
+pub struct ClickLog {
+    sender: mpsc::Sender<Message>,
+}
+
+impl ClickLog {
+    /// Starts the task writing the queued clicks to `store`
+    pub fn start(config: &ClickLogConfig, store: Arc<dyn LinkStore>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.capacity);
+        let writer = Writer {
+            store,
+            batch: Vec::with_capacity(config.batch_size),
+            batch_size: config.batch_size,
+        };
+        tokio::spawn(writer.run(receiver, Duration::from_millis(config.flush_interval_ms)));
+        ClickLog { sender }
+    }
+
+    /// Queues a click on the link with `id`, or writes it to `store` right away if the queue
+    /// is full
+    pub async fn record(&self, store: &dyn LinkStore, id: u64, click: Click) -> QrLinkResult<()> {
+        let Err(error) = self.sender.try_send(Message::Click(id, Box::new(click))) else {
+            return Ok(());
+        };
+        match error.into_inner() {
+            Message::Click(id, click) => {
+                metrics::record_click_log_overflow();
+                store.record_clicks(vec![(id, *click)]).await
+            }
+            Message::Flush(_) => Ok(()),
+        }
+    }
+
+    /// Waits for every click queued so far to be written
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.sender.send(Message::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+/// Owns the receiving end of the queue and the batch being gathered from it
+struct Writer {
+    store: Arc<dyn LinkStore>,
+    batch: Vec<(u64, Click)>,
+    batch_size: usize,
+}
+
+impl Writer {
+    async fn run(mut self, mut receiver: mpsc::Receiver<Message>, flush_interval: Duration) {
+        // Ticking first a whole interval in, where `interval` would tick right away and write
+        // whatever few clicks came in before the writer got to run
+        let mut ticker = tokio::time::interval_at(Instant::now() + flush_interval, flush_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                message = receiver.recv() => match message {
+                    Some(Message::Click(id, click)) => {
+                        self.batch.push((id, *click));
+                        if self.batch.len() >= self.batch_size {
+                            self.write().await;
+                        }
+                    }
+                    Some(Message::Flush(done)) => {
+                        self.write().await;
+                        let _ = done.send(());
+                    }
+                    None => {
+                        self.write().await;
+                        return;
+                    }
+                },
+                _ = ticker.tick() => self.write().await,
+            }
+            metrics::set_click_log_queued(receiver.len());
+        }
+    }
+
+    /// Writes the clicks gathered so far in one transaction. Should that fail, they are lost,
+    /// rather than held on to while the database may stay out of reach.
+    async fn write(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(self.batch_size));
+        let count = batch.len();
+        match self.store.record_clicks(batch).await {
+            Ok(()) => metrics::record_click_log_written(count, true),
+            Err(error) => {
+                metrics::record_click_log_written(count, false);
+                tracing::error!(%error, count, "queued clicks could not be written");
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! The address of the client behind a request, which click records and rate limits go by.
+//! Behind a reverse proxy the connection comes from the proxy, which passes the client's
+//! address on in `Forwarded` or `X-Forwarded-For`, and the scheme the client used, which short
+//! links are built with without a `base_url`, in `X-Forwarded-Proto`. As anyone can send those
+//! headers, they are only believed from the peers in `trusted_proxies`, and from connections
+//! over Unix sockets.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use axum::extract::rejection::ExtensionRejection;
+use axum::extract::{ConnectInfo, Extension, FromRequestParts, Request, State};
+use axum::http::HeaderMap;
+use axum::http::request::Parts;
+use axum::middleware::Next;
+use axum::response::Response;
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::AppState;
+use crate::error::{Error, QrLinkResult};
+
+/// The networks of the proxies whose forwarding headers are believed
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "Vec<String>")]
+pub struct TrustedProxies(Vec<IpNet>);
+
+/// Proxies on the same host, which are trusted unless `trusted_proxies` says otherwise
+impl Default for TrustedProxies {
+    fn default() -> Self {
+        TrustedProxies(vec![
+            IpNet::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8).expect("8 is a valid prefix"),
+            IpNet::from(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        ])
+    }
+}
+
+/// Parses networks like `10.0.0.0/8`, or single addresses
+impl TryFrom<Vec<String>> for TrustedProxies {
+    type Error = Error;
+
+    fn try_from(proxies: Vec<String>) -> QrLinkResult<Self> {
+        proxies
+            .iter()
+            .map(|proxy| {
+                let proxy = proxy.trim();
+                proxy
+                    .parse()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| {
+                        Error::Config(format!(
+                            "trusted_proxies: '{proxy}' isn't an address or a network like \
+                             10.0.0.0/8"
+                        ))
+                    })
+            })
+            .collect::<QrLinkResult<_>>()
+            .map(TrustedProxies)
+    }
+}
+
+impl TrustedProxies {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|network| network.contains(&ip))
+    }
+}
+
+/// The header a proxy names the scheme the client used in
+pub(crate) const FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Marks connections whose peer is trusted whatever its address, like those over Unix sockets,
+/// which only a proxy on the same host can open
+#[derive(Clone, Copy)]
+pub(crate) struct TrustedPeer;
+
+/// The address of the client, as [resolve] found it
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = ExtensionRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, ExtensionRejection> {
+        let Extension(client_ip) = Extension::<ClientIp>::from_request_parts(parts, state).await?;
+        Ok(client_ip)
+    }
+}
+
+/// Middleware finding the client address of requests served with [ConnectInfo], for the
+/// routes and middleware inside it to extract as [ClientIp]
+pub async fn resolve(
+    State(app_state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(&ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let trusted = request.extensions().get::<TrustedPeer>().is_some()
+            || app_state.config.trusted_proxies.contains(peer.ip());
+        let ip = if trusted {
+            forwarded_for(request.headers(), &app_state.config.trusted_proxies)
+                .unwrap_or_else(|| peer.ip())
+        } else {
+            // Dropped so that nothing further in reads a scheme the client made up
+            request.headers_mut().remove(FORWARDED_PROTO);
+            peer.ip()
+        };
+        request.extensions_mut().insert(ClientIp(ip.to_canonical()));
+    }
+    next.run(request).await
+}
+
+/// The client address a trusted proxy passed on: the last one in the chain of proxies that
+/// isn't trusted itself, as anything before it could have been made up by the client
+fn forwarded_for(headers: &HeaderMap, trusted_proxies: &TrustedProxies) -> Option<IpAddr> {
+    let chain: Vec<IpAddr> = if headers.contains_key("forwarded") {
+        headers
+            .get_all("forwarded")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    name.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| forwarded_node(value))?
+                })
+            })
+            .collect()
+    } else {
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|address| address.trim().parse().ok())
+            .collect()
+    };
+    chain
+        .iter()
+        .rev()
+        .find(|ip| !trusted_proxies.contains(**ip))
+        .or(chain.first())
+        .copied()
+}
+
+/// The address in a `Forwarded` `for=` value, such as `192.0.2.60`, `"192.0.2.60:4711"` or
+/// `"[2001:db8::1]:4711"`; `None` for `unknown` and obfuscated ones
+fn forwarded_node(value: &str) -> Option<IpAddr> {
+    let node = value.trim().trim_matches('"');
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split(']').next()?.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|address| address.ip()))
+}
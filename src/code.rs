@@ -0,0 +1,51 @@
+use rand::Rng;
+
+use crate::error::{Error, QrLinkResult};
+
+pub static BASE62: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+pub const DEFAULT_LENGTH: usize = 7;
+
+/// Generates the random, non-sequential codes that links are published under
+#[derive(Clone, Debug)]
+pub struct CodeGenerator {
+    alphabet: Vec<char>,
+    length: usize,
+}
+
+impl CodeGenerator {
+    pub fn new(alphabet: &str, length: usize) -> QrLinkResult<Self> {
+        let alphabet: Vec<char> = alphabet.chars().collect();
+        if alphabet.len() < 2 {
+            return Err(Error::Validation(
+                "code alphabet needs at least two characters".into(),
+            ));
+        }
+        if alphabet
+            .iter()
+            .enumerate()
+            .any(|(i, c)| alphabet[..i].contains(c))
+        {
+            return Err(Error::Validation(
+                "code alphabet contains duplicate characters".into(),
+            ));
+        }
+        if length == 0 {
+            return Err(Error::Validation("code length must be positive".into()));
+        }
+        Ok(CodeGenerator { alphabet, length })
+    }
+
+    pub fn generate(&self) -> String {
+        let mut rng = rand::rng();
+        (0..self.length)
+            .map(|_| self.alphabet[rng.random_range(0..self.alphabet.len())])
+            .collect()
+    }
+}
+
+impl Default for CodeGenerator {
+    fn default() -> Self {
+        CodeGenerator::new(BASE62, DEFAULT_LENGTH).expect("base62 is a valid alphabet")
+    }
+}
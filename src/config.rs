@@ -0,0 +1,859 @@
+//! Settings, read from a TOML file and overridden by `QRLINK_*` environment variables and then
+//! by command-line flags. See `qrlink.example.toml` for every setting.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser};
+use serde::Deserialize;
+use url::Url;
+
+use crate::backup::BackupConfig;
+use crate::card::CardConfig;
+use crate::cli::Command;
+use crate::click_log::ClickLogConfig;
+use crate::client_ip::TrustedProxies;
+use crate::code::{BASE62, CodeGenerator, DEFAULT_LENGTH};
+use crate::cors::CorsConfig;
+use crate::destination::{self, DestinationPolicy};
+use crate::error::{Error, QrLinkResult};
+use crate::legacy_api::LegacyApiConfig;
+use crate::listen::{self, ListenAddr};
+use crate::logging::{LogConfig, LogFormat};
+use crate::oidc::OidcConfig;
+use crate::privacy::PrivacyConfig;
+use crate::quotas::QuotaConfig;
+use crate::rate_limit::{Quota, RateLimits};
+use crate::retention::RetentionConfig;
+use crate::security_headers::{SecurityHeaders, SecurityHeadersConfig};
+use crate::session::{MIN_SECRET_LENGTH, SessionConfig};
+use crate::site::SiteConfig;
+use crate::store::{self, sqlite::SqliteConfig};
+use crate::tls::TlsConfig;
+
+/// Read when no `--config` is given, if it exists
+pub static DEFAULT_CONFIG_PATH: &str = "qrlink.toml";
+
+/// Largest QR code, in pixels, that may be drawn or be the default size
+pub const MAX_QR_SIZE: u32 = 4096;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "A URL shortener that draws QR codes for its links")]
+pub struct Cli {
+    /// TOML file to read settings from [default: qrlink.toml, if present]
+    #[arg(long, global = true, env = "QRLINK_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Apply pending database migrations and exit, as the migrate command does
+    #[arg(long, hide = true)]
+    pub migrate_only: bool,
+
+    #[command(flatten)]
+    pub overrides: Overrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Settings that can be given on the command line or in the environment, taking precedence
+/// over the config file
+#[derive(Debug, Default, Args)]
+pub struct Overrides {
+    /// SQLite file, :memory:, or a postgres:// URL
+    #[arg(long, global = true, env = "QRLINK_DATABASE")]
+    pub database: Option<String>,
+
+    /// Addresses to serve on, comma-separated, each e.g. 0.0.0.0:3000 or
+    /// unix:/run/qrlink/http.sock
+    #[arg(long, global = true, env = "QRLINK_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<ListenAddr>,
+
+    /// Addresses to serve the API and dashboard on, apart from the public routes
+    #[arg(
+        long,
+        global = true,
+        env = "QRLINK_ADMIN_LISTEN",
+        value_delimiter = ','
+    )]
+    pub admin_listen: Vec<ListenAddr>,
+
+    /// Addresses to serve the gRPC API on, which is off without any
+    #[arg(long, global = true, env = "QRLINK_GRPC_LISTEN", value_delimiter = ',')]
+    pub grpc_listen: Vec<ListenAddr>,
+
+    /// Externally visible URL of the service, e.g. <https://s.example.org>
+    #[arg(long, global = true, env = "QRLINK_BASE_URL")]
+    pub base_url: Option<String>,
+
+    /// Proxies whose X-Forwarded-For and Forwarded headers are believed, comma-separated, each
+    /// an address or a network like 10.0.0.0/8
+    #[arg(
+        long,
+        global = true,
+        env = "QRLINK_TRUSTED_PROXIES",
+        value_delimiter = ','
+    )]
+    pub trusted_proxies: Vec<String>,
+
+    /// Token that can issue and revoke API keys
+    #[arg(
+        long,
+        global = true,
+        env = "QRLINK_ADMIN_TOKEN",
+        hide_env_values = true
+    )]
+    pub admin_token: Option<String>,
+
+    /// Google Safe Browsing API key, to quarantine links to flagged destinations
+    #[arg(
+        long,
+        global = true,
+        env = "QRLINK_SAFE_BROWSING_KEY",
+        hide_env_values = true
+    )]
+    pub safe_browsing_key: Option<String>,
+
+    /// MaxMind GeoLite2 Country or City database
+    #[arg(long, global = true, env = "QRLINK_GEOIP_DATABASE")]
+    pub geoip_database: Option<PathBuf>,
+
+    /// QR code size in pixels when a request doesn't ask for one
+    #[arg(long, global = true, env = "QRLINK_QR_DEFAULT_SIZE")]
+    pub qr_default_size: Option<u32>,
+
+    /// Image drawn in the middle of PNG QR codes that ask for it with ?logo=true
+    #[arg(long, global = true, env = "QRLINK_QR_LOGO")]
+    pub qr_logo: Option<PathBuf>,
+
+    /// Directory SQLite backups are written to
+    #[arg(long, global = true, env = "QRLINK_BACKUP_DIRECTORY")]
+    pub backup_directory: Option<PathBuf>,
+
+    /// Rendered QR codes kept in memory, 0 to render every request
+    #[arg(long, global = true, env = "QRLINK_QR_CACHE_CAPACITY")]
+    pub qr_cache_capacity: Option<usize>,
+
+    /// Seconds clients and proxies may reuse a QR code for, sent as Cache-Control max-age
+    #[arg(long, global = true, env = "QRLINK_QR_MAX_AGE")]
+    pub qr_max_age: Option<u64>,
+
+    /// Links whose destinations are kept in memory, 0 to look every redirect up
+    #[arg(long, global = true, env = "QRLINK_REDIRECT_CACHE_CAPACITY")]
+    pub redirect_cache_capacity: Option<usize>,
+
+    /// Seconds a cached destination is trusted before it is looked up again
+    #[arg(long, global = true, env = "QRLINK_REDIRECT_CACHE_TTL")]
+    pub redirect_cache_ttl: Option<u64>,
+
+    /// Link creations allowed per client, as REQUESTS/SECONDS
+    #[arg(long, global = true, env = "QRLINK_RATE_LIMIT_CREATE")]
+    pub rate_limit_create: Option<Quota>,
+
+    /// QR code renders allowed per client, as REQUESTS/SECONDS
+    #[arg(long, global = true, env = "QRLINK_RATE_LIMIT_QR")]
+    pub rate_limit_qr: Option<Quota>,
+
+    /// Redirects allowed per client, as REQUESTS/SECONDS
+    #[arg(long, global = true, env = "QRLINK_RATE_LIMIT_REDIRECT")]
+    pub rate_limit_redirect: Option<Quota>,
+
+    /// Whether redirects are recorded for /{id}/stats
+    #[arg(long, global = true, env = "QRLINK_CLICK_TRACKING")]
+    pub click_tracking: Option<bool>,
+
+    /// Whether /{id}/qr is served
+    #[arg(long, global = true, env = "QRLINK_QR_CODES")]
+    pub qr_codes: Option<bool>,
+
+    /// Whether /metrics is served
+    #[arg(long, global = true, env = "QRLINK_METRICS")]
+    pub metrics: Option<bool>,
+
+    /// Whether anyone can create links with the form at /app, without an API key
+    #[arg(long, global = true, env = "QRLINK_WEB_FORM")]
+    pub web_form: Option<bool>,
+
+    /// Secret session cookies for the dashboard are signed with, at least 32 bytes long
+    #[arg(
+        long,
+        global = true,
+        env = "QRLINK_SESSION_SECRET",
+        hide_env_values = true
+    )]
+    pub session_secret: Option<String>,
+
+    /// Client secret for signing in through the OpenID Connect provider
+    #[arg(
+        long,
+        global = true,
+        env = "QRLINK_OIDC_CLIENT_SECRET",
+        hide_env_values = true
+    )]
+    pub oidc_client_secret: Option<String>,
+
+    /// Secret the URLs of QR codes are signed with, at least 32 bytes long
+    #[arg(
+        long,
+        global = true,
+        env = "QRLINK_QR_SIGNING_KEY",
+        hide_env_values = true
+    )]
+    pub qr_signing_key: Option<String>,
+
+    /// PEM file with the certificate to serve HTTPS with, followed by any intermediates
+    #[arg(long, global = true, env = "QRLINK_TLS_CERTIFICATE")]
+    pub tls_certificate: Option<PathBuf>,
+
+    /// PEM file with the certificate's private key
+    #[arg(long, global = true, env = "QRLINK_TLS_PRIVATE_KEY")]
+    pub tls_private_key: Option<PathBuf>,
+
+    /// Which log events to write, e.g. info or warn,tower_http=debug
+    #[arg(long, global = true, env = "QRLINK_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// How log events are written
+    #[arg(long, global = true, env = "QRLINK_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: String,
+    #[serde(deserialize_with = "listen::one_or_many")]
+    pub listen: Vec<ListenAddr>,
+    #[serde(deserialize_with = "listen::one_or_many")]
+    pub admin_listen: Vec<ListenAddr>,
+    #[serde(deserialize_with = "listen::one_or_many")]
+    pub grpc_listen: Vec<ListenAddr>,
+    pub base_url: Option<Url>,
+    pub trusted_proxies: TrustedProxies,
+    pub admin_token: Option<String>,
+    pub geoip_database: Option<PathBuf>,
+    pub sqlite: SqliteConfig,
+    pub qr: QrConfig,
+    pub redirects: RedirectConfig,
+    pub requests: RequestConfig,
+    pub codes: CodeConfig,
+    pub destinations: DestinationPolicy,
+    pub rate_limits: RateLimits,
+    pub features: Features,
+    pub webhooks: WebhookConfig,
+    pub page_info: PageInfoConfig,
+    pub health_checks: HealthCheckConfig,
+    pub safety: SafetyConfig,
+    pub privacy: PrivacyConfig,
+    pub quotas: QuotaConfig,
+    pub click_log: ClickLogConfig,
+    pub retention: RetentionConfig,
+    pub sessions: SessionConfig,
+    pub oidc: OidcConfig,
+    pub cors: CorsConfig,
+    pub security_headers: SecurityHeadersConfig,
+    pub site: SiteConfig,
+    pub card: CardConfig,
+    pub legacy_api: LegacyApiConfig,
+    pub tls: TlsConfig,
+    pub backups: BackupConfig,
+    pub live: LiveConfig,
+    pub log: LogConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            database: "forum.db".into(),
+            listen: vec![ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], 3000)))],
+            admin_listen: Vec::new(),
+            grpc_listen: Vec::new(),
+            base_url: None,
+            trusted_proxies: TrustedProxies::default(),
+            admin_token: None,
+            geoip_database: None,
+            sqlite: SqliteConfig::default(),
+            qr: QrConfig::default(),
+            redirects: RedirectConfig::default(),
+            requests: RequestConfig::default(),
+            codes: CodeConfig::default(),
+            destinations: DestinationPolicy::default(),
+            rate_limits: RateLimits::default(),
+            features: Features::default(),
+            webhooks: WebhookConfig::default(),
+            page_info: PageInfoConfig::default(),
+            health_checks: HealthCheckConfig::default(),
+            safety: SafetyConfig::default(),
+            privacy: PrivacyConfig::default(),
+            quotas: QuotaConfig::default(),
+            click_log: ClickLogConfig::default(),
+            retention: RetentionConfig::default(),
+            sessions: SessionConfig::default(),
+            oidc: OidcConfig::default(),
+            cors: CorsConfig::default(),
+            security_headers: SecurityHeadersConfig::default(),
+            site: SiteConfig::default(),
+            card: CardConfig::default(),
+            legacy_api: LegacyApiConfig::default(),
+            tls: TlsConfig::default(),
+            backups: BackupConfig::default(),
+            live: LiveConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QrConfig {
+    pub default_size: u32,
+    pub logo: Option<PathBuf>,
+    /// Rendered codes kept in memory for repeat requests; 0 renders every request
+    pub cache_capacity: usize,
+    /// How long clients and proxies may reuse a code, in seconds
+    pub max_age_secs: u64,
+    /// Secret `/<id>/qr` URLs are signed with, at least 32 bytes long; with one, codes are only
+    /// drawn for URLs carrying its `sig`
+    pub signing_key: Option<String>,
+}
+
+impl Default for QrConfig {
+    fn default() -> Self {
+        QrConfig {
+            default_size: 300,
+            logo: None,
+            cache_capacity: 1000,
+            max_age_secs: 3600,
+            signing_key: None,
+        }
+    }
+}
+
+/// How much of the redirect path is served from memory
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedirectConfig {
+    /// Links whose destinations are kept in memory; 0 looks every redirect up
+    pub cache_capacity: usize,
+    /// Seconds a cached destination is trusted, bounding how long a change made by another
+    /// instance on the same database goes unnoticed
+    pub cache_ttl_secs: u64,
+    /// HTML page shown instead of the built-in one by links that aren't active yet
+    pub coming_soon_page: Option<PathBuf>,
+    /// Whether HEAD requests, which link-preview bots and uptime checks send, count as clicks
+    pub count_head_requests: bool,
+}
+
+impl Default for RedirectConfig {
+    fn default() -> Self {
+        RedirectConfig {
+            cache_capacity: 10_000,
+            cache_ttl_secs: 60,
+            coming_soon_page: None,
+            count_head_requests: false,
+        }
+    }
+}
+
+/// How long, how large and how many requests are taken on
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestConfig {
+    /// Seconds a request has to be answered in, body included, before it gets 408
+    pub timeout_secs: u64,
+    /// Largest request body accepted, in bytes, other than uploads
+    pub max_body_bytes: usize,
+    /// Largest image accepted by /qr/decode, in bytes
+    pub max_upload_bytes: usize,
+    /// Requests handled at once, beyond which more are turned away with 503
+    pub max_concurrent: usize,
+    /// Whether responses are gzip or brotli compressed for clients accepting it, e.g. not when
+    /// a proxy in front compresses them
+    pub compress: bool,
+}
+
+impl Default for RequestConfig {
+    fn default() -> Self {
+        RequestConfig {
+            timeout_secs: 30,
+            max_body_bytes: 1024 * 1024,
+            max_upload_bytes: 10 * 1024 * 1024,
+            max_concurrent: 1024,
+            compress: true,
+        }
+    }
+}
+
+/// The alphabet and length of generated link codes
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CodeConfig {
+    pub alphabet: String,
+    pub length: usize,
+}
+
+impl Default for CodeConfig {
+    fn default() -> Self {
+        CodeConfig {
+            alphabet: BASE62.into(),
+            length: DEFAULT_LENGTH,
+        }
+    }
+}
+
+/// Parts of the service that can be switched off
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    pub click_tracking: bool,
+    pub qr_codes: bool,
+    pub metrics: bool,
+    /// The page at /app where visitors without an API key can shorten links
+    pub web_form: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            click_tracking: true,
+            qr_codes: true,
+            metrics: true,
+            web_form: true,
+        }
+    }
+}
+
+/// How events are sent to webhooks
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Seconds an endpoint has to answer a delivery
+    pub timeout_secs: u64,
+    /// Attempts at a delivery before it is given up on
+    pub max_attempts: u32,
+    /// Seconds before the first retry, doubling with every further one
+    pub retry_base_secs: u64,
+    /// Whether endpoints may be plain http, e.g. on a private network
+    pub allow_http: bool,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            timeout_secs: 10,
+            max_attempts: 8,
+            retry_base_secs: 30,
+            allow_http: false,
+        }
+    }
+}
+
+/// Whether, and within what limits, the title and description of destination pages are fetched
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PageInfoConfig {
+    /// Whether new and repointed links have their destination page fetched in the background
+    pub fetch: bool,
+    /// Seconds a destination has to send its page
+    pub timeout_secs: u64,
+    /// Bytes read from the start of a page, where its title and description are
+    pub max_bytes: usize,
+    /// Whether pages on loopback, private and link-local addresses are fetched too, for links
+    /// within a network everyone creating them may read
+    pub allow_private_addresses: bool,
+}
+
+impl Default for PageInfoConfig {
+    fn default() -> Self {
+        PageInfoConfig {
+            fetch: false,
+            timeout_secs: 5,
+            max_bytes: 256 * 1024,
+            allow_private_addresses: false,
+        }
+    }
+}
+
+/// Whether, and how often, the destinations of active links are checked for whether they still
+/// answer
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    pub enabled: bool,
+    /// Seconds between two checks of the same destination
+    pub interval_secs: u64,
+    /// Seconds a destination has to answer before it counts as broken
+    pub timeout_secs: u64,
+    /// Whether destinations on loopback, private and link-local addresses are checked too,
+    /// rather than counted as broken
+    pub allow_private_addresses: bool,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            enabled: false,
+            interval_secs: 86_400,
+            timeout_secs: 10,
+            allow_private_addresses: false,
+        }
+    }
+}
+
+/// Where destinations are looked up for malware and phishing; off unless there is a blocklist
+/// or a Safe Browsing key
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafetyConfig {
+    /// File of domains whose links are quarantined, one per line
+    pub blocklist: Option<PathBuf>,
+    /// Google Safe Browsing API key, for destinations to be looked up there too
+    pub safe_browsing_key: Option<String>,
+    /// Where Safe Browsing lookups are sent
+    pub safe_browsing_url: Url,
+    /// Seconds between two checks of the same destination
+    pub recheck_interval_secs: u64,
+    /// Seconds Safe Browsing has to answer a lookup
+    pub timeout_secs: u64,
+}
+
+impl SafetyConfig {
+    pub fn enabled(&self) -> bool {
+        self.blocklist.is_some() || self.safe_browsing_key.is_some()
+    }
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        SafetyConfig {
+            blocklist: None,
+            safe_browsing_key: None,
+            safe_browsing_url: Url::parse(
+                "https://safebrowsing.googleapis.com/v4/threatMatches:find",
+            )
+            .expect("the Safe Browsing URL is valid"),
+            recheck_interval_secs: 86_400,
+            timeout_secs: 5,
+        }
+    }
+}
+
+/// How live statistics are pushed to dashboards
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LiveConfig {
+    /// Seconds between the counters pushed over /ws/stats
+    pub stats_interval_secs: u64,
+}
+
+impl Default for LiveConfig {
+    fn default() -> Self {
+        LiveConfig {
+            stats_interval_secs: 5,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file `cli` points at, applies its overrides and validates the result
+    pub fn load(cli: &Cli) -> QrLinkResult<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Config::default(),
+        };
+        config.apply(&cli.overrides)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> QrLinkResult<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| Error::Config(format!("can't read {}: {error}", path.display())))?;
+        toml::from_str(&text).map_err(|error| Error::Config(format!("{}: {error}", path.display())))
+    }
+
+    fn apply(&mut self, overrides: &Overrides) -> QrLinkResult<()> {
+        if let Some(database) = &overrides.database {
+            self.database = database.clone();
+        }
+        if !overrides.listen.is_empty() {
+            self.listen = overrides.listen.clone();
+        }
+        if !overrides.admin_listen.is_empty() {
+            self.admin_listen = overrides.admin_listen.clone();
+        }
+        if !overrides.grpc_listen.is_empty() {
+            self.grpc_listen = overrides.grpc_listen.clone();
+        }
+        if let Some(base_url) = &overrides.base_url {
+            let base_url = Url::parse(base_url)
+                .map_err(|error| Error::Config(format!("base_url '{base_url}': {error}")))?;
+            self.base_url = Some(base_url);
+        }
+        if !overrides.trusted_proxies.is_empty() {
+            self.trusted_proxies = overrides.trusted_proxies.clone().try_into()?;
+        }
+        if let Some(admin_token) = &overrides.admin_token {
+            self.admin_token = Some(admin_token.clone());
+        }
+        if let Some(secret) = &overrides.session_secret {
+            self.sessions.secret = Some(secret.clone());
+        }
+        if let Some(secret) = &overrides.oidc_client_secret {
+            self.oidc.client_secret = Some(secret.clone());
+        }
+        if let Some(key) = &overrides.qr_signing_key {
+            self.qr.signing_key = Some(key.clone());
+        }
+        if let Some(key) = &overrides.safe_browsing_key {
+            self.safety.safe_browsing_key = Some(key.clone());
+        }
+        if let Some(geoip_database) = &overrides.geoip_database {
+            self.geoip_database = Some(geoip_database.clone());
+        }
+        if let Some(certificate) = &overrides.tls_certificate {
+            self.tls.certificate = Some(certificate.clone());
+        }
+        if let Some(private_key) = &overrides.tls_private_key {
+            self.tls.private_key = Some(private_key.clone());
+        }
+        if let Some(directory) = &overrides.backup_directory {
+            self.backups.directory = Some(directory.clone());
+        }
+        if let Some(size) = overrides.qr_default_size {
+            self.qr.default_size = size;
+        }
+        if let Some(logo) = &overrides.qr_logo {
+            self.qr.logo = Some(logo.clone());
+        }
+        if let Some(capacity) = overrides.qr_cache_capacity {
+            self.qr.cache_capacity = capacity;
+        }
+        if let Some(max_age) = overrides.qr_max_age {
+            self.qr.max_age_secs = max_age;
+        }
+        if let Some(capacity) = overrides.redirect_cache_capacity {
+            self.redirects.cache_capacity = capacity;
+        }
+        if let Some(ttl) = overrides.redirect_cache_ttl {
+            self.redirects.cache_ttl_secs = ttl;
+        }
+        if let Some(quota) = overrides.rate_limit_create {
+            self.rate_limits.create = quota;
+        }
+        if let Some(quota) = overrides.rate_limit_qr {
+            self.rate_limits.qr = quota;
+        }
+        if let Some(quota) = overrides.rate_limit_redirect {
+            self.rate_limits.redirect = quota;
+        }
+        if let Some(enabled) = overrides.click_tracking {
+            self.features.click_tracking = enabled;
+        }
+        if let Some(enabled) = overrides.qr_codes {
+            self.features.qr_codes = enabled;
+        }
+        if let Some(enabled) = overrides.metrics {
+            self.features.metrics = enabled;
+        }
+        if let Some(enabled) = overrides.web_form {
+            self.features.web_form = enabled;
+        }
+        if let Some(level) = &overrides.log_level {
+            self.log.level = level.clone();
+        }
+        if let Some(format) = overrides.log_format {
+            self.log.format = format;
+        }
+        Ok(())
+    }
+
+    /// Checks the settings that can be wrong without failing to parse
+    pub fn validate(&self) -> QrLinkResult<()> {
+        if self.database.trim().is_empty() {
+            return Err(Error::Config("database must not be empty".into()));
+        }
+        if self.listen.is_empty() {
+            return Err(Error::Config("listen needs at least one address".into()));
+        }
+        if let Some(address) = self
+            .admin_listen
+            .iter()
+            .find(|address| self.listen.contains(address))
+        {
+            return Err(Error::Config(format!(
+                "{address} can't be in both listen and admin_listen"
+            )));
+        }
+        if let Some(address) = self
+            .grpc_listen
+            .iter()
+            .find(|address| self.listen.contains(address) || self.admin_listen.contains(address))
+        {
+            return Err(Error::Config(format!(
+                "{address} can't be in grpc_listen and also listen or admin_listen"
+            )));
+        }
+        if let Some(base_url) = &self.base_url {
+            if !matches!(base_url.scheme(), "http" | "https") || base_url.host().is_none() {
+                return Err(Error::Config(format!(
+                    "base_url '{base_url}' must be an http or https URL with a host"
+                )));
+            }
+            if base_url.query().is_some() || base_url.fragment().is_some() {
+                return Err(Error::Config(format!(
+                    "base_url '{base_url}' must not have a query or fragment"
+                )));
+            }
+        }
+        if self.admin_token.as_deref().is_some_and(str::is_empty) {
+            return Err(Error::Config(
+                "admin_token must not be empty; leave it out to disable the admin endpoints".into(),
+            ));
+        }
+        if !(1..=MAX_QR_SIZE).contains(&self.qr.default_size) {
+            return Err(Error::Config(format!(
+                "qr.default_size must be between 1 and {MAX_QR_SIZE}"
+            )));
+        }
+        if self.webhooks.timeout_secs == 0 || self.webhooks.max_attempts == 0 {
+            return Err(Error::Config(
+                "webhooks.timeout_secs and webhooks.max_attempts must be at least 1".into(),
+            ));
+        }
+        if self.page_info.timeout_secs == 0 || self.page_info.max_bytes == 0 {
+            return Err(Error::Config(
+                "page_info.timeout_secs and page_info.max_bytes must be at least 1".into(),
+            ));
+        }
+        if self.health_checks.interval_secs == 0 || self.health_checks.timeout_secs == 0 {
+            return Err(Error::Config(
+                "health_checks.interval_secs and health_checks.timeout_secs must be at least 1"
+                    .into(),
+            ));
+        }
+        if self.safety.recheck_interval_secs == 0 || self.safety.timeout_secs == 0 {
+            return Err(Error::Config(
+                "safety.recheck_interval_secs and safety.timeout_secs must be at least 1".into(),
+            ));
+        }
+        if !matches!(self.safety.safe_browsing_url.scheme(), "http" | "https") {
+            return Err(Error::Config(
+                "safety.safe_browsing_url must be an http or https URL".into(),
+            ));
+        }
+        if self.click_log.capacity == 0
+            || self.click_log.batch_size == 0
+            || self.click_log.flush_interval_ms == 0
+        {
+            return Err(Error::Config(
+                "click_log.capacity, click_log.batch_size and click_log.flush_interval_ms must \
+                be at least 1"
+                    .into(),
+            ));
+        }
+        if self.quotas.active_links == Some(0) || self.quotas.links_per_day == Some(0) {
+            return Err(Error::Config(
+                "quotas.active_links and quotas.links_per_day must be at least 1, or left out \
+                for no limit"
+                    .into(),
+            ));
+        }
+        if self.retention.interval_secs == 0 {
+            return Err(Error::Config(
+                "retention.interval_secs must be at least 1".into(),
+            ));
+        }
+        if self
+            .sessions
+            .secret
+            .as_ref()
+            .is_some_and(|secret| secret.len() < MIN_SECRET_LENGTH)
+        {
+            return Err(Error::Config(format!(
+                "sessions.secret must be at least {MIN_SECRET_LENGTH} bytes long"
+            )));
+        }
+        if self
+            .qr
+            .signing_key
+            .as_ref()
+            .is_some_and(|key| key.len() < MIN_SECRET_LENGTH)
+        {
+            return Err(Error::Config(format!(
+                "qr.signing_key must be at least {MIN_SECRET_LENGTH} bytes long"
+            )));
+        }
+        if self.sessions.ttl_hours == 0 {
+            return Err(Error::Config(
+                "sessions.ttl_hours must be at least 1".into(),
+            ));
+        }
+        if self.oidc.issuer.is_some() {
+            if self.base_url.is_none() {
+                return Err(Error::Config(
+                    "oidc needs base_url, which the provider sends people back to".into(),
+                ));
+            }
+            if self.oidc.client_id.is_empty() || self.oidc.timeout_secs == 0 {
+                return Err(Error::Config(
+                    "oidc.client_id must be set and oidc.timeout_secs at least 1".into(),
+                ));
+            }
+        }
+        if self.backups.directory.is_some() && store::is_postgres(&self.database) {
+            return Err(Error::Config(
+                "backups.directory only applies to SQLite databases, use pg_dump for Postgres"
+                    .into(),
+            ));
+        }
+        if self.backups.keep == 0 {
+            return Err(Error::Config("backups.keep must be at least 1".into()));
+        }
+        if self.requests.timeout_secs == 0
+            || self.requests.max_body_bytes == 0
+            || self.requests.max_upload_bytes == 0
+            || self.requests.max_concurrent == 0
+        {
+            return Err(Error::Config(
+                "requests.timeout_secs, requests.max_body_bytes, requests.max_upload_bytes and \
+                requests.max_concurrent must be at least 1"
+                    .into(),
+            ));
+        }
+        if self.live.stats_interval_secs == 0 {
+            return Err(Error::Config(
+                "live.stats_interval_secs must be at least 1".into(),
+            ));
+        }
+        self.code_generator()?;
+        self.log.filter()?;
+        self.cors.layer()?;
+        SecurityHeaders::new(&self.security_headers)?;
+        self.tls.validate(self.base_url.as_ref())?;
+        if self.destinations.allowed_schemes.is_empty() {
+            return Err(Error::Config(
+                "destinations.allowed_schemes must name at least one scheme".into(),
+            ));
+        }
+        for domain in self
+            .destinations
+            .allowed_domains
+            .iter()
+            .chain(&self.destinations.denied_domains)
+        {
+            destination::domain_pattern(domain)
+                .map_err(|message| Error::Config(format!("destinations: {message}")))?;
+        }
+        Ok(())
+    }
+
+    pub fn code_generator(&self) -> QrLinkResult<CodeGenerator> {
+        CodeGenerator::new(&self.codes.alphabet, self.codes.length).map_err(|error| match error {
+            Error::Validation(message) => Error::Config(format!("codes: {message}")),
+            error => error,
+        })
+    }
+}
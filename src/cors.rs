@@ -0,0 +1,123 @@
+//! Cross-origin resource sharing, which lets frontends served from other sites call the API
+//! from the browser. It is off until `cors.allowed_origins` names an origin.
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use serde::Deserialize;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+use url::Url;
+
+use crate::error::{Error, QrLinkResult};
+
+/// Allows any origin, method or header in place of a list of them
+const ANY: &str = "*";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins such as `https://app.example.org` whose pages may call the API, or `*` for any
+    pub allowed_origins: Vec<String>,
+    /// Methods those pages may call it with, or `*` for any
+    pub allowed_methods: Vec<String>,
+    /// Request headers they may send, or `*` for any
+    pub allowed_headers: Vec<String>,
+    /// Whether they may send cookies and `Authorization` headers the browser keeps for us,
+    /// which rules out `*` in the lists above
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PATCH", "DELETE"].map(str::to_owned).into(),
+            allowed_headers: ["authorization", "content-type", "idempotency-key"]
+                .map(str::to_owned)
+                .into(),
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// The layer answering preflight requests and marking responses as `cors` says, unless no
+    /// origin is allowed
+    pub fn layer(&self) -> QrLinkResult<Option<CorsLayer>> {
+        if self.allowed_origins.is_empty() {
+            return Ok(None);
+        }
+        let any = |list: &[String]| list.iter().any(|item| item == ANY);
+        if self.allow_credentials
+            && (any(&self.allowed_origins)
+                || any(&self.allowed_methods)
+                || any(&self.allowed_headers))
+        {
+            return Err(Error::Config(
+                "cors.allow_credentials can't be combined with \"*\"".into(),
+            ));
+        }
+
+        let origins = if any(&self.allowed_origins) {
+            AllowOrigin::any()
+        } else {
+            let origins = self
+                .allowed_origins
+                .iter()
+                .map(|origin| parse_origin(origin))
+                .collect::<QrLinkResult<Vec<_>>>()?;
+            AllowOrigin::list(origins)
+        };
+        let methods = if any(&self.allowed_methods) {
+            AllowMethods::any()
+        } else {
+            let methods = self
+                .allowed_methods
+                .iter()
+                .map(|method| {
+                    Method::from_bytes(method.trim().to_uppercase().as_bytes()).map_err(|_| {
+                        Error::Config(format!("cors.allowed_methods: '{method}' isn't a method"))
+                    })
+                })
+                .collect::<QrLinkResult<Vec<_>>>()?;
+            AllowMethods::list(methods)
+        };
+        let headers = if any(&self.allowed_headers) {
+            AllowHeaders::any()
+        } else {
+            let headers = self
+                .allowed_headers
+                .iter()
+                .map(|header| {
+                    HeaderName::from_bytes(header.trim().as_bytes()).map_err(|_| {
+                        Error::Config(format!(
+                            "cors.allowed_headers: '{header}' isn't a header name"
+                        ))
+                    })
+                })
+                .collect::<QrLinkResult<Vec<_>>>()?;
+            AllowHeaders::list(headers)
+        };
+        Ok(Some(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods(methods)
+                .allow_headers(headers)
+                .allow_credentials(self.allow_credentials),
+        ))
+    }
+}
+
+/// `origin` as browsers send it in `Origin` headers: a scheme and host, and a port unless it is
+/// the scheme's own, with nothing after
+fn parse_origin(origin: &str) -> QrLinkResult<HeaderValue> {
+    let invalid = || {
+        Error::Config(format!(
+            "cors.allowed_origins: '{origin}' isn't an origin like https://app.example.org"
+        ))
+    };
+    let url = Url::parse(origin.trim()).map_err(|_| invalid())?;
+    let serialized = url.origin().ascii_serialization();
+    if serialized != origin.trim().trim_end_matches('/') {
+        return Err(invalid());
+    }
+    HeaderValue::from_str(&serialized).map_err(|_| invalid())
+}
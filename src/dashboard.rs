@@ -0,0 +1,167 @@
+//! The dashboard at /admin, for people who would rather not build a frontend on the API.
+//! Browsers sign in at /login, and see the links of the user they signed in as, or every link
+//! when signed in as an admin; see [crate::session]. Viewers see links but can't change them.
+
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Redirect, Response};
+use chrono::Utc;
+use maud::Markup;
+use serde::Deserialize;
+
+use crate::auth::{Authorized, Caller, Edit, Read};
+use crate::error::QrLinkResult;
+use crate::extract::{Form, Path, Query};
+use crate::store::{Granularity, LinkFilter, LinkSort, Role, SortOrder};
+use crate::{AppState, UpdateUrlParams, delete_link, pages, short_url, update_link};
+
+/// Links listed per page
+const PAGE_SIZE: u32 = 50;
+
+/// Days of clicks each link's sparkline covers, today included
+pub const RECENT_DAYS: usize = 14;
+
+/// GET /admin?q=...&page=2 lists the links whose destinations contain `q`, newest first
+#[derive(Deserialize)]
+pub struct DashboardQuery {
+    #[serde(default)]
+    q: String,
+    page: Option<u32>,
+}
+
+pub async fn get_dashboard(
+    State(app_state): State<AppState>,
+    caller: Authorized<Read>,
+    headers: HeaderMap,
+    Query(query): Query<DashboardQuery>,
+) -> QrLinkResult<Markup> {
+    dashboard(&app_state, &caller, &headers, &query, None).await
+}
+
+/// The page of the dashboard `query` asks for, of the links `caller` sees, with the reason a
+/// change failed if one did
+async fn dashboard(
+    app_state: &AppState,
+    caller: &Caller,
+    headers: &HeaderMap,
+    query: &DashboardQuery,
+    error: Option<String>,
+) -> QrLinkResult<Markup> {
+    let search = query.q.trim();
+    let page = query.page.unwrap_or(1).max(1);
+    let listed = app_state
+        .store
+        .list(LinkFilter {
+            deleted: Some(false),
+            search: (!search.is_empty()).then(|| search.to_owned()),
+            sort: LinkSort::CreatedAt,
+            order: SortOrder::Desc,
+            limit: PAGE_SIZE,
+            offset: (page - 1).saturating_mul(PAGE_SIZE),
+            scope: caller.scope(),
+            ..Default::default()
+        })
+        .await?;
+
+    let today = Utc::now().date_naive();
+    let mut links = Vec::with_capacity(listed.links.len());
+    for summary in listed.links {
+        let link = summary.link;
+        let stats = app_state.store.stats(link.id, Granularity::Day).await?;
+        let mut recent_clicks = vec![0; RECENT_DAYS];
+        for bucket in stats.buckets {
+            let days_ago = (today - bucket.start.date_naive()).num_days();
+            if (0..RECENT_DAYS as i64).contains(&days_ago) {
+                recent_clicks[RECENT_DAYS - 1 - days_ago as usize] += bucket.clicks;
+            }
+        }
+        let public_id = link.public_id();
+        links.push(pages::DashboardLink {
+            short_url: short_url(app_state, headers, &public_id),
+            public_id,
+            title: link.title.or(link.page_title),
+            url: link.url,
+            created_at: link.created_at,
+            clicks: summary.clicks,
+            recent_clicks,
+        });
+    }
+
+    Ok(pages::dashboard(&pages::Dashboard {
+        search,
+        page,
+        pages: listed.total.div_ceil(PAGE_SIZE.into()).max(1) as u32,
+        total: listed.total,
+        error,
+        editable: caller.role() >= Role::Editor,
+        links,
+    }))
+}
+
+/// POST /admin/links/<id> with a new `url` repoints a link, and POST /admin/links/<id>/delete
+/// deletes it; both go back to the page of the list they were sent from
+#[derive(Deserialize)]
+pub struct LinkChange {
+    #[serde(default)]
+    url: String,
+    // Not a flattened DashboardQuery, which form bodies can't fill in numbers of
+    #[serde(default)]
+    q: String,
+    page: Option<u32>,
+}
+
+pub async fn update_from_dashboard(
+    State(app_state): State<AppState>,
+    caller: Authorized<Edit>,
+    headers: HeaderMap,
+    Path(external_id): Path<String>,
+    Form(change): Form<LinkChange>,
+) -> QrLinkResult<Response> {
+    let params = UpdateUrlParams {
+        url: Some(change.url),
+        ..Default::default()
+    };
+    let updated = update_link(&app_state, &headers, caller.scope(), &external_id, params).await;
+    let back = DashboardQuery {
+        q: change.q,
+        page: change.page,
+    };
+    back_to_dashboard(&app_state, &caller, &headers, &back, updated.map(drop)).await
+}
+
+pub async fn delete_from_dashboard(
+    State(app_state): State<AppState>,
+    caller: Authorized<Edit>,
+    headers: HeaderMap,
+    Path(external_id): Path<String>,
+    Form(back): Form<DashboardQuery>,
+) -> QrLinkResult<Response> {
+    let deleted = delete_link(&app_state, caller.scope(), &external_id).await;
+    back_to_dashboard(&app_state, &caller, &headers, &back, deleted).await
+}
+
+/// Sends the browser back to the list after a change, or shows the list with why the change
+/// was refused
+async fn back_to_dashboard(
+    app_state: &AppState,
+    caller: &Caller,
+    headers: &HeaderMap,
+    back: &DashboardQuery,
+    changed: QrLinkResult<()>,
+) -> QrLinkResult<Response> {
+    match changed {
+        Ok(()) => {
+            let query: String = url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("q", &back.q)
+                .append_pair("page", &back.page.unwrap_or(1).to_string())
+                .finish();
+            Ok(Redirect::to(&format!("/admin?{query}")).into_response())
+        }
+        Err(error) if error.status_code().is_client_error() => {
+            let status = error.status_code();
+            let page = dashboard(app_state, caller, headers, back, Some(error.to_string())).await?;
+            Ok((status, page).into_response())
+        }
+        Err(error) => Err(error),
+    }
+}
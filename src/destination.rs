@@ -0,0 +1,196 @@
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::error::{Error, QrLinkResult};
+use crate::store::Link;
+
+pub const DEFAULT_MAX_LENGTH: usize = 2048;
+
+/// The longest UTM template a link can have
+const MAX_UTM_TEMPLATE_LENGTH: usize = 512;
+
+/// What UTM templates can have filled in: the slug, which is the code for links without one,
+/// and the code
+const UTM_PLACEHOLDERS: [&str; 2] = ["{slug}", "{code}"];
+
+/// Rules a URL must satisfy before the service agrees to redirect to it
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DestinationPolicy {
+    pub allowed_schemes: Vec<String>,
+    pub max_length: usize,
+    /// Domains destinations must be on, if any are given: exact domains, or `*.example.com`
+    /// for any host under `example.com`
+    pub allowed_domains: Vec<String>,
+    /// Domains destinations may not be on, even when also allowed
+    pub denied_domains: Vec<String>,
+}
+
+impl Default for DestinationPolicy {
+    fn default() -> Self {
+        DestinationPolicy {
+            allowed_schemes: vec!["http".into(), "https".into()],
+            max_length: DEFAULT_MAX_LENGTH,
+            allowed_domains: Vec::new(),
+            denied_domains: Vec::new(),
+        }
+    }
+}
+
+impl DestinationPolicy {
+    /// Parses and normalizes `raw`, lowercasing and punycoding the host.
+    ///
+    /// `own_host` is the host the service is reached under; destinations pointing there are
+    /// refused so a link can never redirect to another short link and loop.
+    pub fn normalize(&self, raw: &str, own_host: Option<&str>) -> QrLinkResult<Url> {
+        let raw = raw.trim();
+        if raw.len() > self.max_length {
+            return Err(Error::Validation(format!(
+                "url is longer than {} characters",
+                self.max_length
+            )));
+        }
+        let url = Url::parse(raw)
+            .map_err(|error| Error::Validation(format!("url is not valid: {}", error)))?;
+        if !self
+            .allowed_schemes
+            .iter()
+            .any(|scheme| scheme == url.scheme())
+        {
+            return Err(Error::Validation(format!(
+                "url scheme '{}' is not allowed, use one of: {}",
+                url.scheme(),
+                self.allowed_schemes.join(", ")
+            )));
+        }
+        let Some(host) = url.host_str() else {
+            return Err(Error::Validation("url must have a host".into()));
+        };
+        if own_host.is_some_and(|own_host| strip_port(own_host).eq_ignore_ascii_case(host)) {
+            return Err(Error::Validation("url points back at this service".into()));
+        }
+        self.check_domain(host)?;
+        Ok(url)
+    }
+
+    /// Refuses `host` if it is denied, or if domains are allowed and it isn't one of them
+    fn check_domain(&self, host: &str) -> QrLinkResult<()> {
+        let host = host.trim_end_matches('.');
+        let listed = |domains: &[String]| {
+            domains
+                .iter()
+                .filter_map(|domain| domain_pattern(domain).ok())
+                .any(|domain| domain_matches(&domain, host))
+        };
+        if listed(&self.denied_domains)
+            || (!self.allowed_domains.is_empty() && !listed(&self.allowed_domains))
+        {
+            return Err(Error::DomainNotAllowed(host.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `host` is `domain`, or, for a `*.example.com` domain, any host under `example.com`
+/// but not `example.com` itself
+fn domain_matches(domain: &str, host: &str) -> bool {
+    match domain.strip_prefix("*.") {
+        Some(suffix) => host.len().checked_sub(suffix.len() + 1).is_some_and(|dot| {
+            host.as_bytes()[dot] == b'.' && host[dot + 1..].eq_ignore_ascii_case(suffix)
+        }),
+        None => host.eq_ignore_ascii_case(domain),
+    }
+}
+
+/// `domain` as it is matched against hosts: lowercased and punycoded, keeping a leading `*.`,
+/// or an error saying what is wrong with it
+pub fn domain_pattern(domain: &str) -> Result<String, String> {
+    let (wildcard, name) = match domain.trim().strip_prefix("*.") {
+        Some(name) => ("*.", name),
+        None => ("", domain.trim()),
+    };
+    match url::Host::parse(name) {
+        Ok(url::Host::Domain(name)) if !name.contains('*') => Ok(format!("{wildcard}{name}")),
+        Ok(host) if wildcard.is_empty() => Ok(host.to_string()),
+        _ => Err(format!("'{domain}' is not a domain or a *.domain wildcard")),
+    }
+}
+
+/// The hash links created with `dedupe` keep of their destination, once normalized. Those of a
+/// user hash their owner along with it, so they are only ever deduplicated to the user's own.
+pub fn url_hash(destination: &str, owner_id: Option<u64>) -> String {
+    let hashed = match owner_id {
+        Some(owner_id) => format!("{owner_id}\n{destination}"),
+        None => destination.to_owned(),
+    };
+    Sha256::digest(hashed)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// `destination` with `path` appended to its path and `query` to its query string, for links
+/// that forward what visitors add to them; either may be empty
+pub fn forward(destination: &str, path: &str, query: &str) -> String {
+    if path.is_empty() && query.is_empty() {
+        return destination.to_owned();
+    }
+    let Ok(mut url) = Url::parse(destination) else {
+        return destination.to_owned();
+    };
+    if !path.is_empty() {
+        let joined = format!("{}/{path}", url.path().trim_end_matches('/'));
+        url.set_path(&joined);
+    }
+    if !query.is_empty() {
+        let joined = match url.query().filter(|existing| !existing.is_empty()) {
+            Some(existing) => format!("{existing}&{query}"),
+            None => query.to_owned(),
+        };
+        url.set_query(Some(&joined));
+    }
+    url.into()
+}
+
+/// `template` the way UTM templates are kept, without a leading `?`, refusing anything but
+/// `key=value` pairs joined by `&` that use only the known placeholders
+pub fn utm_template(template: &str) -> QrLinkResult<String> {
+    let template = template.trim().trim_start_matches('?');
+    if template.len() > MAX_UTM_TEMPLATE_LENGTH {
+        return Err(Error::Validation(format!(
+            "utm_template is longer than {MAX_UTM_TEMPLATE_LENGTH} characters"
+        )));
+    }
+    for pair in template.split('&') {
+        let plain = UTM_PLACEHOLDERS
+            .iter()
+            .fold(pair.to_owned(), |pair, placeholder| {
+                pair.replace(placeholder, "")
+            });
+        if pair.split_once('=').is_none_or(|(key, _)| key.is_empty())
+            || plain.contains(|c: char| c.is_whitespace() || "#{}".contains(c))
+        {
+            return Err(Error::Validation(format!(
+                "utm_template must be key=value pairs joined by &, using only the placeholders {}",
+                UTM_PLACEHOLDERS.join(" and ")
+            )));
+        }
+    }
+    Ok(template.to_owned())
+}
+
+/// The query string a UTM template gives for `link`
+pub fn utm_query(template: &str, link: &Link) -> String {
+    let encode = |value: &str| url::form_urlencoded::byte_serialize(value.as_bytes()).collect();
+    let code: String = encode(link.code.as_deref().unwrap_or_default());
+    let slug: String = link.slug.as_deref().map_or_else(|| code.clone(), encode);
+    template.replace("{slug}", &slug).replace("{code}", &code)
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    }
+}
@@ -1,40 +1,251 @@
-use axum::{http::StatusCode, response::IntoResponse};
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::{Json, response::IntoResponse};
+use serde::Serialize;
+use std::time::Duration;
 use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::request_id;
 
 pub type QrLinkResult<T> = Result<T, Error>;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Database error: {0}")]
-    DatabaseError(rusqlite::Error),
+    Database(rusqlite::Error),
+
+    #[error("Database pool error: {0}")]
+    Pool(r2d2::Error),
+
+    #[error("Background task failed: {0}")]
+    Task(tokio::task::JoinError),
+
+    #[error("Migration failed: {0}")]
+    Migration(String),
 
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+
+    #[cfg(feature = "postgres")]
     #[error("Database error: {0}")]
-    DatabaseErrorTwo(String),
+    Postgres(tokio_postgres::Error),
+
+    #[cfg(feature = "postgres")]
+    #[error("Database pool error: {0}")]
+    PostgresPool(String),
+
+    #[error("No link with id {0}")]
+    NotFound(String),
+
+    #[error("Link {0} has been deleted")]
+    Gone(String),
+
+    #[error("No API key with id {0}")]
+    UnknownApiKey(u64),
+
+    #[error("No webhook with id {0}")]
+    UnknownWebhook(u64),
+
+    #[error("No user with id {0}")]
+    UnknownUser(u64),
+
+    #[error("No backup has been made yet")]
+    NoBackup,
+
+    #[error("Invalid request: {0}")]
+    Validation(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Too many requests, retry in {} seconds", retry_after_secs(*.0))]
+    RateLimited(Duration),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Too busy to take the request, retry later")]
+    Overloaded,
+
+    #[error("Request too large: {0}")]
+    TooLarge(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Links to {0} are not allowed")]
+    DomainNotAllowed(String),
+
+    #[error("QR code generation failed: {0}")]
+    QrGeneration(qrcode::types::QrError),
+
+    #[error("No QR code could be read in the image")]
+    Unreadable,
+
+    #[error("Image encoding failed: {0}")]
+    Image(image::ImageError),
+
+    #[error("Archive could not be written: {0}")]
+    Archive(zip::result::ZipError),
 
-    #[error("Lock poisoned: {0}")]
-    LockError(String),
+    #[error("Identity provider failed: {0}")]
+    IdentityProvider(String),
+}
+
+impl Error {
+    /// Maps a lookup of `id` that matched no rows to [Error::NotFound], and anything else to
+    /// [Error::Database]
+    pub fn lookup(id: &str) -> impl FnOnce(rusqlite::Error) -> Error + '_ {
+        move |error| Error::Database(error).or_not_found(id)
+    }
+
+    /// Turns a database error caused by no rows matching into [Error::NotFound] for `id`
+    pub fn or_not_found(self, id: &str) -> Error {
+        match self {
+            Error::Database(rusqlite::Error::QueryReturnedNoRows) => Error::NotFound(id.to_owned()),
+            error => error,
+        }
+    }
+
+    /// The machine-readable code clients can match on, alongside the HTTP status
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Database(_)
+            | Error::Pool(_)
+            | Error::Task(_)
+            | Error::Migration(_)
+            | Error::Config(_)
+            | Error::Io(_)
+            | Error::Image(_)
+            | Error::Archive(_) => "internal_error",
+            #[cfg(feature = "postgres")]
+            Error::Postgres(_) | Error::PostgresPool(_) => "internal_error",
+            Error::NotFound(_)
+            | Error::UnknownApiKey(_)
+            | Error::UnknownWebhook(_)
+            | Error::UnknownUser(_)
+            | Error::NoBackup => "not_found",
+            Error::Gone(_) => "gone",
+            Error::Validation(_) => "validation_failed",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::RateLimited(_) => "rate_limited",
+            Error::QuotaExceeded(_) => "quota_exceeded",
+            Error::Overloaded => "overloaded",
+            Error::TooLarge(_) => "too_large",
+            Error::Conflict(_) => "conflict",
+            Error::DomainNotAllowed(_) => "domain_not_allowed",
+            Error::QrGeneration(_) => "qr_generation_failed",
+            Error::Unreadable => "qr_unreadable",
+            Error::IdentityProvider(_) => "identity_provider_failed",
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Database(_)
+            | Error::Pool(_)
+            | Error::Task(_)
+            | Error::Migration(_)
+            | Error::Config(_)
+            | Error::Io(_)
+            | Error::Image(_)
+            | Error::Archive(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            #[cfg(feature = "postgres")]
+            Error::Postgres(_) | Error::PostgresPool(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::NotFound(_)
+            | Error::UnknownApiKey(_)
+            | Error::UnknownWebhook(_)
+            | Error::UnknownUser(_)
+            | Error::NoBackup => StatusCode::NOT_FOUND,
+            Error::Gone(_) => StatusCode::GONE,
+            Error::Validation(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::RateLimited(_) | Error::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::QrGeneration(_) | Error::Unreadable | Error::DomainNotAllowed(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Error::IdentityProvider(_) => StatusCode::BAD_GATEWAY,
+            Error::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+}
+
+/// The JSON envelope every error is answered with
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ErrorBody {
+    /// Machine-readable, e.g. `not_found` or `validation_failed`
+    code: &'static str,
+    message: String,
+    /// The `X-Request-Id` of the failed request
+    request_id: String,
+    /// The link the error is about, for `not_found` and `gone`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// The destination domain that isn't allowed, for `domain_not_allowed`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain: Option<String>,
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        let (status_code, message) = match &self {
-            Error::DatabaseError(error) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", error))
-            }
-            Error::LockError(error) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", error)),
-            Error::DatabaseErrorTwo(_) => todo!(),
+        let id = match &self {
+            Error::NotFound(id) | Error::Gone(id) => Some(id.to_owned()),
+            _ => None,
+        };
+        let domain = match &self {
+            Error::DomainNotAllowed(domain) => Some(domain.to_owned()),
+            _ => None,
         };
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            request_id: request_id::current(),
+            id,
+            domain,
+        };
+
+        let status = self.status_code();
+        if status.is_server_error() {
+            tracing::error!(error = %self, code = body.code, "request failed");
+        } else {
+            tracing::debug!(error = %self, code = body.code, "request rejected");
+        }
 
-        (status_code, message).into_response()
+        let mut response = (status, Json(body)).into_response();
+        match self {
+            Error::Unauthorized(_) => {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            Error::RateLimited(retry_after) => {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, retry_after_secs(retry_after).into());
+            }
+            _ => {}
+        }
+        response
     }
 }
 
+/// Whole seconds to wait, rounded up so a client retrying on time finds a token
+fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
 impl From<Error> for String {
     fn from(value: Error) -> Self {
-        match &value {
-            Error::DatabaseError(error) => format!("{}", error),
-            Error::LockError(error) => error.to_owned(),
-            Error::DatabaseErrorTwo(_) => todo!(),
-        }
+        value.to_string()
     }
 }
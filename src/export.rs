@@ -0,0 +1,276 @@
+//! Streaming every link, and optionally its click totals, as CSV or JSON
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::body::{Body, Bytes};
+use chrono::{DateTime, Utc};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::error::{Error, QrLinkResult};
+use crate::store::{Granularity, LinkFilter, LinkSort, LinkStore, LinkSummary, SortOrder};
+
+/// Links read from the store at a time, and so written per chunk
+const PAGE_SIZE: u32 = 500;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ToSchema, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/json",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+/// Which columns an export has besides the link id, parsed from e.g. `links,stats`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Include {
+    /// The destination, code, slug and dates of each link
+    pub links: bool,
+    /// The click totals of each link, which take a query per link
+    pub stats: bool,
+}
+
+impl Default for Include {
+    fn default() -> Self {
+        Include {
+            links: true,
+            stats: false,
+        }
+    }
+}
+
+impl FromStr for Include {
+    type Err = Error;
+
+    fn from_str(value: &str) -> QrLinkResult<Self> {
+        let mut include = Include {
+            links: false,
+            stats: false,
+        };
+        for part in value.split(',').map(str::trim) {
+            match part {
+                "links" => include.links = true,
+                "stats" => include.stats = true,
+                other => {
+                    return Err(Error::Validation(format!(
+                        "can't include '{other}', only links and stats"
+                    )));
+                }
+            }
+        }
+        Ok(include)
+    }
+}
+
+#[derive(Serialize)]
+struct LinkColumns {
+    url: String,
+    code: Option<String>,
+    slug: Option<String>,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct StatsColumns {
+    clicks: u64,
+    scans: u64,
+    unique_visitors: u64,
+    first_click: Option<DateTime<Utc>>,
+    last_click: Option<DateTime<Utc>>,
+}
+
+/// One exported link, with the columns that were asked for
+#[derive(Serialize)]
+struct Row {
+    id: u64,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    link: Option<LinkColumns>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    stats: Option<StatsColumns>,
+}
+
+impl Row {
+    async fn read(
+        store: &dyn LinkStore,
+        summary: LinkSummary,
+        include: Include,
+    ) -> QrLinkResult<Self> {
+        let link = summary.link;
+        let stats = if include.stats {
+            let stats = store.stats(link.id, Granularity::default()).await?;
+            Some(StatsColumns {
+                clicks: stats.clicks,
+                scans: stats.scans,
+                unique_visitors: stats.unique_visitors,
+                first_click: stats.first_click,
+                last_click: stats.last_click,
+            })
+        } else {
+            None
+        };
+        Ok(Row {
+            id: link.id,
+            link: include.links.then_some(LinkColumns {
+                url: link.url,
+                code: link.code,
+                slug: link.slug,
+                created_at: link.created_at,
+                expires_at: link.expires_at,
+                deleted_at: link.deleted_at,
+            }),
+            stats,
+        })
+    }
+
+    fn csv_header(include: Include) -> String {
+        let mut columns = vec!["id"];
+        if include.links {
+            columns.extend([
+                "url",
+                "code",
+                "slug",
+                "created_at",
+                "expires_at",
+                "deleted_at",
+            ]);
+        }
+        if include.stats {
+            columns.extend([
+                "clicks",
+                "scans",
+                "unique_visitors",
+                "first_click",
+                "last_click",
+            ]);
+        }
+        columns.join(",") + "\r\n"
+    }
+
+    fn csv_record(&self) -> String {
+        let time = |time: Option<DateTime<Utc>>| time.map(|time| time.to_rfc3339());
+        let mut fields = vec![Some(self.id.to_string())];
+        if let Some(link) = &self.link {
+            fields.extend([
+                Some(link.url.clone()),
+                link.code.clone(),
+                link.slug.clone(),
+                time(Some(link.created_at)),
+                time(link.expires_at),
+                time(link.deleted_at),
+            ]);
+        }
+        if let Some(stats) = &self.stats {
+            fields.extend([
+                Some(stats.clicks.to_string()),
+                Some(stats.scans.to_string()),
+                Some(stats.unique_visitors.to_string()),
+                time(stats.first_click),
+                time(stats.last_click),
+            ]);
+        }
+        let fields: Vec<_> = fields
+            .into_iter()
+            .map(|field| csv_field(&field.unwrap_or_default()))
+            .collect();
+        fields.join(",") + "\r\n"
+    }
+}
+
+/// `value` quoted as RFC 4180 asks when it holds a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Where an export has got to
+struct Progress {
+    offset: u32,
+    started: bool,
+    finished: bool,
+}
+
+/// Every link `filter` lets through, oldest first, as a body written a page at a time so the
+/// whole dataset is never held in memory. JSON exports are one array of objects.
+pub fn stream(
+    store: Arc<dyn LinkStore>,
+    filter: LinkFilter,
+    format: ExportFormat,
+    include: Include,
+) -> Body {
+    let progress = Progress {
+        offset: 0,
+        started: false,
+        finished: false,
+    };
+    let chunks = stream::try_unfold(progress, move |mut progress| {
+        let store = store.clone();
+        let filter = filter.clone();
+        async move {
+            if progress.finished {
+                return Ok(None);
+            }
+            let mut chunk = String::new();
+            if !progress.started {
+                match format {
+                    ExportFormat::Csv => chunk.push_str(&Row::csv_header(include)),
+                    ExportFormat::Json => chunk.push('['),
+                }
+            }
+            let page = store
+                .list(LinkFilter {
+                    sort: LinkSort::CreatedAt,
+                    order: SortOrder::Asc,
+                    limit: PAGE_SIZE,
+                    offset: progress.offset,
+                    ..filter
+                })
+                .await?;
+            let count = page.links.len() as u32;
+            for (index, summary) in page.links.into_iter().enumerate() {
+                let row = Row::read(store.as_ref(), summary, include).await?;
+                match format {
+                    ExportFormat::Csv => chunk.push_str(&row.csv_record()),
+                    ExportFormat::Json => {
+                        if progress.started || index > 0 {
+                            chunk.push(',');
+                        }
+                        chunk.push_str(
+                            &serde_json::to_string(&row).expect("rows serialize to JSON"),
+                        );
+                    }
+                }
+            }
+            progress.started = true;
+            progress.offset += count;
+            progress.finished = count < PAGE_SIZE;
+            if progress.finished && format == ExportFormat::Json {
+                chunk.push(']');
+            }
+            Ok::<_, Error>(Some((Bytes::from(chunk), progress)))
+        }
+    });
+    Body::from_stream(chunks)
+}
@@ -0,0 +1,68 @@
+//! Country and city lookups for client addresses, from a MaxMind GeoLite2 database
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use maxminddb::{Reader, geoip2};
+
+/// Where a client address is, as far as the database knows
+#[derive(Clone, Debug, Default)]
+pub struct Location {
+    /// ISO 3166-1 alpha-2 code, e.g. `DK`
+    pub country: Option<String>,
+    /// English name; only a GeoLite2-City database has these
+    pub city: Option<String>,
+}
+
+/// A GeoLite2 Country or City database, or nothing if none is configured or it can't be read, in
+/// which case every address has an unknown location
+#[derive(Clone, Default)]
+pub struct GeoIp {
+    reader: Option<Arc<Reader<Vec<u8>>>>,
+}
+
+impl GeoIp {
+    /// Reads the database at `path`, falling back to unknown locations if it can't be read
+    pub fn open(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        match Reader::open_readfile(path) {
+            Ok(reader) => GeoIp {
+                reader: Some(Arc::new(reader)),
+            },
+            Err(error) => {
+                tracing::warn!(
+                    path = %path.display(),
+                    %error,
+                    "GeoIP database unavailable, not resolving locations"
+                );
+                GeoIp::default()
+            }
+        }
+    }
+
+    /// Whether a database was read, so locations can be resolved
+    pub fn is_loaded(&self) -> bool {
+        self.reader.is_some()
+    }
+
+    pub fn locate(&self, ip_addr: IpAddr) -> Location {
+        let Some(reader) = &self.reader else {
+            return Location::default();
+        };
+        // The City record is a superset of the Country one, so it decodes either database
+        let Ok(Some(record)) = reader.lookup::<geoip2::City>(ip_addr) else {
+            return Location::default();
+        };
+        Location {
+            country: record
+                .country
+                .and_then(|country| country.iso_code)
+                .map(str::to_owned),
+            city: record
+                .city
+                .and_then(|city| city.names)
+                .and_then(|names| names.get("en").map(|name| (*name).to_owned())),
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! The core of the API over gRPC, for internal services that would rather not speak HTTP and
+//! JSON: creating and resolving links, their statistics, once or streamed as clicks come in,
+//! and their QR codes. Served on `grpc_listen`, apart from the HTTP routes, with the service in
+//! `proto/qrlink/v1/links.proto`. Callers authenticate as over HTTP, with an
+//! `authorization: Bearer <key>` metadata entry.
+
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use axum::http::{HeaderMap, HeaderValue};
+use axum::middleware;
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, stream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::MissedTickBehavior;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Request, Response, Status};
+
+use crate::auth::{self, Caller, Edit, Permission, Read};
+use crate::error::{Error, QrLinkResult};
+use crate::qr::{ErrorCorrection, Format, Style};
+use crate::store::{self, Granularity, Link};
+use crate::{AppState, CreateUrlParams, QrQuery, QrTarget, client_ip, metrics};
+
+/// The messages and service generated from the `.proto`, with a client for Rust callers
+pub mod proto {
+    tonic::include_proto!("qrlink.v1");
+}
+
+use proto::links_server::{Links, LinksServer};
+
+/// The gRPC service as a router to serve on `grpc_listen`, believing the forwarding headers in
+/// the metadata as the HTTP routes do
+pub fn router(app_state: AppState) -> axum::Router {
+    let resolve = middleware::from_fn_with_state(app_state.clone(), client_ip::resolve);
+    tonic::service::Routes::new(LinksServer::new(GrpcLinks { app_state }))
+        .into_axum_router()
+        .layer(resolve)
+}
+
+struct GrpcLinks {
+    app_state: AppState,
+}
+
+type StatsStream = Pin<Box<dyn Stream<Item = Result<proto::Stats, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Links for GrpcLinks {
+    async fn create_link(
+        &self,
+        request: Request<proto::CreateLinkRequest>,
+    ) -> Result<Response<proto::Link>, Status> {
+        let mut headers = request.metadata().clone().into_headers();
+        let caller = self.authorize::<Edit>(&headers).await?;
+        let request = request.into_inner();
+        if let Some(key) = &request.idempotency_key {
+            let key = HeaderValue::from_str(key)
+                .map_err(|_| Status::invalid_argument("idempotency_key must be visible text"))?;
+            headers.insert("idempotency-key", key);
+        }
+        let idempotency_key = auth::idempotency_key(&headers)?;
+        let params = CreateUrlParams {
+            url: request.url,
+            slug: request.slug,
+            title: request.title,
+            notes: request.notes,
+            tags: (!request.tags.is_empty()).then_some(request.tags),
+            activate_at: request.activate_at.map(date_time).transpose()?,
+            expires_at: request.expires_at.map(date_time).transpose()?,
+            max_clicks: request.max_clicks,
+            ..CreateUrlParams::default()
+        };
+        let link = crate::create_link(
+            &self.app_state,
+            &headers,
+            params,
+            idempotency_key,
+            Some(&caller),
+        )
+        .await?;
+        Ok(Response::new(self.link(&headers, link).await?))
+    }
+
+    async fn resolve_link(
+        &self,
+        request: Request<proto::ResolveLinkRequest>,
+    ) -> Result<Response<proto::Link>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let caller = self.authorize::<Read>(&headers).await?;
+        let link = self
+            .app_state
+            .store
+            .resolve_active_in(&request.get_ref().id, caller.scope())
+            .await?;
+        Ok(Response::new(self.link(&headers, link).await?))
+    }
+
+    async fn get_stats(
+        &self,
+        request: Request<proto::GetStatsRequest>,
+    ) -> Result<Response<proto::Stats>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let caller = self.authorize::<Read>(&headers).await?;
+        let request = request.get_ref();
+        let link = self
+            .app_state
+            .store
+            .resolve_active_in(&request.id, caller.scope())
+            .await?;
+        let stats = statistics(&self.app_state, &link, granularity(request.granularity)).await?;
+        Ok(Response::new(stats))
+    }
+
+    type WatchStatsStream = StatsStream;
+
+    async fn watch_stats(
+        &self,
+        request: Request<proto::GetStatsRequest>,
+    ) -> Result<Response<StatsStream>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let caller = self.authorize::<Read>(&headers).await?;
+        let request = request.get_ref();
+        let link = self
+            .app_state
+            .store
+            .resolve_active_in(&request.id, caller.scope())
+            .await?;
+        let watch = Watch {
+            app_state: self.app_state.clone(),
+            clicks: self.app_state.live.subscribe(),
+            ticks: None,
+            link,
+            granularity: granularity(request.granularity),
+            clicked: true,
+        };
+        Ok(Response::new(Box::pin(stream::unfold(watch, Watch::next))))
+    }
+
+    async fn render_qr(
+        &self,
+        request: Request<proto::RenderQrRequest>,
+    ) -> Result<Response<proto::QrCode>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let caller = self.authorize::<Read>(&headers).await?;
+        let request = request.into_inner();
+        let link = self
+            .app_state
+            .store
+            .resolve_active_in(&request.id, caller.scope())
+            .await?;
+        let format = qr_format(request.format());
+        let params = QrQuery {
+            size: request.size,
+            width_mm: request.width_mm,
+            format: Some(format),
+            quality: request
+                .quality
+                .map(|quality| u8::try_from(quality).unwrap_or(u8::MAX)),
+            quiet_zone: request.quiet_zone,
+            ec: Some(error_correction(request.ec())),
+            version: request
+                .version
+                .map(|version| u8::try_from(version).unwrap_or(u8::MAX)),
+            fg: request.fg.as_deref().map(str::parse).transpose()?,
+            bg: request.bg.as_deref().map(str::parse).transpose()?,
+            logo: request.logo,
+            style: Some(qr_style(request.style())),
+            compact: request.compact,
+            frame: request.frame.as_deref().map(str::parse).transpose()?,
+            label: request.label,
+            label_size: request.label_size,
+            ..QrQuery::default()
+        };
+        let rendering = crate::qr_rendering(&self.app_state, &params, format)?;
+        let url = crate::qr_payload(
+            &self.app_state,
+            &headers,
+            &link,
+            QrTarget::Short,
+            rendering.compact,
+        );
+        let warning = rendering.warning();
+        let started = Instant::now();
+        let data = tokio::task::spawn_blocking(move || rendering.render(&url))
+            .await
+            .map_err(Error::Task)??;
+        metrics::record_qr_render(format.as_str(), started);
+        Ok(Response::new(proto::QrCode {
+            data,
+            content_type: format.content_type().to_owned(),
+            warning,
+        }))
+    }
+}
+
+impl GrpcLinks {
+    /// The caller the bearer token in the metadata stands for, if their role allows `P`
+    async fn authorize<P: Permission>(&self, headers: &HeaderMap) -> Result<Caller, Status> {
+        let caller = auth::authenticate(&self.app_state, headers).await?;
+        if caller.role() < P::ROLE {
+            return Err(Error::Forbidden(format!(
+                "the {} role is needed for this",
+                P::ROLE.as_str()
+            ))
+            .into());
+        }
+        Ok(caller)
+    }
+
+    async fn link(&self, headers: &HeaderMap, link: Link) -> Result<proto::Link, Status> {
+        let tags = self.app_state.store.tags(link.id).await?;
+        Ok(proto::Link {
+            stored_id: link.id.to_string(),
+            short_url: crate::short_url(&self.app_state, headers, &link.public_id()),
+            url: link.url,
+            code: link.code,
+            slug: link.slug,
+            title: link.title,
+            tags,
+            created_at: Some(timestamp(link.created_at)),
+            activate_at: link.activate_at.map(timestamp),
+            expires_at: link.expires_at.map(timestamp),
+            max_clicks: link.max_clicks,
+        })
+    }
+}
+
+/// A `WatchStats` call, between the statistics it sends
+struct Watch {
+    app_state: AppState,
+    clicks: tokio::sync::broadcast::Receiver<crate::live::LiveClick>,
+    /// Paces the statistics, once the first are sent
+    ticks: Option<tokio::time::Interval>,
+    link: Link,
+    granularity: Granularity,
+    /// Whether the link was clicked since the statistics were last sent
+    clicked: bool,
+}
+
+impl Watch {
+    /// The next statistics, once the link has been clicked and the interval is up, or `None`
+    /// once the service is shutting down
+    async fn next(mut self) -> Option<(Result<proto::Stats, Status>, Self)> {
+        if let Some(ticks) = &mut self.ticks {
+            loop {
+                tokio::select! {
+                    click = self.clicks.recv() => match click {
+                        Ok(click) if click.link_id == self.link.id => self.clicked = true,
+                        Ok(_) => {}
+                        // Some of the missed clicks may well have been on the link
+                        Err(RecvError::Lagged(_)) => self.clicked = true,
+                        Err(RecvError::Closed) => return None,
+                    },
+                    _ = ticks.tick(), if self.clicked => break,
+                }
+            }
+        } else {
+            let interval = Duration::from_secs(self.app_state.config.live.stats_interval_secs);
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // The first tick completes right away, and the first statistics are sent now
+            ticks.tick().await;
+            self.ticks = Some(ticks);
+        }
+        self.clicked = false;
+        let stats = statistics(&self.app_state, &self.link, self.granularity).await;
+        Some((stats, self))
+    }
+}
+
+/// The statistics of `link` as a message
+async fn statistics(
+    app_state: &AppState,
+    link: &Link,
+    granularity: Granularity,
+) -> Result<proto::Stats, Status> {
+    let stats = crate::link_statistics(app_state, link, granularity).await?;
+    let breakdowns = |breakdowns: Vec<store::Breakdown>| {
+        breakdowns
+            .into_iter()
+            .map(|breakdown| proto::Breakdown {
+                name: breakdown.name,
+                clicks: breakdown.clicks,
+            })
+            .collect()
+    };
+    Ok(proto::Stats {
+        stored_id: stats.stored_id,
+        clicks: stats.clicks,
+        scans: stats.scans,
+        direct_clicks: stats.direct_clicks,
+        unique_visitors: stats.unique_visitors,
+        first_click: stats.first_click.map(timestamp),
+        last_click: stats.last_click.map(timestamp),
+        buckets: stats
+            .buckets
+            .into_iter()
+            .map(|bucket| proto::ClickBucket {
+                start: Some(timestamp(bucket.start)),
+                clicks: bucket.clicks,
+            })
+            .collect(),
+        referrers: breakdowns(stats.referrers),
+        browsers: breakdowns(stats.browsers),
+        operating_systems: breakdowns(stats.operating_systems),
+        countries: breakdowns(stats.countries),
+        variants: stats
+            .variants
+            .into_iter()
+            .map(|variant| proto::VariantStats {
+                url: variant.url,
+                clicks: variant.clicks,
+                unique_visitors: variant.unique_visitors,
+            })
+            .collect(),
+    })
+}
+
+fn granularity(granularity: i32) -> Granularity {
+    match proto::Granularity::try_from(granularity) {
+        Ok(proto::Granularity::Hour) => Granularity::Hour,
+        Ok(proto::Granularity::Week) => Granularity::Week,
+        Ok(proto::Granularity::Day) | Err(_) => Granularity::Day,
+    }
+}
+
+fn qr_format(format: proto::QrFormat) -> Format {
+    match format {
+        proto::QrFormat::Png => Format::Png,
+        proto::QrFormat::Svg => Format::Svg,
+        proto::QrFormat::Pdf => Format::Pdf,
+        proto::QrFormat::Text => Format::Ascii,
+        proto::QrFormat::Jpeg => Format::Jpeg,
+        proto::QrFormat::Webp => Format::Webp,
+        proto::QrFormat::Bmp => Format::Bmp,
+    }
+}
+
+fn qr_style(style: proto::QrStyle) -> Style {
+    match style {
+        proto::QrStyle::Square => Style::Square,
+        proto::QrStyle::Rounded => Style::Rounded,
+        proto::QrStyle::Dots => Style::Dots,
+    }
+}
+
+fn error_correction(level: proto::ErrorCorrection) -> ErrorCorrection {
+    match level {
+        proto::ErrorCorrection::M => ErrorCorrection::M,
+        proto::ErrorCorrection::L => ErrorCorrection::L,
+        proto::ErrorCorrection::Q => ErrorCorrection::Q,
+        proto::ErrorCorrection::H => ErrorCorrection::H,
+    }
+}
+
+fn timestamp(at: DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: at.timestamp(),
+        nanos: i32::try_from(at.timestamp_subsec_nanos()).unwrap_or_default(),
+    }
+}
+
+fn date_time(at: prost_types::Timestamp) -> QrLinkResult<DateTime<Utc>> {
+    u32::try_from(at.nanos)
+        .ok()
+        .and_then(|nanos| DateTime::from_timestamp(at.seconds, nanos))
+        .ok_or_else(|| Error::Validation("timestamp out of range".into()))
+}
+
+/// Errors as the gRPC status closest to their HTTP one, with the machine-readable code of the
+/// HTTP API in the `error-code` metadata entry
+impl From<Error> for Status {
+    fn from(error: Error) -> Self {
+        let http_status = error.status_code();
+        let code = match http_status.as_u16() {
+            400 | 413 | 422 => Code::InvalidArgument,
+            401 => Code::Unauthenticated,
+            403 => Code::PermissionDenied,
+            404 | 410 => Code::NotFound,
+            409 => Code::AlreadyExists,
+            429 => Code::ResourceExhausted,
+            502 | 503 => Code::Unavailable,
+            _ => Code::Internal,
+        };
+        if http_status.is_server_error() {
+            tracing::error!(error = %error, code = error.code(), "gRPC call failed");
+        } else {
+            tracing::debug!(error = %error, code = error.code(), "gRPC call rejected");
+        }
+        let mut status = Status::new(code, error.to_string());
+        status
+            .metadata_mut()
+            .insert("error-code", MetadataValue::from_static(error.code()));
+        status
+    }
+}
@@ -0,0 +1,82 @@
+//! The API as it was before it was versioned, with links managed at their own paths, e.g.
+//! `PATCH /<code>`, and everything else under `/api/`. Its routes are served alongside
+//! `/api/v1` for a transition window, every answer saying they are deprecated, when they go
+//! away and where their successor is, so clients can move over before `legacy_api.sunset`.
+
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::AppState;
+
+/// When the unversioned routes were deprecated, 2026-10-15, as seconds since the epoch
+const DEPRECATED_AT: i64 = 1_792_022_400;
+
+/// When the unversioned routes go away unless configured otherwise, six months later
+const DEFAULT_SUNSET: i64 = 1_807_747_200;
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LegacyApiConfig {
+    /// Whether the unversioned routes are served at all
+    pub enabled: bool,
+    /// When they are announced to go away, in the `Sunset` header
+    pub sunset: DateTime<Utc>,
+}
+
+impl Default for LegacyApiConfig {
+    fn default() -> Self {
+        LegacyApiConfig {
+            enabled: true,
+            sunset: DateTime::from_timestamp(DEFAULT_SUNSET, 0).expect("the sunset is a date"),
+        }
+    }
+}
+
+/// Middleware marking the answers of unversioned routes with `Deprecation`, `Sunset` and a
+/// `Link` to the same resource under `/api/v1`
+pub async fn deprecate(
+    State(app_state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let successor = successor(request.uri().path());
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(
+        DEPRECATION,
+        HeaderValue::from_str(&format!("@{DEPRECATED_AT}")).expect("a number is a valid header"),
+    );
+    let sunset = app_state.config.legacy_api.sunset;
+    let sunset = sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    headers.insert(
+        SUNSET,
+        HeaderValue::from_str(&sunset).expect("a date is a valid header"),
+    );
+    let link = format!("<{successor}>; rel=\"successor-version\"");
+    if let Ok(link) = HeaderValue::from_str(&link) {
+        headers.append(axum::http::header::LINK, link);
+    }
+    response
+}
+
+/// Where an unversioned path is served under `/api/v1`: `/api/<rest>` at `/api/v1/<rest>`,
+/// `/` and `/<id>` at `/api/v1/links` and `/api/v1/links/<id>`, `/<id>/meta` at the link
+/// itself, and the other paths below a link, e.g. `/<id>/stats`, below it there too
+fn successor(path: &str) -> String {
+    if let Some(rest) = path.strip_prefix("/api/") {
+        return format!("/api/v1/{rest}");
+    }
+    match path.trim_start_matches('/').split_once('/') {
+        None if path == "/" => "/api/v1/links".to_owned(),
+        None => format!("/api/v1/links{path}"),
+        Some((id, "meta")) => format!("/api/v1/links/{id}"),
+        Some((id, rest)) => format!("/api/v1/links/{id}/{rest}"),
+    }
+}
@@ -0,0 +1,3683 @@
+//! A URL shortener that draws QR codes for its links.
+//!
+//! [run] serves a [Config] the way the `qr-link-service` binary does; [build_router] gives the
+//! routes alone, for embedding the service in another server or driving it in tests.
+
+use auth::{Authorized, Caller, Edit, Manage, Read};
+use axum::body::Bytes;
+use axum::extract::{FromRequest, FromRequestParts, Multipart, Request};
+use axum::http::{HeaderMap, Method, StatusCode, Uri, header};
+use axum::response::{IntoResponse, Response};
+use axum::{
+    Router,
+    error_handling::HandleErrorLayer,
+    extract::{DefaultBodyLimit, RawQuery, State},
+    middleware,
+    response::Redirect,
+    routing::{delete, get, patch, post},
+};
+use backup::Backups;
+use cache::{CachedQr, CachedRedirect, QrCache, RedirectCache};
+use card::Cards;
+use chrono::{DateTime, Utc};
+use click_log::ClickLog;
+use client_ip::ClientIp;
+use code::CodeGenerator;
+use config::Config;
+use destination::DestinationPolicy;
+use error::{Error, ErrorBody, QrLinkResult};
+use export::ExportFormat;
+use extract::{Form, Json, Path, Query, body_rejected};
+use futures_util::future;
+use geoip::GeoIp;
+use image::RgbaImage;
+use link_health::HealthChecker;
+use listen::ListenAddr;
+use live::LiveClicks;
+use oidc::Oidc;
+use page_info::PageFetcher;
+use privacy::Privacy;
+use qr::{Color, ErrorCorrection, Format, Style};
+use qrcode::types::QrError;
+use rate_limit::RateLimiter;
+use retention::ClickPruner;
+use safety::SafetyChecker;
+use security_headers::SecurityHeaders;
+use serde::{Deserialize, Serialize};
+use session::Sessions;
+use site::SiteFiles;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use store::{
+    Breakdown, Click, ClickBucket, ClickSource, CountryTarget, Granularity, HealthFilter,
+    HistoryEntry, LanguageTarget, Link, LinkFilter, LinkHealth, LinkSort, LinkStore, LinkTargets,
+    LinkUpdate, NewLink, Platform, Quarantine, RecordedClick, Role, Scope, SortOrder, TagCount,
+    Threat, User, Variant, VariantStats, WebhookEvent,
+};
+use tokio::sync::watch;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::{BoxError, ServiceBuilder};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::CorsLayer;
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::timeout::TimeoutLayer;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+use webhooks::{Event, Webhooks};
+mod auth;
+pub mod backup;
+mod cache;
+mod card;
+pub mod cli;
+pub mod click_log;
+pub mod client_ip;
+pub mod code;
+pub mod config;
+pub mod cors;
+mod dashboard;
+pub mod destination;
+pub mod error;
+mod export;
+mod extract;
+pub mod geoip;
+pub mod grpc;
+pub mod legacy_api;
+pub mod link_health;
+pub mod listen;
+mod live;
+pub mod logging;
+mod metrics;
+mod oembed;
+pub mod oidc;
+pub mod openapi;
+mod options;
+mod outbound;
+mod page_info;
+mod pages;
+mod payload;
+pub mod privacy;
+mod qr;
+pub mod quotas;
+mod rate_limit;
+mod request_id;
+pub mod retention;
+pub mod safety;
+pub mod security_headers;
+pub mod session;
+pub mod sheet;
+mod signed_qr;
+pub mod site;
+mod slug;
+pub mod store;
+mod targeting;
+mod text;
+pub mod tls;
+mod webhooks;
+
+/// What every handler shares
+#[derive(Clone)]
+pub struct AppState {
+    pub store: Arc<dyn LinkStore>,
+    pub codes: CodeGenerator,
+    pub destinations: DestinationPolicy,
+    pub geoip: GeoIp,
+    /// The configured `qr.logo`, decoded
+    pub logo: Option<Arc<RgbaImage>>,
+    /// The configured `redirects.coming_soon_page`
+    coming_soon: Option<Arc<str>>,
+    /// Recently rendered QR codes, unless `qr.cache_capacity` is 0
+    qr_cache: Option<Arc<QrCache>>,
+    /// Destinations of recently followed links, unless `redirects.cache_capacity` is 0
+    redirect_cache: Option<Arc<RedirectCache>>,
+    /// The registered webhooks, loaded by [run] or once one is registered
+    webhooks: Arc<Webhooks>,
+    /// Fetches destination pages for their titles, if `page_info.fetch` is on
+    page_fetcher: Option<Arc<PageFetcher>>,
+    /// Checks destinations for malware and phishing, if `safety` has a blocklist or a key
+    safety: Option<Arc<SafetyChecker>>,
+    /// What is kept of the visitors who follow links
+    privacy: Arc<Privacy>,
+    /// The queue clicks wait in to be written in batches, if `click_log.buffered` is on
+    click_log: Option<Arc<ClickLog>>,
+    /// Deletes clicks older than `retention` keeps them
+    pruner: Arc<ClickPruner>,
+    /// Snapshots of the database, if `backups` has a directory for them
+    backups: Option<Arc<Backups>>,
+    /// Clicks as they happen, for whoever is streaming them
+    live: LiveClicks,
+    /// Signs and checks the cookies browsers stay signed in to the dashboard with
+    sessions: Arc<Sessions>,
+    /// Signs people in through an identity provider, if `oidc` names one
+    oidc: Option<Arc<Oidc>>,
+    /// Lets other sites' pages call the API, if `cors` allows any origin
+    cors: Option<CorsLayer>,
+    /// Set on every response, as `security_headers` says
+    security_headers: Arc<SecurityHeaders>,
+    /// `/robots.txt` and `/favicon.ico`, as `site` configures them
+    site: Arc<SiteFiles>,
+    /// Draws the social cards of links in the font and colors of `card`
+    cards: Arc<Cards>,
+    pub config: Arc<Config>,
+}
+
+impl AppState {
+    /// State for serving `config` from `store`, which should already be migrated
+    pub fn new(config: Config, store: Arc<dyn LinkStore>) -> QrLinkResult<Self> {
+        let click_log = config
+            .click_log
+            .buffered
+            .then(|| Arc::new(ClickLog::start(&config.click_log, store.clone())));
+        Ok(AppState {
+            store,
+            codes: config.code_generator()?,
+            cors: config.cors.layer()?,
+            security_headers: Arc::new(SecurityHeaders::new(&config.security_headers)?),
+            site: Arc::new(SiteFiles::load(&config.site)?),
+            cards: Arc::new(Cards::new(&config.card)?),
+            destinations: config.destinations.clone(),
+            geoip: config
+                .geoip_database
+                .as_ref()
+                .map_or_else(GeoIp::default, GeoIp::open),
+            logo: config
+                .qr
+                .logo
+                .as_deref()
+                .map(qr::load_logo)
+                .transpose()?
+                .map(Arc::new),
+            coming_soon: config
+                .redirects
+                .coming_soon_page
+                .as_deref()
+                .map(pages::load_coming_soon)
+                .transpose()?,
+            qr_cache: (config.qr.cache_capacity > 0)
+                .then(|| Arc::new(QrCache::new(config.qr.cache_capacity))),
+            redirect_cache: (config.redirects.cache_capacity > 0).then(|| {
+                Arc::new(RedirectCache::new(
+                    config.redirects.cache_capacity,
+                    Duration::from_secs(config.redirects.cache_ttl_secs),
+                ))
+            }),
+            webhooks: Arc::new(Webhooks::new(&config.webhooks)?),
+            page_fetcher: config
+                .page_info
+                .fetch
+                .then(|| PageFetcher::new(&config.page_info))
+                .transpose()?
+                .map(Arc::new),
+            safety: config
+                .safety
+                .enabled()
+                .then(|| SafetyChecker::new(&config.safety))
+                .transpose()?
+                .map(Arc::new),
+            privacy: Arc::new(Privacy::new(&config.privacy)),
+            click_log,
+            pruner: Arc::new(ClickPruner::new(&config.retention)),
+            backups: Backups::new(&config.backups).map(Arc::new),
+            live: LiveClicks::default(),
+            sessions: Arc::new(Sessions::new(&config.sessions, config.base_url.as_ref())),
+            oidc: Oidc::new(&config.oidc, config.base_url.as_ref())?.map(Arc::new),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Drops whatever is cached about a link that changed or went away
+    fn forget_link(&self, link_id: u64) {
+        if let Some(cache) = &self.qr_cache {
+            cache.invalidate(link_id);
+        }
+        if let Some(cache) = &self.redirect_cache {
+            cache.invalidate(link_id);
+        }
+    }
+
+    /// Has the page a new or repointed link leads to fetched for its title, if that is on
+    fn fetch_page_info(&self, link: &Link) {
+        if let Some(page_fetcher) = &self.page_fetcher {
+            page_fetcher.fetch_in_background(self.store.clone(), link);
+        }
+    }
+
+    /// Has the destinations of new or repointed links checked for threats, if that is on,
+    /// quarantining the links flagged
+    async fn check_safety<'a>(
+        &self,
+        links: impl IntoIterator<Item = &'a mut Link>,
+    ) -> QrLinkResult<()> {
+        if let Some(safety) = &self.safety {
+            safety.check(&*self.store, links).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens the configured database and brings its schema up to date
+pub async fn open_store(config: &Config) -> QrLinkResult<Arc<dyn LinkStore>> {
+    let store = store::open(&config.database, &config.sqlite).await?;
+    let version = store.migrate().await?;
+    tracing::info!(version, "database schema is up to date");
+    Ok(store)
+}
+
+/// Every route the service serves, as switched on in `app_state.config`. Handlers read the
+/// client address from [ConnectInfo](axum::extract::ConnectInfo), so serve it with
+/// `into_make_service_with_connect_info::<SocketAddr>()`.
+pub fn build_router(app_state: AppState) -> Router {
+    router(app_state, Routes::All)
+}
+
+/// The routes visitors following links reach: the redirects, QR codes and previews, along with
+/// `/robots.txt` and `/favicon.ico`, for `listen` when every other route is served on
+/// `admin_listen`
+pub fn build_public_router(app_state: AppState) -> Router {
+    router(app_state, Routes::Visitors)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Routes {
+    All,
+    Visitors,
+}
+
+fn router(app_state: AppState, routes: Routes) -> Router {
+    let limits = app_state.config.rate_limits;
+    let limited =
+        |quota| middleware::from_fn_with_state(RateLimiter::new(quota), rate_limit::enforce);
+    let embeddable = || middleware::from_fn(security_headers::embeddable);
+    // What visitors following links reach, which is all `listen` serves with `admin_listen`
+    let mut visitors = Router::new()
+        .route(
+            "/{external_id}",
+            get(get_url).route_layer(limited(limits.redirect)),
+        )
+        .route(
+            "/{external_id}/{*path}",
+            get(get_url_path).route_layer(limited(limits.redirect)),
+        )
+        .route("/{external_id}/preview", get(get_preview))
+        .route("/oembed", get(oembed::get_oembed))
+        .route("/robots.txt", get(site::get_robots_txt))
+        .route("/favicon.ico", get(site::get_favicon));
+    if app_state.config.features.qr_codes {
+        visitors = visitors.route(
+            "/{external_id}/qr",
+            get(get_qr)
+                .route_layer(limited(limits.qr))
+                .route_layer(embeddable()),
+        );
+        visitors = visitors.route(
+            "/{external_id}/card.png",
+            get(card::get_card)
+                .route_layer(limited(limits.qr))
+                .route_layer(embeddable()),
+        );
+    }
+    let mut public = Router::new()
+        .route("/", get(get_info))
+        .route("/healthz", get(get_health))
+        .route("/readyz", get(get_readiness))
+        .route("/login", get(session::get_login))
+        .route(
+            "/login",
+            post(session::post_login).route_layer(limited(limits.create)),
+        )
+        .route("/logout", post(session::post_logout))
+        .merge(
+            Router::from(
+                SwaggerUi::new("/docs").url("/openapi.json", openapi::spec(&app_state.config)),
+            )
+            .layer(middleware::from_fn(security_headers::own_scripts)),
+        );
+    if app_state.config.features.metrics {
+        metrics::handle();
+        public = public.route("/metrics", get(get_metrics));
+    }
+    if app_state.config.features.web_form {
+        public = public
+            .route("/app", get(get_app))
+            .route("/app", post(post_app).route_layer(limited(limits.create)));
+    }
+    if app_state.config.sessions.signup {
+        public = public.route("/signup", get(session::get_signup)).route(
+            "/signup",
+            post(session::post_signup).route_layer(limited(limits.create)),
+        );
+    }
+    if app_state.oidc.is_some() {
+        public = public
+            .route("/login/oidc", get(oidc::start_sign_in))
+            .route(oidc::CALLBACK_PATH, get(oidc::finish_sign_in));
+    }
+    let mut keyed = Router::new();
+    if app_state.config.features.click_tracking {
+        keyed = keyed.route("/ws/stats", get(live::stats_socket));
+    }
+    if app_state.config.features.qr_codes {
+        keyed = keyed
+            .route(
+                "/qr",
+                get(get_payload_qr)
+                    .route_layer(limited(limits.qr))
+                    .route_layer(embeddable()),
+            )
+            .route(
+                "/qr/wifi",
+                post(post_wifi_qr)
+                    .route_layer(limited(limits.qr))
+                    .route_layer(embeddable()),
+            )
+            .route(
+                "/qr/vcard",
+                post(post_vcard_qr)
+                    .route_layer(limited(limits.qr))
+                    .route_layer(embeddable()),
+            );
+    }
+    let keyed = keyed.route_layer(middleware::from_fn_with_state(
+        app_state.clone(),
+        auth::require_api_key,
+    ));
+    // Apart from the others, which bodies as large as uploads would be wasted on
+    let mut uploads = Router::new();
+    if app_state.config.features.qr_codes {
+        uploads = uploads
+            .route(
+                "/qr/decode",
+                post(decode_qr).route_layer(limited(limits.qr)),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                auth::require_api_key,
+            ));
+    }
+    let dashboard = Router::new()
+        .route("/admin", get(dashboard::get_dashboard))
+        .route(
+            "/admin/links/{external_id}",
+            post(dashboard::update_from_dashboard),
+        )
+        .route(
+            "/admin/links/{external_id}/delete",
+            post(dashboard::delete_from_dashboard),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            session::require_session,
+        ));
+    let requests = &app_state.config.requests;
+    let body_limit = |max| {
+        ServiceBuilder::new()
+            .layer(DefaultBodyLimit::disable())
+            .layer(RequestBodyLimitLayer::new(max))
+    };
+    let mut router = visitors;
+    if routes == Routes::All && app_state.config.legacy_api.enabled {
+        router = router.merge(legacy_api_routes(&app_state).route_layer(
+            middleware::from_fn_with_state(app_state.clone(), legacy_api::deprecate),
+        ));
+    }
+    if routes == Routes::All {
+        router = router
+            .merge(public)
+            .merge(keyed)
+            .nest("/api/v1", api_v1(&app_state))
+            .merge(dashboard)
+            .layer(body_limit(requests.max_body_bytes))
+            .merge(uploads.layer(body_limit(requests.max_upload_bytes)));
+    } else {
+        router = router.layer(body_limit(requests.max_body_bytes));
+    }
+    let mut router = router.layer(middleware::from_fn(metrics::track));
+    // Outside the routes, so preflight requests are answered before any route turns them away
+    if let Some(cors) = app_state.cors.clone() {
+        router = router.layer(cors);
+    }
+    if requests.compress {
+        // The default predicate leaves out images other than SVG, which are compressed already,
+        // event streams, which it would hold back, and bodies too small to gain from it
+        router = router.layer(CompressionLayer::new().br(true).gzip(true));
+    }
+    let router = router
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|_: BoxError| async {
+                    Error::Overloaded
+                }))
+                .load_shed()
+                // One limit shared by every route, each of which the layer wraps on its own
+                .layer(GlobalConcurrencyLimitLayer::new(requests.max_concurrent)),
+        )
+        .layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            Duration::from_secs(requests.timeout_secs),
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            security_headers::set,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            client_ip::resolve,
+        ))
+        .layer(logging::trace_layer())
+        .layer(middleware::from_fn(request_id::assign))
+        .with_state(app_state);
+    // Around the router as a whole, as it only adds the `Allow` header once a route is done
+    Router::new()
+        .fallback_service(router)
+        .layer(middleware::from_fn(options::answer))
+}
+
+/// The JSON API for managing links and everything around them, served under `/api/v1`. A later
+/// version gets a function of its own, nested under its own prefix beside this one, so clients
+/// can move over one route at a time.
+fn api_v1(app_state: &AppState) -> Router<AppState> {
+    let limits = app_state.config.rate_limits;
+    let limited =
+        |quota| middleware::from_fn_with_state(RateLimiter::new(quota), rate_limit::enforce);
+    let mut public = Router::new()
+        .route("/links/{external_id}", get(get_meta))
+        .route("/links/{external_id}/stats", get(get_stats));
+    if app_state.config.features.click_tracking {
+        public = public.route("/links/{external_id}/events", get(live::stream_link_clicks));
+    }
+    let mut keyed = Router::new()
+        .route(
+            "/links",
+            get(list_links).merge(post(create_url).route_layer(limited(limits.create))),
+        )
+        .route(
+            "/links/batch",
+            post(create_batch).route_layer(limited(limits.create)),
+        )
+        .route("/links/{external_id}", patch(update_url).delete(delete_url))
+        .route("/links/{external_id}/restore", post(restore_url))
+        .route("/tags", get(list_tags))
+        .route("/export", get(export_links))
+        .route("/me/usage", get(quotas::get_usage));
+    if app_state.config.features.click_tracking {
+        keyed = keyed.route("/events", get(live::stream_clicks));
+    }
+    if app_state.config.features.qr_codes {
+        keyed = keyed
+            .route(
+                "/qr/batch",
+                post(get_qr_batch).route_layer(limited(limits.qr)),
+            )
+            .route(
+                "/qr/sheet",
+                post(get_qr_sheet).route_layer(limited(limits.qr)),
+            )
+            .route("/links/{external_id}/qr-url", get(signed_qr::get_qr_url));
+    }
+    keyed = admin_api_routes(app_state, keyed);
+    public.merge(keyed.route_layer(middleware::from_fn_with_state(
+        app_state.clone(),
+        auth::require_api_key,
+    )))
+}
+
+/// Users, keys, webhooks, stats pruning and backups, managed by admins, at the same paths
+/// under `/api/v1` as under `/api` before it
+fn admin_api_routes(app_state: &AppState, routes: Router<AppState>) -> Router<AppState> {
+    let mut routes = routes
+        .route("/keys", post(issue_api_key))
+        .route("/keys/{id}", delete(revoke_api_key))
+        .route("/users", get(list_users).post(add_user))
+        .route("/users/{id}", patch(update_user))
+        .route("/stats", delete(prune_stats))
+        .route(
+            "/webhooks",
+            get(webhooks::list_webhooks).post(webhooks::register_webhook),
+        )
+        .route("/webhooks/{id}", delete(webhooks::delete_webhook))
+        .route(
+            "/webhooks/{id}/deliveries",
+            get(webhooks::webhook_deliveries),
+        );
+    if app_state.backups.is_some() {
+        routes = routes.route(
+            "/admin/backup",
+            get(backup::download_backup).post(backup::take_backup),
+        );
+    }
+    routes
+}
+
+/// The routes of the API before `/api/v1`, served as they were for clients yet to move over
+fn legacy_api_routes(app_state: &AppState) -> Router<AppState> {
+    let limits = app_state.config.rate_limits;
+    let limited =
+        |quota| middleware::from_fn_with_state(RateLimiter::new(quota), rate_limit::enforce);
+    let mut public = Router::new()
+        .route("/{external_id}/meta", get(get_meta))
+        .route("/{external_id}/stats", get(get_stats));
+    if app_state.config.features.click_tracking {
+        public = public.route("/{external_id}/events", get(live::stream_link_clicks));
+    }
+    let mut keyed = Router::new()
+        .route("/{external_id}", delete(delete_url))
+        .route("/{external_id}", patch(update_url))
+        .route("/{external_id}/restore", post(restore_url))
+        .route("/api/links", get(list_links))
+        .route("/api/tags", get(list_tags))
+        .route("/api/export", get(export_links))
+        .route("/api/me/usage", get(quotas::get_usage))
+        .route(
+            "/api/links/batch",
+            post(create_batch).route_layer(limited(limits.create)),
+        )
+        .route("/", post(create_url).route_layer(limited(limits.create)));
+    if app_state.config.features.click_tracking {
+        keyed = keyed.route("/api/events", get(live::stream_clicks));
+    }
+    if app_state.config.features.qr_codes {
+        keyed = keyed
+            .route(
+                "/api/qr/batch",
+                post(get_qr_batch).route_layer(limited(limits.qr)),
+            )
+            .route(
+                "/api/qr/sheet",
+                post(get_qr_sheet).route_layer(limited(limits.qr)),
+            );
+    }
+    let keyed = keyed.nest("/api", admin_api_routes(app_state, Router::new()));
+    public.merge(keyed.route_layer(middleware::from_fn_with_state(
+        app_state.clone(),
+        auth::require_api_key,
+    )))
+}
+
+/// Serves `config` until Ctrl-C or SIGTERM, then lets in-flight requests finish and closes
+/// the database. Webhook deliveries queued before, e.g. retries, are picked up again.
+pub async fn run(config: Config) -> QrLinkResult<()> {
+    let store = open_store(&config).await?;
+    let app_state = AppState::new(config, store.clone())?;
+    app_state.webhooks.reload(&*store).await?;
+    app_state.webhooks.start(store.clone());
+    if app_state.config.health_checks.enabled {
+        Arc::new(HealthChecker::new(&app_state.config.health_checks)?).start(store.clone());
+    }
+    if let Some(safety) = &app_state.safety {
+        safety.clone().start(app_state.clone());
+    }
+    app_state.pruner.clone().start(store.clone());
+    if let Some(backups) = &app_state.backups {
+        backups.clone().start(store.clone());
+    }
+    let click_log = app_state.click_log.clone();
+    let tls = tls::start(&app_state.config.tls, app_state.config.base_url.as_ref()).await?;
+    let listen = app_state.config.listen.clone();
+    let admin_listen = app_state.config.admin_listen.clone();
+    let grpc_listen = app_state.config.grpc_listen.clone();
+    let grpc = grpc::router(app_state.clone());
+    let (public, admin) = if admin_listen.is_empty() {
+        (build_router(app_state), None)
+    } else {
+        (
+            build_public_router(app_state.clone()),
+            Some(build_router(app_state)),
+        )
+    };
+
+    // Bound before serving any, so an address taken stops the service from starting at all
+    let mut servers = Vec::new();
+    for address in &listen {
+        servers.push((listen::bind(address).await?, public.clone()));
+    }
+    for address in &admin_listen {
+        if let ListenAddr::Tcp(tcp) = address
+            && tcp.ip().is_unspecified()
+        {
+            tracing::warn!(%address, "admin_listen is reachable on every interface");
+        }
+        let admin = admin.clone().expect("admin_listen has a router of its own");
+        servers.push((listen::bind(address).await?, admin));
+    }
+    for address in &grpc_listen {
+        servers.push((listen::bind(address).await?, grpc.clone()));
+    }
+    let (stop, shutdown) = watch::channel(());
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = stop.send(());
+    });
+    future::try_join_all(
+        servers
+            .into_iter()
+            .map(|(bound, router)| listen::serve(bound, router, tls.clone(), shutdown.clone())),
+    )
+    .await
+    .map_err(Error::Io)?;
+
+    if let Some(click_log) = click_log {
+        click_log.flush().await;
+    }
+    tracing::info!("requests drained, closing the database");
+    store.close().await
+}
+
+/// Resolves on Ctrl-C or SIGTERM, after which the server stops accepting connections and
+/// finishes the requests it has
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("can't listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("can't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => {}
+        () = terminate => {}
+    }
+    tracing::info!("shutting down");
+}
+
+/// GET /<code or slug> forwards to a databased URL, or to the target for the visitor's
+/// platform or country, and records the click. It shows a page saying it is coming soon
+/// before it is activated and a warning while it is quarantined for a harmful destination,
+/// 410s if deleted, expired or out of clicks, or 404s. `?src=qr`
+/// marks the click as a QR code scan. Links that forward the query string append the rest of
+/// it to the destination.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RedirectQuery {
+    /// `qr` when the link was reached by scanning its QR code
+    src: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/{external_id}",
+    summary = "Redirect to URL",
+    params(("external_id" = String, Path, description = "Code or slug"), RedirectQuery),
+    responses(
+        (status = 303, description = "Redirect to the stored URL or a target"),
+        (status = 200, description = "Coming soon, before the link is activated",
+            content_type = "text/html", body = String),
+        (status = 403, description = "A warning instead, while the link is quarantined",
+            content_type = "text/html", body = String),
+        (status = 404, description = "No such link", body = ErrorBody),
+        (status = 410, description = "Deleted, expired or out of clicks", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody),
+    ),
+    tag = "links"
+)]
+async fn get_url(
+    Path(external_id): Path<String>,
+    Query(query): Query<RedirectQuery>,
+    State(app_state): State<AppState>,
+    ClientIp(ip_addr): ClientIp,
+    method: Method,
+    headers: HeaderMap,
+    uri: Uri,
+) -> QrLinkResult<Response> {
+    let visit = Visit {
+        ip_addr,
+        method,
+        headers,
+        uri,
+    };
+    follow(app_state, external_id, "", query, visit).await
+}
+
+/// GET /<code or slug>/<path> forwards like /<code or slug> for links that forward paths,
+/// appending `path` to the destination, and 404s for any other link
+#[utoipa::path(
+    get,
+    path = "/{external_id}/{path}",
+    summary = "Redirect to URL, with more path",
+    params(
+        ("external_id" = String, Path, description = "Code or slug"),
+        ("path" = String, Path, description = "Appended to the destination's path"),
+        RedirectQuery,
+    ),
+    responses(
+        (status = 303, description = "Redirect to the stored URL or a target, with `path`"),
+        (status = 200, description = "Coming soon, before the link is activated",
+            content_type = "text/html", body = String),
+        (status = 403, description = "A warning instead, while the link is quarantined",
+            content_type = "text/html", body = String),
+        (status = 404, description = "No such link, or it doesn't forward paths",
+            body = ErrorBody),
+        (status = 410, description = "Deleted, expired or out of clicks", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody),
+    ),
+    tag = "links"
+)]
+async fn get_url_path(
+    Path(params): Path<(String, String)>,
+    Query(query): Query<RedirectQuery>,
+    State(app_state): State<AppState>,
+    ClientIp(ip_addr): ClientIp,
+    method: Method,
+    headers: HeaderMap,
+    uri: Uri,
+) -> QrLinkResult<Response> {
+    let (external_id, _) = params;
+    // The path as sent rather than as decoded, so it reaches the destination unchanged
+    let path = uri
+        .path()
+        .trim_start_matches('/')
+        .split_once('/')
+        .map_or("", |(_, path)| path);
+    let path = path.to_owned();
+    let visit = Visit {
+        ip_addr,
+        method,
+        headers,
+        uri,
+    };
+    follow(app_state, external_id, &path, query, visit).await
+}
+
+/// The request of someone following a link
+struct Visit {
+    ip_addr: IpAddr,
+    method: Method,
+    headers: HeaderMap,
+    uri: Uri,
+}
+
+/// Redirects a visitor who followed `external_id`, with `path` after it, and records the click
+async fn follow(
+    app_state: AppState,
+    external_id: String,
+    path: &str,
+    query: RedirectQuery,
+    visit: Visit,
+) -> QrLinkResult<Response> {
+    let Visit {
+        ip_addr,
+        method,
+        headers,
+        uri,
+    } = visit;
+    let cached = app_state
+        .redirect_cache
+        .as_ref()
+        .and_then(|cache| cache.get(&external_id));
+    metrics::record_redirect_lookup(cached.is_some());
+    let redirect = match cached {
+        Some(redirect) => redirect,
+        None => {
+            let mut resolved = app_state.store.resolve(&external_id).await;
+            if let (Err(Error::NotFound(_)), Some(slug)) =
+                (&resolved, slug::from_capitals(&external_id))
+            {
+                resolved = app_state.store.resolve(&slug).await;
+            }
+            let link = resolved?;
+            if link.deleted_at.is_some() || link.is_expired() {
+                return Err(Error::Gone(external_id));
+            }
+            if let Some(quarantine) = &link.quarantine {
+                return Ok(quarantined(&link, quarantine.threat));
+            }
+            if let Some(activate_at) = link.activate_at.filter(|_| link.is_pending()) {
+                return Ok(coming_soon(&app_state, activate_at));
+            }
+            let targets = app_state.store.targets(link.id).await?;
+            let redirect = CachedRedirect::new(&link, targets);
+            if let Some(cache) = &app_state.redirect_cache {
+                cache.insert(&external_id, redirect.clone());
+            }
+            redirect
+        }
+    };
+    if !path.is_empty() && !redirect.forward_path {
+        return Err(Error::NotFound(format!("{external_id}/{path}")));
+    }
+    let link_id = redirect.link_id;
+    let header_value = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let location = app_state.geoip.locate(ip_addr);
+    let visitor = targeting::Visitor {
+        user_agent: header_value(header::USER_AGENT),
+        country: location.country.as_deref(),
+        accept_language: header_value(header::ACCEPT_LANGUAGE),
+    };
+    let targets = &redirect.targets;
+    let target = targeting::target(targets, visitor);
+    let variant = match target {
+        Some(_) => None,
+        None => targeting::variant(&targets.variants, link_id, ip_addr, Utc::now().date_naive()),
+    };
+    let url = target
+        .or(variant.map(|variant| variant.url.as_str()))
+        .unwrap_or(&redirect.url);
+    let forwarded = match redirect.forward_query {
+        true => forwarded_query(&uri),
+        false => String::new(),
+    };
+    let query_string = [
+        redirect.utm_query.as_deref().unwrap_or_default(),
+        &forwarded,
+    ]
+    .into_iter()
+    .filter(|part| !part.is_empty())
+    .collect::<Vec<_>>()
+    .join("&");
+    let url = &destination::forward(url, path, &query_string);
+
+    let opted_out = app_state.privacy.opted_out(&headers);
+    // Link-preview bots and uptime checks sending HEAD aren't visitors, unless said otherwise
+    let counted = method != Method::HEAD || app_state.config.redirects.count_head_requests;
+    // Visits asking not to be tracked are only counted toward a click limit
+    if app_state.config.features.click_tracking
+        && counted
+        && (!opted_out || redirect.has_click_limit)
+    {
+        let source = match query.src.as_deref() {
+            Some("qr") => ClickSource::Qr,
+            _ => ClickSource::Link,
+        };
+        let click = if opted_out {
+            Click::anonymous(source)
+        } else {
+            let mut click = Click {
+                country: location.country,
+                city: location.city,
+                source,
+                variant: variant.map(|variant| variant.url.clone()),
+                ..Click::new(
+                    ip_addr,
+                    header_value(header::REFERER),
+                    header_value(header::USER_AGENT),
+                )
+            };
+            app_state
+                .privacy
+                .anonymize(&*app_state.store, &mut click, ip_addr)
+                .await?;
+            click
+        };
+        let event = Event::click(&external_id, url, &click);
+        let recorded = match &app_state.click_log {
+            // Limited links need to know whether this was their last click
+            Some(click_log) if !redirect.has_click_limit => {
+                click_log.record(&*app_state.store, link_id, click).await?;
+                RecordedClick::Counted
+            }
+            _ => app_state.store.record_click(link_id, click).await?,
+        };
+        if recorded != RecordedClick::Counted {
+            app_state.forget_link(link_id);
+        }
+        if recorded == RecordedClick::Refused {
+            return Err(Error::Gone(external_id));
+        }
+        if !opted_out {
+            let owner_id = redirect.owner_id;
+            app_state
+                .live
+                .publish(link_id, owner_id, &external_id, &event);
+            webhooks::publish(&app_state, link_id, event).await;
+        }
+        if recorded == RecordedClick::Last {
+            let link = app_state.store.resolve(&external_id).await?;
+            let event = Event::link(WebhookEvent::LinkDeleted, &link);
+            webhooks::publish(&app_state, link_id, event).await;
+        }
+    }
+
+    let mut response = Redirect::to(url).into_response();
+    if redirect.noindex {
+        site::noindex(&mut response);
+    }
+    Ok(response)
+}
+
+/// The query string a visitor brought, less the `src` parameter the service reads itself
+fn forwarded_query(uri: &Uri) -> String {
+    uri.query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty() && *pair != "src" && !pair.starts_with("src="))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The page a link shows until `activate_at`, which no one should keep past it
+fn coming_soon(app_state: &AppState, activate_at: DateTime<Utc>) -> Response {
+    let page = match &app_state.coming_soon {
+        Some(page) => page.to_string(),
+        None => pages::coming_soon(activate_at).into_string(),
+    };
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        axum::response::Html(page),
+    )
+        .into_response()
+}
+
+/// The warning a link shows while it is quarantined, which no one should keep past that
+fn quarantined(link: &Link, threat: Threat) -> Response {
+    let host = url::Url::parse(&link.url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_default();
+    (
+        StatusCode::FORBIDDEN,
+        [(header::CACHE_CONTROL, "no-store")],
+        axum::response::Html(pages::quarantined(&host, threat).into_string()),
+    )
+        .into_response()
+}
+
+/// GET /<id>/qr?size=300 draws a QR-kode for /<id>, size is optional
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct QrQuery {
+    /// Smallest width and height in pixels, for the raster formats and SVG, at most 4096
+    size: Option<u32>,
+    /// Printed width and height of PDFs in millimeters, quiet zone included, 40 by default
+    width_mm: Option<f64>,
+    /// Takes precedence over the `Accept` header, which picks the format otherwise
+    format: Option<Format>,
+    /// JPEG quality from 1 to 100, 90 by default
+    quality: Option<u8>,
+    target: Option<QrTarget>,
+    /// Whether SVGs and PDFs get the blank border scanners expect, on by default
+    quiet_zone: Option<bool>,
+    /// Error correction level, `M` by default
+    ec: Option<ErrorCorrection>,
+    /// Version from 1 to 40, fixing the number of modules; by default the smallest that fits
+    version: Option<u8>,
+    /// Whether to draw the smallest code there is, for printing very small: a Micro QR code
+    /// when the link fits in one, else the smallest version, with the short link in capitals
+    /// but for a code, and without `?src=qr`, so that its scans count as direct clicks
+    compact: Option<bool>,
+    /// Color of the dark modules as RRGGBB hex, black by default
+    #[param(value_type = Option<String>)]
+    fg: Option<Color>,
+    /// Color of the light modules and border as RRGGBB hex, white by default
+    #[param(value_type = Option<String>)]
+    bg: Option<Color>,
+    /// How modules are drawn in raster formats and SVG: `square` by default, `rounded` or
+    /// `dots`. The finder patterns in the corners stay square.
+    style: Option<Style>,
+    /// Color of a frame drawn around the code as RRGGBB hex, for raster formats and SVG; that
+    /// of the dark modules by default when there is a `label`
+    #[param(value_type = Option<String>)]
+    frame: Option<Color>,
+    /// Text centered below the code in the frame, such as `SCAN ME` or the slug
+    label: Option<String>,
+    /// Height of the label's letters in pixels, a twelfth of `size` by default
+    label_size: Option<u32>,
+    /// Whether to draw the configured logo in the middle, for raster formats; forces `ec=H`
+    logo: Option<bool>,
+    /// Whether the browser should save the code rather than show it
+    #[serde(default, deserialize_with = "extract::flag")]
+    download: Option<bool>,
+    /// Name the code is saved under, `{slug}-qr.{extension}` by default; the extension is
+    /// added when it is missing
+    filename: Option<String>,
+}
+
+/// Whether a QR code encodes the short link or the stored destination
+#[derive(Deserialize, Default, Clone, Copy, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum QrTarget {
+    #[default]
+    Short,
+    Long,
+}
+
+#[utoipa::path(
+    get,
+    path = "/{external_id}/qr",
+    summary = "Return QR code as PNG, SVG, ASCII, JPEG, WebP, BMP, PDF or a data URI",
+    params(
+        ("external_id" = String, Path, description = "Code or slug"),
+        QrQuery,
+        ("sig" = Option<String>, Query, description = "Signature of the other parameters, \
+            which `qr.signing_key` calls for, from `GET /api/v1/links/{external_id}/qr-url`"),
+    ),
+    responses(
+        (status = 200, description = "The QR code", content(
+            (Vec<u8> = "image/png"),
+            (String = "image/svg+xml"),
+            (String = "text/plain"),
+            (Vec<u8> = "image/jpeg"),
+            (Vec<u8> = "image/webp"),
+            (Vec<u8> = "image/bmp"),
+            (Vec<u8> = "application/pdf"),
+            (qr::DataUri = "application/json"),
+        ), headers(
+            ("x-qr-warning" = String, description = "Why the colors may not scan"),
+            ("content-disposition" = String, description = "The file name to save the code \
+                under, when `download` or `filename` is given"),
+            ("vary" = String, description = "`accept`, which picks the format when `format` \
+                isn't given"),
+            ("etag" = String, description = "Changes whenever the drawn code does"),
+            ("cache-control" = String, description = "`public`, with the configured \
+                `qr.max_age_secs` as `max-age`"),
+        )),
+        (status = 304, description = "The code named in `If-None-Match` is still current"),
+        (status = 400, description = "Invalid parameters, or the link doesn't fit in the \
+            requested version", body = ErrorBody),
+        (status = 403, description = "A `sig` is missing or doesn't match the parameters, or \
+            a signed URL names no `format`", body = ErrorBody),
+        (status = 404, description = "No such link", body = ErrorBody),
+        (status = 422, description = "The link doesn't fit in a QR code", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody),
+    ),
+    tag = "qr"
+)]
+async fn get_qr(
+    Path(external_id): Path<String>,
+    State(app_state): State<AppState>,
+    Query(params): Query<QrQuery>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> QrLinkResult<Response> {
+    signed_qr::verify(&app_state, &external_id, query.as_deref())?;
+    let link = app_state.store.resolve_active(&external_id).await?;
+    let format = qr_format(&params, &headers);
+    let rendering = qr_rendering(&app_state, &params, format)?;
+    let url = qr_payload(
+        &app_state,
+        &headers,
+        &link,
+        params.target.unwrap_or_default(),
+        rendering.compact,
+    );
+    let disposition = qr_disposition(&params, &format!("{}-qr", link.public_id()), format)?;
+
+    // The key holds the encoded URL, so a code drawn before the link changed can't be served
+    // for it even before the cache hears about the change
+    let key = rendering.cache_key(&url);
+    let cached = app_state
+        .qr_cache
+        .as_ref()
+        .and_then(|cache| cache.get(link.id, &key));
+    let qr = match cached {
+        Some(qr) => qr,
+        None => {
+            let qr = render_qr(&rendering, &url).await?;
+            if let Some(cache) = &app_state.qr_cache {
+                cache.insert(link.id, key, qr.clone());
+            }
+            qr
+        }
+    };
+
+    let cache_control = format!("public, max-age={}", app_state.config.qr.max_age_secs);
+    Ok(qr_response(
+        &headers,
+        &rendering,
+        qr,
+        cache_control,
+        disposition,
+    ))
+}
+
+/// GET /qr?data=... draws a QR code for any text rather than a stored link, taking the same
+/// parameters as /<id>/qr
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PayloadQuery {
+    /// The text to encode, such as a URL, a `WIFI:` network or a vCard
+    data: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/qr",
+    summary = "Return QR code for any text",
+    description = "Takes the parameters of `GET /{external_id}/qr` besides `target`, and \
+        answers the same way.",
+    params(PayloadQuery, QrQuery),
+    responses(
+        (status = 200, description = "The QR code", content(
+            (Vec<u8> = "image/png"),
+            (String = "image/svg+xml"),
+            (String = "text/plain"),
+            (Vec<u8> = "image/jpeg"),
+            (Vec<u8> = "image/webp"),
+            (Vec<u8> = "image/bmp"),
+            (Vec<u8> = "application/pdf"),
+            (qr::DataUri = "application/json"),
+        )),
+        (status = 304, description = "The code named in `If-None-Match` is still current"),
+        (status = 400, description = "Invalid parameters, or the text doesn't fit in the \
+            requested version", body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 422, description = "The text doesn't fit in a QR code", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "qr"
+)]
+async fn get_payload_qr(
+    State(app_state): State<AppState>,
+    _: Authorized<Read>,
+    Query(payload): Query<PayloadQuery>,
+    Query(params): Query<QrQuery>,
+    headers: HeaderMap,
+) -> QrLinkResult<Response> {
+    if payload.data.is_empty() {
+        return Err(Error::Validation("data must not be empty".into()));
+    }
+    payload_qr(&app_state, &headers, &params, &payload.data, "qr").await
+}
+
+/// POST /qr/wifi draws a QR code that joins a Wi-Fi network when scanned, taking the same
+/// query parameters as /<id>/qr
+#[utoipa::path(
+    post,
+    path = "/qr/wifi",
+    summary = "Return QR code joining a Wi-Fi network",
+    description = "Takes the parameters of `GET /{external_id}/qr` besides `target` in the \
+        query string, and answers the same way.",
+    params(QrQuery),
+    request_body = payload::Wifi,
+    responses(
+        (status = 200, description = "The QR code", content(
+            (Vec<u8> = "image/png"),
+            (String = "image/svg+xml"),
+            (String = "text/plain"),
+            (Vec<u8> = "image/jpeg"),
+            (Vec<u8> = "image/webp"),
+            (Vec<u8> = "image/bmp"),
+            (Vec<u8> = "application/pdf"),
+            (qr::DataUri = "application/json"),
+        )),
+        (status = 304, description = "The code named in `If-None-Match` is still current"),
+        (status = 400, description = "Invalid network or parameters", body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "qr"
+)]
+async fn post_wifi_qr(
+    State(app_state): State<AppState>,
+    _: Authorized<Read>,
+    Query(params): Query<QrQuery>,
+    headers: HeaderMap,
+    Json(wifi): Json<payload::Wifi>,
+) -> QrLinkResult<Response> {
+    let data = wifi.payload()?;
+    payload_qr(&app_state, &headers, &params, &data, "wifi-qr").await
+}
+
+/// POST /qr/vcard draws a QR code that saves a contact when scanned, taking the same query
+/// parameters as /<id>/qr
+#[utoipa::path(
+    post,
+    path = "/qr/vcard",
+    summary = "Return QR code holding a contact",
+    description = "Encodes the contact as a vCard 3.0. Takes the parameters of \
+        `GET /{external_id}/qr` besides `target` in the query string, and answers the same way.",
+    params(QrQuery),
+    request_body = payload::Vcard,
+    responses(
+        (status = 200, description = "The QR code", content(
+            (Vec<u8> = "image/png"),
+            (String = "image/svg+xml"),
+            (String = "text/plain"),
+            (Vec<u8> = "image/jpeg"),
+            (Vec<u8> = "image/webp"),
+            (Vec<u8> = "image/bmp"),
+            (Vec<u8> = "application/pdf"),
+            (qr::DataUri = "application/json"),
+        )),
+        (status = 304, description = "The code named in `If-None-Match` is still current"),
+        (status = 400, description = "Invalid contact or parameters, or the contact doesn't fit \
+            in a QR code", body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "qr"
+)]
+async fn post_vcard_qr(
+    State(app_state): State<AppState>,
+    _: Authorized<Read>,
+    Query(params): Query<QrQuery>,
+    headers: HeaderMap,
+    Json(vcard): Json<payload::Vcard>,
+) -> QrLinkResult<Response> {
+    let data = vcard.payload()?;
+    payload_qr(&app_state, &headers, &params, &data, "contact-qr")
+        .await
+        .map_err(|error| match error {
+            Error::QrGeneration(QrError::DataTooLong) => Error::Validation(format!(
+                "the contact takes {} bytes, more than a QR code holds at this error \
+                 correction; leave out a field or ask for a lower level",
+                data.len()
+            )),
+            error => error,
+        })
+}
+
+/// `data` drawn as `params` ask, saved as `{stem}.{extension}` unless they name the file
+async fn payload_qr(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    params: &QrQuery,
+    data: &str,
+    stem: &str,
+) -> QrLinkResult<Response> {
+    let format = qr_format(params, headers);
+    let rendering = qr_rendering(app_state, params, format)?;
+    let disposition = qr_disposition(params, stem, format)?;
+
+    let qr = render_qr(&rendering, data).await?;
+
+    // Only the key holder asked for this code, so shared caches shouldn't keep it
+    let cache_control = format!("private, max-age={}", app_state.config.qr.max_age_secs);
+    Ok(qr_response(
+        headers,
+        &rendering,
+        qr,
+        cache_control,
+        disposition,
+    ))
+}
+
+/// Draws `data` as `rendering` asks, on a blocking thread: a large code takes long enough to
+/// hold up every other request on the worker, and the request timeout can't cut it short
+async fn render_qr(rendering: &qr::Rendering, data: &str) -> QrLinkResult<CachedQr> {
+    let (rendering, data) = (rendering.clone(), data.to_owned());
+    let started = Instant::now();
+    let format = rendering.format;
+    let qr = tokio::task::spawn_blocking(move || rendering.render(&data))
+        .await
+        .map_err(Error::Task)??;
+    metrics::record_qr_render(format.as_str(), started);
+    Ok(CachedQr::new(qr))
+}
+
+/// The format `params` ask for, or else the one the `Accept` header prefers
+fn qr_format(params: &QrQuery, headers: &HeaderMap) -> Format {
+    params.format.unwrap_or_else(|| {
+        Format::negotiate(
+            headers
+                .get(header::ACCEPT)
+                .and_then(|value| value.to_str().ok()),
+        )
+    })
+}
+
+/// The `Content-Disposition` `params` ask for, saving the code as `{stem}.{extension}` unless
+/// they name it
+fn qr_disposition(params: &QrQuery, stem: &str, format: Format) -> QrLinkResult<Option<String>> {
+    let download = params.download.unwrap_or(false);
+    if !download && params.filename.is_none() {
+        return Ok(None);
+    }
+    // Inline codes keep a given name for when they are saved from the page after all
+    let kind = if download { "attachment" } else { "inline" };
+    let name = qr::file_name(params.filename.as_deref(), stem, format.extension())?;
+    Ok(Some(format!("{kind}; filename=\"{name}\"")))
+}
+
+/// Answers with a drawn code, or with 304 when the client's copy named in `If-None-Match` is
+/// still current
+fn qr_response(
+    request_headers: &HeaderMap,
+    rendering: &qr::Rendering,
+    qr: CachedQr,
+    cache_control: String,
+    disposition: Option<String>,
+) -> Response {
+    let caching = [
+        (header::ETAG, qr.etag.to_string()),
+        (header::CACHE_CONTROL, cache_control),
+        (header::VARY, "accept".into()),
+    ];
+    let if_none_match = request_headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
+    if qr.matches(if_none_match) {
+        return (StatusCode::NOT_MODIFIED, caching).into_response();
+    }
+    let disposition = disposition.map(|value| [(header::CONTENT_DISPOSITION, value)]);
+    let warning = rendering
+        .warning()
+        .map(|warning| [(qr::WARNING_HEADER, warning)]);
+    (
+        [(header::CONTENT_TYPE, rendering.format.content_type())],
+        caching,
+        disposition,
+        warning,
+        qr.body,
+    )
+        .into_response()
+}
+
+/// What the QR code of `link` encodes. Compact codes leave out the `?src=qr` that tells scans
+/// from other clicks, which would take more modules than all of the rest, and have their URL
+/// in the capitals [qr::compact_url] puts it in, their slug too, which is followed in capitals
+/// as well.
+fn qr_payload(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    link: &Link,
+    target: QrTarget,
+    compact: bool,
+) -> String {
+    let short_url = |external_id: &str| short_url(app_state, headers, external_id);
+    match (target, compact) {
+        (QrTarget::Short, false) => format!("{}?src=qr", short_url(&link.public_id())),
+        (QrTarget::Short, true) => {
+            let external_id = match &link.slug {
+                Some(slug) => slug.to_ascii_uppercase(),
+                None => link.public_id(),
+            };
+            qr::compact_url(&short_url(&external_id))
+        }
+        (QrTarget::Long, false) => link.url.clone(),
+        (QrTarget::Long, true) => qr::compact_url(&link.url),
+    }
+}
+
+/// POST /qr/decode reads the QR codes in an uploaded image, to check that a printed proof
+/// scans back to what was drawn
+#[derive(Serialize, ToSchema)]
+struct QrDecodeResponse {
+    /// Every code that could be read, usually just one
+    codes: Vec<qr::Decoded>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/qr/decode",
+    summary = "Read the QR codes in an image",
+    request_body(
+        description = "The image as the request body, or as the `image` field of a form",
+        content((Vec<u8> = "image/*"), (Vec<u8> = "multipart/form-data")),
+    ),
+    responses(
+        (status = 200, description = "The codes found", body = QrDecodeResponse),
+        (status = 400, description = "No upload, or it isn't an image", body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 413, description = "The upload is too large", body = ErrorBody),
+        (status = 422, description = "No QR code could be read in the image", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "qr"
+)]
+async fn decode_qr(
+    _: Authorized<Read>,
+    ImageUpload(image): ImageUpload,
+) -> QrLinkResult<axum::Json<QrDecodeResponse>> {
+    let codes = tokio::task::spawn_blocking(move || qr::decode(&image))
+        .await
+        .map_err(Error::Task)??;
+    Ok(axum::Json(QrDecodeResponse { codes }))
+}
+
+/// Takes an image from the `image` field, or else the first file, of a `multipart/form-data`
+/// upload, and from the raw body otherwise
+struct ImageUpload(Bytes);
+
+impl<S: Send + Sync> FromRequest<S> for ImageUpload {
+    type Rejection = Error;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_form = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("multipart/form-data"));
+
+        let image = if is_form {
+            let mut form = Multipart::from_request(req, state)
+                .await
+                .map_err(|rejection| body_rejected(rejection.status(), rejection.body_text()))?;
+            let mut image = None;
+            while let Some(field) = form
+                .next_field()
+                .await
+                .map_err(|error| body_rejected(error.status(), error.body_text()))?
+            {
+                if field.name() == Some("image") || field.file_name().is_some() {
+                    let bytes = field
+                        .bytes()
+                        .await
+                        .map_err(|error| body_rejected(error.status(), error.body_text()))?;
+                    image = Some(bytes);
+                    break;
+                }
+            }
+            image.unwrap_or_default()
+        } else {
+            Bytes::from_request(req, state)
+                .await
+                .map_err(|rejection| body_rejected(rejection.status(), rejection.body_text()))?
+        };
+        if image.is_empty() {
+            return Err(Error::Validation("no image was uploaded".into()));
+        }
+        Ok(ImageUpload(image))
+    }
+}
+
+/// POST /api/v1/qr/batch?format=svg with {"ids": [...]} answers with a ZIP archive holding a QR
+/// code for each link, named after its slug or code and drawn as the same query parameters ask
+/// of /<id>/qr
+#[derive(Deserialize, ToSchema)]
+struct QrBatchParams {
+    /// Codes, slugs or ids of the links, at most 1000
+    ids: Vec<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/qr/batch",
+    summary = "Download the QR codes of many links as a ZIP archive",
+    description = "Takes the query parameters of `GET /{external_id}/qr`, except that `format` \
+        defaults to PNG and can't be `datauri`, and `filename` names the archive. Each link's \
+        code is saved as `{slug or code}.{extension}`.",
+    params(QrQuery),
+    request_body = QrBatchParams,
+    responses(
+        (status = 200, description = "The archive", content_type = "application/zip",
+            body = Vec<u8>),
+        (status = 400, description = "Invalid parameters, or no links or too many",
+            body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 404, description = "One of the links doesn't exist", body = ErrorBody),
+        (status = 422, description = "A link doesn't fit in a QR code", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "qr"
+)]
+async fn get_qr_batch(
+    State(app_state): State<AppState>,
+    caller: Authorized<Read>,
+    Query(params): Query<QrQuery>,
+    headers: HeaderMap,
+    Json(batch): Json<QrBatchParams>,
+) -> QrLinkResult<impl IntoResponse> {
+    if batch.ids.is_empty() || batch.ids.len() > MAX_BATCH_SIZE {
+        return Err(Error::Validation(format!(
+            "a batch must have between 1 and {MAX_BATCH_SIZE} links"
+        )));
+    }
+    let format = params.format.unwrap_or_default();
+    if format == Format::DataUri {
+        return Err(Error::Validation(
+            "datauri codes can't be archived, ask for png".into(),
+        ));
+    }
+    let rendering = qr_rendering(&app_state, &params, format)?;
+    let target = params.target.unwrap_or_default();
+    let archive_name = qr::file_name(params.filename.as_deref(), "qr-codes", "zip")?;
+
+    let mut names = HashSet::new();
+    let mut codes = Vec::with_capacity(batch.ids.len());
+    for external_id in &batch.ids {
+        let link = app_state
+            .store
+            .resolve_active_in(external_id, caller.scope())
+            .await?;
+        let name = format!("{}.{}", link.public_id(), format.extension());
+        // The same link asked for twice, or by code and by slug, is archived once
+        if names.insert(name.clone()) {
+            codes.push((
+                name,
+                qr_payload(&app_state, &headers, &link, target, rendering.compact),
+            ));
+        }
+    }
+    let started = Instant::now();
+    let archive = tokio::task::spawn_blocking(move || qr::archive(&rendering, &codes))
+        .await
+        .map_err(Error::Task)??;
+    metrics::record_qr_render("zip", started);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{archive_name}\""),
+            ),
+        ],
+        archive,
+    ))
+}
+
+/// POST /api/v1/qr/sheet with {"links": [{"id": ..., "label": ...}], "paper": "a4", "columns": 3,
+/// "rows": 7, ...} answers with a PDF of the links' QR codes laid out in a grid for printing on
+/// sticker sheets, each captioned with its label or else its slug or code
+#[derive(Deserialize, ToSchema)]
+struct QrSheetParams {
+    /// The links in the order they are laid out, at most 1000
+    links: Vec<SheetLink>,
+    #[serde(flatten)]
+    layout: sheet::Layout,
+}
+
+#[derive(Clone, Deserialize, ToSchema)]
+struct SheetLink {
+    /// Code, slug or id
+    id: String,
+    /// Printed below the code instead of the link's slug or code
+    label: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/qr/sheet",
+    summary = "Lay out the QR codes of many links on printable PDF pages",
+    description = "Takes the query parameters of `GET /{external_id}/qr` that apply to PDFs, \
+        with `filename` naming the sheet. The codes fill as many pages as they need, left to \
+        right and top to bottom.",
+    params(QrQuery),
+    request_body = QrSheetParams,
+    responses(
+        (status = 200, description = "The sheet", content_type = "application/pdf",
+            body = Vec<u8>),
+        (status = 400, description = "Invalid parameters or layout, or no links or too many",
+            body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 404, description = "One of the links doesn't exist", body = ErrorBody),
+        (status = 422, description = "A link doesn't fit in a QR code", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "qr"
+)]
+async fn get_qr_sheet(
+    State(app_state): State<AppState>,
+    caller: Authorized<Read>,
+    Query(params): Query<QrQuery>,
+    headers: HeaderMap,
+    Json(sheet): Json<QrSheetParams>,
+) -> QrLinkResult<impl IntoResponse> {
+    let file_name = qr::file_name(params.filename.as_deref(), "qr-sheet", "pdf")?;
+    let pdf = qr_sheet(
+        &app_state,
+        &headers,
+        caller.scope(),
+        &params,
+        sheet.links,
+        sheet.layout,
+    )
+    .await?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/pdf".to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{file_name}\""),
+            ),
+        ],
+        pdf,
+    ))
+}
+
+/// The sheet of the codes of `links` within `scope`, drawn as `params` ask and laid out as
+/// `layout` says
+async fn qr_sheet(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    scope: Scope,
+    params: &QrQuery,
+    links: Vec<SheetLink>,
+    layout: sheet::Layout,
+) -> QrLinkResult<Vec<u8>> {
+    if links.is_empty() || links.len() > sheet::MAX_STICKERS {
+        return Err(Error::Validation(format!(
+            "a sheet must have between 1 and {} links",
+            sheet::MAX_STICKERS
+        )));
+    }
+    let rendering = qr_rendering(app_state, params, Format::Pdf)?;
+    let target = params.target.unwrap_or_default();
+    let mut stickers = Vec::with_capacity(links.len());
+    for sheet_link in links {
+        let link = app_state
+            .store
+            .resolve_active_in(&sheet_link.id, scope)
+            .await?;
+        stickers.push(sheet::Sticker {
+            data: qr_payload(app_state, headers, &link, target, rendering.compact),
+            caption: sheet_link.label.unwrap_or_else(|| link.public_id()),
+        });
+    }
+    let started = Instant::now();
+    let pdf = tokio::task::spawn_blocking(move || sheet::render(&rendering, &stickers, &layout))
+        .await
+        .map_err(Error::Task)??;
+    metrics::record_qr_render("sheet", started);
+    Ok(pdf)
+}
+
+/// The drawing `params` ask for in `format`, once they are checked
+fn qr_rendering(
+    app_state: &AppState,
+    params: &QrQuery,
+    format: Format,
+) -> QrLinkResult<qr::Rendering> {
+    let quality = params.quality.unwrap_or(qr::DEFAULT_QUALITY);
+    if !(1..=100).contains(&quality) {
+        return Err(Error::Validation(
+            "quality must be between 1 and 100".into(),
+        ));
+    }
+    let width_mm = params.width_mm.unwrap_or(qr::DEFAULT_PDF_WIDTH_MM);
+    if !qr::PDF_WIDTH_MM.contains(&width_mm) {
+        return Err(Error::Validation(format!(
+            "width_mm must be between {} and {}",
+            qr::PDF_WIDTH_MM.start(),
+            qr::PDF_WIDTH_MM.end()
+        )));
+    }
+    let logo = match (params.logo.unwrap_or(false), &app_state.logo) {
+        (false, _) => None,
+        (true, None) => return Err(Error::Validation("no logo is configured".into())),
+        (true, Some(_)) if !format.is_raster() => {
+            return Err(Error::Validation(format!(
+                "logos can't be drawn on {} QR codes",
+                format.as_str()
+            )));
+        }
+        (true, Some(logo)) => Some(logo.clone()),
+    };
+    let compact = params.compact.unwrap_or(false);
+    if compact && params.version.is_some() {
+        return Err(Error::Validation(
+            "compact codes take the smallest version there is, leave out version".into(),
+        ));
+    }
+    let style = params.style.unwrap_or_default();
+    if style != Style::Square && !(format.is_raster() || format == Format::Svg) {
+        return Err(Error::Validation(format!(
+            "modules can't be drawn {} on {} QR codes",
+            style.as_str(),
+            format.as_str()
+        )));
+    }
+    let size = params.size.unwrap_or(app_state.config.qr.default_size);
+    if !(1..=config::MAX_QR_SIZE).contains(&size) {
+        return Err(Error::Validation(format!(
+            "size must be between 1 and {}",
+            config::MAX_QR_SIZE
+        )));
+    }
+    let frame = qr_frame(app_state, params, format, size)?;
+    // A logo hides modules, so the code needs all the error correction it can get
+    let level = match logo {
+        Some(_) => ErrorCorrection::H,
+        None => params.ec.unwrap_or_default(),
+    };
+    Ok(qr::Rendering {
+        format,
+        size,
+        quality,
+        width_mm,
+        quiet_zone: params.quiet_zone.unwrap_or(true),
+        level,
+        version: params.version,
+        compact,
+        foreground: params.fg.unwrap_or(Color::BLACK),
+        background: params.bg.unwrap_or(Color::WHITE),
+        style,
+        frame,
+        logo,
+    })
+}
+
+/// The frame `params` ask to draw around a code `size` wide, if any
+fn qr_frame(
+    app_state: &AppState,
+    params: &QrQuery,
+    format: Format,
+    size: u32,
+) -> QrLinkResult<Option<qr::Frame>> {
+    if params.frame.is_none() && params.label.is_none() {
+        return Ok(None);
+    }
+    if !(format.is_raster() || format == Format::Svg) {
+        return Err(Error::Validation(format!(
+            "frames can't be drawn around {} QR codes",
+            format.as_str()
+        )));
+    }
+    let label = params.label.as_deref().map(str::trim);
+    if let Some(label) = label
+        && (label.is_empty() || label.chars().count() > qr::MAX_LABEL_LENGTH)
+    {
+        return Err(Error::Validation(format!(
+            "label must be between 1 and {} characters",
+            qr::MAX_LABEL_LENGTH
+        )));
+    }
+    let label_size = params.label_size.unwrap_or((size / 12).max(10));
+    if !qr::LABEL_SIZE.contains(&label_size) {
+        return Err(Error::Validation(format!(
+            "label_size must be between {} and {}",
+            qr::LABEL_SIZE.start(),
+            qr::LABEL_SIZE.end()
+        )));
+    }
+    Ok(Some(qr::Frame {
+        color: params.frame.or(params.fg).unwrap_or(Color::BLACK),
+        label: label.map(str::to_owned),
+        label_size,
+        font: app_state.cards.font().clone(),
+    }))
+}
+
+/// The `Host` the client used to reach us
+fn request_host(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// The public short link for <code or slug>, under the configured `base_url`, or else built from
+/// the `Host` the client used to reach us, and the scheme a trusted proxy says it used
+fn short_url(app_state: &AppState, headers: &HeaderMap, external_id: &str) -> String {
+    if let Some(base_url) = &app_state.config.base_url {
+        return format!(
+            "{}/{}",
+            base_url.as_str().trim_end_matches('/'),
+            external_id
+        );
+    }
+    let host = request_host(headers).unwrap_or("localhost:3000");
+    let scheme = headers
+        .get(client_ip::FORWARDED_PROTO)
+        .and_then(|value| value.to_str().ok())
+        .filter(|scheme| ["http", "https"].contains(scheme))
+        .unwrap_or("http");
+    format!("{}://{}/{}", scheme, host, external_id)
+}
+
+/// The host short links live under, which destinations may not point back at
+fn own_host<'a>(app_state: &'a AppState, headers: &'a HeaderMap) -> Option<&'a str> {
+    match &app_state.config.base_url {
+        Some(base_url) => base_url.host_str(),
+        None => request_host(headers),
+    }
+}
+
+/// GET /api/v1/links/<id> returns a JSON object with meta data
+#[utoipa::path(
+    get,
+    path = "/api/v1/links/{external_id}",
+    summary = "Return metadata",
+    description = "Links of a user are only found with a key of theirs or the admin token, like \
+        their stats.",
+    params(("external_id" = String, Path, description = "Code or slug")),
+    responses(
+        (status = 200, body = LinkMeta),
+        (status = 404, description = "No such link, or none the caller sees", body = ErrorBody),
+    ),
+    tag = "links"
+)]
+async fn get_meta(
+    Path(external_id): Path<String>,
+    State(app_state): State<AppState>,
+    caller: Option<Caller>,
+    headers: HeaderMap,
+) -> QrLinkResult<axum::Json<LinkMeta>> {
+    let scope = Caller::scope_of(caller.as_ref());
+    let link = app_state
+        .store
+        .resolve_active_in(&external_id, scope)
+        .await?;
+    Ok(axum::Json(link_meta(&app_state, &headers, link).await?))
+}
+
+/// The meta data of a link, including the destinations it has had before
+#[derive(Serialize, ToSchema)]
+struct LinkMeta {
+    stored_id: String,
+    stored_url: String,
+    short_url: String,
+    code: Option<String>,
+    slug: Option<String>,
+    created_at: DateTime<Utc>,
+    activate_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    max_clicks: Option<u64>,
+    forward_query: bool,
+    forward_path: bool,
+    noindex: bool,
+    utm_template: Option<String>,
+    title: Option<String>,
+    /// The `<title>` of the destination page, if it has been fetched
+    page_title: Option<String>,
+    /// The description the destination page gives of itself, if it has been fetched
+    page_description: Option<String>,
+    /// Where visitors on these platforms are sent instead of `stored_url`
+    targets: BTreeMap<Platform, String>,
+    /// Where visitors accepting these languages are sent instead, unless their platform has a
+    /// target
+    language_targets: Vec<LanguageTarget>,
+    /// Where visitors from these countries are sent instead, unless their platform or language
+    /// has a target
+    country_targets: Vec<CountryTarget>,
+    /// Where everyone no target is for is split between instead of `stored_url`
+    variants: Vec<Variant>,
+    /// Alphabetically
+    tags: Vec<String>,
+    /// How the destination answered when it was last checked, if health checks are on
+    health: Option<LinkHealth>,
+    /// Set while the destination is flagged as harmful, when the link shows a warning instead
+    /// of redirecting
+    quarantine: Option<Quarantine>,
+    /// Earlier destinations, most recently replaced first
+    history: Vec<HistoryEntry>,
+}
+
+async fn link_meta(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    link: Link,
+) -> QrLinkResult<LinkMeta> {
+    let history = app_state.store.history(link.id).await?;
+    let targets = app_state.store.targets(link.id).await?;
+    let tags = app_state.store.tags(link.id).await?;
+    let health = app_state.store.health(link.id).await?;
+    Ok(LinkMeta {
+        stored_id: link.id.to_string(),
+        short_url: short_url(app_state, headers, &link.public_id()),
+        stored_url: link.url,
+        code: link.code,
+        slug: link.slug,
+        created_at: link.created_at,
+        activate_at: link.activate_at,
+        expires_at: link.expires_at,
+        max_clicks: link.max_clicks,
+        forward_query: link.forward_query,
+        forward_path: link.forward_path,
+        noindex: link.noindex,
+        utm_template: link.utm_template,
+        title: link.title,
+        page_title: link.page_title,
+        page_description: link.page_description,
+        targets: targets.platforms,
+        language_targets: targets.languages,
+        country_targets: targets.countries,
+        variants: targets.variants,
+        tags,
+        health,
+        quarantine: link.quarantine,
+        history,
+    })
+}
+
+/// GET /<id>/preview shows where a link leads, its QR code and how often it was followed, with
+/// a button to continue there, for visitors who want to check a short link before trusting it.
+/// A quarantined link shows the warning it redirects to instead, and one not yet activated the
+/// page it shows until then.
+#[utoipa::path(
+    get,
+    path = "/{external_id}/preview",
+    summary = "Show where a link leads before following it",
+    params(("external_id" = String, Path, description = "Code or slug")),
+    responses(
+        (status = 200, description = "The preview page, or the link's coming soon page until it \
+            is activated", content_type = "text/html", body = String),
+        (status = 403, description = "A warning instead, while the link is quarantined",
+            content_type = "text/html", body = String),
+        (status = 404, description = "No such link", body = ErrorBody),
+        (status = 410, description = "Deleted or expired", body = ErrorBody),
+    ),
+    tag = "links"
+)]
+async fn get_preview(
+    Path(external_id): Path<String>,
+    State(app_state): State<AppState>,
+    caller: Option<Caller>,
+    headers: HeaderMap,
+) -> QrLinkResult<Response> {
+    let link = app_state.store.resolve(&external_id).await?;
+    if link.deleted_at.is_some() || link.is_expired() {
+        return Err(Error::Gone(external_id));
+    }
+    if let Some(quarantine) = &link.quarantine {
+        return Ok(quarantined(&link, quarantine.threat));
+    }
+    if let Some(activate_at) = link.activate_at.filter(|_| link.is_pending()) {
+        return Ok(coming_soon(&app_state, activate_at));
+    }
+    // Anyone may check where a link leads, but its clicks are only shown to whoever sees its
+    // stats
+    let scope = Caller::scope_of(caller.as_ref());
+    let clicks = if app_state.config.features.click_tracking && scope.allows(link.owner_id) {
+        let stats = app_state
+            .store
+            .stats(link.id, Granularity::default())
+            .await?;
+        Some(stats.clicks)
+    } else {
+        None
+    };
+    let host = url::Url::parse(&link.url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_default();
+    let short_url = short_url(&app_state, &headers, &link.public_id());
+    // Relative to the page, so it resolves under whatever prefix a proxy serves us from
+    let qr_src = app_state.config.features.qr_codes.then(|| {
+        let query = signed_qr::sign(&app_state, &link.public_id(), "format=svg");
+        format!("qr?{query}")
+    });
+    let card_url = format!("{short_url}/card.png");
+    let mut response = pages::preview(&pages::Preview {
+        short_url: &short_url,
+        url: &link.url,
+        host: &host,
+        created_at: link.created_at,
+        clicks,
+        qr_src: qr_src.as_deref(),
+        oembed_url: &oembed::discovery_url(&app_state, &headers, &short_url),
+        title: &oembed::title(&link),
+        card_url: app_state
+            .config
+            .features
+            .qr_codes
+            .then_some(card_url.as_str()),
+    })
+    .into_response();
+    if link.noindex {
+        site::noindex(&mut response);
+    }
+    Ok(response)
+}
+
+#[derive(Deserialize, Default, ToSchema)]
+struct UpdateUrlParams {
+    url: Option<String>,
+    slug: Option<String>,
+    activate_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    max_clicks: Option<u64>,
+    forward_query: Option<bool>,
+    forward_path: Option<bool>,
+    noindex: Option<bool>,
+    /// Replaces the UTM template, `""` removing it
+    utm_template: Option<String>,
+    /// Replaces the title, `""` removing it
+    title: Option<String>,
+    /// Replaces the notes, `""` removing them
+    notes: Option<String>,
+    /// Replaces every platform target, `{}` removing them all
+    targets: Option<BTreeMap<Platform, String>>,
+    /// Replaces every language target, `[]` removing them all
+    language_targets: Option<Vec<LanguageTarget>>,
+    /// Replaces every country target, `[]` removing them all
+    country_targets: Option<Vec<CountryTarget>>,
+    /// Replaces every variant, `[]` sending everyone to `url` again
+    variants: Option<Vec<Variant>>,
+    /// Replaces every tag, `[]` removing them all
+    tags: Option<Vec<String>>,
+}
+
+/// PATCH /api/v1/links/<id> with {"url": ..., "slug": ..., "activate_at": ..., "expires_at": ...,
+/// "max_clicks": ..., "forward_query": ..., "forward_path": ..., "noindex": ...,
+/// "utm_template": ..., "title": ..., "notes": ..., "targets": ..., "language_targets": ...,
+/// "country_targets": ..., "variants": ..., "tags": ...} repoints a link, keeping the previous
+/// destination in its history, and returns the same JSON object as GET on the link does
+#[utoipa::path(
+    patch,
+    path = "/api/v1/links/{external_id}",
+    summary = "Change destination, targets, slug, activation, expiry or click limit",
+    params(("external_id" = String, Path, description = "Code or slug")),
+    request_body = UpdateUrlParams,
+    responses(
+        (status = 200, body = LinkMeta),
+        (status = 400, description = "Invalid destination or slug", body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The caller is a viewer", body = ErrorBody),
+        (status = 404, description = "No such link", body = ErrorBody),
+        (status = 409, description = "Slug taken", body = ErrorBody),
+        (status = 422, description = "The destination's domain isn't allowed", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "links"
+)]
+async fn update_url(
+    Path(external_id): Path<String>,
+    State(app_state): State<AppState>,
+    caller: Authorized<Edit>,
+    headers: HeaderMap,
+    Json(params): Json<UpdateUrlParams>,
+) -> QrLinkResult<axum::Json<LinkMeta>> {
+    let link = update_link(&app_state, &headers, caller.scope(), &external_id, params).await?;
+    Ok(axum::Json(link_meta(&app_state, &headers, link).await?))
+}
+
+/// Validates `params` and applies them to the active link `external_id` names within `scope`
+async fn update_link(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    scope: Scope,
+    external_id: &str,
+    params: UpdateUrlParams,
+) -> QrLinkResult<Link> {
+    if let Some(slug) = &params.slug {
+        slug::validate(slug)?;
+    }
+    validate_max_clicks(app_state, params.max_clicks)?;
+    let url = params
+        .url
+        .as_deref()
+        .map(|url| {
+            app_state
+                .destinations
+                .normalize(url, own_host(app_state, headers))
+        })
+        .transpose()?
+        .map(String::from);
+    let platform_targets = params
+        .targets
+        .map(|targets| platform_targets(app_state, headers, targets))
+        .transpose()?;
+    let language_targets = params
+        .language_targets
+        .map(|targets| language_targets(app_state, headers, targets))
+        .transpose()?;
+    let country_targets = params
+        .country_targets
+        .map(|targets| country_targets(app_state, headers, targets))
+        .transpose()?;
+    let variants = params
+        .variants
+        .map(|variants| link_variants(app_state, headers, variants))
+        .transpose()?;
+    let utm_template = params
+        .utm_template
+        .as_deref()
+        .map(utm_template)
+        .transpose()?;
+    let tags = params.tags.map(link_tags).transpose()?;
+    let title = params
+        .title
+        .as_deref()
+        .map(|title| link_text("title", title, MAX_TITLE_LENGTH))
+        .transpose()?;
+    let notes = params
+        .notes
+        .as_deref()
+        .map(|notes| link_text("notes", notes, MAX_NOTES_LENGTH))
+        .transpose()?;
+
+    let link = app_state
+        .store
+        .resolve_active_in(external_id, scope)
+        .await?;
+    validate_window(
+        params.activate_at.or(link.activate_at),
+        params.expires_at.or(link.expires_at),
+    )?;
+    let update = LinkUpdate {
+        url,
+        slug: params.slug,
+        expires_at: params.expires_at,
+        max_clicks: params.max_clicks,
+        activate_at: params.activate_at,
+        forward_query: params.forward_query,
+        forward_path: params.forward_path,
+        noindex: params.noindex,
+        utm_template,
+        title,
+        notes,
+        platform_targets,
+        country_targets,
+        language_targets,
+        variants,
+        tags,
+    };
+    let previous_url = link.url;
+    let mut link = app_state
+        .store
+        .update(link.id, update)
+        .await
+        .map_err(|error| error.or_not_found(external_id))?;
+    if link.url != previous_url {
+        app_state.check_safety([&mut link]).await?;
+        app_state.fetch_page_info(&link);
+    }
+    app_state.forget_link(link.id);
+    Ok(link)
+}
+
+/// DELETE /api/v1/links/<id> soft-deletes a databased URL, so it 410s from then on
+#[utoipa::path(
+    delete,
+    path = "/api/v1/links/{external_id}",
+    summary = "Soft-delete short URL",
+    params(("external_id" = String, Path, description = "Code or slug")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The caller is a viewer", body = ErrorBody),
+        (status = 404, description = "No such link", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "links"
+)]
+async fn delete_url(
+    Path(external_id): Path<String>,
+    State(app_state): State<AppState>,
+    caller: Authorized<Edit>,
+) -> QrLinkResult<StatusCode> {
+    delete_link(&app_state, caller.scope(), &external_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Soft-deletes the link `external_id` names within `scope`
+async fn delete_link(app_state: &AppState, scope: Scope, external_id: &str) -> QrLinkResult<()> {
+    let link = app_state.store.resolve_in(external_id, scope).await?;
+    app_state.store.delete(link.id).await?;
+    app_state.forget_link(link.id);
+    if link.deleted_at.is_none() {
+        let event = Event::link(WebhookEvent::LinkDeleted, &link);
+        webhooks::publish(app_state, link.id, event).await;
+    }
+    Ok(())
+}
+
+/// POST /api/v1/links/<id>/restore undoes a soft-delete and returns the same JSON object as GET
+/// on the link does
+#[utoipa::path(
+    post,
+    path = "/api/v1/links/{external_id}/restore",
+    summary = "Restore deleted short URL",
+    params(("external_id" = String, Path, description = "Code or slug")),
+    responses(
+        (status = 200, body = LinkMeta),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The caller is a viewer", body = ErrorBody),
+        (status = 404, description = "No such link", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "links"
+)]
+async fn restore_url(
+    Path(external_id): Path<String>,
+    State(app_state): State<AppState>,
+    caller: Authorized<Edit>,
+    headers: HeaderMap,
+) -> QrLinkResult<axum::Json<LinkMeta>> {
+    let link = app_state
+        .store
+        .resolve_in(&external_id, caller.scope())
+        .await?;
+    let link = app_state.store.restore(link.id).await?;
+
+    Ok(axum::Json(link_meta(&app_state, &headers, link).await?))
+}
+
+/// GET /api/v1/links/<id>/stats?granularity=day returns a JSON object with the click totals, the
+/// clicks per hour, day or week, and the top referrers, browsers, operating systems and
+/// countries. Those of a user's links are only shown with an API key of theirs or the admin
+/// token.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct StatsQuery {
+    /// Width of the `buckets`, `day` by default
+    granularity: Option<Granularity>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct LinkStatistics {
+    stored_id: String,
+    clicks: u64,
+    /// Clicks that came from scanning the QR code
+    scans: u64,
+    direct_clicks: u64,
+    unique_visitors: u64,
+    first_click: Option<DateTime<Utc>>,
+    last_click: Option<DateTime<Utc>>,
+    buckets: Vec<ClickBucket>,
+    referrers: Vec<Breakdown>,
+    browsers: Vec<Breakdown>,
+    operating_systems: Vec<Breakdown>,
+    countries: Vec<Breakdown>,
+    /// Clicks and visitors per variant, for links that split visitors, most clicks first
+    variants: Vec<VariantStats>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/links/{external_id}/stats",
+    summary = "Return click statistics per hour, day or week",
+    params(("external_id" = String, Path, description = "Code or slug"), StatsQuery),
+    responses(
+        (status = 200, body = LinkStatistics),
+        (status = 401, description = "Unknown or revoked API key", body = ErrorBody),
+        (
+            status = 404,
+            description = "No such link, or one of a user the caller isn't",
+            body = ErrorBody
+        ),
+    ),
+    security((), ("api_key" = [])),
+    tag = "stats"
+)]
+async fn get_stats(
+    Path(external_id): Path<String>,
+    Query(query): Query<StatsQuery>,
+    State(app_state): State<AppState>,
+    caller: Option<Caller>,
+) -> QrLinkResult<axum::Json<LinkStatistics>> {
+    let scope = Caller::scope_of(caller.as_ref());
+    let link = app_state
+        .store
+        .resolve_active_in(&external_id, scope)
+        .await?;
+    let granularity = query.granularity.unwrap_or_default();
+    Ok(axum::Json(
+        link_statistics(&app_state, &link, granularity).await?,
+    ))
+}
+
+/// The clicks on `link`, counted per `granularity`
+async fn link_statistics(
+    app_state: &AppState,
+    link: &Link,
+    granularity: Granularity,
+) -> QrLinkResult<LinkStatistics> {
+    let stats = app_state.store.stats(link.id, granularity).await?;
+    Ok(LinkStatistics {
+        stored_id: link.id.to_string(),
+        clicks: stats.clicks,
+        scans: stats.scans,
+        direct_clicks: stats.clicks - stats.scans,
+        unique_visitors: stats.unique_visitors,
+        first_click: stats.first_click,
+        last_click: stats.last_click,
+        buckets: stats.buckets,
+        referrers: stats.referrers,
+        browsers: stats.browsers,
+        operating_systems: stats.operating_systems,
+        countries: stats.countries,
+        variants: stats.variants,
+    })
+}
+
+/// Links listed per page unless `limit` says otherwise
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 200;
+
+/// GET /api/v1/links?limit=50&offset=0 lists the caller's links with their click counts and tags,
+/// or every link for the admin. They can be narrowed by `created_after`, `created_before`,
+/// `deleted`, `q` (a substring of the destination), `tag`, `health` (ok, broken or unchecked)
+/// and `quarantined`, and ordered by `sort` (created_at or clicks) and `order` (asc or desc).
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListLinksQuery {
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    /// Only deleted links, or only links that aren't
+    deleted: Option<bool>,
+    /// Case-insensitive substring of the destination
+    q: Option<String>,
+    /// Only links with this tag
+    tag: Option<String>,
+    /// Only links whose destination last answered like this, when health checks are on
+    health: Option<HealthFilter>,
+    /// Only links quarantined for a harmful destination, or only links that aren't
+    quarantined: Option<bool>,
+    sort: Option<LinkSort>,
+    order: Option<SortOrder>,
+    /// Links per page, 1 to 200, 50 by default
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ListedLink {
+    stored_id: String,
+    stored_url: String,
+    code: Option<String>,
+    slug: Option<String>,
+    created_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+    activate_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    max_clicks: Option<u64>,
+    forward_query: bool,
+    forward_path: bool,
+    noindex: bool,
+    utm_template: Option<String>,
+    title: Option<String>,
+    notes: Option<String>,
+    /// The `<title>` of the destination page, if it has been fetched
+    page_title: Option<String>,
+    /// The description the destination page gives of itself, if it has been fetched
+    page_description: Option<String>,
+    /// Set while the destination is flagged as harmful
+    quarantine: Option<Quarantine>,
+    /// The user it belongs to, if any
+    owner_id: Option<u64>,
+    clicks: u64,
+    /// Alphabetically
+    tags: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct LinkList {
+    links: Vec<ListedLink>,
+    /// Links matching the filters, on every page
+    total: u64,
+    limit: u32,
+    offset: u32,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/links",
+    summary = "List links with paging, filters and sorting",
+    params(ListLinksQuery),
+    responses(
+        (status = 200, body = LinkList),
+        (status = 400, description = "Invalid paging", body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "links"
+)]
+async fn list_links(
+    Query(query): Query<ListLinksQuery>,
+    State(app_state): State<AppState>,
+    caller: Authorized<Read>,
+) -> QrLinkResult<axum::Json<LinkList>> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(Error::Validation(format!(
+            "limit must be between 1 and {}",
+            MAX_PAGE_SIZE
+        )));
+    }
+    let offset = query.offset.unwrap_or(0);
+    let tag = query.tag.as_deref().map(link_tag).transpose()?;
+    let page = app_state
+        .store
+        .list(LinkFilter {
+            created_after: query.created_after,
+            created_before: query.created_before,
+            deleted: query.deleted,
+            search: query.q.filter(|q| !q.is_empty()),
+            tag,
+            health: query.health,
+            quarantined: query.quarantined,
+            scope: caller.scope(),
+            sort: query.sort.unwrap_or_default(),
+            order: query.order.unwrap_or_default(),
+            limit,
+            offset,
+        })
+        .await?;
+
+    let links = page
+        .links
+        .into_iter()
+        .map(|summary| {
+            let link = summary.link;
+            ListedLink {
+                stored_id: link.id.to_string(),
+                stored_url: link.url,
+                code: link.code,
+                slug: link.slug,
+                created_at: link.created_at,
+                deleted_at: link.deleted_at,
+                activate_at: link.activate_at,
+                expires_at: link.expires_at,
+                max_clicks: link.max_clicks,
+                forward_query: link.forward_query,
+                forward_path: link.forward_path,
+                noindex: link.noindex,
+                utm_template: link.utm_template,
+                title: link.title,
+                notes: link.notes,
+                page_title: link.page_title,
+                page_description: link.page_description,
+                quarantine: link.quarantine,
+                owner_id: link.owner_id,
+                clicks: summary.clicks,
+                tags: summary.tags,
+            }
+        })
+        .collect();
+    Ok(axum::Json(LinkList {
+        links,
+        total: page.total,
+        limit,
+        offset,
+    }))
+}
+
+/// GET /api/v1/tags lists every tag an active link of the caller's has, with how many do
+#[utoipa::path(
+    get,
+    path = "/api/v1/tags",
+    summary = "List tags with their link counts",
+    responses(
+        (status = 200, body = Vec<TagCount>),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "links"
+)]
+async fn list_tags(
+    State(app_state): State<AppState>,
+    caller: Authorized<Read>,
+) -> QrLinkResult<axum::Json<Vec<TagCount>>> {
+    Ok(axum::Json(
+        app_state.store.tag_counts(caller.scope()).await?,
+    ))
+}
+
+/// GET /api/v1/export?format=csv&include=links,stats downloads every link of the caller's, or those
+/// created in a time range, with their click totals if asked
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ExportQuery {
+    /// CSV by default
+    format: Option<ExportFormat>,
+    /// Comma-separated column groups: `links` for destinations, codes, slugs and dates,
+    /// `stats` for click totals; `links` by default
+    include: Option<String>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/export",
+    summary = "Export links and click totals as CSV or JSON",
+    description = "Deleted links are included. Rows are sorted oldest first and each starts \
+        with the link's `id`.",
+    params(ExportQuery),
+    responses(
+        (status = 200, description = "The export, as an attachment", content(
+            (String = "text/csv"),
+            (String = "application/json"),
+        )),
+        (status = 400, description = "Unknown format or column group", body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "links"
+)]
+async fn export_links(
+    Query(query): Query<ExportQuery>,
+    State(app_state): State<AppState>,
+    caller: Authorized<Read>,
+) -> QrLinkResult<impl IntoResponse> {
+    let format = query.format.unwrap_or_default();
+    let include = query
+        .include
+        .as_deref()
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let filter = LinkFilter {
+        created_after: query.created_after,
+        created_before: query.created_before,
+        scope: caller.scope(),
+        ..LinkFilter::default()
+    };
+    let file_name = format!(
+        "links-{}.{}",
+        Utc::now().format("%Y%m%d"),
+        format.extension()
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{file_name}\""),
+            ),
+        ],
+        export::stream(app_state.store.clone(), filter, format, include),
+    ))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct IssueApiKeyParams {
+    /// What the key is for, e.g. the client using it
+    name: String,
+    /// The user whose links the key works on; without one, it works on the links of no user
+    user_id: Option<u64>,
+    /// What the key may do, never more than its user may; editor by default
+    #[serde(default)]
+    role: Role,
+}
+
+#[derive(Serialize, ToSchema)]
+struct IssuedApiKey {
+    id: u64,
+    name: String,
+    /// The start of the key, enough to tell keys apart
+    prefix: String,
+    created_at: DateTime<Utc>,
+    user_id: Option<u64>,
+    role: Role,
+    /// The key itself, to be sent as `Authorization: Bearer <key>`
+    key: String,
+}
+
+/// POST /api/v1/keys with {"name": ..., "user_id": ..., "role": ...} issues an API key, for the
+/// links of a user if given one; the key is only ever shown in this response
+#[utoipa::path(
+    post,
+    path = "/api/v1/keys",
+    summary = "Issue an API key (admin)",
+    request_body = IssueApiKeyParams,
+    responses(
+        (status = 201, body = IssuedApiKey),
+        (status = 400, description = "Empty name", body = ErrorBody),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+        (status = 404, description = "No such user", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "keys"
+)]
+async fn issue_api_key(
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+    Json(params): Json<IssueApiKeyParams>,
+) -> QrLinkResult<(StatusCode, axum::Json<IssuedApiKey>)> {
+    let name = params.name.trim();
+    if name.is_empty() {
+        return Err(Error::Validation("API key name must not be empty".into()));
+    }
+    let (key_hash, prefix, key) = auth::generate_key();
+    let api_key = app_state
+        .store
+        .create_api_key(name, &key_hash, &prefix, params.user_id, params.role)
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        axum::Json(IssuedApiKey {
+            id: api_key.id,
+            name: api_key.name,
+            prefix: api_key.prefix,
+            created_at: api_key.created_at,
+            user_id: api_key.user_id,
+            role: api_key.role,
+            key,
+        }),
+    ))
+}
+
+/// DELETE /api/v1/keys/<id> revokes an API key
+#[utoipa::path(
+    delete,
+    path = "/api/v1/keys/{id}",
+    summary = "Revoke an API key (admin)",
+    params(("id" = u64, Path, description = "The key's id")),
+    responses(
+        (status = 204, description = "Revoked"),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+        (status = 404, description = "No such key", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "keys"
+)]
+async fn revoke_api_key(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+) -> QrLinkResult<StatusCode> {
+    app_state.store.revoke_api_key(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The longest user name accepted
+pub const MAX_USER_NAME_LENGTH: usize = 100;
+
+#[derive(Deserialize, ToSchema)]
+struct AddUserParams {
+    /// Unique, e.g. the team's name
+    name: String,
+    /// Editor by default
+    #[serde(default)]
+    role: Role,
+}
+
+/// POST /api/v1/users with {"name": ..., "role": ...} adds a user, whom API keys can then be
+/// issued to
+#[utoipa::path(
+    post,
+    path = "/api/v1/users",
+    summary = "Add a user (admin)",
+    request_body = AddUserParams,
+    responses(
+        (status = 201, body = User),
+        (status = 400, description = "Empty or overlong name", body = ErrorBody),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+        (status = 409, description = "Name taken", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "keys"
+)]
+async fn add_user(
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+    Json(params): Json<AddUserParams>,
+) -> QrLinkResult<(StatusCode, axum::Json<User>)> {
+    let name = params.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_USER_NAME_LENGTH {
+        return Err(Error::Validation(format!(
+            "user name must be 1 to {MAX_USER_NAME_LENGTH} characters"
+        )));
+    }
+    let user = app_state.store.create_user(name, None, params.role).await?;
+    Ok((StatusCode::CREATED, axum::Json(user)))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct UpdateUserParams {
+    role: Role,
+}
+
+/// PATCH /api/v1/users/<id> with {"role": ...} changes what a user, and the keys issued to them,
+/// may do
+#[utoipa::path(
+    patch,
+    path = "/api/v1/users/{id}",
+    summary = "Change a user's role (admin)",
+    params(("id" = u64, Path, description = "The user's id")),
+    request_body = UpdateUserParams,
+    responses(
+        (status = 200, body = User),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+        (status = 404, description = "No such user", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "keys"
+)]
+async fn update_user(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+    Json(params): Json<UpdateUserParams>,
+) -> QrLinkResult<axum::Json<User>> {
+    let user = app_state.store.set_user_role(id, params.role).await?;
+    tracing::info!(user_id = id, role = user.role.as_str(), "user role changed");
+    Ok(axum::Json(user))
+}
+
+/// GET /api/v1/users lists every user, oldest first
+#[utoipa::path(
+    get,
+    path = "/api/v1/users",
+    summary = "List users (admin)",
+    responses(
+        (status = 200, body = Vec<User>),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "keys"
+)]
+async fn list_users(
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+) -> QrLinkResult<axum::Json<Vec<User>>> {
+    Ok(axum::Json(app_state.store.users().await?))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PruneStatsQuery {
+    /// Clicks made before this are deleted
+    before: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PrunedStats {
+    /// How many clicks were deleted
+    clicks: u64,
+    /// Whether they were added to the daily totals first, as `retention.roll_up` says
+    rolled_up: bool,
+}
+
+/// DELETE /api/v1/stats?before=<time> deletes every link's clicks made before then, the way old
+/// clicks are pruned on the `retention` schedule
+#[utoipa::path(
+    delete,
+    path = "/api/v1/stats",
+    summary = "Prune old clicks (admin)",
+    params(PruneStatsQuery),
+    responses(
+        (status = 200, body = PrunedStats),
+        (status = 400, description = "Missing or malformed before", body = ErrorBody),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "stats"
+)]
+async fn prune_stats(
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+    Query(query): Query<PruneStatsQuery>,
+) -> QrLinkResult<axum::Json<PrunedStats>> {
+    let clicks = app_state
+        .pruner
+        .prune(&*app_state.store, query.before)
+        .await?;
+    tracing::info!(clicks, before = %query.before, "clicks pruned");
+    Ok(axum::Json(PrunedStats {
+        clicks,
+        rolled_up: app_state.config.retention.roll_up,
+    }))
+}
+
+/// GET /metrics returns request, QR rendering and database pool metrics for Prometheus
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    summary = "Prometheus metrics",
+    responses((status = 200, body = String, content_type = "text/plain; version=0.0.4")),
+    tag = "operations"
+)]
+async fn get_metrics(State(app_state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(app_state.store.pool_stats()),
+    )
+}
+
+#[derive(Serialize, ToSchema)]
+struct Health {
+    /// Always `ok`
+    status: &'static str,
+}
+
+/// GET /healthz answers as long as the process is serving requests
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    summary = "Liveness probe",
+    responses((status = 200, body = Health)),
+    tag = "operations"
+)]
+async fn get_health() -> axum::Json<Health> {
+    axum::Json(Health { status: "ok" })
+}
+
+#[derive(Serialize, ToSchema)]
+struct Readiness {
+    /// `ready` or `unavailable`
+    status: &'static str,
+    checks: ReadinessChecks,
+}
+
+/// `ok`, or what is wrong
+#[derive(Serialize, ToSchema)]
+struct ReadinessChecks {
+    database: String,
+    migrations: String,
+    geoip: String,
+}
+
+/// GET /readyz checks that the database answers and is fully migrated, and that the GeoIP
+/// database was read if one is configured, with 503 if anything isn't ready
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    summary = "Readiness probe: database, migrations, GeoIP",
+    responses(
+        (status = 200, body = Readiness),
+        (status = 503, description = "Something isn't ready", body = Readiness),
+    ),
+    tag = "operations"
+)]
+async fn get_readiness(State(app_state): State<AppState>) -> impl IntoResponse {
+    let latest = app_state.store.latest_schema_version();
+    let (database, migrations) = match app_state.store.schema_version().await {
+        Ok(version) if version == latest => ("ok".to_owned(), "ok".to_owned()),
+        Ok(version) => (
+            "ok".to_owned(),
+            format!("schema is at version {version}, expected {latest}"),
+        ),
+        Err(error) => {
+            tracing::warn!(%error, "readiness check can't reach the database");
+            (error.to_string(), "unknown".to_owned())
+        }
+    };
+    let geoip = if app_state.config.geoip_database.is_some() && !app_state.geoip.is_loaded() {
+        "configured database could not be read".to_owned()
+    } else {
+        "ok".to_owned()
+    };
+
+    let ready = [&database, &migrations, &geoip]
+        .iter()
+        .all(|check| *check == "ok");
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        axum::Json(Readiness {
+            status: if ready { "ready" } else { "unavailable" },
+            checks: ReadinessChecks {
+                database,
+                migrations,
+                geoip,
+            },
+        }),
+    )
+}
+
+/// GET / returns the same OpenAPI document as /openapi.json, or sends browsers to the link
+/// form at /app when it is on
+async fn get_info(State(app_state): State<AppState>, headers: HeaderMap) -> Response {
+    let browser = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    if browser && app_state.config.features.web_form {
+        return Redirect::to("/app").into_response();
+    }
+    axum::Json(openapi::spec(&app_state.config)).into_response()
+}
+
+/// GET /app shows a form that shortens a link without an API key, and POST /app submits it
+#[derive(Deserialize)]
+struct LinkForm {
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    slug: String,
+}
+
+async fn get_app() -> maud::Markup {
+    pages::home(&pages::Home::default())
+}
+
+async fn post_app(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<LinkForm>,
+) -> QrLinkResult<(StatusCode, maud::Markup)> {
+    let slug = form.slug.trim();
+    let params = CreateUrlParams {
+        url: form.url.trim().to_owned(),
+        slug: (!slug.is_empty()).then(|| slug.to_owned()),
+        ..Default::default()
+    };
+    match create_link(&app_state, &headers, params, None, None).await {
+        Ok(link) => {
+            let public_id = link.public_id();
+            let short_url = short_url(&app_state, &headers, &public_id);
+            let qr_url = |query: &str| {
+                let query = signed_qr::sign(&app_state, &public_id, query);
+                format!("{public_id}/qr?{query}")
+            };
+            let qr = app_state
+                .config
+                .features
+                .qr_codes
+                .then(|| pages::CreatedQr {
+                    src: qr_url("format=svg"),
+                    downloads: ["png", "svg", "pdf"]
+                        .map(|format| (format, qr_url(&format!("format={format}&download=1"))))
+                        .to_vec(),
+                });
+            let created = pages::Created {
+                short_url: &short_url,
+                qr,
+            };
+            let page = pages::home(&pages::Home {
+                created: Some(created),
+                ..Default::default()
+            });
+            Ok((StatusCode::OK, page))
+        }
+        // Shown above the form as it was filled in, to be corrected
+        Err(error) if error.status_code().is_client_error() => {
+            let page = pages::home(&pages::Home {
+                url: &form.url,
+                slug: &form.slug,
+                error: Some(error.to_string()),
+                created: None,
+            });
+            Ok((error.status_code(), page))
+        }
+        Err(error) => Err(error),
+    }
+}
+
+#[derive(Deserialize, Default, ToSchema, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CreateUrlParams {
+    /// The destination
+    url: String,
+    /// A name to use instead of a generated code
+    slug: Option<String>,
+    /// When the link starts redirecting, showing a page saying it is coming soon until then
+    activate_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    /// Clicks after which the link is deleted, e.g. 1 for a link that can be followed only
+    /// once. Needs click tracking.
+    max_clicks: Option<u64>,
+    /// Whether the query string visitors bring, e.g. `?utm_source=x`, is appended to the
+    /// destination; off by default
+    forward_query: Option<bool>,
+    /// Whether a path visitors add after the code or slug, as in `/<code>/extra/path`, is
+    /// appended to the destination's path; off by default
+    forward_path: Option<bool>,
+    /// Whether search engines are asked not to index the link and its preview page, for links
+    /// that shouldn't turn up in search results; off by default
+    noindex: Option<bool>,
+    /// Query parameters appended to the destination, e.g.
+    /// `utm_source=qr&utm_campaign={slug}`; `{slug}` and `{code}` are filled in from the link
+    utm_template: Option<String>,
+    /// Whether to answer with the link an earlier `dedupe` request for the same destination
+    /// created, while it is active, instead of creating another. The rest of the request is
+    /// ignored when there is one.
+    dedupe: Option<bool>,
+    /// Destinations for visitors on these platforms, e.g. an app store listing; everyone else
+    /// goes to `url`
+    targets: Option<BTreeMap<Platform, String>>,
+    /// Destinations for visitors accepting these languages, e.g. `da` for `da-DK` too, for those
+    /// whose platform has no target. The visitor's most preferred language that has one wins.
+    language_targets: Option<Vec<LanguageTarget>>,
+    /// Destinations for visitors from these countries, the first that matches winning, for
+    /// those whose platform and languages have no target. Needs a GeoIP database to match
+    /// anyone.
+    country_targets: Option<Vec<CountryTarget>>,
+    /// Destinations to split everyone no target is for between instead of sending them to
+    /// `url`, e.g. two landing pages weighted 1 and 1 for a 50/50 test. Each visitor keeps
+    /// getting the same one for the rest of the day.
+    variants: Option<Vec<Variant>>,
+    /// Names to organize links by, e.g. `campaign-2025`; lowercase letters, digits, `-`, `_`
+    /// and `.`
+    tags: Option<Vec<String>>,
+    /// What the link is, for listings; without one they show the destination page's own title
+    /// if it is fetched
+    title: Option<String>,
+    /// Free text for whoever manages the link, shown only to them
+    notes: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct CreatedLink {
+    stored_id: String,
+    stored_url: String,
+    short_url: String,
+    code: Option<String>,
+    slug: Option<String>,
+    activate_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    max_clicks: Option<u64>,
+    forward_query: bool,
+    forward_path: bool,
+    noindex: bool,
+    utm_template: Option<String>,
+    title: Option<String>,
+    notes: Option<String>,
+    /// Alphabetically
+    tags: Vec<String>,
+    /// Set if the destination is flagged as harmful, when the link shows a warning instead of
+    /// redirecting
+    quarantine: Option<Quarantine>,
+}
+
+/// Takes [CreateUrlParams] from a JSON body when the client sends `application/json`,
+/// and from the query string otherwise
+struct CreateUrlInput(CreateUrlParams);
+
+impl<S: Send + Sync> FromRequest<S> for CreateUrlInput {
+    type Rejection = Error;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_json = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+
+        if is_json {
+            let Json(params) = Json::from_request(req, state).await?;
+            Ok(CreateUrlInput(params))
+        } else {
+            let (mut parts, _) = req.into_parts();
+            let Query(params) = Query::from_request_parts(&mut parts, state).await?;
+            Ok(CreateUrlInput(params))
+        }
+    }
+}
+
+/// POST /api/v1/links?url=...&slug=... or with {"url": ...} creates a databased URL under a
+/// random code and forwards to /<code>/meta
+#[utoipa::path(
+    post,
+    path = "/api/v1/links",
+    summary = "Create short URL",
+    description = "Takes a JSON body, or the same fields as query parameters without one",
+    request_body(content = CreateUrlParams, content_type = "application/json"),
+    params(
+        (
+            "Idempotency-Key" = Option<String>,
+            Header,
+            description = "Retries with the same key within 24 hours get the link back \
+                instead of another one"
+        ),
+    ),
+    responses(
+        (status = 200, body = CreatedLink),
+        (status = 400, description = "Invalid destination or slug", body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The caller is a viewer", body = ErrorBody),
+        (
+            status = 409,
+            description = "Slug taken, or Idempotency-Key used for another destination",
+            body = ErrorBody
+        ),
+        (status = 422, description = "The destination's domain isn't allowed", body = ErrorBody),
+        (
+            status = 429,
+            description = "Rate limited, or a quota of the caller's is used up",
+            body = ErrorBody
+        ),
+    ),
+    security(("api_key" = [])),
+    tag = "links"
+)]
+async fn create_url(
+    State(app_state): State<AppState>,
+    caller: Authorized<Edit>,
+    headers: HeaderMap,
+    CreateUrlInput(params): CreateUrlInput,
+) -> QrLinkResult<axum::Json<CreatedLink>> {
+    let idempotency_key = auth::idempotency_key(&headers)?;
+    let link = create_link(&app_state, &headers, params, idempotency_key, Some(&caller)).await?;
+    let tags = app_state.store.tags(link.id).await?;
+    Ok(axum::Json(CreatedLink {
+        stored_id: link.id.to_string(),
+        short_url: short_url(&app_state, &headers, &link.public_id()),
+        stored_url: link.url,
+        code: link.code,
+        slug: link.slug,
+        activate_at: link.activate_at,
+        expires_at: link.expires_at,
+        max_clicks: link.max_clicks,
+        forward_query: link.forward_query,
+        forward_path: link.forward_path,
+        noindex: link.noindex,
+        utm_template: link.utm_template,
+        title: link.title,
+        notes: link.notes,
+        tags,
+        quarantine: link.quarantine,
+    }))
+}
+
+/// Validates `params` and stores the link they describe for `caller`, or finds the link an
+/// earlier request with the same idempotency key, or `dedupe` request for the same destination,
+/// created. Only a new link counts toward the caller's quotas, so that retries of a request
+/// that went through get its link back however many the caller has since made.
+async fn create_link(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    params: CreateUrlParams,
+    idempotency_key: Option<String>,
+    caller: Option<&Caller>,
+) -> QrLinkResult<Link> {
+    let owner_id = caller.and_then(Caller::owner_id);
+    let link = NewLink {
+        idempotency_key,
+        ..new_link(app_state, headers, &params, owner_id)?
+    };
+    if let Some(existing) = earlier_link(app_state, &link).await? {
+        return Ok(existing);
+    }
+    if let Some(caller) = caller {
+        quotas::check(app_state, caller, 1).await?;
+    }
+    let retry = (link.idempotency_key.is_some() || link.url_hash.is_some()).then(|| link.clone());
+    let mut link = match (app_state.store.create(link, &app_state.codes).await, retry) {
+        // Another request created it in the meantime
+        (Err(error @ Error::Conflict(_)), Some(retry)) => {
+            return earlier_link(app_state, &retry).await?.ok_or(error);
+        }
+        (result, _) => result?,
+    };
+    app_state.check_safety([&mut link]).await?;
+    app_state.fetch_page_info(&link);
+    webhooks::publish(
+        app_state,
+        link.id,
+        Event::link(WebhookEvent::LinkCreated, &link),
+    )
+    .await;
+    Ok(link)
+}
+
+/// Validates `params` into the link they describe, belonging to `owner_id`
+fn new_link(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    params: &CreateUrlParams,
+    owner_id: Option<u64>,
+) -> QrLinkResult<NewLink> {
+    if let Some(slug) = &params.slug {
+        slug::validate(slug)?;
+    }
+    validate_max_clicks(app_state, params.max_clicks)?;
+    validate_window(params.activate_at, params.expires_at)?;
+    let url = String::from(
+        app_state
+            .destinations
+            .normalize(&params.url, own_host(app_state, headers))?,
+    );
+    let targets = link_targets(app_state, headers, params)?;
+    let utm_template = params
+        .utm_template
+        .as_deref()
+        .map(utm_template)
+        .transpose()?
+        .flatten();
+    let url_hash = params
+        .dedupe
+        .unwrap_or_default()
+        .then(|| destination::url_hash(&url, owner_id));
+    let tags = params.tags.clone().map(link_tags).transpose()?;
+    let title = params
+        .title
+        .as_deref()
+        .map(|title| link_text("title", title, MAX_TITLE_LENGTH))
+        .transpose()?
+        .flatten();
+    let notes = params
+        .notes
+        .as_deref()
+        .map(|notes| link_text("notes", notes, MAX_NOTES_LENGTH))
+        .transpose()?
+        .flatten();
+
+    Ok(NewLink {
+        url,
+        slug: params.slug.clone(),
+        expires_at: params.expires_at,
+        max_clicks: params.max_clicks,
+        activate_at: params.activate_at,
+        forward_query: params.forward_query.unwrap_or_default(),
+        forward_path: params.forward_path.unwrap_or_default(),
+        noindex: params.noindex.unwrap_or_default(),
+        utm_template,
+        title,
+        notes,
+        url_hash,
+        idempotency_key: None,
+        tags: tags.unwrap_or_default(),
+        targets,
+        owner_id,
+    })
+}
+
+/// The link an earlier request created that `link` should get instead of a new one: the one
+/// of the same owner created with its idempotency key, while that is recent enough, or else
+/// the one [duplicate_of] finds
+async fn earlier_link(app_state: &AppState, link: &NewLink) -> QrLinkResult<Option<Link>> {
+    if let Some(idempotency_key) = &link.idempotency_key {
+        let earlier = app_state
+            .store
+            .find_idempotent(idempotency_key, link.owner_id)
+            .await?
+            .filter(|earlier| Utc::now() - earlier.created_at < store::IDEMPOTENCY_TTL);
+        if let Some(earlier) = earlier {
+            if earlier.url != link.url {
+                return Err(Error::Conflict(
+                    "Idempotency-Key was already used for another destination".into(),
+                ));
+            }
+            return Ok(Some(earlier));
+        }
+    }
+    duplicate_of(app_state, link.url_hash.as_deref()).await
+}
+
+/// The link a `dedupe` request for the destination `url_hash` is the hash of gets instead of
+/// a new one, if there is one that hasn't expired
+async fn duplicate_of(app_state: &AppState, url_hash: Option<&str>) -> QrLinkResult<Option<Link>> {
+    let Some(url_hash) = url_hash else {
+        return Ok(None);
+    };
+    let link = app_state.store.find_duplicate(url_hash).await?;
+    Ok(link.filter(|link| !link.is_expired()))
+}
+
+/// The targets a new link is created with, validated
+fn link_targets(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    params: &CreateUrlParams,
+) -> QrLinkResult<LinkTargets> {
+    let platforms = params.targets.clone().unwrap_or_default();
+    let countries = params.country_targets.clone().unwrap_or_default();
+    let languages = params.language_targets.clone().unwrap_or_default();
+    let variants = params.variants.clone().unwrap_or_default();
+    Ok(LinkTargets {
+        platforms: platform_targets(app_state, headers, platforms)?,
+        countries: country_targets(app_state, headers, countries)?,
+        languages: language_targets(app_state, headers, languages)?,
+        variants: link_variants(app_state, headers, variants)?,
+    })
+}
+
+/// Validates the destinations of `platforms` the way a link's own destination is
+fn platform_targets(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    platforms: BTreeMap<Platform, String>,
+) -> QrLinkResult<BTreeMap<Platform, String>> {
+    platforms
+        .into_iter()
+        .map(|(platform, url)| {
+            let url = app_state
+                .destinations
+                .normalize(&url, own_host(app_state, headers))?;
+            Ok((platform, url.into()))
+        })
+        .collect()
+}
+
+/// Validates the countries and destinations of `countries`, each country only once
+fn country_targets(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    countries: Vec<CountryTarget>,
+) -> QrLinkResult<Vec<CountryTarget>> {
+    let mut seen = HashSet::new();
+    countries
+        .into_iter()
+        .map(|target| {
+            let country = targeting::country_code(&target.country)?;
+            if !seen.insert(country.clone()) {
+                return Err(Error::Validation(format!(
+                    "country {country} has more than one target"
+                )));
+            }
+            let url = app_state
+                .destinations
+                .normalize(&target.url, own_host(app_state, headers))?;
+            Ok(CountryTarget {
+                country,
+                url: url.into(),
+            })
+        })
+        .collect()
+}
+
+/// Validates the languages and destinations of `languages`, each language only once
+fn language_targets(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    languages: Vec<LanguageTarget>,
+) -> QrLinkResult<Vec<LanguageTarget>> {
+    let mut seen = HashSet::new();
+    languages
+        .into_iter()
+        .map(|target| {
+            let lang = targeting::language_tag(&target.lang)?;
+            if !seen.insert(lang.clone()) {
+                return Err(Error::Validation(format!(
+                    "language {lang} has more than one target"
+                )));
+            }
+            let url = app_state
+                .destinations
+                .normalize(&target.url, own_host(app_state, headers))?;
+            Ok(LanguageTarget {
+                lang,
+                url: url.into(),
+            })
+        })
+        .collect()
+}
+
+/// Validates a UTM template, an empty one meaning none
+fn utm_template(template: &str) -> QrLinkResult<Option<String>> {
+    match template.trim() {
+        "" => Ok(None),
+        template => destination::utm_template(template).map(Some),
+    }
+}
+
+/// The longest title a link can have, in characters
+const MAX_TITLE_LENGTH: usize = 200;
+/// The longest notes a link can have, in characters
+const MAX_NOTES_LENGTH: usize = 2000;
+
+/// Validates the `field` of a link, trimmed, an empty one meaning none
+fn link_text(field: &str, text: &str, max: usize) -> QrLinkResult<Option<String>> {
+    let text = text.trim();
+    if text.chars().count() > max {
+        return Err(Error::Validation(format!(
+            "{field} must be at most {max} characters"
+        )));
+    }
+    Ok((!text.is_empty()).then(|| text.to_owned()))
+}
+
+/// The most tags a link can have
+const MAX_TAGS: usize = 20;
+/// The longest a tag can be
+const MAX_TAG_LENGTH: usize = 50;
+
+/// Validates `tags`, lowercased, each only once and alphabetically
+fn link_tags(tags: Vec<String>) -> QrLinkResult<Vec<String>> {
+    let tags = tags
+        .iter()
+        .map(|tag| link_tag(tag))
+        .collect::<QrLinkResult<BTreeSet<_>>>()?;
+    if tags.len() > MAX_TAGS {
+        return Err(Error::Validation(format!(
+            "a link can have at most {MAX_TAGS} tags"
+        )));
+    }
+    Ok(tags.into_iter().collect())
+}
+
+/// Validates a tag, lowercased
+fn link_tag(tag: &str) -> QrLinkResult<String> {
+    let tag = tag.trim().to_lowercase();
+    let valid = (1..=MAX_TAG_LENGTH).contains(&tag.chars().count())
+        && tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(Error::Validation(format!(
+            "tags must be 1 to {MAX_TAG_LENGTH} letters, digits, '-', '_' or '.'"
+        )));
+    }
+    Ok(tag)
+}
+
+/// The largest weight a variant can have
+const MAX_VARIANT_WEIGHT: u32 = 1000;
+
+/// Validates the weights and destinations of `variants`, each destination only once so their
+/// clicks can be told apart
+fn link_variants(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    variants: Vec<Variant>,
+) -> QrLinkResult<Vec<Variant>> {
+    let mut seen = HashSet::new();
+    variants
+        .into_iter()
+        .map(|variant| {
+            if !(1..=MAX_VARIANT_WEIGHT).contains(&variant.weight) {
+                return Err(Error::Validation(format!(
+                    "variant weights must be from 1 to {MAX_VARIANT_WEIGHT}"
+                )));
+            }
+            let url: String = app_state
+                .destinations
+                .normalize(&variant.url, own_host(app_state, headers))?
+                .into();
+            if !seen.insert(url.clone()) {
+                return Err(Error::Validation(format!("{url} is more than one variant")));
+            }
+            Ok(Variant {
+                url,
+                weight: variant.weight,
+            })
+        })
+        .collect()
+}
+
+/// Refuses a `max_clicks` of 0, and any at all when no clicks are recorded to count against it
+fn validate_max_clicks(app_state: &AppState, max_clicks: Option<u64>) -> QrLinkResult<()> {
+    match max_clicks {
+        Some(0) => Err(Error::Validation("max_clicks must be at least 1".into())),
+        Some(_) if !app_state.config.features.click_tracking => Err(Error::Validation(
+            "max_clicks needs click tracking, which is switched off".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Refuses a link that would expire before it is activated
+fn validate_window(
+    activate_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+) -> QrLinkResult<()> {
+    if let (Some(activate_at), Some(expires_at)) = (activate_at, expires_at)
+        && expires_at <= activate_at
+    {
+        return Err(Error::Validation(
+            "expires_at must be later than activate_at".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Most links one batch can create
+const MAX_BATCH_SIZE: usize = 1000;
+
+/// A link to create as part of a batch: just its destination, or the same fields as `POST /`
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+enum BatchItem {
+    Url(String),
+    Link(Box<CreateUrlParams>),
+}
+
+/// Takes the links of a batch from a JSON array when the client sends `application/json`,
+/// and otherwise from a plain list of destinations, one per line
+struct BatchInput(Vec<CreateUrlParams>);
+
+impl<S: Send + Sync> FromRequest<S> for BatchInput {
+    type Rejection = Error;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_json = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+
+        let items = if is_json {
+            let Json(items) = Json::<Vec<BatchItem>>::from_request(req, state).await?;
+            items
+                .into_iter()
+                .map(|item| match item {
+                    BatchItem::Url(url) => CreateUrlParams {
+                        url,
+                        ..Default::default()
+                    },
+                    BatchItem::Link(params) => *params,
+                })
+                .collect()
+        } else {
+            let body = String::from_request(req, state)
+                .await
+                .map_err(|rejection| Error::Validation(rejection.body_text()))?;
+            body.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(|url| CreateUrlParams {
+                    url: url.to_owned(),
+                    ..Default::default()
+                })
+                .collect()
+        };
+        Ok(BatchInput(items))
+    }
+}
+
+/// What became of one link of a batch: its short link, or why it wasn't created
+#[derive(Serialize, ToSchema)]
+struct BatchResult {
+    /// The destination as it was submitted
+    url: String,
+    short_url: Option<String>,
+    code: Option<String>,
+    slug: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<BatchError>,
+}
+
+/// Why a link of a batch wasn't created, as in the body of a failed `POST /`
+#[derive(Serialize, ToSchema)]
+struct BatchError {
+    /// Machine-readable, e.g. `validation_failed` or `conflict`
+    code: &'static str,
+    message: String,
+}
+
+/// POST /api/v1/links/batch creates up to [MAX_BATCH_SIZE] links at once, in one transaction, and
+/// answers with what became of each in the order they were sent
+#[utoipa::path(
+    post,
+    path = "/api/v1/links/batch",
+    summary = "Create many short URLs",
+    description = "Takes a JSON array of destinations or of `POST /` bodies, or a plain list \
+        of destinations, one per line. Links that are invalid or whose slug is taken get an \
+        `error` and are left out; the others are created, unless `dedupe` finds them \
+        created already.",
+    request_body(content = Vec<BatchItem>, content_type = "application/json"),
+    responses(
+        (status = 200, body = Vec<BatchResult>),
+        (status = 400, description = "No links, or too many", body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The caller is a viewer", body = ErrorBody),
+        (
+            status = 429,
+            description = "Rate limited, or a quota of the caller's is used up",
+            body = ErrorBody
+        ),
+    ),
+    security(("api_key" = [])),
+    tag = "links"
+)]
+async fn create_batch(
+    State(app_state): State<AppState>,
+    caller: Authorized<Edit>,
+    headers: HeaderMap,
+    BatchInput(items): BatchInput,
+) -> QrLinkResult<axum::Json<Vec<BatchResult>>> {
+    if items.is_empty() || items.len() > MAX_BATCH_SIZE {
+        return Err(Error::Validation(format!(
+            "a batch must have between 1 and {MAX_BATCH_SIZE} links"
+        )));
+    }
+    let validated: Vec<QrLinkResult<NewLink>> = items
+        .iter()
+        .map(|params| new_link(&app_state, &headers, params, caller.owner_id()))
+        .collect();
+    // Links deduplicated to one that already exists aren't created again
+    let mut existing = Vec::with_capacity(items.len());
+    for link in &validated {
+        let url_hash = link.as_ref().ok().and_then(|link| link.url_hash.as_deref());
+        existing.push(duplicate_of(&app_state, url_hash).await?);
+    }
+    let valid: Vec<NewLink> = validated
+        .iter()
+        .zip(&existing)
+        .filter(|(_, existing)| existing.is_none())
+        .filter_map(|(link, _)| link.as_ref().ok().cloned())
+        .collect();
+    quotas::check(&app_state, &caller, valid.len() as u64).await?;
+    let mut created = app_state.store.create_many(valid, &app_state.codes).await?;
+    app_state.check_safety(created.iter_mut().flatten()).await?;
+    for link in created.iter().flatten() {
+        app_state.fetch_page_info(link);
+        let event = Event::link(WebhookEvent::LinkCreated, link);
+        webhooks::publish(&app_state, link.id, event).await;
+    }
+    let mut created = created.into_iter();
+
+    let mut results = Vec::with_capacity(items.len());
+    for ((params, validated), existing) in items.into_iter().zip(validated).zip(existing) {
+        let link = match (validated, existing) {
+            (_, Some(existing)) => Ok(existing),
+            (Err(error), None) => Err(error),
+            (Ok(link), None) => match created
+                .next()
+                .expect("the store answers for every link it was given")
+            {
+                // An earlier link of the batch, or another request, created it in the meantime
+                Err(error @ Error::Conflict(_)) if link.url_hash.is_some() => {
+                    duplicate_of(&app_state, link.url_hash.as_deref())
+                        .await?
+                        .ok_or(error)
+                }
+                result => result,
+            },
+        };
+        results.push(match link {
+            Ok(link) => BatchResult {
+                url: params.url,
+                short_url: Some(short_url(&app_state, &headers, &link.public_id())),
+                code: link.code,
+                slug: link.slug,
+                error: None,
+            },
+            Err(error) => BatchResult {
+                url: params.url,
+                short_url: None,
+                code: None,
+                slug: params.slug,
+                error: Some(BatchError {
+                    code: error.code(),
+                    message: error.to_string(),
+                }),
+            },
+        });
+    }
+    Ok(axum::Json(results))
+}
@@ -0,0 +1,138 @@
+//! Health checks: every active link's destination is sent a HEAD request once per
+//! `health_checks.interval_secs`, and counts as broken while it answers with a 4xx or 5xx
+//! status, or not within `health_checks.timeout_secs`.
+//!
+//! Only the latest check of each link is kept. Instances sharing a database each check the
+//! links due, so a destination may now and then be checked twice in a row.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures_util::{StreamExt, stream};
+use reqwest::StatusCode;
+
+use crate::config::HealthCheckConfig;
+use crate::error::{Error, QrLinkResult};
+use crate::outbound;
+use crate::store::{Link, LinkHealth, LinkStore};
+use crate::webhooks::error_chain;
+
+/// Links checked per round
+const BATCH_SIZE: u32 = 50;
+
+/// Destinations checked at once
+const CONCURRENCY: usize = 8;
+
+/// How often the checker looks for links due when the last round found none to spare
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Redirects followed to get to a destination
+const MAX_REDIRECTS: usize = 10;
+
+/// Longest error message kept for a destination that didn't answer
+const MAX_ERROR_LENGTH: usize = 512;
+
+/// Checks whether the destinations of active links still answer
+pub struct HealthChecker {
+    client: reqwest::Client,
+    interval: Duration,
+    allow_private_addresses: bool,
+}
+
+impl HealthChecker {
+    pub fn new(config: &HealthCheckConfig) -> QrLinkResult<Self> {
+        let client = outbound::client(
+            Duration::from_secs(config.timeout_secs),
+            MAX_REDIRECTS,
+            config.allow_private_addresses,
+        )
+        .map_err(|error| Error::Config(format!("can't set up the health check client: {error}")))?;
+        Ok(HealthChecker {
+            client,
+            interval: Duration::from_secs(config.interval_secs),
+            allow_private_addresses: config.allow_private_addresses,
+        })
+    }
+
+    /// Keeps checking the links in `store` as they come due, until the process exits
+    pub fn start(self: Arc<Self>, store: Arc<dyn LinkStore>) {
+        tokio::spawn(async move {
+            loop {
+                match self.check_due(&*store).await {
+                    // There may be more
+                    Ok(checked) if checked == BATCH_SIZE as usize => continue,
+                    Ok(_) => {}
+                    Err(error) => tracing::warn!(%error, "link health could not be checked"),
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Checks a batch of the links that are due and records how they answered, returning how
+    /// many were checked
+    pub async fn check_due(&self, store: &dyn LinkStore) -> QrLinkResult<usize> {
+        let checked_before = Utc::now() - self.interval;
+        let links = store
+            .links_due_for_check(checked_before, BATCH_SIZE)
+            .await?;
+        let count = links.len();
+        let checks: Vec<(Link, LinkHealth)> = stream::iter(links)
+            .map(|link| async move {
+                let health = self.check(&link.url).await;
+                (link, health)
+            })
+            .buffer_unordered(CONCURRENCY)
+            .collect()
+            .await;
+        for (link, health) in checks {
+            if health.broken {
+                tracing::debug!(link = link.id, url = %link.url, ?health, "link is broken");
+            }
+            store.record_health(link.id, &link.url, health).await?;
+        }
+        Ok(count)
+    }
+
+    /// How `url` answers a HEAD request, or a GET request if it doesn't take HEAD requests
+    async fn check(&self, url: &str) -> LinkHealth {
+        if !self.allow_private_addresses
+            && let Err(error) = outbound::check(url)
+        {
+            return LinkHealth {
+                checked_at: Utc::now(),
+                status: None,
+                latency_ms: 0,
+                error: Some(error),
+                broken: true,
+            };
+        }
+        let started = Instant::now();
+        let mut answer = self.client.head(url).send().await;
+        if answer.as_ref().is_ok_and(|response| {
+            matches!(
+                response.status(),
+                StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+            )
+        }) {
+            answer = self.client.get(url).send().await;
+        }
+        let latency_ms = started.elapsed().as_millis() as u64;
+        let (status, error) = match answer {
+            Ok(response) => (Some(response.status()), None),
+            Err(error) => (
+                None,
+                Some(error_chain(&error).chars().take(MAX_ERROR_LENGTH).collect()),
+            ),
+        };
+        LinkHealth {
+            checked_at: Utc::now(),
+            status: status.map(|status| status.as_u16()),
+            latency_ms,
+            error,
+            broken: status
+                .is_none_or(|status| status.is_client_error() || status.is_server_error()),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! The addresses the service is served on: any number of TCP addresses, e.g. one for IPv4 and
+//! one for IPv6, and Unix socket paths for a proxy on the same host to connect to. Unix sockets
+//! serve plain HTTP even with `tls` configured, the proxy being the one to speak HTTPS.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use axum::Router;
+use axum::extract::ConnectInfo;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::watch;
+
+#[cfg(unix)]
+use crate::client_ip::TrustedPeer;
+use crate::error::{Error, QrLinkResult};
+use crate::tls::Tls;
+
+/// Prefix of Unix socket paths, as in `unix:/run/qrlink/http.sock`
+const UNIX_PREFIX: &str = "unix:";
+
+/// Stands in for the client address of requests over Unix sockets, which have none; the
+/// proxy in front passes the client's on in `X-Forwarded-For`, which is believed from it
+#[cfg(unix)]
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// A TCP address, or the path of a Unix socket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Parses `<host>:<port>`, e.g. `[::]:3000`, or `unix:<path>`
+impl FromStr for ListenAddr {
+    type Err = Error;
+
+    fn from_str(value: &str) -> QrLinkResult<Self> {
+        let value = value.trim();
+        if let Some(path) = value.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(Error::Config("listen: 'unix:' needs a socket path".into()));
+            }
+            return Ok(ListenAddr::Unix(path.into()));
+        }
+        value.parse().map(ListenAddr::Tcp).map_err(|_| {
+            Error::Config(format!(
+                "listen: '{value}' isn't an address like 0.0.0.0:3000 or unix:/path/to.sock"
+            ))
+        })
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(address) => address.fmt(f),
+            ListenAddr::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+/// Reads an address list from either one address or a list of them
+pub fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<ListenAddr>, D::Error> {
+    struct Addresses;
+
+    impl<'de> Visitor<'de> for Addresses {
+        type Value = Vec<ListenAddr>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an address, or a list of them")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            value
+                .parse()
+                .map(|address| vec![address])
+                .map_err(E::custom)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut addresses = Vec::new();
+            while let Some(address) = seq.next_element::<String>()? {
+                addresses.push(address.parse().map_err(de::Error::custom)?);
+            }
+            Ok(addresses)
+        }
+    }
+
+    deserializer.deserialize_any(Addresses)
+}
+
+/// A listener bound to one of the addresses
+pub enum Bound {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Binds `address`, replacing a socket left behind at a Unix socket path
+pub async fn bind(address: &ListenAddr) -> QrLinkResult<Bound> {
+    let io_error = |error: io::Error| {
+        Error::Io(io::Error::new(
+            error.kind(),
+            format!("can't listen on {address}: {error}"),
+        ))
+    };
+    match address {
+        ListenAddr::Tcp(address) => TcpListener::bind(address)
+            .await
+            .map(Bound::Tcp)
+            .map_err(io_error),
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => {
+            use std::os::unix::fs::FileTypeExt;
+            if std::fs::symlink_metadata(path)
+                .is_ok_and(|metadata| metadata.file_type().is_socket())
+            {
+                std::fs::remove_file(path).map_err(io_error)?;
+            }
+            UnixListener::bind(path)
+                .map(|listener| Bound::Unix(listener, path.clone()))
+                .map_err(io_error)
+        }
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => Err(Error::Config(format!(
+            "listen: {address} is a Unix socket, which this system doesn't have"
+        ))),
+    }
+}
+
+/// Serves `router` on `bound` until `shutdown` changes, over HTTPS if `tls` is given and the
+/// listener is TCP, then lets in-flight requests finish
+pub async fn serve(
+    bound: Bound,
+    router: Router,
+    tls: Option<Tls>,
+    shutdown: watch::Receiver<()>,
+) -> io::Result<()> {
+    match bound {
+        Bound::Tcp(listener) => {
+            let address = listener.local_addr()?;
+            let app = router.into_make_service_with_connect_info::<SocketAddr>();
+            match tls {
+                None => {
+                    tracing::info!(listen = %address, "listening");
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(stopped(shutdown))
+                        .await
+                }
+                Some(Tls::Files(rustls)) => {
+                    tracing::info!(listen = %address, "listening over HTTPS");
+                    axum_server::Server::from_listener(listener)
+                        .acceptor(axum_server::tls_rustls::RustlsAcceptor::new(rustls))
+                        .handle(shutdown_handle(shutdown))
+                        .serve(app)
+                        .await
+                }
+                Some(Tls::Acme(acceptor)) => {
+                    tracing::info!(
+                        listen = %address,
+                        "listening over HTTPS, with certificates from ACME"
+                    );
+                    axum_server::Server::from_listener(listener)
+                        .acceptor(acceptor)
+                        .handle(shutdown_handle(shutdown))
+                        .serve(app)
+                        .await
+                }
+            }
+        }
+        #[cfg(unix)]
+        Bound::Unix(listener, path) => {
+            tracing::info!(listen = %path.display(), "listening on a Unix socket");
+            let app = router
+                .layer(axum::Extension(ConnectInfo(UNIX_PEER)))
+                .layer(axum::Extension(TrustedPeer));
+            let served = axum::serve(listener, app)
+                .with_graceful_shutdown(stopped(shutdown))
+                .await;
+            let _ = std::fs::remove_file(&path);
+            served
+        }
+    }
+}
+
+/// Resolves once `shutdown` changes, or its sender is gone
+async fn stopped(mut shutdown: watch::Receiver<()>) {
+    let _ = shutdown.changed().await;
+}
+
+/// A handle shutting an HTTPS server down once `shutdown` changes
+fn shutdown_handle(shutdown: watch::Receiver<()>) -> axum_server::Handle<SocketAddr> {
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            stopped(shutdown).await;
+            handle.graceful_shutdown(None);
+        }
+    });
+    handle
+}
@@ -0,0 +1,407 @@
+//! Clicks as they happen, streamed to dashboards as Server-Sent Events, and counted up into
+//! statistics pushed over a WebSocket
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::response::Response;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use chrono::{DateTime, Utc};
+use futures_util::Stream;
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::AppState;
+use crate::auth::{Authorized, Caller, Read};
+use crate::error::{ErrorBody, QrLinkResult};
+use crate::extract::Path;
+use crate::store::Scope;
+use crate::webhooks::Event;
+
+/// Clicks held for listeners that fall behind; a listener further behind misses clicks and is
+/// told how many
+pub const CHANNEL_CAPACITY: usize = 1024;
+
+/// A click, serialized once for every listener
+#[derive(Clone, Debug)]
+pub struct LiveClick {
+    pub link_id: u64,
+    /// The user the link belongs to, so only they and the admin hear of it
+    pub owner_id: Option<u64>,
+    /// The code or slug that was followed
+    pub external_id: Arc<str>,
+    /// The same JSON as the body of a `click` webhook
+    pub data: Arc<str>,
+}
+
+/// Where the redirect handler announces clicks
+#[derive(Clone)]
+pub struct LiveClicks {
+    sender: broadcast::Sender<LiveClick>,
+}
+
+impl Default for LiveClicks {
+    fn default() -> Self {
+        LiveClicks {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+        }
+    }
+}
+
+impl LiveClicks {
+    /// Announces a click on the link `link_id` of `owner_id`, followed as `external_id`, unless
+    /// no one is listening
+    pub fn publish(&self, link_id: u64, owner_id: Option<u64>, external_id: &str, click: &Event) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let data = serde_json::to_string(click).expect("events serialize");
+        // Sending only fails when the last listener went away meanwhile
+        let _ = self.sender.send(LiveClick {
+            link_id,
+            owner_id,
+            external_id: external_id.into(),
+            data: data.into(),
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveClick> {
+        self.sender.subscribe()
+    }
+}
+
+/// The clicks on `link_id`, or on every link in `scope`, as `click` events, with a `lagged`
+/// event saying how many were missed whenever the listener falls behind
+fn click_events(
+    receiver: broadcast::Receiver<LiveClick>,
+    scope: Scope,
+    link_id: Option<u64>,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let events = stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(click)
+                    if scope.allows(click.owner_id)
+                        && link_id.is_none_or(|id| id == click.link_id) =>
+                {
+                    SseEvent::default().event("click").data(&*click.data)
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    SseEvent::default().event("lagged").data(missed.to_string())
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((Ok(event), receiver));
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// GET /api/v1/events streams every click on the caller's links as it happens, as Server-Sent
+/// Events
+#[utoipa::path(
+    get,
+    path = "/api/v1/events",
+    summary = "Stream clicks",
+    description = "Server-Sent Events: a `click` event for every followed link, with the same \
+        JSON as a `click` webhook, and a `lagged` event with how many clicks were missed if \
+        the client reads too slowly.",
+    responses(
+        (status = 200, content_type = "text/event-stream", body = String),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "stats"
+)]
+pub async fn stream_clicks(
+    State(app_state): State<AppState>,
+    caller: Authorized<Read>,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    click_events(app_state.live.subscribe(), caller.scope(), None)
+}
+
+/// GET /api/v1/links/<id>/events streams the clicks on one link as they happen, like /api/v1/events
+#[utoipa::path(
+    get,
+    path = "/api/v1/links/{external_id}/events",
+    summary = "Stream a link's clicks",
+    description = "Server-Sent Events, as from `/api/v1/events`, for the clicks on one link",
+    params(("external_id" = String, Path, description = "Code or slug")),
+    responses(
+        (status = 200, content_type = "text/event-stream", body = String),
+        (status = 401, description = "Unknown or revoked API key", body = ErrorBody),
+        (
+            status = 404,
+            description = "No such link, or one of a user the caller isn't",
+            body = ErrorBody
+        ),
+    ),
+    security((), ("api_key" = [])),
+    tag = "stats"
+)]
+pub async fn stream_link_clicks(
+    Path(external_id): Path<String>,
+    State(app_state): State<AppState>,
+    caller: Option<Caller>,
+) -> QrLinkResult<Sse<impl Stream<Item = Result<SseEvent, Infallible>>>> {
+    let scope = Caller::scope_of(caller.as_ref());
+    let link = app_state
+        .store
+        .resolve_active_in(&external_id, scope)
+        .await?;
+    Ok(click_events(
+        app_state.live.subscribe(),
+        scope,
+        Some(link.id),
+    ))
+}
+
+/// Most links a stats message ranks when no links are subscribed to
+pub const TOP_LINKS: usize = 10;
+
+/// How far back clicks count towards the per-minute rates
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// What a dashboard can send over /ws/stats
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Narrows the counters down to these links, by code or slug
+    Subscribe {
+        links: Vec<String>,
+    },
+    Unsubscribe {
+        links: Vec<String>,
+    },
+}
+
+/// What /ws/stats sends
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Stats(LiveStats),
+    /// The links subscribed to, after a change; every link is counted when there are none
+    Subscribed {
+        links: Vec<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Counters since the dashboard connected, of the subscribed links or of every link
+#[derive(Serialize)]
+struct LiveStats {
+    at: DateTime<Utc>,
+    clicks: u64,
+    clicks_per_minute: u64,
+    /// The subscribed links, or the [TOP_LINKS] links with the most clicks; most clicks first
+    top_links: Vec<LinkCounter>,
+    /// Clicks that went uncounted because the connection fell behind
+    missed: u64,
+}
+
+#[derive(Serialize)]
+struct LinkCounter {
+    id: Arc<str>,
+    clicks: u64,
+    clicks_per_minute: u64,
+}
+
+/// The clicks one connection has seen, all of them, so subscriptions can change at any time
+#[derive(Default)]
+struct Counters {
+    /// Clicks per link, under the code or slug it was first seen or subscribed to as
+    clicks: HashMap<u64, (Arc<str>, u64)>,
+    /// When the clicks of the last [RATE_WINDOW] happened, oldest first
+    recent: VecDeque<(Instant, u64)>,
+    /// Subscribed links by id; every link counts while there are none
+    subscribed: BTreeMap<u64, Arc<str>>,
+    /// The links the connection may count, leaving out clicks on any others
+    scope: Scope,
+    missed: u64,
+}
+
+impl Counters {
+    fn count(&mut self, click: &LiveClick) {
+        self.clicks
+            .entry(click.link_id)
+            .or_insert_with(|| (click.external_id.clone(), 0))
+            .1 += 1;
+        self.recent.push_back((Instant::now(), click.link_id));
+    }
+
+    fn stats(&mut self) -> LiveStats {
+        while self
+            .recent
+            .front()
+            .is_some_and(|(at, _)| at.elapsed() > RATE_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        let mut per_minute: HashMap<u64, u64> = HashMap::new();
+        for (_, link_id) in &self.recent {
+            *per_minute.entry(*link_id).or_default() += 1;
+        }
+        let counter = |link_id: u64, id: Arc<str>, clicks: u64| LinkCounter {
+            id,
+            clicks,
+            clicks_per_minute: per_minute.get(&link_id).copied().unwrap_or_default(),
+        };
+
+        let mut links: Vec<LinkCounter> = if self.subscribed.is_empty() {
+            self.clicks
+                .iter()
+                .map(|(&link_id, (id, clicks))| counter(link_id, id.clone(), *clicks))
+                .collect()
+        } else {
+            self.subscribed
+                .iter()
+                .map(|(&link_id, id)| {
+                    let clicks = self.clicks.get(&link_id).map_or(0, |(_, clicks)| *clicks);
+                    counter(link_id, id.clone(), clicks)
+                })
+                .collect()
+        };
+        let clicks = links.iter().map(|link| link.clicks).sum();
+        let clicks_per_minute = if self.subscribed.is_empty() {
+            self.recent.len() as u64
+        } else {
+            links.iter().map(|link| link.clicks_per_minute).sum()
+        };
+        links.sort_by(|a, b| {
+            (b.clicks, b.clicks_per_minute)
+                .cmp(&(a.clicks, a.clicks_per_minute))
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        if self.subscribed.is_empty() {
+            links.truncate(TOP_LINKS);
+        }
+        LiveStats {
+            at: Utc::now(),
+            clicks,
+            clicks_per_minute,
+            top_links: links,
+            missed: self.missed,
+        }
+    }
+}
+
+/// GET /ws/stats opens a WebSocket that pushes click counters every `live.stats_interval_secs`
+#[utoipa::path(
+    get,
+    path = "/ws/stats",
+    summary = "Live click counters over a WebSocket",
+    description = "Sends `{\"type\": \"stats\", ...}` with the clicks since connecting, the \
+        clicks in the last minute and the top links, right away and then every \
+        `live.stats_interval_secs`. Send `{\"type\": \"subscribe\", \"links\": [...]}` with \
+        codes or slugs to count only those links, and `unsubscribe` to stop; each change is \
+        answered with `{\"type\": \"subscribed\", \"links\": [...]}`.",
+    responses(
+        (status = 101, description = "Switched to a WebSocket"),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "stats"
+)]
+pub async fn stats_socket(
+    upgrade: WebSocketUpgrade,
+    State(app_state): State<AppState>,
+    caller: Authorized<Read>,
+) -> Response {
+    upgrade.on_upgrade(move |socket| push_stats(socket, app_state, caller.scope()))
+}
+
+async fn push_stats(mut socket: WebSocket, app_state: AppState, scope: Scope) {
+    let mut clicks = app_state.live.subscribe();
+    let mut ticks = tokio::time::interval(Duration::from_secs(
+        app_state.config.live.stats_interval_secs,
+    ));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut counters = Counters {
+        scope,
+        ..Counters::default()
+    };
+    loop {
+        let reply = tokio::select! {
+            click = clicks.recv() => {
+                match click {
+                    Ok(click) if scope.allows(click.owner_id) => counters.count(&click),
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => counters.missed += missed,
+                    Err(RecvError::Closed) => break,
+                }
+                continue;
+            }
+            _ = ticks.tick() => ServerMessage::Stats(counters.stats()),
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => answer(&app_state, &mut counters, &text).await,
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                // Pings are answered by axum
+                Some(Ok(_)) => continue,
+            },
+        };
+        let reply = serde_json::to_string(&reply).expect("messages serialize");
+        if socket.send(Message::Text(reply.into())).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Answers a message from the dashboard
+async fn answer(app_state: &AppState, counters: &mut Counters, text: &str) -> ServerMessage {
+    match serde_json::from_str(text) {
+        Ok(message) => change_subscription(app_state, counters, message).await,
+        Err(error) => ServerMessage::Error {
+            message: error.to_string(),
+        },
+    }
+}
+
+/// Applies a subscription change, answering with the links subscribed to afterwards
+async fn change_subscription(
+    app_state: &AppState,
+    counters: &mut Counters,
+    message: ClientMessage,
+) -> ServerMessage {
+    match message {
+        ClientMessage::Subscribe { links } => {
+            // All or nothing, so a mistyped link doesn't leave half the list subscribed
+            let mut resolved = Vec::with_capacity(links.len());
+            for external_id in links {
+                match app_state
+                    .store
+                    .resolve_active_in(&external_id, counters.scope)
+                    .await
+                {
+                    Ok(link) => resolved.push((link.id, external_id.into())),
+                    Err(error) => {
+                        return ServerMessage::Error {
+                            message: error.to_string(),
+                        };
+                    }
+                }
+            }
+            counters.subscribed.extend(resolved);
+        }
+        ClientMessage::Unsubscribe { links } => {
+            counters
+                .subscribed
+                .retain(|_, id| !links.iter().any(|link| **link == **id));
+        }
+    }
+    ServerMessage::Subscribed {
+        links: counters
+            .subscribed
+            .values()
+            .map(|id| id.to_string())
+            .collect(),
+    }
+}
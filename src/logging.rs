@@ -0,0 +1,73 @@
+//! Log output through `tracing`, with a span per request carrying its id
+
+use axum::extract::Request;
+use serde::Deserialize;
+use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+use crate::error::{Error, QrLinkResult};
+use crate::request_id;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// An `EnvFilter` directive, e.g. `info` or `warn,qr_link_service=debug`
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".into(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn filter(&self) -> QrLinkResult<EnvFilter> {
+        EnvFilter::try_new(&self.level)
+            .map_err(|error| Error::Config(format!("log.level '{}': {error}", self.level)))
+    }
+}
+
+/// Sends log output to stderr as `config` says; called once, before anything is logged
+pub fn init(config: &LogConfig) -> QrLinkResult<()> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(config.filter()?)
+        .with_writer(std::io::stderr);
+    match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    }
+    .map_err(|error| Error::Config(format!("can't set up logging: {error}")))
+}
+
+/// A span per request naming its method, path and id, closed with its status and latency
+pub fn trace_layer() -> TraceLayer<SharedClassifier<ServerErrorsAsFailures>, fn(&Request) -> Span> {
+    TraceLayer::new_for_http()
+        .make_span_with(request_span as fn(&Request) -> Span)
+        .on_response(DefaultOnResponse::new().level(tracing::Level::INFO))
+}
+
+fn request_span(request: &Request) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        request_id = %request_id::current(),
+    )
+}
@@ -0,0 +1,99 @@
+//! Prometheus metrics: every request is counted and timed by [track], and `GET /metrics` renders
+//! them together with the database pool's state
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+use crate::store::PoolStats;
+
+/// Histogram buckets in seconds, from a cached redirect to a large QR code render
+const DURATION_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// The process-wide recorder, installed on first use so routers built later share it
+pub fn handle() -> &'static PrometheusHandle {
+    HANDLE.get_or_init(|| {
+        PrometheusBuilder::new()
+            .set_buckets(DURATION_BUCKETS)
+            .expect("buckets are not empty")
+            .install_recorder()
+            .expect("no other metrics recorder is installed")
+    })
+}
+
+/// Middleware counting requests by method, route and status, and timing them by route.
+/// Redirect latency is the `/{external_id}` GET series.
+pub async fn track(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    // Unmatched paths are lumped together, so scanners can't blow up the label set
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_owned();
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    metrics::counter!(
+        "http_requests_total",
+        "method" => method.clone(),
+        "route" => route.clone(),
+        "status" => status
+    )
+    .increment(1);
+    metrics::histogram!(
+        "http_request_duration_seconds",
+        "method" => method,
+        "route" => route
+    )
+    .record(started.elapsed());
+    response
+}
+
+/// Times the rendering of one QR code in `format`
+pub fn record_qr_render(format: &'static str, started: Instant) {
+    metrics::histogram!("qr_render_duration_seconds", "format" => format).record(started.elapsed());
+}
+
+/// Counts a redirect lookup answered from the cache, or one that had to go to the database
+pub fn record_redirect_lookup(cached: bool) {
+    let result = if cached { "hit" } else { "miss" };
+    metrics::counter!("redirect_cache_lookups_total", "result" => result).increment(1);
+}
+
+/// Counts a click written as it happened because the click log's queue was full
+pub fn record_click_log_overflow() {
+    metrics::counter!("click_log_overflows_total").increment(1);
+}
+
+/// Sets how many clicks are waiting in the click log's queue
+pub fn set_click_log_queued(queued: usize) {
+    metrics::gauge!("click_log_queued").set(queued as f64);
+}
+
+/// Counts `count` queued clicks written in one batch, or lost because the batch failed
+pub fn record_click_log_written(count: usize, written: bool) {
+    let result = if written { "ok" } else { "failed" };
+    metrics::counter!("click_log_clicks_total", "result" => result).increment(count as u64);
+}
+
+/// Everything recorded so far in the Prometheus text format, with the pool's current state
+pub fn render(pool: PoolStats) -> String {
+    metrics::gauge!("db_pool_connections").set(pool.connections);
+    metrics::gauge!("db_pool_idle_connections").set(pool.idle);
+    metrics::gauge!("db_pool_max_connections").set(pool.max);
+
+    let handle = handle();
+    handle.run_upkeep();
+    handle.render()
+}
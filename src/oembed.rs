@@ -0,0 +1,158 @@
+//! oEmbed, which chat apps and CMSes ask what a URL pasted into them is, to show a card for it
+//! rather than the bare URL: for short links, their title and their QR code as the thumbnail.
+//! Preview pages point consumers here with a discovery `<link>`.
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::error::{Error, ErrorBody, QrLinkResult};
+use crate::extract::Query;
+use crate::store::Link;
+use crate::{AppState, signed_qr};
+
+/// Smallest thumbnail offered, however small `maxwidth` and `maxheight` are
+const MIN_THUMBNAIL_SIZE: u32 = 50;
+
+/// GET /oembed?url=<short link>&format=json
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OEmbedQuery {
+    /// A short link of this service, or its preview page
+    url: String,
+    /// Only `json` is served
+    format: Option<String>,
+    /// Widest the thumbnail may be, in pixels
+    maxwidth: Option<u32>,
+    /// Tallest the thumbnail may be, in pixels
+    maxheight: Option<u32>,
+}
+
+/// A `link` response in the oEmbed 1.0 format
+#[derive(Serialize, ToSchema)]
+pub struct OEmbed {
+    /// Always `1.0`
+    version: &'static str,
+    /// Always `link`
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    provider_name: String,
+    provider_url: String,
+    /// Seconds the response may be cached for
+    cache_age: u64,
+    /// The link's QR code as a PNG, when QR codes are on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_height: Option<u32>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/oembed",
+    summary = "Describe a short link for embedding",
+    description = "The oEmbed endpoint for short links and their preview pages, which preview \
+        pages name in a discovery `<link>`. The thumbnail is the link's QR code.",
+    params(OEmbedQuery),
+    responses(
+        (status = 200, body = OEmbed),
+        (status = 404, description = "Not a link of this service, no such link, or one that is \
+            quarantined or not yet activated", body = ErrorBody),
+        (status = 501, description = "A `format` other than `json`"),
+    ),
+    tag = "links"
+)]
+pub async fn get_oembed(
+    State(app_state): State<AppState>,
+    Query(query): Query<OEmbedQuery>,
+    headers: HeaderMap,
+) -> QrLinkResult<Response> {
+    if query
+        .format
+        .as_deref()
+        .is_some_and(|format| format != "json")
+    {
+        return Ok(StatusCode::NOT_IMPLEMENTED.into_response());
+    }
+    let external_id = link_id(&app_state, &headers, &query.url)
+        .ok_or_else(|| Error::NotFound(query.url.clone()))?;
+    let link = app_state.store.resolve_active(&external_id).await?;
+    // Nothing to embed while the destination is flagged as harmful, or before it is revealed
+    if link.quarantine.is_some() || link.is_pending() {
+        return Err(Error::NotFound(query.url));
+    }
+    let short_url = crate::short_url(&app_state, &headers, &link.public_id());
+    let provider_url = crate::short_url(&app_state, &headers, "");
+    let provider_name = Url::parse(&provider_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_default();
+    let size = [query.maxwidth, query.maxheight]
+        .into_iter()
+        .flatten()
+        .fold(app_state.config.qr.default_size, u32::min)
+        .max(MIN_THUMBNAIL_SIZE);
+    let thumbnail = app_state.config.features.qr_codes.then_some(size);
+    let oembed = OEmbed {
+        version: "1.0",
+        kind: "link",
+        title: title(&link),
+        provider_name,
+        provider_url,
+        cache_age: app_state.config.qr.max_age_secs,
+        thumbnail_url: thumbnail.map(|size| {
+            let query = format!("format=png&size={size}");
+            let query = signed_qr::sign(&app_state, &link.public_id(), &query);
+            format!("{short_url}/qr?{query}")
+        }),
+        thumbnail_width: thumbnail,
+        thumbnail_height: thumbnail,
+    };
+    let cache_control = format!("public, max-age={}", app_state.config.qr.max_age_secs);
+    Ok(([(header::CACHE_CONTROL, cache_control)], axum::Json(oembed)).into_response())
+}
+
+/// Where consumers find the oEmbed of `short_url`, for the discovery `<link>` of its preview
+pub fn discovery_url(app_state: &AppState, headers: &HeaderMap, short_url: &str) -> String {
+    let mut url =
+        Url::parse(&crate::short_url(app_state, headers, "oembed")).expect("short links are URLs");
+    url.query_pairs_mut()
+        .append_pair("url", short_url)
+        .append_pair("format", "json");
+    url.into()
+}
+
+/// What a link is called on cards: its own title, that of the page it leads to, or the host
+pub fn title(link: &Link) -> String {
+    link.title
+        .clone()
+        .or_else(|| link.page_title.clone())
+        .unwrap_or_else(|| {
+            let host = Url::parse(&link.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_owned))
+                .unwrap_or_default();
+            format!("Link to {host}")
+        })
+}
+
+/// The code or slug of the short link `url`, if it is one of ours: under `base_url`, or else
+/// on the host the request came in on, with or without `/preview`
+fn link_id(app_state: &AppState, headers: &HeaderMap, url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let base = Url::parse(&crate::short_url(app_state, headers, "")).ok()?;
+    if url.host_str() != base.host_str()
+        || url.port_or_known_default() != base.port_or_known_default()
+    {
+        return None;
+    }
+    let path = url.path().strip_prefix(base.path())?;
+    let path = path.strip_suffix("/preview").unwrap_or(path);
+    (!path.is_empty() && !path.contains('/')).then(|| path.to_owned())
+}
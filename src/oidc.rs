@@ -0,0 +1,357 @@
+//! Signing in to the dashboard through an OpenID Connect provider, for deployments whose people
+//! already have accounts there. /login/oidc sends the browser to the provider with the
+//! authorization code flow and PKCE, and /login/oidc/callback trades the code it comes back with
+//! for an ID token. A user is made for each subject on their first sign-in, named after the
+//! `oidc.name_claim` claim, and members of `oidc.admin_group` are signed in as the admin.
+//!
+//! The ID token's signature isn't checked: it comes straight from the provider's token endpoint,
+//! which TLS already vouches for, as OpenID Connect Core 3.1.3.7 allows. Its issuer, audience,
+//! expiry and nonce are.
+
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum_extra::extract::SignedCookieJar;
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use base64::prelude::{BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use url::Url;
+
+use crate::code::{BASE62, CodeGenerator};
+use crate::error::{Error, QrLinkResult};
+use crate::extract::Query;
+use crate::session::Session;
+use crate::webhooks::error_chain;
+use crate::{AppState, MAX_USER_NAME_LENGTH, pages};
+
+/// The cookie a sign-in keeps its state, nonce and PKCE verifier in on its way to the provider
+const COOKIE_NAME: &str = "qrlink_oidc";
+
+/// Where the provider sends the browser back to, under `base_url`
+pub const CALLBACK_PATH: &str = "/login/oidc/callback";
+
+/// Random characters in the state and nonce, and in the PKCE verifier
+const STATE_LENGTH: usize = 32;
+const VERIFIER_LENGTH: usize = 64;
+
+/// Seconds a browser has to sign in at the provider and come back
+const SIGN_IN_TTL_SECS: i64 = 600;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    /// The provider's issuer, whose `/.well-known/openid-configuration` says where to send
+    /// people; without it, OpenID Connect is off
+    pub issuer: Option<Url>,
+    pub client_id: String,
+    /// Sent with HTTP Basic authentication when exchanging codes; public clients have none
+    pub client_secret: Option<String>,
+    pub scopes: Vec<String>,
+    /// The claim new users are named after, falling back to `email` and then the subject
+    pub name_claim: String,
+    /// The claim listing the groups someone is in
+    pub groups_claim: String,
+    /// Members of this group are signed in as the admin
+    pub admin_group: Option<String>,
+    /// Seconds to wait for the provider
+    pub timeout_secs: u64,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            issuer: None,
+            client_id: String::new(),
+            client_secret: None,
+            scopes: ["openid", "profile", "email"].map(String::from).to_vec(),
+            name_claim: "preferred_username".into(),
+            groups_claim: "groups".into(),
+            admin_group: None,
+            timeout_secs: 10,
+        }
+    }
+}
+
+/// What the provider's discovery document says about it
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: Url,
+    token_endpoint: Url,
+}
+
+/// Sends people to the provider and makes sessions of what it says about them
+pub struct Oidc {
+    config: OidcConfig,
+    issuer: Url,
+    redirect_uri: String,
+    client: reqwest::Client,
+    /// Looked up on the first sign-in, and again after a lookup fails
+    provider: OnceCell<ProviderMetadata>,
+}
+
+impl Oidc {
+    /// The provider `config` names, if it names one, sending people back to `base_url`
+    pub fn new(config: &OidcConfig, base_url: Option<&Url>) -> QrLinkResult<Option<Self>> {
+        let (Some(issuer), Some(base_url)) = (&config.issuer, base_url) else {
+            return Ok(None);
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .user_agent(concat!("qr-link-service/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|error| Error::Config(format!("can't set up the OIDC client: {error}")))?;
+        Ok(Some(Oidc {
+            config: config.clone(),
+            issuer: issuer.clone(),
+            redirect_uri: format!("{}{CALLBACK_PATH}", base_url.as_str().trim_end_matches('/')),
+            client,
+            provider: OnceCell::new(),
+        }))
+    }
+
+    async fn provider(&self) -> QrLinkResult<&ProviderMetadata> {
+        self.provider
+            .get_or_try_init(|| async {
+                let mut url = self.issuer.clone();
+                url.path_segments_mut()
+                    .map_err(|()| Error::Config("oidc.issuer can't be a base URL".into()))?
+                    .pop_if_empty()
+                    .extend([".well-known", "openid-configuration"]);
+                let provider: ProviderMetadata = self.fetch(self.client.get(url)).await?;
+                if !same_issuer(&provider.issuer, self.issuer.as_str()) {
+                    return Err(Error::IdentityProvider(format!(
+                        "discovery document is for issuer {}",
+                        provider.issuer
+                    )));
+                }
+                Ok(provider)
+            })
+            .await
+    }
+
+    /// The JSON `request` is answered with
+    async fn fetch<T: serde::de::DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> QrLinkResult<T> {
+        let fetched = async { request.send().await?.error_for_status()?.json().await };
+        fetched
+            .await
+            .map_err(|error: reqwest::Error| Error::IdentityProvider(error_chain(&error)))
+    }
+
+    /// The claims of the ID token `code` is exchanged for, checked against `nonce`
+    async fn claims(&self, code: &str, verifier: &str, nonce: &str) -> QrLinkResult<Value> {
+        let provider = self.provider().await?;
+        let mut request = self.client.post(provider.token_endpoint.clone()).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", verifier),
+        ]);
+        if let Some(secret) = &self.config.client_secret {
+            request = request.basic_auth(&self.config.client_id, Some(secret));
+        }
+        let tokens: TokenResponse = self.fetch(request).await?;
+
+        let invalid = |why: &str| Error::IdentityProvider(format!("ID token {why}"));
+        let payload = tokens
+            .id_token
+            .split('.')
+            .nth(1)
+            .and_then(|payload| BASE64_URL_SAFE_NO_PAD.decode(payload).ok())
+            .ok_or_else(|| invalid("is malformed"))?;
+        let claims: Value =
+            serde_json::from_slice(&payload).map_err(|_| invalid("has malformed claims"))?;
+        let issuer = claims["iss"].as_str().unwrap_or_default();
+        if !same_issuer(issuer, &provider.issuer) {
+            return Err(invalid("is from another issuer"));
+        }
+        let audience = match &claims["aud"] {
+            Value::String(audience) => audience == &self.config.client_id,
+            Value::Array(audiences) => audiences.iter().any(|aud| aud == &*self.config.client_id),
+            _ => false,
+        };
+        if !audience {
+            return Err(invalid("is for another client"));
+        }
+        if claims["exp"]
+            .as_i64()
+            .is_none_or(|exp| exp <= Utc::now().timestamp())
+        {
+            return Err(invalid("has expired"));
+        }
+        if claims["nonce"].as_str() != Some(nonce) {
+            return Err(invalid("doesn't match the sign-in"));
+        }
+        Ok(claims)
+    }
+
+    /// The session the person `claims` are about signs in to, making them a user if they are
+    /// new
+    async fn session(&self, app_state: &AppState, claims: &Value) -> QrLinkResult<Session> {
+        let subject = claims["sub"]
+            .as_str()
+            .filter(|subject| !subject.is_empty())
+            .ok_or_else(|| Error::IdentityProvider("ID token has no subject".into()))?;
+        let name: String = [&*self.config.name_claim, "email"]
+            .iter()
+            .find_map(|claim| {
+                claims[claim]
+                    .as_str()
+                    .filter(|name| !name.trim().is_empty())
+            })
+            .unwrap_or(subject)
+            .trim()
+            .chars()
+            .take(MAX_USER_NAME_LENGTH)
+            .collect();
+        let user = app_state.store.oidc_user(subject, &name).await?;
+        let admin = self
+            .config
+            .admin_group
+            .as_deref()
+            .is_some_and(|admin_group| match &claims[&self.config.groups_claim] {
+                Value::String(group) => group == admin_group,
+                Value::Array(groups) => groups.iter().any(|group| group == admin_group),
+                _ => false,
+            });
+        tracing::info!(user_id = user.id, admin, "signed in through OIDC");
+        Ok(if admin {
+            Session::Admin
+        } else {
+            Session::User(user.id)
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Whether two spellings of an issuer are the same, trailing slash or not
+fn same_issuer(a: &str, b: &str) -> bool {
+    a.trim_end_matches('/') == b.trim_end_matches('/')
+}
+
+/// GET /login/oidc sends the browser to the provider to sign in
+pub(crate) async fn start_sign_in(
+    State(app_state): State<AppState>,
+    jar: SignedCookieJar,
+) -> QrLinkResult<Response> {
+    let oidc = app_state
+        .oidc
+        .as_ref()
+        .expect("routed only with oidc configured");
+    let provider = oidc.provider().await?;
+    let random = |length| {
+        CodeGenerator::new(BASE62, length)
+            .expect("base62 is a valid alphabet")
+            .generate()
+    };
+    let (state, nonce, verifier) = (
+        random(STATE_LENGTH),
+        random(STATE_LENGTH),
+        random(VERIFIER_LENGTH),
+    );
+    let challenge = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(&verifier));
+
+    let mut url = provider.authorization_endpoint.clone();
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &oidc.config.client_id)
+        .append_pair("redirect_uri", &oidc.redirect_uri)
+        .append_pair("scope", &oidc.config.scopes.join(" "))
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256");
+    let expires_at = Utc::now().timestamp() + SIGN_IN_TTL_SECS;
+    // Lax, so it comes along when the provider sends the browser back
+    let cookie = Cookie::build((
+        COOKIE_NAME,
+        format!("{state}.{nonce}.{verifier}.{expires_at}"),
+    ))
+    .path("/login/oidc")
+    .http_only(true)
+    .same_site(SameSite::Lax)
+    .secure(app_state.sessions.secure());
+    Ok((jar.add(cookie), Redirect::to(url.as_str())).into_response())
+}
+
+/// GET /login/oidc/callback?code=...&state=..., or ?error=... when the provider didn't sign
+/// the browser in
+#[derive(Deserialize)]
+pub(crate) struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+pub(crate) async fn finish_sign_in(
+    State(app_state): State<AppState>,
+    jar: SignedCookieJar,
+    Query(callback): Query<Callback>,
+) -> QrLinkResult<Response> {
+    let oidc = app_state
+        .oidc
+        .as_ref()
+        .expect("routed only with oidc configured");
+    let started = jar.get(COOKIE_NAME).map(|cookie| cookie.value().to_owned());
+    let jar = jar.remove(Cookie::build(COOKIE_NAME).path("/login/oidc"));
+    let signed_in = async {
+        if let Some(error) = callback.error {
+            return Err(Error::Unauthorized(format!(
+                "the identity provider didn't sign you in: {}",
+                callback.error_description.unwrap_or(error)
+            )));
+        }
+        let expired = || Error::Validation("the sign-in expired, try again".into());
+        let started = started.ok_or_else(expired)?;
+        let mut parts = started.splitn(4, '.');
+        let (Some(state), Some(nonce), Some(verifier), Some(expires_at)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(expired());
+        };
+        if expires_at
+            .parse::<i64>()
+            .ok()
+            .is_none_or(|expires_at| expires_at <= Utc::now().timestamp())
+            || callback.state.as_deref() != Some(state)
+        {
+            return Err(expired());
+        }
+        let code = callback
+            .code
+            .ok_or_else(|| Error::Validation("the identity provider sent no code".into()))?;
+        let claims = oidc.claims(&code, verifier, nonce).await?;
+        oidc.session(&app_state, &claims).await
+    };
+    match signed_in.await {
+        Ok(session) => {
+            let jar = app_state.sessions.start(jar, session);
+            Ok((jar, Redirect::to("/admin")).into_response())
+        }
+        // Shown on the sign-in page, to be tried again from there
+        Err(error) if error.status_code().is_client_error() => {
+            let page = pages::sign_in(&pages::SignIn {
+                signup: app_state.sessions.signup(),
+                oidc: true,
+                error: Some(error.to_string()),
+                ..Default::default()
+            });
+            Ok((error.status_code(), jar, page).into_response())
+        }
+        Err(error) => Err(error),
+    }
+}
@@ -0,0 +1,122 @@
+//! The OpenAPI document, generated from the `#[utoipa::path]` annotations on the handlers
+
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::openapi::{OpenApi as Document, Server};
+use utoipa::{Modify, OpenApi};
+
+use crate::config::Config;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "QR Link Shortener API",
+        description = "A URL shortener that draws QR codes for its links"
+    ),
+    paths(
+        crate::create_url,
+        crate::create_batch,
+        crate::export_links,
+        crate::get_url,
+        crate::get_url_path,
+        crate::update_url,
+        crate::delete_url,
+        crate::restore_url,
+        crate::get_meta,
+        crate::get_preview,
+        crate::oembed::get_oembed,
+        crate::get_qr,
+        crate::signed_qr::get_qr_url,
+        crate::card::get_card,
+        crate::get_payload_qr,
+        crate::post_wifi_qr,
+        crate::post_vcard_qr,
+        crate::decode_qr,
+        crate::get_qr_batch,
+        crate::get_qr_sheet,
+        crate::get_stats,
+        crate::prune_stats,
+        crate::live::stream_clicks,
+        crate::live::stream_link_clicks,
+        crate::live::stats_socket,
+        crate::quotas::get_usage,
+        crate::list_links,
+        crate::list_tags,
+        crate::issue_api_key,
+        crate::revoke_api_key,
+        crate::add_user,
+        crate::list_users,
+        crate::update_user,
+        crate::webhooks::register_webhook,
+        crate::webhooks::list_webhooks,
+        crate::webhooks::delete_webhook,
+        crate::webhooks::webhook_deliveries,
+        crate::backup::take_backup,
+        crate::backup::download_backup,
+        crate::get_metrics,
+        crate::get_health,
+        crate::get_readiness,
+    ),
+    components(schemas(crate::webhooks::Event)),
+    modifiers(&BearerSchemes),
+    tags(
+        (name = "links", description = "Creating, following and managing short links"),
+        (name = "qr", description = "QR codes for short links"),
+        (name = "stats", description = "Click statistics"),
+        (name = "keys", description = "Users and their API keys, managed by admins"),
+        (name = "webhooks", description = "Endpoints sent link events (admin)"),
+        (name = "operations", description = "Probes and metrics"),
+    )
+)]
+struct ApiDoc;
+
+/// Both kinds of credentials are sent as `Authorization: Bearer`
+struct BearerSchemes;
+
+impl Modify for BearerSchemes {
+    fn modify(&self, openapi: &mut Document) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        for name in ["api_key", "admin_token"] {
+            components.add_security_scheme(
+                name,
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
+
+/// The document for the routes `config` switches on, served from its `base_url` if it has one
+pub fn spec(config: &Config) -> Document {
+    let mut spec = ApiDoc::openapi();
+    // utoipa fills the license in from Cargo.toml, which doesn't name one
+    spec.info.license.take_if(|license| license.name.is_empty());
+    if !config.features.qr_codes {
+        spec.paths.paths.remove("/{external_id}/qr");
+        spec.paths.paths.remove("/{external_id}/card.png");
+        spec.paths.paths.remove("/qr");
+        spec.paths.paths.remove("/qr/wifi");
+        spec.paths.paths.remove("/qr/vcard");
+        spec.paths.paths.remove("/qr/decode");
+        spec.paths.paths.remove("/api/v1/qr/batch");
+        spec.paths.paths.remove("/api/v1/qr/sheet");
+        spec.paths
+            .paths
+            .remove("/api/v1/links/{external_id}/qr-url");
+    }
+    if !config.features.click_tracking {
+        spec.paths.paths.remove("/api/v1/events");
+        spec.paths
+            .paths
+            .remove("/api/v1/links/{external_id}/events");
+        spec.paths.paths.remove("/ws/stats");
+    }
+    if !config.features.metrics {
+        spec.paths.paths.remove("/metrics");
+    }
+    if config.backups.directory.is_none() {
+        spec.paths.paths.remove("/api/v1/admin/backup");
+    }
+    if let Some(base_url) = &config.base_url {
+        spec.servers = Some(vec![Server::new(base_url.as_str().trim_end_matches('/'))]);
+    }
+    spec
+}
@@ -0,0 +1,38 @@
+//! `OPTIONS` requests asking which methods a route takes. The router turns them away with the
+//! `Allow` header listing those methods, which is the answer they are after, so they get it
+//! with a success status instead. CORS preflights are answered before they get here.
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{HeaderValue, Method, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::Response;
+
+/// Middleware answering `OPTIONS` on any route with `204 No Content` and the methods it takes
+pub async fn answer(request: Request, next: Next) -> Response {
+    if request.method() != Method::OPTIONS {
+        return next.run(request).await;
+    }
+    let response = next.run(request).await;
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+    let Some(allowed) = response
+        .headers()
+        .get(header::ALLOW)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return response;
+    };
+    let allow = if allowed.is_empty() {
+        "OPTIONS".to_owned()
+    } else {
+        format!("{allowed},OPTIONS")
+    };
+    let mut answer = Response::new(Body::empty());
+    *answer.status_mut() = StatusCode::NO_CONTENT;
+    if let Ok(value) = HeaderValue::from_str(&allow) {
+        answer.headers_mut().insert(header::ALLOW, value);
+    }
+    answer
+}
@@ -0,0 +1,115 @@
+//! Requests the service sends to link destinations on its own, to read their pages and check
+//! their health. Destinations are whatever link creators give, so unless
+//! `allow_private_addresses` is set, only hosts on the public internet are reached: loopback,
+//! private, link-local and other addresses that aren't global are refused, and names resolving
+//! only to such addresses, before the first request and after every redirect, so that the
+//! service can't be made to read its own network.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use url::{Host, Url};
+
+/// A client for destinations, following up to `max_redirects` redirects
+pub fn client(
+    timeout: Duration,
+    max_redirects: usize,
+    allow_private_addresses: bool,
+) -> reqwest::Result<reqwest::Client> {
+    let builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .user_agent(concat!("qr-link-service/", env!("CARGO_PKG_VERSION")));
+    if allow_private_addresses {
+        return builder.redirect(Policy::limited(max_redirects)).build();
+    }
+    builder
+        .dns_resolver(Arc::new(PublicOnly))
+        .redirect(Policy::custom(move |attempt| {
+            if attempt.previous().len() > max_redirects {
+                attempt.error("too many redirects")
+            } else if let Err(error) = check(attempt.url().as_str()) {
+                attempt.error(error)
+            } else {
+                attempt.follow()
+            }
+        }))
+        .build()
+}
+
+/// Refuses `url` if its host is an address that isn't public, for clients that only reach
+/// public ones to check before sending a request. Host names are checked once resolved, by the
+/// client's resolver.
+pub fn check(url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|error| format!("{url}: {error}"))?;
+    let ip = match url.host() {
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        Some(Host::Domain(_)) | None => return Ok(()),
+    };
+    if is_public(ip) {
+        Ok(())
+    } else {
+        Err(format!("{ip} isn't a public address"))
+    }
+}
+
+/// Resolves host names to their public addresses only, failing for those that have none
+struct PublicOnly;
+
+impl Resolve for PublicOnly {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|address| is_public(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{host} has no public address").into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether `ip` is reachable over the public internet, rather than on this host or a network
+/// of its own
+fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [first, second, third, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network", shared address space for carrier-grade NAT, IETF protocol
+        // assignments, benchmarking and the reserved 240.0.0.0/4
+        || first == 0
+        || (first == 100 && (64..128).contains(&second))
+        || (first == 192 && second == 0 && third == 0)
+        || (first == 198 && (18..20).contains(&second))
+        || first >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let [first, second, ..] = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // Documentation, and the NAT64 prefix, which reaches IPv4 addresses of any kind
+        || (first == 0x2001 && second == 0xdb8)
+        || (first == 0x64 && second == 0xff9b))
+}
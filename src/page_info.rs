@@ -0,0 +1,260 @@
+//! The title and description of destination pages, fetched in the background when a link is
+//! created or repointed, so links can be told apart in listings without each being named.
+//!
+//! Only the start of a page is read, up to `page_info.max_bytes`, which is where its `<head>`
+//! is; a page that doesn't answer within `page_info.timeout_secs` is given up on.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::header;
+
+use crate::config::PageInfoConfig;
+use crate::error::{Error, QrLinkResult};
+use crate::outbound;
+use crate::store::{Link, LinkStore, PageInfo};
+
+/// Longest title kept, in characters
+const MAX_TITLE_LENGTH: usize = 200;
+
+/// Longest description kept, in characters
+const MAX_DESCRIPTION_LENGTH: usize = 500;
+
+/// Redirects followed to get to a page
+const MAX_REDIRECTS: usize = 5;
+
+/// Fetches destination pages for what they say about themselves
+pub struct PageFetcher {
+    client: reqwest::Client,
+    max_bytes: usize,
+    allow_private_addresses: bool,
+}
+
+impl PageFetcher {
+    pub fn new(config: &PageInfoConfig) -> QrLinkResult<Self> {
+        let client = outbound::client(
+            Duration::from_secs(config.timeout_secs),
+            MAX_REDIRECTS,
+            config.allow_private_addresses,
+        )
+        .map_err(|error| Error::Config(format!("can't set up the page client: {error}")))?;
+        Ok(PageFetcher {
+            client,
+            max_bytes: config.max_bytes,
+            allow_private_addresses: config.allow_private_addresses,
+        })
+    }
+
+    /// Fetches the destination of `link` without waiting for it, and stores what the page says
+    /// about itself unless the link has been repointed in the meantime
+    pub fn fetch_in_background(self: &Arc<Self>, store: Arc<dyn LinkStore>, link: &Link) {
+        let (fetcher, id, url) = (self.clone(), link.id, link.url.clone());
+        tokio::spawn(async move {
+            if !fetcher.allow_private_addresses
+                && let Err(error) = outbound::check(&url)
+            {
+                tracing::debug!(link = id, %url, %error, "destination page not fetched");
+                return;
+            }
+            let page = match fetcher.fetch(&url).await {
+                Ok(page) => page,
+                Err(error) => {
+                    tracing::debug!(link = id, %url, %error, "destination page not fetched");
+                    return;
+                }
+            };
+            if let Err(error) = store.set_page_info(id, &url, page).await {
+                tracing::warn!(link = id, %error, "destination page info not stored");
+            }
+        });
+    }
+
+    async fn fetch(&self, url: &str) -> reqwest::Result<PageInfo> {
+        let mut response = self
+            .client
+            .get(url)
+            .header(header::ACCEPT, "text/html")
+            .send()
+            .await?
+            .error_for_status()?;
+        let is_html = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| {
+                value.starts_with("text/html") || value.starts_with("application/xhtml+xml")
+            });
+        if !is_html {
+            return Ok(PageInfo::default());
+        }
+        let mut body = Vec::new();
+        while body.len() < self.max_bytes
+            && let Some(chunk) = response.chunk().await?
+        {
+            body.extend_from_slice(&chunk);
+        }
+        body.truncate(self.max_bytes);
+        Ok(parse(&String::from_utf8_lossy(&body)))
+    }
+}
+
+/// What the start of an HTML page says about itself: its `<title>`, or failing that its
+/// `og:title`, and its `og:description`, or failing that its `description`
+pub fn parse(html: &str) -> PageInfo {
+    // Lowercasing ASCII keeps every byte where it was, so offsets into it are offsets into html
+    let lower = html.to_ascii_lowercase();
+    let (mut title, mut og_title) = (None, None);
+    let (mut description, mut og_description) = (None, None);
+    let mut at = 0;
+    while let Some(start) = lower[at..].find('<').map(|offset| at + offset) {
+        let Some(end) = lower[start..].find('>').map(|offset| start + offset) else {
+            break;
+        };
+        let tag = &html[start + 1..end];
+        let name = lower[start + 1..end]
+            .split(|c: char| c.is_ascii_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        at = end + 1;
+        match name {
+            "title" | "script" | "style" => {
+                let close = lower[at..]
+                    .find(&format!("</{name}"))
+                    .map_or(lower.len(), |offset| at + offset);
+                if name == "title" && title.is_none() {
+                    title = clean(&html[at..close], MAX_TITLE_LENGTH);
+                }
+                at = close;
+            }
+            "meta" => {
+                let attributes = attributes(tag);
+                let value = |wanted: &str| {
+                    attributes
+                        .iter()
+                        .find(|(attribute, _)| attribute == wanted)
+                        .map(|(_, value)| value.to_ascii_lowercase())
+                };
+                let content = attributes
+                    .iter()
+                    .find(|(attribute, _)| attribute == "content")
+                    .map(|(_, content)| content.as_str());
+                let (key, content) = match (value("property").or_else(|| value("name")), content) {
+                    (Some(key), Some(content)) => (key, content),
+                    _ => continue,
+                };
+                let (field, max) = match key.as_str() {
+                    "og:title" => (&mut og_title, MAX_TITLE_LENGTH),
+                    "og:description" => (&mut og_description, MAX_DESCRIPTION_LENGTH),
+                    "description" => (&mut description, MAX_DESCRIPTION_LENGTH),
+                    _ => continue,
+                };
+                if field.is_none() {
+                    *field = clean(content, max);
+                }
+            }
+            "/head" | "body" => break,
+            _ => {}
+        }
+    }
+    PageInfo {
+        title: title.or(og_title),
+        description: og_description.or(description),
+    }
+}
+
+/// The attributes of the tag `tag` is the inside of, e.g. `meta name="description"`, with
+/// their names lowercased
+fn attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    // Past the tag's name
+    let mut rest = tag.trim_start_matches(|c: char| !c.is_ascii_whitespace());
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+        let name_end = rest
+            .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        if name_end == 0 {
+            return attributes;
+        }
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+        let Some(value) = rest.strip_prefix('=') else {
+            attributes.push((name, String::new()));
+            continue;
+        };
+        let value = value.trim_start();
+        let (value, after) = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let value = &value[1..];
+                let end = value.find(quote).unwrap_or(value.len());
+                (&value[..end], value.get(end + 1..).unwrap_or_default())
+            }
+            _ => {
+                let end = value
+                    .find(|c: char| c.is_ascii_whitespace())
+                    .unwrap_or(value.len());
+                (&value[..end], &value[end..])
+            }
+        };
+        attributes.push((name, value.to_owned()));
+        rest = after;
+    }
+}
+
+/// `text` with its character references decoded and its whitespace collapsed, cut to `max`
+/// characters, unless nothing is left of it
+fn clean(text: &str, max: usize) -> Option<String> {
+    let decoded = decode_entities(text);
+    let cleaned: String = decoded
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(max)
+        .collect();
+    (!cleaned.is_empty()).then_some(cleaned)
+}
+
+/// `text` with the character references pages commonly use in titles decoded, and any others
+/// left as they are
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest
+            .get(1..)
+            .and_then(|after| after.find(';'))
+            .filter(|&end| end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let character = reference.and_then(|reference| match reference {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let number = reference.strip_prefix('#')?;
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => number.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (reference, character) {
+            (Some(reference), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[reference.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
@@ -0,0 +1,456 @@
+//! The HTML pages, for people visiting in a browser rather than clients of the API
+
+use std::path::Path;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use maud::{DOCTYPE, Markup, PreEscaped, html};
+
+use crate::card;
+use crate::dashboard::RECENT_DAYS;
+use crate::error::{Error, QrLinkResult};
+use crate::store::Threat;
+
+/// Kept inline so every page is a single request and works without static files
+const STYLE: &str = "\
+    body { margin: 0; font-family: system-ui, sans-serif; color: #1f2328; background: #f6f8fa; }
+    main { max-width: 36rem; margin: 3rem auto; padding: 2rem; background: #fff;
+        border: 1px solid #d0d7de; border-radius: 0.5rem; }
+    h1 { margin-top: 0; font-size: 1.5rem; overflow-wrap: anywhere; }
+    code { overflow-wrap: anywhere; }
+    dl { display: grid; grid-template-columns: auto 1fr; gap: 0.5rem 1rem; }
+    dt { color: #59636e; }
+    dd { margin: 0; }
+    img.qr { display: block; width: 12rem; height: 12rem; margin: 1.5rem auto; }
+    a.button, button { display: inline-block; padding: 0.6rem 1.2rem; border: 0;
+        border-radius: 0.375rem; background: #1f883d; color: #fff; font: inherit;
+        font-weight: 600; text-decoration: none; cursor: pointer; }
+    .secondary { background: #f6f8fa; color: #1f2328; border: 1px solid #d0d7de; }
+    label { display: block; margin-bottom: 1rem; font-weight: 600; }
+    input { display: block; box-sizing: border-box; width: 100%; margin-top: 0.25rem;
+        padding: 0.5rem; font: inherit; border: 1px solid #d0d7de; border-radius: 0.375rem; }
+    .error { padding: 0.75rem; border-radius: 0.375rem; background: #ffebe9; color: #82071e; }
+    .created { margin-top: 2rem; padding-top: 1rem; border-top: 1px solid #d0d7de; }
+    .downloads { display: flex; flex-wrap: wrap; gap: 0.5rem; }
+    main.wide { max-width: 72rem; }
+    header.dashboard { display: flex; justify-content: space-between; align-items: start; }
+    table { width: 100%; border-collapse: collapse; }
+    th, td { padding: 0.5rem; border-bottom: 1px solid #d0d7de; text-align: left; }
+    td form { display: flex; gap: 0.5rem; margin: 0; }
+    td input { margin: 0; }
+    td button { padding: 0.4rem 0.8rem; }
+    button.danger { background: #cf222e; }
+    form.search { display: flex; gap: 0.5rem; margin-bottom: 1rem; }
+    form.search input { margin: 0; }
+    svg.sparkline { width: 7rem; height: 1.5rem; stroke: #0969da; fill: none; }
+    nav.pages { display: flex; justify-content: space-between; margin-top: 1rem; }";
+
+/// What the preview page shows of a link
+pub struct Preview<'a> {
+    pub short_url: &'a str,
+    pub url: &'a str,
+    /// The host of `url`, which is what a visitor should check before following it
+    pub host: &'a str,
+    pub created_at: DateTime<Utc>,
+    /// Left out when clicks aren't tracked, or kept from a viewer who doesn't see the link's stats
+    pub clicks: Option<u64>,
+    /// Where the QR code for the link is drawn, when QR codes are on
+    pub qr_src: Option<&'a str>,
+    /// Where oEmbed consumers find the link described, with `title`
+    pub oembed_url: &'a str,
+    pub title: &'a str,
+    /// The link's social card, for `og:image`, when QR codes are on
+    pub card_url: Option<&'a str>,
+}
+
+/// A page showing where a short link leads before following it
+pub fn preview(preview: &Preview) -> Markup {
+    page_with_head(
+        &format!("Link to {}", preview.host),
+        false,
+        html! {
+            link rel="alternate" type="application/json+oembed" href=(preview.oembed_url)
+                title=(preview.title);
+            meta property="og:type" content="website";
+            meta property="og:title" content=(preview.title);
+            meta property="og:url" content=(preview.short_url);
+            @if let Some(card_url) = preview.card_url {
+                meta property="og:image" content=(card_url);
+                meta property="og:image:width" content=(card::WIDTH);
+                meta property="og:image:height" content=(card::HEIGHT);
+                meta name="twitter:card" content="summary_large_image";
+            }
+        },
+        html! {
+            h1 { "This link leads to " (preview.host) }
+            dl {
+                dt { "Short link" }
+                dd { code { (preview.short_url) } }
+                dt { "Destination" }
+                dd { code { (preview.url) } }
+                dt { "Created" }
+                dd { (preview.created_at.format("%Y-%m-%d %H:%M UTC")) }
+                @if let Some(clicks) = preview.clicks {
+                    dt { "Clicks" }
+                    dd { (clicks) }
+                }
+            }
+            @if let Some(qr_src) = preview.qr_src {
+                img.qr src=(qr_src) alt={ "QR code for " (preview.short_url) };
+            }
+            p {
+                a.button href=(preview.short_url) rel="nofollow" { "Continue to " (preview.host) }
+            }
+        },
+    )
+}
+
+/// The page a link shows until it is activated at `activate_at`
+pub fn coming_soon(activate_at: DateTime<Utc>) -> Markup {
+    page(
+        "Coming soon",
+        false,
+        html! {
+            h1 { "Coming soon" }
+            p {
+                "This link opens on "
+                time datetime=(activate_at.to_rfc3339()) {
+                    (activate_at.format("%Y-%m-%d at %H:%M UTC"))
+                }
+                "."
+            }
+        },
+    )
+}
+
+/// The warning a quarantined link shows instead of sending visitors to `host`
+pub fn quarantined(host: &str, threat: Threat) -> Markup {
+    let reason = match threat {
+        Threat::Malware => "spreading malware",
+        Threat::Phishing => "phishing",
+        Threat::UnwantedSoftware => "spreading unwanted software",
+        Threat::HarmfulApplication => "spreading harmful apps",
+        Threat::Blocklisted => "being on this service's blocklist",
+    };
+    page(
+        "Warning: harmful link",
+        false,
+        html! {
+            h1 { "This link may be harmful" }
+            p.error {
+                "The page it leads to, on " code { (host) } ", has been flagged for " (reason)
+                ", so you were not sent there."
+            }
+            p { "If you trust where the link came from, let its sender know." }
+        },
+    )
+}
+
+/// Reads the configured `redirects.coming_soon_page`
+pub fn load_coming_soon(path: &Path) -> QrLinkResult<Arc<str>> {
+    std::fs::read_to_string(path)
+        .map(Arc::from)
+        .map_err(|error| {
+            Error::Config(format!(
+                "redirects.coming_soon_page {}: {error}",
+                path.display()
+            ))
+        })
+}
+
+/// What the link form shows: what was entered, and what became of it
+#[derive(Default)]
+pub struct Home<'a> {
+    pub url: &'a str,
+    pub slug: &'a str,
+    /// Why the link wasn't created
+    pub error: Option<String>,
+    pub created: Option<Created<'a>>,
+}
+
+/// A link the form just created
+pub struct Created<'a> {
+    pub short_url: &'a str,
+    /// Its QR code, when QR codes are on
+    pub qr: Option<CreatedQr>,
+}
+
+/// Where a created link's QR code is, relative to the form, at URLs signed if they need to be
+pub struct CreatedQr {
+    /// The SVG shown
+    pub src: String,
+    /// Where to download it from, by format
+    pub downloads: Vec<(&'static str, String)>,
+}
+
+/// A page with a form that shortens a link, showing the result below it
+pub fn home(home: &Home) -> Markup {
+    page(
+        "Shorten a link",
+        false,
+        html! {
+            h1 { "Shorten a link" }
+            @if let Some(error) = &home.error {
+                p.error role="alert" { (error) }
+            }
+            form method="post" action="app" {
+                label {
+                    "Link"
+                    input type="url" name="url" value=(home.url) required
+                        placeholder="https://example.com/a/long/address";
+                }
+                label {
+                    "Custom name (optional)"
+                    input type="text" name="slug" value=(home.slug) pattern="[a-z0-9_-]+";
+                }
+                button type="submit" { "Shorten" }
+            }
+            @if let Some(created) = &home.created {
+                section.created {
+                    p { "Your short link: " a href=(created.short_url) { (created.short_url) } }
+                    @if let Some(qr) = &created.qr {
+                        img.qr src=(qr.src) alt={ "QR code for " (created.short_url) };
+                        p.downloads {
+                            @for (format, href) in &qr.downloads {
+                                a.button.secondary href=(href) {
+                                    "Download " (format.to_uppercase())
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// What the sign-in and sign-up forms show
+#[derive(Default)]
+pub struct SignIn<'a> {
+    /// The name entered last time
+    pub name: &'a str,
+    /// Whether the form makes a new user rather than signing one in
+    pub new_user: bool,
+    /// Whether anyone can sign up, so the sign-in form links to where they do
+    pub signup: bool,
+    /// Whether people can sign in through an OpenID Connect provider instead
+    pub oidc: bool,
+    /// Why the browser wasn't let in
+    pub error: Option<String>,
+}
+
+/// A form signing in to the dashboard, or making a new user to sign in as
+pub fn sign_in(sign_in: &SignIn) -> Markup {
+    let (title, action, password) = if sign_in.new_user {
+        ("Sign up", "signup", "new-password")
+    } else {
+        ("Sign in", "login", "current-password")
+    };
+    page(
+        title,
+        false,
+        html! {
+            h1 { (title) }
+            @if let Some(error) = &sign_in.error {
+                p.error role="alert" { (error) }
+            }
+            form method="post" action=(action) {
+                label {
+                    "Name"
+                    input type="text" name="name" value=(sign_in.name) autocomplete="username";
+                }
+                label {
+                    "Password"
+                    input type="password" name="password" required autocomplete=(password);
+                }
+                button type="submit" { (title) }
+            }
+            @if sign_in.oidc && !sign_in.new_user {
+                p { a.button.secondary href="/login/oidc" { "Sign in with your organization" } }
+            }
+            @if sign_in.new_user {
+                p { "Already signed up? " a href="login" { "Sign in" } }
+            } @else if sign_in.signup {
+                p { "New here? " a href="signup" { "Sign up" } }
+            }
+        },
+    )
+}
+
+/// One page of the dashboard's list of links
+pub struct Dashboard<'a> {
+    /// What the destinations were searched for
+    pub search: &'a str,
+    /// Counted from 1
+    pub page: u32,
+    pub pages: u32,
+    /// How many links matched the search
+    pub total: u64,
+    /// Why the last change wasn't made
+    pub error: Option<String>,
+    /// Whether links can be changed, which viewers can't
+    pub editable: bool,
+    pub links: Vec<DashboardLink>,
+}
+
+/// A link as the dashboard lists it
+pub struct DashboardLink {
+    pub public_id: String,
+    pub short_url: String,
+    /// Its own title, or else its destination page's
+    pub title: Option<String>,
+    pub url: String,
+    pub created_at: DateTime<Utc>,
+    pub clicks: u64,
+    /// Clicks on each of the last [RECENT_DAYS] days, oldest first
+    pub recent_clicks: Vec<u64>,
+}
+
+/// The admin dashboard: every link with its clicks and recent activity, to search, repoint
+/// and delete
+pub fn dashboard(dashboard: &Dashboard) -> Markup {
+    // Changes come back to the page of the list they were made on
+    let back = html! {
+        input type="hidden" name="q" value=(dashboard.search);
+        input type="hidden" name="page" value=(dashboard.page);
+    };
+    let page_link = |page: u32| {
+        let query: String = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("q", dashboard.search)
+            .append_pair("page", &page.to_string())
+            .finish();
+        format!("admin?{query}")
+    };
+    page(
+        "Links",
+        true,
+        html! {
+            header.dashboard {
+                h1 { "Links" }
+                form method="post" action="logout" {
+                    button.secondary type="submit" { "Sign out" }
+                }
+            }
+            @if let Some(error) = &dashboard.error {
+                p.error role="alert" { (error) }
+            }
+            form.search method="get" action="admin" {
+                input type="search" name="q" value=(dashboard.search)
+                    placeholder="Search destinations" aria-label="Search destinations";
+                button type="submit" { "Search" }
+            }
+            p { (dashboard.total) " links" }
+            table {
+                thead {
+                    tr {
+                        th { "Link" }
+                        th { "Destination" }
+                        th { "Created" }
+                        th { "Clicks" }
+                        th { "Last " (RECENT_DAYS) " days" }
+                        th {}
+                    }
+                }
+                tbody {
+                    @for link in &dashboard.links {
+                        tr {
+                            td {
+                                a href=(link.short_url) { (link.public_id) }
+                                @if let Some(title) = &link.title {
+                                    br;
+                                    small { (title) }
+                                }
+                            }
+                            td {
+                                @if dashboard.editable {
+                                    form method="post"
+                                        action={ "admin/links/" (link.public_id) } {
+                                        (back)
+                                        input type="url" name="url" value=(link.url) required
+                                            aria-label={ "Destination of " (link.public_id) };
+                                        button type="submit" { "Save" }
+                                    }
+                                } @else {
+                                    (link.url)
+                                }
+                            }
+                            td { (link.created_at.format("%Y-%m-%d")) }
+                            td { (link.clicks) }
+                            td { (sparkline(&link.recent_clicks)) }
+                            td {
+                                @if dashboard.editable {
+                                    form method="post"
+                                        action={ "admin/links/" (link.public_id) "/delete" } {
+                                        (back)
+                                        button.danger type="submit" { "Delete" }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            nav.pages {
+                span {
+                    @if dashboard.page > 1 {
+                        a href=(page_link(dashboard.page - 1)) { "Previous" }
+                    }
+                }
+                span { "Page " (dashboard.page) " of " (dashboard.pages) }
+                span {
+                    @if dashboard.page < dashboard.pages {
+                        a href=(page_link(dashboard.page + 1)) { "Next" }
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// A line through `counts`, scaled to the largest of them
+fn sparkline(counts: &[u64]) -> Markup {
+    const WIDTH: usize = 100;
+    const HEIGHT: u64 = 20;
+    let max = counts.iter().copied().max().unwrap_or_default().max(1);
+    let step = WIDTH as f64 / counts.len().saturating_sub(1).max(1) as f64;
+    let points: Vec<String> = counts
+        .iter()
+        .enumerate()
+        .map(|(day, &clicks)| {
+            let x = day as f64 * step;
+            let y = HEIGHT - clicks * HEIGHT / max;
+            format!("{x:.1},{y}")
+        })
+        .collect();
+    let total: u64 = counts.iter().sum();
+    html! {
+        svg.sparkline viewBox={ "0 -1 " (WIDTH) " " (HEIGHT + 2) } preserveAspectRatio="none"
+            role="img" aria-label={ (total) " clicks" } {
+            polyline points=(points.join(" ")) vector-effect="non-scaling-stroke";
+        }
+    }
+}
+
+/// `body` in the document every page shares, in a wider frame for tables. Pages aren't meant
+/// for search engines.
+fn page(title: &str, wide: bool, body: Markup) -> Markup {
+    page_with_head(title, wide, html! {}, body)
+}
+
+/// [`page`] with `head` added to the `<head>` every page shares
+fn page_with_head(title: &str, wide: bool, head: Markup, body: Markup) -> Markup {
+    html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                meta name="robots" content="noindex";
+                title { (title) }
+                style { (PreEscaped(STYLE)) }
+                (head)
+            }
+            body { main.wide[wide] { (body) } }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Payloads for QR codes that hold something other than a link, in the formats phone cameras
+//! recognize
+
+use serde::Deserialize;
+use url::Url;
+use utoipa::ToSchema;
+
+use crate::error::{Error, QrLinkResult};
+
+/// How a Wi-Fi network is secured
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ToSchema)]
+pub enum WifiSecurity {
+    /// WPA, WPA2 or WPA3 personal
+    #[default]
+    #[serde(rename = "WPA", alias = "wpa", alias = "WPA2", alias = "wpa2")]
+    Wpa,
+    #[serde(rename = "WEP", alias = "wep")]
+    Wep,
+    /// An open network
+    #[serde(rename = "nopass", alias = "none", alias = "open")]
+    Open,
+}
+
+/// A Wi-Fi network to join by scanning
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub struct Wifi {
+    pub ssid: String,
+    /// `WPA` by default
+    #[serde(default)]
+    pub security: WifiSecurity,
+    /// Required unless the network is open
+    pub password: Option<String>,
+    /// Whether the network doesn't broadcast its SSID
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+impl Wifi {
+    /// The `WIFI:T:WPA;S:...;P:...;;` string Android and iOS cameras offer to join
+    pub fn payload(&self) -> QrLinkResult<String> {
+        if self.ssid.is_empty() {
+            return Err(Error::Validation("ssid must not be empty".into()));
+        }
+        let password = match (self.security, self.password.as_deref()) {
+            (WifiSecurity::Open, None | Some("")) => None,
+            (WifiSecurity::Open, Some(_)) => {
+                return Err(Error::Validation(
+                    "open networks (nopass) have no password".into(),
+                ));
+            }
+            (_, None | Some("")) => {
+                return Err(Error::Validation(
+                    "password is required unless security is nopass".into(),
+                ));
+            }
+            (_, Some(password)) => Some(password),
+        };
+
+        let security = match self.security {
+            WifiSecurity::Wpa => "WPA",
+            WifiSecurity::Wep => "WEP",
+            WifiSecurity::Open => "nopass",
+        };
+        let mut payload = format!("WIFI:T:{security};S:{};", wifi_value(&self.ssid));
+        if let Some(password) = password {
+            payload.push_str(&format!("P:{};", wifi_value(password)));
+        }
+        if self.hidden {
+            payload.push_str("H:true;");
+        }
+        payload.push(';');
+        Ok(payload)
+    }
+}
+
+/// A contact to save by scanning
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub struct Vcard {
+    /// The full name; its last word is taken for the family name
+    pub name: String,
+    /// The company or organization
+    pub org: Option<String>,
+    pub phone: Option<String>,
+    pub email: Option<String>,
+    /// An `http` or `https` website
+    pub url: Option<String>,
+}
+
+impl Vcard {
+    /// The vCard 3.0 text phone cameras offer to add to the address book
+    pub fn payload(&self) -> QrLinkResult<String> {
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err(Error::Validation("name must not be empty".into()));
+        }
+        let fields = [
+            ("name", Some(name)),
+            ("org", self.org.as_deref()),
+            ("phone", self.phone.as_deref()),
+            ("email", self.email.as_deref()),
+            ("url", self.url.as_deref()),
+        ];
+        for (field, value) in fields {
+            if value.is_some_and(|value| value.chars().any(char::is_control)) {
+                return Err(Error::Validation(format!(
+                    "{field} must be a single line of text"
+                )));
+            }
+        }
+        if let Some(phone) = &self.phone {
+            let valid = phone.chars().any(|c| c.is_ascii_digit())
+                && phone
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || " +-.()/".contains(c));
+            if !valid {
+                return Err(Error::Validation(format!("'{phone}' isn't a phone number")));
+            }
+        }
+        if let Some(email) = &self.email {
+            let valid = email
+                .split_once('@')
+                .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'));
+            if !valid || email.contains(char::is_whitespace) {
+                return Err(Error::Validation(format!(
+                    "'{email}' isn't an email address"
+                )));
+            }
+        }
+        if let Some(url) = &self.url {
+            let valid = Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+            if !valid {
+                return Err(Error::Validation(format!(
+                    "'{url}' isn't an http or https URL"
+                )));
+            }
+        }
+
+        let (given, family) = name.rsplit_once(' ').unwrap_or(("", name));
+        let mut lines = vec![
+            "BEGIN:VCARD".to_owned(),
+            "VERSION:3.0".to_owned(),
+            format!("N:{};{};;;", vcard_text(family), vcard_text(given.trim())),
+            format!("FN:{}", vcard_text(name)),
+        ];
+        if let Some(org) = &self.org {
+            lines.push(format!("ORG:{}", vcard_text(org)));
+        }
+        if let Some(phone) = &self.phone {
+            lines.push(format!("TEL;TYPE=VOICE:{phone}"));
+        }
+        if let Some(email) = &self.email {
+            lines.push(format!("EMAIL;TYPE=INTERNET:{email}"));
+        }
+        if let Some(url) = &self.url {
+            lines.push(format!("URL:{url}"));
+        }
+        lines.push("END:VCARD".to_owned());
+        Ok(lines.join("\r\n"))
+    }
+}
+
+/// `value` as a vCard text value, whose separators are escaped
+fn vcard_text(value: &str) -> String {
+    escape(value, &['\\', ';', ','])
+}
+
+/// `value` with the characters that delimit fields escaped, and in quotes if readers could
+/// otherwise take it for a hex string
+fn wifi_value(value: &str) -> String {
+    let escaped = escape(value, &['\\', ';', ',', ':', '"']);
+    if value.chars().all(|c| c.is_ascii_hexdigit()) {
+        format!("\"{escaped}\"")
+    } else {
+        escaped
+    }
+}
+
+/// `value` with a backslash before every one of `special`
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
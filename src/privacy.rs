@@ -0,0 +1,117 @@
+//! What is kept about the visitors who follow links: their addresses can be truncated or
+//! replaced by a hash salted anew every day, and visits asking not to be tracked can be left out
+//! of the statistics.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+
+use axum::http::HeaderMap;
+use chrono::{NaiveDate, Utc};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::code::{BASE62, CodeGenerator};
+use crate::error::QrLinkResult;
+use crate::store::{Click, LinkStore};
+
+/// Random characters in a day's salt
+const SALT_LENGTH: usize = 32;
+
+/// How much is kept of the address of a visitor
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpAddresses {
+    /// The address as it is
+    #[default]
+    Full,
+    /// The network of the address: its first 24 bits for IPv4, and its first 48 for IPv6
+    Truncated,
+    /// A hash of the address with the day's salt, which is deleted once the day is over
+    Hashed,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivacyConfig {
+    pub ip_addresses: IpAddresses,
+    /// Whether visits sending `DNT: 1` or `Sec-GPC: 1` are left out of the statistics. Those to
+    /// links with a click limit are still counted toward it, without anything about the visitor.
+    pub honor_do_not_track: bool,
+}
+
+/// Applies the `privacy` settings to the clicks recorded
+pub struct Privacy {
+    config: PrivacyConfig,
+    /// The salt of the day addresses were last hashed on
+    salt: Mutex<Option<(NaiveDate, String)>>,
+}
+
+impl Privacy {
+    pub fn new(config: &PrivacyConfig) -> Self {
+        Privacy {
+            config: config.clone(),
+            salt: Mutex::new(None),
+        }
+    }
+
+    /// Whether a visit sending `headers` asks not to be tracked, and that is honored
+    pub fn opted_out(&self, headers: &HeaderMap) -> bool {
+        self.config.honor_do_not_track
+            && ["dnt", "sec-gpc"]
+                .into_iter()
+                .any(|name| headers.get(name).is_some_and(|value| value == "1"))
+    }
+
+    /// Keeps of `click`, made by a visitor from `ip_addr`, only as much of the address as
+    /// configured. Visitors are still told apart within a day when it isn't kept in full.
+    pub async fn anonymize(
+        &self,
+        store: &dyn LinkStore,
+        click: &mut Click,
+        ip_addr: IpAddr,
+    ) -> QrLinkResult<()> {
+        if self.config.ip_addresses == IpAddresses::Full {
+            return Ok(());
+        }
+        let visitor = salted_hash(&self.salt(store).await?, ip_addr);
+        click.ip_addr = match self.config.ip_addresses {
+            IpAddresses::Truncated => truncate(ip_addr).to_string(),
+            _ => visitor.clone(),
+        };
+        click.visitor = visitor;
+        Ok(())
+    }
+
+    /// Today's salt, as kept by `store` so every instance sharing it hashes alike
+    async fn salt(&self, store: &dyn LinkStore) -> QrLinkResult<String> {
+        let today = Utc::now().date_naive();
+        if let Some((day, salt)) = &*self.salt.lock().expect("salt lock poisoned")
+            && *day == today
+        {
+            return Ok(salt.clone());
+        }
+        let fresh = CodeGenerator::new(BASE62, SALT_LENGTH)
+            .expect("base62 is a valid alphabet")
+            .generate();
+        let salt = store.visitor_salt(today, &fresh).await?;
+        *self.salt.lock().expect("salt lock poisoned") = Some((today, salt.clone()));
+        Ok(salt)
+    }
+}
+
+/// The network `ip_addr` is in, with the bits that tell hosts on it apart zeroed
+pub fn truncate(ip_addr: IpAddr) -> IpAddr {
+    match ip_addr {
+        IpAddr::V4(ip) => Ipv4Addr::from(u32::from(ip) & 0xffff_ff00).into(),
+        IpAddr::V6(ip) => Ipv6Addr::from(u128::from(ip) & !((1 << 80) - 1)).into(),
+    }
+}
+
+/// `ip_addr` hashed with `salt`, as long as the hashes visitors are otherwise kept as
+fn salted_hash(salt: &str, ip_addr: IpAddr) -> String {
+    Sha256::digest(format!("{salt}/{ip_addr}"))
+        .iter()
+        .take(16)
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
@@ -0,0 +1,1142 @@
+//! Encoding links as QR codes
+
+use std::fmt::{self, Write};
+use std::io::{Cursor, Write as _};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use ab_glyph::FontArc;
+use base64::prelude::{BASE64_STANDARD, Engine};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageFormat, ImageReader, Rgba, RgbaImage};
+use qrcode::render::svg;
+use qrcode::types::QrError;
+use qrcode::{EcLevel, QrCode, Version};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::error::{Error, QrLinkResult};
+use crate::text;
+
+/// The largest QR code version, 177 modules across
+pub const MAX_VERSION: u8 = 40;
+
+/// The most characters a frame's label may have
+pub const MAX_LABEL_LENGTH: usize = 64;
+
+/// The sizes a frame's label may be drawn at, in pixels
+pub const LABEL_SIZE: std::ops::RangeInclusive<u32> = 6..=200;
+
+/// The largest Micro QR code version, M4
+const MAX_MICRO_VERSION: i16 = 4;
+
+/// Contrast ratio below which scanners are likely to struggle with a code
+pub const MIN_CONTRAST: f64 = 3.0;
+
+/// JPEG quality when a request doesn't ask for one
+pub const DEFAULT_QUALITY: u8 = 90;
+
+/// Printed width of PDF codes, quiet zone included, when a request doesn't ask for one
+pub const DEFAULT_PDF_WIDTH_MM: f64 = 40.0;
+
+/// The printed widths PDF codes may have
+pub const PDF_WIDTH_MM: std::ops::RangeInclusive<f64> = 5.0..=1000.0;
+
+/// PDF points per millimeter
+pub const POINTS_PER_MM: f64 = 72.0 / 25.4;
+
+/// Widest a logo may be, as a share of the code's width without its quiet zone. At a quarter
+/// it covers about 6% of the modules, well within the 30% that error correction level H
+/// recovers.
+pub const LOGO_MAX_FRACTION: f64 = 0.25;
+
+/// Response header explaining why a code as requested may not scan
+pub static WARNING_HEADER: &str = "x-qr-warning";
+
+/// Longest file name a code can be saved under
+pub const MAX_FILE_NAME: usize = 100;
+
+/// What a QR code is drawn as
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Png,
+    Svg,
+    /// Text, two characters per module
+    Ascii,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    /// Lossless WebP
+    Webp,
+    Bmp,
+    /// A single page of vector artwork at a printed size
+    Pdf,
+    /// JSON with a PNG as a `data:` URI, to be put straight into a page
+    DataUri,
+}
+
+impl Format {
+    /// Every format, in the order they are preferred when a client accepts several equally
+    const PREFERENCE: [Format; 8] = [
+        Format::Png,
+        Format::Svg,
+        Format::Pdf,
+        Format::Ascii,
+        Format::Jpeg,
+        Format::Webp,
+        Format::Bmp,
+        Format::DataUri,
+    ];
+
+    /// The format an `Accept` header asks for: the one with the highest quality value, taken
+    /// from the most specific media range naming it. PNG when the header is missing or accepts
+    /// none of them.
+    pub fn negotiate(accept: Option<&str>) -> Format {
+        let ranges: Vec<(&str, f32)> = accept
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let media_type = parts.next().filter(|media_type| !media_type.is_empty())?;
+                let quality = parts
+                    .filter_map(|parameter| parameter.strip_prefix("q="))
+                    .find_map(|quality| quality.parse().ok())
+                    .unwrap_or(1.0);
+                Some((media_type, quality))
+            })
+            .collect();
+        let quality = |format: Format| {
+            let content_type = format.content_type();
+            let (main_type, _) = content_type.split_once('/').unwrap_or_default();
+            let matching = |pattern: &str| {
+                ranges
+                    .iter()
+                    .find(|(media_type, _)| media_type.eq_ignore_ascii_case(pattern))
+                    .map(|(_, quality)| *quality)
+            };
+            matching(content_type)
+                .or_else(|| matching(&format!("{main_type}/*")))
+                .or_else(|| matching("*/*"))
+                .unwrap_or(0.0)
+        };
+        Format::PREFERENCE
+            .into_iter()
+            .map(|format| (format, quality(format)))
+            .filter(|(_, quality)| *quality > 0.0)
+            .fold(
+                None,
+                |best: Option<(Format, f32)>, (format, quality)| match best {
+                    Some((_, best_quality)) if best_quality >= quality => best,
+                    _ => Some((format, quality)),
+                },
+            )
+            .map_or(Format::Png, |(format, _)| format)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Svg => "svg",
+            Format::Ascii => "ascii",
+            Format::Jpeg => "jpeg",
+            Format::Webp => "webp",
+            Format::Bmp => "bmp",
+            Format::Pdf => "pdf",
+            Format::DataUri => "datauri",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Png => "image/png",
+            Format::Svg => "image/svg+xml",
+            Format::Ascii => "text/plain",
+            Format::Jpeg => "image/jpeg",
+            Format::Webp => "image/webp",
+            Format::Bmp => "image/bmp",
+            Format::Pdf => "application/pdf",
+            Format::DataUri => "application/json",
+        }
+    }
+
+    /// The file name extension of a saved code
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Png | Format::DataUri => "png",
+            Format::Svg => "svg",
+            Format::Ascii => "txt",
+            Format::Jpeg => "jpg",
+            Format::Webp => "webp",
+            Format::Bmp => "bmp",
+            Format::Pdf => "pdf",
+        }
+    }
+
+    /// The format of a file saved with `extension`, the other way around from [Format::extension]
+    pub fn from_extension(extension: &str) -> Option<Format> {
+        match extension.to_ascii_lowercase().as_str() {
+            "png" => Some(Format::Png),
+            "svg" => Some(Format::Svg),
+            "txt" => Some(Format::Ascii),
+            "jpg" | "jpeg" => Some(Format::Jpeg),
+            "webp" => Some(Format::Webp),
+            "bmp" => Some(Format::Bmp),
+            "pdf" => Some(Format::Pdf),
+            _ => None,
+        }
+    }
+
+    /// Whether the code is drawn in pixels, so it can have a logo
+    pub fn is_raster(self) -> bool {
+        !matches!(self, Format::Svg | Format::Ascii | Format::Pdf)
+    }
+}
+
+/// The name a file is saved under: `requested` with `extension` added if it lacks it, or
+/// `{stem}.{extension}`. Requested names are kept to characters that need no quoting or
+/// escaping in a header.
+pub fn file_name(requested: Option<&str>, stem: &str, extension: &str) -> QrLinkResult<String> {
+    let Some(requested) = requested else {
+        return Ok(format!("{stem}.{extension}"));
+    };
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_');
+    if requested.is_empty()
+        || requested.len() > MAX_FILE_NAME
+        || requested.starts_with('.')
+        || !requested.chars().all(allowed)
+    {
+        return Err(Error::Validation(format!(
+            "filename must be 1 to {MAX_FILE_NAME} letters, digits, '.', '-' or '_', \
+             not starting with '.'"
+        )));
+    }
+    match requested.strip_suffix(extension) {
+        Some(stem) if stem.ends_with('.') => Ok(requested.to_owned()),
+        _ => Ok(format!("{requested}.{extension}")),
+    }
+}
+
+/// How to draw a code, with a request's choices checked and the defaults filled in
+#[derive(Clone)]
+pub struct Rendering {
+    pub format: Format,
+    /// Smallest width and height in pixels, for the raster formats and SVG
+    pub size: u32,
+    /// JPEG quality from 1 to 100
+    pub quality: u8,
+    /// Printed width and height of PDFs
+    pub width_mm: f64,
+    /// Whether SVGs and PDFs get a blank border
+    pub quiet_zone: bool,
+    pub level: ErrorCorrection,
+    pub version: Option<u8>,
+    /// Whether to draw the smallest code `data` fits in, a Micro QR code if it can be
+    pub compact: bool,
+    pub foreground: Color,
+    pub background: Color,
+    /// How the modules of raster codes and SVGs are drawn
+    pub style: Style,
+    /// Drawn around raster codes and SVGs
+    pub frame: Option<Frame>,
+    /// Drawn in the middle of raster codes
+    pub logo: Option<Arc<RgbaImage>>,
+}
+
+impl Rendering {
+    /// Tells apart the codes drawn for `data`: it holds everything the drawing depends on
+    pub fn cache_key(&self, data: &str) -> String {
+        format!(
+            "{} {} {} {} {} {:?} {:?} {} {} {} {:?} {:?} {} {data}",
+            self.format.as_str(),
+            self.size,
+            self.quality,
+            self.width_mm,
+            self.quiet_zone,
+            self.level,
+            self.version,
+            self.compact,
+            self.foreground,
+            self.background,
+            self.style,
+            self.frame.as_ref().map(|frame| (
+                frame.color.to_string(),
+                &frame.label,
+                frame.label_size
+            )),
+            self.logo.is_some(),
+        )
+    }
+
+    /// `data` encoded at [Rendering::level], in the version asked for or the smallest it fits
+    pub fn encode(&self, data: &str) -> QrLinkResult<QrCode> {
+        if self.compact {
+            encode_compact(data, self.level)
+        } else {
+            encode(data, self.level, self.version)
+        }
+    }
+
+    /// `data` drawn as a code, encoded as [Rendering::format]
+    pub fn render(&self, data: &str) -> QrLinkResult<Vec<u8>> {
+        let code = self.encode(data)?;
+        let body = match self.format {
+            Format::Ascii => code
+                .render::<char>()
+                .quiet_zone(false)
+                .module_dimensions(2, 1)
+                .build()
+                .into_bytes(),
+            Format::Svg => {
+                let svg = match self.style {
+                    Style::Square => {
+                        let (dark, light) =
+                            (self.foreground.to_string(), self.background.to_string());
+                        code.render::<svg::Color>()
+                            .min_dimensions(self.size, self.size)
+                            .quiet_zone(self.quiet_zone)
+                            .dark_color(svg::Color(&dark))
+                            .light_color(svg::Color(&light))
+                            .build()
+                    }
+                    style => render_styled_svg(
+                        &code,
+                        self.size,
+                        self.quiet_zone,
+                        style,
+                        self.foreground,
+                        self.background,
+                    ),
+                };
+                match &self.frame {
+                    Some(frame) => {
+                        let side = svg_side(&code, self.size, self.quiet_zone);
+                        frame.wrap_svg(&svg, side).into_bytes()
+                    }
+                    None => svg.into_bytes(),
+                }
+            }
+            Format::Pdf => render_pdf(
+                &code,
+                self.width_mm,
+                self.quiet_zone,
+                self.foreground,
+                self.background,
+            ),
+            raster => {
+                let mut image = match self.style {
+                    Style::Square => code
+                        .render::<Rgba<u8>>()
+                        .min_dimensions(self.size, self.size)
+                        .dark_color(self.foreground.rgba())
+                        .light_color(self.background.rgba())
+                        .build(),
+                    style => render_styled_raster(
+                        &code,
+                        self.size,
+                        style,
+                        self.foreground,
+                        self.background,
+                    ),
+                };
+                if let Some(logo) = &self.logo {
+                    overlay_logo(&mut image, &code, logo, self.background);
+                }
+                if let Some(frame) = &self.frame {
+                    image = frame.wrap_raster(&image);
+                }
+                let (width, height) = image.dimensions();
+                let encoded = encode_raster(image, raster, self.quality)?;
+                if raster == Format::DataUri {
+                    let data_uri = data_uri(&encoded);
+                    serde_json::to_vec(&DataUri {
+                        data_uri,
+                        width,
+                        height,
+                    })
+                    .expect("a struct of strings and numbers serializes")
+                } else {
+                    encoded
+                }
+            }
+        };
+        Ok(body)
+    }
+
+    /// Why the colors may keep the code from scanning; text has no colors to warn about
+    pub fn warning(&self) -> Option<String> {
+        match self.format {
+            Format::Ascii => None,
+            _ => color_warning(self.foreground, self.background),
+        }
+    }
+}
+
+/// A PNG code inlined as a `data:` URI, as returned for `format=datauri`
+#[derive(Serialize, ToSchema)]
+#[schema(as = QrDataUri)]
+pub struct DataUri {
+    /// `data:image/png;base64,...`, usable as the `src` of an `<img>`
+    data_uri: String,
+    /// In pixels
+    width: u32,
+    height: u32,
+}
+
+/// Largest width or height of an image read for QR codes, which keeps a small upload from
+/// decompressing into more pixels than the server should scan
+const MAX_DECODE_SIDE: u32 = 8000;
+
+/// A QR code read from an image
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = DecodedQr)]
+pub struct Decoded {
+    /// The text the code holds
+    pub data: String,
+    /// The error correction level it was drawn with
+    pub ec: ErrorCorrection,
+    /// From 1 to 40
+    pub version: u8,
+}
+
+/// Every QR code that can be read in `image`, a PNG, JPEG, WebP, GIF or BMP among others, in
+/// the order they were found
+pub fn decode(image: &[u8]) -> QrLinkResult<Vec<Decoded>> {
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(MAX_DECODE_SIDE);
+    limits.max_image_height = Some(MAX_DECODE_SIDE);
+    let mut reader = ImageReader::new(Cursor::new(image))
+        .with_guessed_format()
+        .map_err(Error::Io)?;
+    reader.limits(limits);
+    let image = reader.decode().map_err(|error| {
+        Error::Validation(format!("the upload can't be read as an image: {error}"))
+    })?;
+
+    let mut prepared = rqrr::PreparedImage::prepare(image.to_luma8());
+    let codes: Vec<_> = prepared
+        .detect_grids()
+        .into_iter()
+        .filter_map(|grid| grid.decode().ok())
+        .map(|(meta, data)| Decoded {
+            data,
+            // The two format bits, which don't run in order of strength
+            ec: match meta.ecc_level {
+                0 => ErrorCorrection::M,
+                1 => ErrorCorrection::L,
+                2 => ErrorCorrection::H,
+                _ => ErrorCorrection::Q,
+            },
+            version: meta.version.0 as u8,
+        })
+        .collect();
+    if codes.is_empty() {
+        return Err(Error::Unreadable);
+    }
+    Ok(codes)
+}
+
+/// A ZIP archive of codes drawn as `rendering` says, one for each `(file name, data)` pair.
+/// Formats that aren't compressed already are deflated.
+pub fn archive(rendering: &Rendering, codes: &[(String, String)]) -> QrLinkResult<Vec<u8>> {
+    let method = match rendering.format {
+        Format::Png | Format::Jpeg | Format::Webp => CompressionMethod::Stored,
+        _ => CompressionMethod::Deflated,
+    };
+    let options = SimpleFileOptions::default().compression_method(method);
+    let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, data) in codes {
+        let body = rendering.render(data)?;
+        archive
+            .start_file(name.as_str(), options)
+            .map_err(Error::Archive)?;
+        archive.write_all(&body).map_err(Error::Io)?;
+    }
+    let archive = archive.finish().map_err(Error::Archive)?;
+    Ok(archive.into_inner())
+}
+
+/// Encodes a rendered code as `format`, which must be a raster format. `quality`, from 1 to
+/// 100, only matters to JPEG. JPEG and BMP drop the alpha channel, which the label printers
+/// that want them tend not to handle.
+pub fn encode_raster(image: RgbaImage, format: Format, quality: u8) -> QrLinkResult<Vec<u8>> {
+    let mut buffer = Cursor::new(Vec::new());
+    match format {
+        Format::Jpeg => JpegEncoder::new_with_quality(&mut buffer, quality)
+            .encode_image(&DynamicImage::ImageRgba8(image).into_rgb8()),
+        Format::Bmp => DynamicImage::ImageRgba8(image)
+            .into_rgb8()
+            .write_to(&mut buffer, ImageFormat::Bmp),
+        Format::Webp => image.write_to(&mut buffer, ImageFormat::WebP),
+        Format::Png | Format::Svg | Format::Ascii | Format::Pdf | Format::DataUri => {
+            image.write_to(&mut buffer, ImageFormat::Png)
+        }
+    }
+    .map_err(Error::Image)?;
+    Ok(buffer.into_inner())
+}
+
+/// A PNG as a `data:` URI, which an `<img src>` shows without fetching anything
+pub fn data_uri(png: &[u8]) -> String {
+    format!("data:image/png;base64,{}", BASE64_STANDARD.encode(png))
+}
+
+/// How much of a QR code can be covered or damaged while it still scans. Higher levels make
+/// room for a logo or wear on print, at the cost of a denser code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub enum ErrorCorrection {
+    /// About 7%
+    #[serde(alias = "l")]
+    L,
+    /// About 15%
+    #[default]
+    #[serde(alias = "m")]
+    M,
+    /// About 25%
+    #[serde(alias = "q")]
+    Q,
+    /// About 30%
+    #[serde(alias = "h")]
+    H,
+}
+
+impl From<ErrorCorrection> for EcLevel {
+    fn from(level: ErrorCorrection) -> Self {
+        match level {
+            ErrorCorrection::L => EcLevel::L,
+            ErrorCorrection::M => EcLevel::M,
+            ErrorCorrection::Q => EcLevel::Q,
+            ErrorCorrection::H => EcLevel::H,
+        }
+    }
+}
+
+/// How the dark modules of a code are drawn. The finder patterns in its corners, which scanners
+/// locate the code by, stay square whatever the style.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Style {
+    #[default]
+    Square,
+    /// Squares with rounded corners
+    Rounded,
+    /// Circles a little smaller than a module, so neighbors stay apart
+    Dots,
+}
+
+impl Style {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Style::Square => "square",
+            Style::Rounded => "rounded",
+            Style::Dots => "dots",
+        }
+    }
+}
+
+/// Radius of the corners of `rounded` modules and of `dots`, in modules
+const ROUNDED_RADIUS: f64 = 0.3;
+const DOT_RADIUS: f64 = 0.45;
+
+/// Whether the module at `x`, `y` belongs to one of the finder patterns of `code`: the 7×7
+/// squares in three of its corners, or in the top left one of Micro QR codes
+fn in_finder_pattern(code: &QrCode, x: usize, y: usize) -> bool {
+    let width = code.width();
+    let near = |position: usize| position < 7;
+    let far = |position: usize| position + 7 >= width;
+    (near(x) && near(y))
+        || (!code.version().is_micro() && ((far(x) && near(y)) || (near(x) && far(y))))
+}
+
+/// The width of the blank border scanners expect around `code`, in modules
+fn quiet_zone_of(code: &QrCode) -> u32 {
+    if code.version().is_micro() { 2 } else { 4 }
+}
+
+/// The width and height of `code` drawn as an SVG at least `size` wide, as the renderers size it:
+/// in whole pixels a module
+fn svg_side(code: &QrCode, size: u32, quiet_zone: bool) -> u32 {
+    let border = if quiet_zone { quiet_zone_of(code) } else { 0 };
+    let modules = code.width() as u32 + 2 * border;
+    size.div_ceil(modules).max(1) * modules
+}
+
+/// A colored frame around a code, thicker at the bottom to hold a label, such as "SCAN ME",
+/// centered in black or white, whichever stands out more
+#[derive(Clone)]
+pub struct Frame {
+    pub color: Color,
+    pub label: Option<String>,
+    /// Height of the label's letters, in pixels, shrunk for labels wider than the frame
+    pub label_size: u32,
+    /// The label's font, the one cards are drawn in
+    pub font: FontArc,
+}
+
+impl Frame {
+    /// Width of the frame's sides and top around a code `side` wide, and its height below it
+    fn borders(&self, side: u32) -> (u32, u32) {
+        let border = (side / 24).max(4);
+        let bottom = match self.label {
+            Some(_) => border + self.label_size * 8 / 5,
+            None => border,
+        };
+        (border, bottom)
+    }
+
+    fn label_color(&self) -> Color {
+        if self.color.contrast(Color::BLACK) >= self.color.contrast(Color::WHITE) {
+            Color::BLACK
+        } else {
+            Color::WHITE
+        }
+    }
+
+    /// The label's size and width, smaller than asked for if it wouldn't fit in `width`
+    fn label_layout(&self, label: &str, width: u32) -> (f32, f32) {
+        let room = width as f32 * 0.9;
+        let size = self.label_size as f32;
+        let natural = text::width(&self.font, label, size);
+        if natural <= room {
+            (size, natural)
+        } else {
+            (size * room / natural, room)
+        }
+    }
+
+    /// Where the label's baseline is, for it to sit in the middle of the bottom of the frame
+    /// around a code `side` wide
+    fn label_baseline(&self, side: u32, size: f32) -> f32 {
+        let (border, bottom) = self.borders(side);
+        // Capitals rise about 0.7 of the size above the baseline
+        (border + side) as f32 + (bottom as f32 + 0.7 * size) / 2.0
+    }
+
+    fn wrap_raster(&self, code: &RgbaImage) -> RgbaImage {
+        let side = code.width();
+        let (border, bottom) = self.borders(side);
+        let width = side + 2 * border;
+        let mut image = RgbaImage::from_pixel(width, side + border + bottom, self.color.rgba());
+        imageops::overlay(&mut image, code, i64::from(border), i64::from(border));
+        if let Some(label) = &self.label {
+            let (size, label_width) = self.label_layout(label, width);
+            let origin = (
+                (width as f32 - label_width) / 2.0,
+                self.label_baseline(side, size),
+            );
+            text::draw(
+                &mut image,
+                &self.font,
+                label,
+                size,
+                origin,
+                self.label_color(),
+            );
+        }
+        image
+    }
+
+    /// `svg`, a code `side` wide, in the frame. The label is set in the reader's bold sans-serif
+    /// font, squeezed into the width it takes in ours if it would be wider.
+    fn wrap_svg(&self, svg: &str, side: u32) -> String {
+        let (border, bottom) = self.borders(side);
+        let width = side + 2 * border;
+        let height = side + border + bottom;
+        let code = svg.split_once("?>").map_or(svg, |(_, code)| code);
+        let label = self.label.as_ref().map(|label| {
+            let (size, label_width) = self.label_layout(label, width);
+            format!(
+                "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" \
+                 font-family=\"DejaVu Sans, Verdana, sans-serif\" font-weight=\"bold\" \
+                 font-size=\"{size}\" textLength=\"{label_width}\" \
+                 lengthAdjust=\"spacingAndGlyphs\" fill=\"{}\">{}</text>",
+                f64::from(width) / 2.0,
+                self.label_baseline(side, size),
+                self.label_color(),
+                escape_xml(label),
+            )
+        });
+        format!(
+            "<?xml version=\"1.0\" standalone=\"yes\"?><svg \
+             xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" width=\"{width}\" \
+             height=\"{height}\" viewBox=\"0 0 {width} {height}\">\
+             <rect x=\"0\" y=\"0\" width=\"{width}\" height=\"{height}\" fill=\"{}\"/>\
+             <g transform=\"translate({border} {border})\">{code}</g>{}</svg>",
+            self.color,
+            label.unwrap_or_default(),
+        )
+    }
+}
+
+/// `text` with the characters that mean something in XML escaped
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Draws `code` as an SVG at least `size` pixels wide, like the stock renderer, but with the
+/// modules outside the finder patterns in `style`
+fn render_styled_svg(
+    code: &QrCode,
+    size: u32,
+    quiet_zone: bool,
+    style: Style,
+    foreground: Color,
+    background: Color,
+) -> String {
+    let border = if quiet_zone { quiet_zone_of(code) } else { 0 };
+    let modules = code.width() as u32 + 2 * border;
+    let unit = size.div_ceil(modules).max(1);
+    let side = modules * unit;
+    let unit = f64::from(unit);
+    let mut path = String::new();
+    for (index, color) in code.to_colors().into_iter().enumerate() {
+        if color == qrcode::Color::Light {
+            continue;
+        }
+        let (x, y) = (index % code.width(), index / code.width());
+        let left = f64::from(x as u32 + border) * unit;
+        let top = f64::from(y as u32 + border) * unit;
+        // Each module a closed shape of its own, starting at its top left corner or left edge
+        let _ = if style == Style::Square || in_finder_pattern(code, x, y) {
+            write!(path, "M{left} {top}h{unit}v{unit}h-{unit}z")
+        } else if style == Style::Dots {
+            let radius = DOT_RADIUS * unit;
+            write!(
+                path,
+                "M{} {}a{radius} {radius} 0 1 0 {} 0a{radius} {radius} 0 1 0 -{} 0z",
+                left + unit / 2.0 - radius,
+                top + unit / 2.0,
+                2.0 * radius,
+                2.0 * radius,
+            )
+        } else {
+            let radius = ROUNDED_RADIUS * unit;
+            let straight = unit - 2.0 * radius;
+            write!(
+                path,
+                "M{} {top}h{straight}a{radius} {radius} 0 0 1 {radius} {radius}\
+                 v{straight}a{radius} {radius} 0 0 1 -{radius} {radius}\
+                 h-{straight}a{radius} {radius} 0 0 1 -{radius} -{radius}\
+                 v-{straight}a{radius} {radius} 0 0 1 {radius} -{radius}z",
+                left + radius,
+            )
+        };
+    }
+    format!(
+        "<?xml version=\"1.0\" standalone=\"yes\"?><svg xmlns=\"http://www.w3.org/2000/svg\" \
+         version=\"1.1\" width=\"{side}\" height=\"{side}\" viewBox=\"0 0 {side} {side}\">\
+         <rect x=\"0\" y=\"0\" width=\"{side}\" height=\"{side}\" fill=\"{background}\"/>\
+         <path fill=\"{foreground}\" d=\"{path}\"/></svg>"
+    )
+}
+
+/// Draws `code` with its quiet zone at least `size` pixels wide, like the stock renderer, but
+/// with the modules outside the finder patterns in `style`, their edges smoothed
+fn render_styled_raster(
+    code: &QrCode,
+    size: u32,
+    style: Style,
+    foreground: Color,
+    background: Color,
+) -> RgbaImage {
+    let border = quiet_zone_of(code);
+    let modules = code.width() as u32 + 2 * border;
+    let unit = size.div_ceil(modules).max(1);
+    let mut image = RgbaImage::from_pixel(modules * unit, modules * unit, background.rgba());
+    let half = f64::from(unit) / 2.0;
+    for (index, color) in code.to_colors().into_iter().enumerate() {
+        if color == qrcode::Color::Light {
+            continue;
+        }
+        let (x, y) = (index % code.width(), index / code.width());
+        let left = (x as u32 + border) * unit;
+        let top = (y as u32 + border) * unit;
+        let square = in_finder_pattern(code, x, y) || style == Style::Square;
+        for dy in 0..unit {
+            for dx in 0..unit {
+                // Signed distance from the pixel's center to the edge of the shape, negative
+                // inside it
+                let px = (f64::from(dx) + 0.5 - half).abs();
+                let py = (f64::from(dy) + 0.5 - half).abs();
+                let distance = if square {
+                    f64::NEG_INFINITY
+                } else if style == Style::Dots {
+                    px.hypot(py) - DOT_RADIUS * f64::from(unit)
+                } else {
+                    let radius = ROUNDED_RADIUS * f64::from(unit);
+                    let (qx, qy) = (px - (half - radius), py - (half - radius));
+                    qx.max(0.0).hypot(qy.max(0.0)) + qx.max(qy).min(0.0) - radius
+                };
+                let coverage = (0.5 - distance).clamp(0.0, 1.0) as f32;
+                blend(
+                    image.get_pixel_mut(left + dx, top + dy),
+                    foreground,
+                    coverage,
+                );
+            }
+        }
+    }
+    image
+}
+
+/// Paints `color` over `pixel`, `coverage` of the way from 0 to 1
+pub fn blend(pixel: &mut Rgba<u8>, color: Color, coverage: f32) {
+    let [red, green, blue, _] = color.rgba().0;
+    for (channel, target) in pixel.0.iter_mut().zip([red, green, blue]) {
+        let mixed = f32::from(*channel) * (1.0 - coverage) + f32::from(target) * coverage;
+        *channel = mixed.round() as u8;
+    }
+}
+
+/// Encodes `data` at `level`, in the smallest version it fits in unless `version` asks for one
+pub fn encode(data: &str, level: ErrorCorrection, version: Option<u8>) -> QrLinkResult<QrCode> {
+    let Some(version) = version else {
+        return QrCode::with_error_correction_level(data, level.into())
+            .map_err(Error::QrGeneration);
+    };
+    if !(1..=MAX_VERSION).contains(&version) {
+        return Err(Error::Validation(format!(
+            "version must be between 1 and {MAX_VERSION}"
+        )));
+    }
+    QrCode::with_version(data, Version::Normal(version.into()), level.into()).map_err(|error| {
+        match error {
+            QrError::DataTooLong => Error::Validation(format!(
+                "{} bytes don't fit in a version {version} QR code at error correction {level:?}; \
+                 ask for a larger version or a lower level",
+                data.len()
+            )),
+            error => Error::QrGeneration(error),
+        }
+    })
+}
+
+/// Encodes `data` at `level` in the smallest Micro QR code it fits in, which has a single finder
+/// pattern and half the quiet zone, or else in the smallest QR code. Micro QR codes have no
+/// level `H`, and hold at most 35 digits or 21 letters, so short links mostly take the latter.
+pub fn encode_compact(data: &str, level: ErrorCorrection) -> QrLinkResult<QrCode> {
+    let micro = (1..=MAX_MICRO_VERSION)
+        .find_map(|version| QrCode::with_version(data, Version::Micro(version), level.into()).ok());
+    match micro {
+        Some(code) => Ok(code),
+        None => encode(data, level, None),
+    }
+}
+
+/// `url` with its scheme and host, which are case-insensitive, in capitals. Encoders fit runs
+/// of digits, capitals and ` $%*+-./:` in the alphanumeric mode, in 5.5 bits a character
+/// rather than 8, so a link on a host in capitals with a code in capitals and digits takes
+/// the fewest modules there are. Everything else is kept as it is: codes and slugs in the path
+/// tell capitals and small letters apart, as query strings, fragments and passwords may.
+pub fn compact_url(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_owned();
+    };
+    let (authority, rest) = rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()));
+    let (user_info, host) = authority.split_at(authority.rfind('@').map_or(0, |at| at + 1));
+    format!(
+        "{}://{user_info}{}{rest}",
+        scheme.to_ascii_uppercase(),
+        host.to_ascii_uppercase()
+    )
+}
+
+/// An opaque color, parsed from `RRGGBB` or `RGB` hex digits with or without a leading `#`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(255, 255, 255);
+
+    pub const fn rgb(red: u8, green: u8, blue: u8) -> Self {
+        Color { red, green, blue }
+    }
+
+    pub fn rgba(self) -> Rgba<u8> {
+        Rgba([self.red, self.green, self.blue, u8::MAX])
+    }
+
+    /// Relative luminance as WCAG defines it, from 0 for black to 1 for white
+    fn luminance(self) -> f64 {
+        let linear = |channel: u8| {
+            let value = f64::from(channel) / 255.0;
+            if value <= 0.04045 {
+                value / 12.92
+            } else {
+                ((value + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        0.2126 * linear(self.red) + 0.7152 * linear(self.green) + 0.0722 * linear(self.blue)
+    }
+
+    /// The WCAG contrast ratio between two colors, from 1 for equal colors to 21 for black on
+    /// white
+    pub fn contrast(self, other: Color) -> f64 {
+        let (lighter, darker) = if self.luminance() > other.luminance() {
+            (self.luminance(), other.luminance())
+        } else {
+            (other.luminance(), self.luminance())
+        };
+        (lighter + 0.05) / (darker + 0.05)
+    }
+}
+
+/// Why a code drawn `foreground` on `background` may not scan, if it may not
+pub fn color_warning(foreground: Color, background: Color) -> Option<String> {
+    let contrast = foreground.contrast(background);
+    if contrast < MIN_CONTRAST {
+        Some(format!(
+            "contrast ratio {contrast:.1}:1 is below {MIN_CONTRAST}:1, the code may not scan"
+        ))
+    } else if foreground.luminance() > background.luminance() {
+        Some("foreground is lighter than background, which many scanners can't read".into())
+    } else {
+        None
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+impl FromStr for Color {
+    type Err = Error;
+
+    fn from_str(value: &str) -> QrLinkResult<Self> {
+        let invalid = || Error::Validation(format!("color '{value}' isn't RRGGBB or RGB hex"));
+        let digits = value.strip_prefix('#').unwrap_or(value);
+        if !digits.chars().all(|digit| digit.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let channel = |hex: &str| u8::from_str_radix(hex, 16).map_err(|_| invalid());
+        match digits.len() {
+            6 => Ok(Color::rgb(
+                channel(&digits[0..2])?,
+                channel(&digits[2..4])?,
+                channel(&digits[4..6])?,
+            )),
+            3 => {
+                let double = |index: usize| channel(&digits[index..=index].repeat(2));
+                Ok(Color::rgb(double(0)?, double(1)?, double(2)?))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = Error;
+
+    fn try_from(value: String) -> QrLinkResult<Self> {
+        value.parse()
+    }
+}
+
+/// Reads the logo image at `path`
+pub fn load_logo(path: &Path) -> QrLinkResult<RgbaImage> {
+    image::open(path)
+        .map(|logo| logo.to_rgba8())
+        .map_err(|error| Error::Config(format!("qr.logo {}: {error}", path.display())))
+}
+
+/// Draws `logo` in the middle of `image`, a rendering of `code` with its quiet zone, on a patch
+/// of `background` one module wider than the logo on each side
+pub fn overlay_logo(image: &mut RgbaImage, code: &QrCode, logo: &RgbaImage, background: Color) {
+    let modules = code.width() as u32;
+    let module_size = image.width() / (modules + 2 * quiet_zone_of(code));
+    let max_side = (f64::from(modules) * LOGO_MAX_FRACTION) as u32 * module_size;
+    if max_side == 0 {
+        return;
+    }
+    let scale = f64::from(max_side) / f64::from(logo.width().max(logo.height()));
+    let width = ((f64::from(logo.width()) * scale) as u32).max(1);
+    let height = ((f64::from(logo.height()) * scale) as u32).max(1);
+    let logo = imageops::resize(logo, width, height, FilterType::Lanczos3);
+
+    let patch = RgbaImage::from_pixel(
+        width + 2 * module_size,
+        height + 2 * module_size,
+        background.rgba(),
+    );
+    let centered = |side: u32, total: u32| i64::from(total.saturating_sub(side) / 2);
+    imageops::overlay(
+        image,
+        &patch,
+        centered(patch.width(), image.width()),
+        centered(patch.height(), image.height()),
+    );
+    imageops::overlay(
+        image,
+        &logo,
+        centered(width, image.width()),
+        centered(height, image.height()),
+    );
+}
+
+/// Draws `code` as a one-page PDF exactly `width_mm` wide and high, quiet zone included, with
+/// each run of dark modules as a filled rectangle so it prints sharp at any size
+pub fn render_pdf(
+    code: &QrCode,
+    width_mm: f64,
+    quiet_zone: bool,
+    foreground: Color,
+    background: Color,
+) -> Vec<u8> {
+    let points = width_mm * POINTS_PER_MM;
+    let mut content = String::new();
+    pdf_code(
+        &mut content,
+        code,
+        (0.0, 0.0),
+        points,
+        quiet_zone,
+        (foreground, background),
+    );
+    pdf_document(&[PdfPage {
+        width: points,
+        height: points,
+        content,
+    }])
+}
+
+/// A page of a PDF, in points, and what is drawn on it
+pub(crate) struct PdfPage {
+    pub width: f64,
+    pub height: f64,
+    pub content: String,
+}
+
+/// Name of the font text is set in on [PdfPage]s, one every PDF reader has
+pub(crate) const PDF_FONT: &str = "/F1";
+
+/// Adds drawing `code` to `content` as a square `side` points wide, quiet zone included, with
+/// its bottom left corner at `origin`
+pub(crate) fn pdf_code(
+    content: &mut String,
+    code: &QrCode,
+    origin: (f64, f64),
+    side: f64,
+    quiet_zone: bool,
+    (foreground, background): (Color, Color),
+) {
+    let modules = code.width();
+    let margin = if quiet_zone {
+        quiet_zone_of(code) as usize
+    } else {
+        0
+    };
+    let total = modules + 2 * margin;
+
+    // Drawn in module units, with the origin at the bottom left as PDF has it
+    let scale = side / total as f64;
+    let (x0, y0) = origin;
+    let _ = writeln!(content, "q {scale:.6} 0 0 {scale:.6} {x0:.4} {y0:.4} cm");
+    let _ = writeln!(
+        content,
+        "{} rg 0 0 {total} {total} re f",
+        pdf_color(background)
+    );
+    let _ = writeln!(content, "{} rg", pdf_color(foreground));
+    let dark: Vec<bool> = code
+        .to_colors()
+        .into_iter()
+        .map(|module| module == qrcode::Color::Dark)
+        .collect();
+    for y in 0..modules {
+        let row = &dark[y * modules..(y + 1) * modules];
+        let mut x = 0;
+        while x < modules {
+            if !row[x] {
+                x += 1;
+                continue;
+            }
+            let start = x;
+            while x < modules && row[x] {
+                x += 1;
+            }
+            let _ = writeln!(
+                content,
+                "{} {} {} 1 re",
+                start + margin,
+                total - margin - y - 1,
+                x - start
+            );
+        }
+    }
+    content.push_str("f Q\n");
+}
+
+/// `pages` as a PDF file, with Helvetica as [PDF_FONT] for any text on them
+pub(crate) fn pdf_document(pages: &[PdfPage]) -> Vec<u8> {
+    // The catalog, the page tree and the font come first, then each page and its contents
+    let first_page = 4;
+    let kids: Vec<String> = (0..pages.len())
+        .map(|index| format!("{} 0 R", first_page + 2 * index))
+        .collect();
+    let mut objects = vec![
+        "<< /Type /Catalog /Pages 2 0 R >>".to_owned(),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            kids.join(" "),
+            pages.len()
+        ),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>"
+            .to_owned(),
+    ];
+    for (index, page) in pages.iter().enumerate() {
+        let contents = first_page + 2 * index + 1;
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.4} {:.4}] \
+             /Contents {contents} 0 R /Resources << /Font << {PDF_FONT} 3 0 R >> >> >>",
+            page.width, page.height
+        ));
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{}endstream",
+            page.content.len(),
+            page.content
+        ));
+    }
+    let mut pdf = String::from("%PDF-1.4\n");
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (number, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        let _ = write!(pdf, "{} 0 obj\n{object}\nendobj\n", number + 1);
+    }
+    let xref = pdf.len();
+    let _ = write!(pdf, "xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+    for offset in offsets {
+        let _ = writeln!(pdf, "{offset:010} 00000 n ");
+    }
+    let _ = write!(
+        pdf,
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+        objects.len() + 1
+    );
+    pdf.into_bytes()
+}
+
+/// A color as the operands of a PDF `rg` operator
+fn pdf_color(color: Color) -> String {
+    let channel = |value: u8| f64::from(value) / 255.0;
+    format!(
+        "{:.3} {:.3} {:.3}",
+        channel(color.red),
+        channel(color.green),
+        channel(color.blue)
+    )
+}
@@ -0,0 +1,126 @@
+//! Per-user quotas, which keep a public instance from being filled up by a few of its users:
+//! how many links a user may have at once, and how many they may create in a day (UTC).
+//! Admins, the admin token and keys issued to no user aren't held to them.
+//!
+//! Quotas are checked before links are created, so requests racing each other can take a user
+//! a few links over.
+
+use axum::extract::State;
+use chrono::{DateTime, Days, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::AppState;
+use crate::auth::{Authorized, Caller, Read};
+use crate::error::{Error, ErrorBody, QrLinkResult};
+use crate::store::{LinkUsage, Role};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    /// Most links a user may have, not counting deleted ones
+    pub active_links: Option<u64>,
+    /// Most links a user may create per day, counting those deleted since
+    pub links_per_day: Option<u64>,
+}
+
+/// Whether `caller` is held to the quotas
+fn limited(caller: &Caller) -> bool {
+    caller.role() < Role::Admin && caller.owner_id().is_some()
+}
+
+/// The start of today, and of tomorrow, when the daily count starts over
+fn today() -> (DateTime<Utc>, DateTime<Utc>) {
+    let start = Utc::now()
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
+        .expect("midnight is a valid time")
+        .and_utc();
+    let end = start
+        .checked_add_days(Days::new(1))
+        .expect("tomorrow is a valid date");
+    (start, end)
+}
+
+/// Refuses `caller` `count` more links, should that take them over a quota
+pub(crate) async fn check(app_state: &AppState, caller: &Caller, count: u64) -> QrLinkResult<()> {
+    let quotas = &app_state.config.quotas;
+    if !limited(caller) || (quotas.active_links.is_none() && quotas.links_per_day.is_none()) {
+        return Ok(());
+    }
+    let usage = app_state
+        .store
+        .link_usage(caller.scope(), today().0)
+        .await?;
+    if let Some(limit) = quotas.active_links
+        && usage.active + count > limit
+    {
+        return Err(Error::QuotaExceeded(format!(
+            "at most {limit} links can be kept at once; delete some to make room"
+        )));
+    }
+    if let Some(limit) = quotas.links_per_day
+        && usage.created_since + count > limit
+    {
+        return Err(Error::QuotaExceeded(format!(
+            "at most {limit} links can be created per day"
+        )));
+    }
+    Ok(())
+}
+
+/// How much of a quota is used up
+#[derive(Serialize, ToSchema)]
+pub struct Consumption {
+    pub used: u64,
+    /// Absent if there is no limit, or the caller isn't held to it
+    pub limit: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Usage {
+    /// Links not deleted
+    pub active_links: Consumption,
+    /// Links created today (UTC), deleted ones included
+    pub links_today: Consumption,
+    /// When the count of links created today starts over
+    pub resets_at: DateTime<Utc>,
+}
+
+/// GET /api/v1/me/usage reports how much of their quotas the caller has used
+#[utoipa::path(
+    get,
+    path = "/api/v1/me/usage",
+    summary = "Report quota usage",
+    description = "The caller's links against their quotas, which are absent for admins and \
+                   keys issued to no user",
+    responses(
+        (status = 200, body = Usage),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "keys"
+)]
+pub async fn get_usage(
+    State(app_state): State<AppState>,
+    caller: Authorized<Read>,
+) -> QrLinkResult<axum::Json<Usage>> {
+    let (start, resets_at) = today();
+    let LinkUsage {
+        active,
+        created_since,
+    } = app_state.store.link_usage(caller.scope(), start).await?;
+    let quotas = &app_state.config.quotas;
+    let limit = |limit: Option<u64>| limit.filter(|_| limited(&caller));
+    Ok(axum::Json(Usage {
+        active_links: Consumption {
+            used: active,
+            limit: limit(quotas.active_links),
+        },
+        links_today: Consumption {
+            used: created_since,
+            limit: limit(quotas.links_per_day),
+        },
+        resets_at,
+    }))
+}
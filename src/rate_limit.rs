@@ -0,0 +1,164 @@
+//! Token-bucket rate limits, kept per caller for authenticated requests and per client address
+//! otherwise
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::Caller;
+use crate::client_ip::ClientIp;
+use crate::error::{Error, QrLinkResult};
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+
+/// Buckets tracked before full ones are dropped, which bounds memory under many clients
+const PRUNE_ABOVE: usize = 10_000;
+
+/// Up to `requests` requests at once, refilled evenly over `period`
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Quota {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    pub const fn per_minute(requests: u32) -> Self {
+        Quota {
+            requests,
+            period: Duration::from_secs(60),
+        }
+    }
+
+    fn refill_per_second(&self) -> f64 {
+        f64::from(self.requests) / self.period.as_secs_f64()
+    }
+}
+
+/// Parses `<requests>/<seconds>`, e.g. `30/60`
+impl FromStr for Quota {
+    type Err = Error;
+
+    fn from_str(value: &str) -> QrLinkResult<Self> {
+        let invalid = || Error::Config(format!("rate limit '{value}' isn't <requests>/<seconds>"));
+        let (requests, seconds) = value.split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let seconds: u64 = seconds.trim().parse().map_err(|_| invalid())?;
+        if requests == 0 || seconds == 0 {
+            return Err(invalid());
+        }
+        Ok(Quota {
+            requests,
+            period: Duration::from_secs(seconds),
+        })
+    }
+}
+
+impl TryFrom<String> for Quota {
+    type Error = Error;
+
+    fn try_from(value: String) -> QrLinkResult<Self> {
+        value.parse()
+    }
+}
+
+/// The quotas for the endpoints worth limiting separately
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub create: Quota,
+    pub qr: Quota,
+    pub redirect: Quota,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            create: Quota::per_minute(20),
+            qr: Quota::per_minute(60),
+            redirect: Quota::per_minute(300),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The buckets of every client for one quota
+#[derive(Clone)]
+pub struct RateLimiter {
+    quota: Quota,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(quota: Quota) -> Self {
+        RateLimiter {
+            quota,
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Takes a token from `client`'s bucket, or says how long until there is one
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let capacity = f64::from(self.quota.requests);
+        let refill = self.quota.refill_per_second();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if buckets.len() > PRUNE_ABOVE {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * refill < capacity
+            });
+        }
+
+        let bucket = buckets.entry(client.to_owned()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / refill))
+        }
+    }
+}
+
+/// Who a request counts against: the caller [require_api_key](crate::auth::require_api_key)
+/// found for it, and otherwise its client address. A bearer token alone doesn't count, as
+/// anyone could send a new one with each request to get a bucket of its own.
+fn client(request: &Request, ip_addr: IpAddr) -> String {
+    match request.extensions().get::<Caller>() {
+        Some(Caller::Admin) => "admin".to_owned(),
+        Some(Caller::Key(key)) => format!("key:{}", key.id),
+        Some(Caller::User(user)) => format!("user:{}", user.id),
+        None => format!("ip:{ip_addr}"),
+    }
+}
+
+/// Middleware answering 429 once a client has used up `limiter`'s quota
+pub async fn enforce(
+    State(limiter): State<RateLimiter>,
+    ClientIp(ip_addr): ClientIp,
+    request: Request,
+    next: Next,
+) -> QrLinkResult<Response> {
+    limiter
+        .check(&client(&request, ip_addr))
+        .map_err(Error::RateLimited)?;
+    Ok(next.run(request).await)
+}
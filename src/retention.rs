@@ -0,0 +1,75 @@
+//! How long clicks are kept: those older than `retention.click_days` are deleted every
+//! `retention.interval_secs`, after being added to daily totals per link and source unless
+//! `retention.roll_up` is off. The totals keep counting toward click counts and limits, while
+//! visitors, referrers, browsers and the like are only known for the clicks still kept.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::error::QrLinkResult;
+use crate::store::LinkStore;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Days clicks are kept in full; 0 keeps them for good
+    pub click_days: u32,
+    /// Whether older clicks are added to daily totals before they're deleted. Without them,
+    /// deleted clicks no longer count anywhere, not even toward a link's `max_clicks`.
+    pub roll_up: bool,
+    /// Seconds between two rounds of deleting the clicks that have grown too old
+    pub interval_secs: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            click_days: 0,
+            roll_up: true,
+            interval_secs: 3600,
+        }
+    }
+}
+
+/// Deletes clicks once they're older than the `retention` settings keep them
+pub struct ClickPruner {
+    config: RetentionConfig,
+}
+
+impl ClickPruner {
+    pub fn new(config: &RetentionConfig) -> Self {
+        ClickPruner {
+            config: config.clone(),
+        }
+    }
+
+    /// Keeps pruning the clicks in `store` once per interval, if they aren't kept for good,
+    /// until the process exits
+    pub fn start(self: Arc<Self>, store: Arc<dyn LinkStore>) {
+        if self.config.click_days == 0 {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+            loop {
+                interval.tick().await;
+                let cutoff = Utc::now() - chrono::Duration::days(self.config.click_days.into());
+                match self.prune(&*store, cutoff).await {
+                    Ok(0) => {}
+                    Ok(pruned) => tracing::info!(pruned, %cutoff, "old clicks pruned"),
+                    Err(error) => tracing::warn!(%error, "old clicks could not be pruned"),
+                }
+            }
+        });
+    }
+
+    /// Deletes the clicks made before `before`, rolling them up if configured to, returning
+    /// how many were deleted
+    pub async fn prune(&self, store: &dyn LinkStore, before: DateTime<Utc>) -> QrLinkResult<u64> {
+        store.prune_clicks(before, self.config.roll_up).await
+    }
+}
@@ -0,0 +1,357 @@
+//! Malware and phishing checks: destinations are looked up in the `safety.blocklist` file, and
+//! with Google Safe Browsing when `safety.safe_browsing_key` is set, as links are created or
+//! repointed and again every `safety.recheck_interval_secs`. A link whose destination is flagged
+//! is quarantined, showing a warning instead of redirecting until a later check finds its
+//! destination clean.
+//!
+//! Only a link's own destination is checked, not those of its targets and variants. A lookup
+//! that fails leaves the links it was for unchecked, to be tried again in the next round.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::AppState;
+use crate::config::SafetyConfig;
+use crate::destination::domain_pattern;
+use crate::error::{Error, QrLinkResult};
+use crate::store::{Link, LinkStore, Quarantine, Threat};
+use crate::webhooks::error_chain;
+
+/// Links checked per round, which is also the most Safe Browsing looks up at once
+const BATCH_SIZE: u32 = 500;
+
+/// How often the checker looks for links due when the last round found none to spare
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The Safe Browsing threat types looked up, and what each is kept as
+const SAFE_BROWSING_THREATS: [(&str, Threat); 4] = [
+    ("MALWARE", Threat::Malware),
+    ("SOCIAL_ENGINEERING", Threat::Phishing),
+    ("UNWANTED_SOFTWARE", Threat::UnwantedSoftware),
+    (
+        "POTENTIALLY_HARMFUL_APPLICATION",
+        Threat::HarmfulApplication,
+    ),
+];
+
+/// Checks destinations for threats and quarantines the links to those that have one
+pub struct SafetyChecker {
+    client: reqwest::Client,
+    blocklist: Option<Blocklist>,
+    /// Where Safe Browsing lookups are sent, with the API key in the query
+    safe_browsing: Option<Url>,
+    interval: Duration,
+}
+
+impl SafetyChecker {
+    pub fn new(config: &SafetyConfig) -> QrLinkResult<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .user_agent(concat!("qr-link-service/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|error| {
+                Error::Config(format!("can't set up the Safe Browsing client: {error}"))
+            })?;
+        let safe_browsing = config.safe_browsing_key.as_ref().map(|key| {
+            let mut url = config.safe_browsing_url.clone();
+            url.query_pairs_mut().append_pair("key", key);
+            url
+        });
+        Ok(SafetyChecker {
+            client,
+            blocklist: config
+                .blocklist
+                .as_deref()
+                .map(Blocklist::open)
+                .transpose()?,
+            safe_browsing,
+            interval: Duration::from_secs(config.recheck_interval_secs),
+        })
+    }
+
+    /// Keeps checking the links of `app_state` as they come due, until the process exits
+    pub fn start(self: Arc<Self>, app_state: AppState) {
+        tokio::spawn(async move {
+            loop {
+                match self.check_due(&app_state).await {
+                    // There may be more
+                    Ok(checked) if checked == BATCH_SIZE as usize => continue,
+                    Ok(_) => {}
+                    Err(error) => tracing::warn!(%error, "link safety could not be checked"),
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Checks a batch of the links that are due, returning how many were checked
+    pub async fn check_due(&self, app_state: &AppState) -> QrLinkResult<usize> {
+        let checked_before = Utc::now() - self.interval;
+        let mut links = app_state
+            .store
+            .links_due_for_safety_check(checked_before, BATCH_SIZE)
+            .await?;
+        let threats: Vec<_> = links.iter().map(threat).collect();
+        let checked = self.check(&*app_state.store, &mut links).await?;
+        for (link, before) in links.iter().zip(threats) {
+            if threat(link) != before {
+                tracing::info!(link = link.id, url = %link.url, threat = ?threat(link),
+                    "link quarantine changed");
+                app_state.forget_link(link.id);
+            }
+        }
+        Ok(checked)
+    }
+
+    /// Looks the destinations of `links` up and records what was found, quarantining or
+    /// releasing each link, returning how many were checked
+    pub async fn check<'a>(
+        &self,
+        store: &dyn LinkStore,
+        links: impl IntoIterator<Item = &'a mut Link>,
+    ) -> QrLinkResult<usize> {
+        let links: Vec<&mut Link> = links.into_iter().collect();
+        if let Some(blocklist) = &self.blocklist {
+            blocklist.reload();
+        }
+        let blocklisted: Vec<bool> = links
+            .iter()
+            .map(|link| {
+                let host = Url::parse(&link.url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_owned));
+                self.blocklist
+                    .as_ref()
+                    .zip(host)
+                    .is_some_and(|(blocklist, host)| blocklist.contains(&host))
+            })
+            .collect();
+        let flagged = match &self.safe_browsing {
+            Some(endpoint) => {
+                let urls: Vec<&str> = links
+                    .iter()
+                    .zip(&blocklisted)
+                    .filter(|(_, blocklisted)| !**blocklisted)
+                    .map(|(link, _)| link.url.as_str())
+                    .collect();
+                match self.look_up(endpoint, &urls).await {
+                    Ok(flagged) => Some(flagged),
+                    Err(error) => {
+                        let error = error_chain(&error);
+                        tracing::warn!(%error, "destinations could not be looked up");
+                        None
+                    }
+                }
+            }
+            None => Some(HashMap::new()),
+        };
+
+        let mut checked = 0;
+        for (link, blocklisted) in links.into_iter().zip(blocklisted) {
+            let found = match (blocklisted, &flagged) {
+                (true, _) => Some(Threat::Blocklisted),
+                (false, Some(flagged)) => flagged.get(&link.url).copied(),
+                // Left unchecked until a lookup succeeds
+                (false, None) => continue,
+            };
+            store.record_safety(link.id, &link.url, found).await?;
+            link.quarantine = found.map(|threat| Quarantine {
+                threat,
+                since: link
+                    .quarantine
+                    .as_ref()
+                    .map_or_else(Utc::now, |quarantine| quarantine.since),
+            });
+            checked += 1;
+        }
+        Ok(checked)
+    }
+
+    /// The threats Safe Browsing knows `urls` for, by URL
+    async fn look_up(
+        &self,
+        endpoint: &Url,
+        urls: &[&str],
+    ) -> reqwest::Result<HashMap<String, Threat>> {
+        let mut flagged = HashMap::new();
+        for urls in urls.chunks(BATCH_SIZE as usize) {
+            let request = FindRequest {
+                client: ClientInfo {
+                    client_id: "qr-link-service",
+                    client_version: env!("CARGO_PKG_VERSION"),
+                },
+                threat_info: ThreatInfo {
+                    threat_types: SAFE_BROWSING_THREATS.map(|(threat_type, _)| threat_type),
+                    platform_types: ["ANY_PLATFORM"],
+                    threat_entry_types: ["URL"],
+                    threat_entries: urls.iter().map(|url| ThreatEntry { url }).collect(),
+                },
+            };
+            let response: FindResponse = self
+                .client
+                .post(endpoint.clone())
+                .json(&request)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            for found in response.matches {
+                let threat = SAFE_BROWSING_THREATS
+                    .iter()
+                    .find(|(threat_type, _)| *threat_type == found.threat_type)
+                    .map(|(_, threat)| *threat);
+                if let Some(threat) = threat {
+                    flagged.entry(found.threat.url).or_insert(threat);
+                }
+            }
+        }
+        Ok(flagged)
+    }
+}
+
+/// What a link is quarantined for, if anything
+fn threat(link: &Link) -> Option<Threat> {
+    link.quarantine.as_ref().map(|quarantine| quarantine.threat)
+}
+
+/// A Safe Browsing `threatMatches:find` request
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FindRequest<'a> {
+    client: ClientInfo,
+    threat_info: ThreatInfo<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientInfo {
+    client_id: &'static str,
+    client_version: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThreatInfo<'a> {
+    threat_types: [&'static str; 4],
+    platform_types: [&'static str; 1],
+    threat_entry_types: [&'static str; 1],
+    threat_entries: Vec<ThreatEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct ThreatEntry<'a> {
+    url: &'a str,
+}
+
+/// What Safe Browsing answers a lookup with, leaving out `matches` when nothing was found
+#[derive(Deserialize)]
+struct FindResponse {
+    #[serde(default)]
+    matches: Vec<ThreatMatch>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ThreatMatch {
+    threat_type: String,
+    threat: MatchedEntry,
+}
+
+#[derive(Deserialize)]
+struct MatchedEntry {
+    url: String,
+}
+
+/// The `safety.blocklist` file, read again whenever it changes
+struct Blocklist {
+    path: PathBuf,
+    /// When the file was last changed, as of reading it, and the domains in it
+    domains: RwLock<(Option<SystemTime>, Domains)>,
+}
+
+impl Blocklist {
+    fn open(path: &Path) -> QrLinkResult<Self> {
+        let (modified, domains) = Domains::read(path).map_err(|error| {
+            Error::Config(format!("safety.blocklist {}: {error}", path.display()))
+        })?;
+        Ok(Blocklist {
+            path: path.to_owned(),
+            domains: RwLock::new((modified, domains)),
+        })
+    }
+
+    /// Reads the file again if it has changed since it was last read, keeping the domains
+    /// read before if it can't be
+    fn reload(&self) {
+        let modified = std::fs::metadata(&self.path).and_then(|metadata| metadata.modified());
+        let current = self.domains.read().expect("blocklist lock poisoned").0;
+        if modified.as_ref().ok() == current.as_ref() {
+            return;
+        }
+        match Domains::read(&self.path) {
+            Ok(read) => *self.domains.write().expect("blocklist lock poisoned") = read,
+            Err(error) => tracing::warn!(path = %self.path.display(), %error,
+                "blocklist could not be read again, keeping the domains read before"),
+        }
+    }
+
+    fn contains(&self, host: &str) -> bool {
+        self.domains
+            .read()
+            .expect("blocklist lock poisoned")
+            .1
+            .contains(host)
+    }
+}
+
+/// Domains, one per line, either exact or `*.example.com` for any host under `example.com`,
+/// as in `destinations.denied_domains`. Blank lines and anything after a `#` are left out.
+#[derive(Default)]
+struct Domains {
+    exact: HashSet<String>,
+    /// The domains hosts under which are listed, without their `*.`
+    under: HashSet<String>,
+}
+
+impl Domains {
+    /// The domains in the file at `path`, and when it was last changed
+    fn read(path: &Path) -> std::io::Result<(Option<SystemTime>, Self)> {
+        let modified = std::fs::metadata(path)?.modified().ok();
+        let text = std::fs::read_to_string(path)?;
+        let mut domains = Domains::default();
+        let mut skipped = 0;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Ok(domain) = domain_pattern(line) else {
+                skipped += 1;
+                continue;
+            };
+            match domain.strip_prefix("*.") {
+                Some(under) => domains.under.insert(under.to_owned()),
+                None => domains.exact.insert(domain),
+            };
+        }
+        if skipped > 0 {
+            tracing::warn!(path = %path.display(), skipped, "blocklist lines that aren't domains");
+        }
+        Ok((modified, domains))
+    }
+
+    fn contains(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        self.exact.contains(host)
+            || host
+                .match_indices('.')
+                .any(|(dot, _)| self.under.contains(&host[dot + 1..]))
+    }
+}
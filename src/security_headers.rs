@@ -0,0 +1,144 @@
+//! Response headers telling browsers to hold our pages to tighter rules: not to guess content
+//! types, how much of the page to pass on as the referrer, where HTML pages may load things
+//! from, not to let other sites frame us, and, if configured, to come back over HTTPS only.
+//!
+//! Routes whose responses are meant to be embedded elsewhere, such as the QR codes, are wrapped
+//! in [embeddable], and those bringing scripts of their own, such as the API docs, in
+//! [own_scripts].
+
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue, header};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+
+use crate::AppState;
+use crate::error::{Error, QrLinkResult};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityHeadersConfig {
+    /// Whether the headers are set at all, e.g. not when a proxy in front sets them
+    pub enabled: bool,
+    /// `Referrer-Policy`, which also applies to the destinations links redirect to; empty to
+    /// leave it out
+    pub referrer_policy: String,
+    /// `Content-Security-Policy` of the HTML pages; empty to leave it out
+    pub content_security_policy: String,
+    /// How long browsers should only come back over HTTPS, as `Strict-Transport-Security`; it
+    /// is left out without one
+    pub hsts_max_age_secs: Option<u64>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            enabled: true,
+            referrer_policy: "strict-origin-when-cross-origin".into(),
+            content_security_policy: "default-src 'none'; style-src 'unsafe-inline'; \
+                img-src 'self' data:; form-action 'self'; frame-ancestors 'none'; \
+                base-uri 'none'"
+                .into(),
+            hsts_max_age_secs: None,
+        }
+    }
+}
+
+/// The headers `security_headers` configures, ready to be set
+#[derive(Clone, Debug, Default)]
+pub struct SecurityHeaders {
+    /// Every header but the content security policy, which only HTML pages get
+    headers: Vec<(HeaderName, HeaderValue)>,
+    content_security_policy: Option<HeaderValue>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &SecurityHeadersConfig) -> QrLinkResult<Self> {
+        if !config.enabled {
+            return Ok(SecurityHeaders::default());
+        }
+        let value = |name: &str, value: &str| {
+            HeaderValue::from_str(value).map_err(|_| {
+                Error::Config(format!(
+                    "security_headers.{name} can't be sent as a header value"
+                ))
+            })
+        };
+        let mut headers = vec![
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+            (header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY")),
+        ];
+        if !config.referrer_policy.is_empty() {
+            let policy = value("referrer_policy", &config.referrer_policy)?;
+            headers.push((header::REFERRER_POLICY, policy));
+        }
+        if let Some(max_age) = config.hsts_max_age_secs {
+            let hsts = HeaderValue::from_str(&format!("max-age={max_age}"))
+                .expect("a number is a valid header value");
+            headers.push((header::STRICT_TRANSPORT_SECURITY, hsts));
+        }
+        let content_security_policy = (!config.content_security_policy.is_empty())
+            .then(|| value("content_security_policy", &config.content_security_policy))
+            .transpose()?;
+        Ok(SecurityHeaders {
+            headers,
+            content_security_policy,
+        })
+    }
+}
+
+/// Marks responses other sites may frame
+#[derive(Clone, Copy)]
+struct Embeddable;
+
+/// Marks responses that get no content security policy
+#[derive(Clone, Copy)]
+struct OwnScripts;
+
+/// Middleware letting other sites frame the responses of the routes it wraps
+pub async fn embeddable(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(Embeddable);
+    response
+}
+
+/// Middleware leaving the content security policy off the pages of the routes it wraps, which
+/// load scripts of their own
+pub async fn own_scripts(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(OwnScripts);
+    response
+}
+
+/// Middleware setting the configured headers on every response, other than those its handler
+/// set itself or its route is let off
+pub async fn set(State(app_state): State<AppState>, request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    let embeddable = response.extensions().get::<Embeddable>().is_some();
+    let own_scripts = response.extensions().get::<OwnScripts>().is_some();
+    let security_headers = &app_state.security_headers;
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    let headers = response.headers_mut();
+    for (name, value) in &security_headers.headers {
+        if embeddable && name == header::X_FRAME_OPTIONS {
+            continue;
+        }
+        headers.entry(name).or_insert_with(|| value.clone());
+    }
+    if let Some(policy) = &security_headers.content_security_policy
+        && is_html
+        && !own_scripts
+    {
+        headers
+            .entry(header::CONTENT_SECURITY_POLICY)
+            .or_insert_with(|| policy.clone());
+    }
+    response
+}
@@ -0,0 +1,317 @@
+//! Signing in to the dashboard: people sign in at /login with the name and password of a user,
+//! or with the admin token as the password under any name, and are kept signed in by a signed
+//! session cookie. Users see and change only their own links there, and the admin every link.
+//! Accounts are made by the admin through /api/v1/users, at /signup if `sessions.signup` is on, or
+//! on signing in through an identity provider; see [crate::oidc]. A user signs up an editor,
+//! and the role of users at the time of each request is what their session may do.
+//! The JSON API doesn't look at the cookie; it keeps taking API keys.
+
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use axum::extract::{FromRef, Request, State};
+use axum::http::Method;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum_extra::extract::SignedCookieJar;
+use axum_extra::extract::cookie::{Cookie, Key, SameSite};
+use chrono::Utc;
+use maud::Markup;
+use serde::Deserialize;
+use sha2::{Digest, Sha512};
+use url::Url;
+
+use crate::auth::{Caller, is_admin_token, is_cross_site};
+use crate::error::{Error, QrLinkResult};
+use crate::extract::Form;
+use crate::store::Role;
+use crate::{AppState, MAX_USER_NAME_LENGTH, pages};
+
+/// The cookie a session is kept in
+const COOKIE_NAME: &str = "qrlink_session";
+
+/// The shortest password a user can sign up with
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// The shortest secret session cookies can be signed with
+pub const MIN_SECRET_LENGTH: usize = 32;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Signs session cookies, at least [MIN_SECRET_LENGTH] bytes long. Without it, a secret is
+    /// made up at startup, so everyone is signed out by a restart and sessions only work on
+    /// the instance that started them.
+    pub secret: Option<String>,
+    /// Hours a session lasts, unless the browser is closed first
+    pub ttl_hours: u64,
+    /// Whether anyone can make themselves a user at /signup
+    pub signup: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            secret: None,
+            ttl_hours: 168,
+            signup: false,
+        }
+    }
+}
+
+/// Issues session cookies and tells which of them are still good
+pub struct Sessions {
+    key: Key,
+    ttl: chrono::Duration,
+    signup: bool,
+    /// Whether cookies are only sent over HTTPS, as they are when the service is served over it
+    secure: bool,
+}
+
+impl Sessions {
+    pub fn new(config: &SessionConfig, base_url: Option<&Url>) -> Self {
+        Sessions {
+            key: config
+                .secret
+                .as_deref()
+                .map_or_else(Key::generate, |secret| Key::from(&Sha512::digest(secret))),
+            ttl: chrono::Duration::hours(config.ttl_hours.try_into().unwrap_or(i64::MAX)),
+            signup: config.signup,
+            secure: base_url.is_some_and(|base_url| base_url.scheme() == "https"),
+        }
+    }
+
+    /// Whether anyone can sign up
+    pub fn signup(&self) -> bool {
+        self.signup
+    }
+
+    /// Whether cookies are set to be sent over HTTPS only
+    pub fn secure(&self) -> bool {
+        self.secure
+    }
+
+    /// `jar` with a cookie starting `session`
+    pub(crate) fn start(&self, jar: SignedCookieJar, session: Session) -> SignedCookieJar {
+        let expires_at = (Utc::now() + self.ttl).timestamp();
+        let value = match session {
+            Session::Admin => format!("admin.{expires_at}"),
+            Session::User(id) => format!("{id}.{expires_at}"),
+        };
+        let cookie = Cookie::build((COOKIE_NAME, value))
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(self.secure);
+        jar.add(cookie)
+    }
+
+    /// The session the cookie in `jar` stands for, unless there is none or it has expired
+    fn find(&self, jar: &SignedCookieJar) -> Option<Session> {
+        let cookie = jar.get(COOKIE_NAME)?;
+        let (who, expires_at) = cookie.value().split_once('.')?;
+        if expires_at.parse::<i64>().ok()? <= Utc::now().timestamp() {
+            return None;
+        }
+        match who {
+            "admin" => Some(Session::Admin),
+            id => id.parse().ok().map(Session::User),
+        }
+    }
+}
+
+/// Lets handlers take the cookies of a request as a [SignedCookieJar]
+impl FromRef<AppState> for Key {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.sessions.key.clone()
+    }
+}
+
+/// Who is signed in to the dashboard
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Session {
+    Admin,
+    User(u64),
+}
+
+/// The caller a session stands for, unless its user has since been removed
+async fn caller(app_state: &AppState, session: Session) -> QrLinkResult<Option<Caller>> {
+    Ok(match session {
+        Session::Admin => Some(Caller::Admin),
+        Session::User(id) => app_state.store.user(id).await?.map(Caller::User),
+    })
+}
+
+/// Middleware letting a browser through only while it is signed in, which handlers then take
+/// as a [Caller], and refusing changes sent from other sites. Pages asked for without a
+/// session send the browser to /login instead. `OPTIONS` requests are let through, as with
+/// [require_api_key](crate::auth::require_api_key).
+pub(crate) async fn require_session(
+    State(app_state): State<AppState>,
+    jar: SignedCookieJar,
+    mut request: Request,
+    next: Next,
+) -> QrLinkResult<Response> {
+    if request.method() == Method::OPTIONS {
+        return Ok(next.run(request).await);
+    }
+    let caller = match app_state.sessions.find(&jar) {
+        Some(session) => caller(&app_state, session).await?,
+        None => None,
+    };
+    let Some(caller) = caller else {
+        if request.method().is_safe() {
+            return Ok(Redirect::to("/login").into_response());
+        }
+        return Err(Error::Unauthorized("sign in first".into()));
+    };
+    if !request.method().is_safe() && is_cross_site(&request) {
+        return Err(Error::Forbidden(
+            "changes must come from the dashboard itself".into(),
+        ));
+    }
+    request.extensions_mut().insert(caller);
+    Ok(next.run(request).await)
+}
+
+/// POST /login and POST /signup with a user name and password
+#[derive(Deserialize)]
+pub(crate) struct Credentials {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    password: String,
+}
+
+pub(crate) async fn get_login(State(app_state): State<AppState>) -> Markup {
+    pages::sign_in(&pages::SignIn {
+        signup: app_state.sessions.signup,
+        oidc: app_state.oidc.is_some(),
+        ..Default::default()
+    })
+}
+
+pub(crate) async fn post_login(
+    State(app_state): State<AppState>,
+    jar: SignedCookieJar,
+    Form(credentials): Form<Credentials>,
+) -> QrLinkResult<Response> {
+    let signed_in = sign_in(&app_state, &credentials).await;
+    signed_in_or_back(&app_state, jar, &credentials, false, signed_in)
+}
+
+/// The session `credentials` sign in to
+async fn sign_in(app_state: &AppState, credentials: &Credentials) -> QrLinkResult<Session> {
+    if is_admin_token(app_state, &credentials.password) {
+        return Ok(Session::Admin);
+    }
+    let wrong = || Error::Unauthorized("wrong name or password".into());
+    let (user, password_hash) = app_state
+        .store
+        .user_credentials(credentials.name.trim())
+        .await?
+        .ok_or_else(wrong)?;
+    let password = credentials.password.clone();
+    // Checking a password takes long enough on purpose to keep it off the runtime
+    let verified = tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&password_hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .map_err(Error::Task)?;
+    if !verified {
+        return Err(wrong());
+    }
+    Ok(Session::User(user.id))
+}
+
+/// POST /logout ends the session and goes back to /login
+pub(crate) async fn post_logout(jar: SignedCookieJar) -> (SignedCookieJar, Redirect) {
+    (
+        jar.remove(Cookie::build(COOKIE_NAME).path("/")),
+        Redirect::to("/login"),
+    )
+}
+
+/// GET /signup, served only if `sessions.signup` is on
+pub(crate) async fn get_signup() -> Markup {
+    pages::sign_in(&pages::SignIn {
+        new_user: true,
+        ..Default::default()
+    })
+}
+
+pub(crate) async fn post_signup(
+    State(app_state): State<AppState>,
+    jar: SignedCookieJar,
+    Form(credentials): Form<Credentials>,
+) -> QrLinkResult<Response> {
+    let signed_up = sign_up(&app_state, &credentials).await;
+    signed_in_or_back(&app_state, jar, &credentials, true, signed_up)
+}
+
+/// Makes a user of `credentials`, signed in right away
+async fn sign_up(app_state: &AppState, credentials: &Credentials) -> QrLinkResult<Session> {
+    let name = credentials.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_USER_NAME_LENGTH {
+        return Err(Error::Validation(format!(
+            "name must be 1 to {MAX_USER_NAME_LENGTH} characters"
+        )));
+    }
+    if credentials.password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(Error::Validation(format!(
+            "password must be at least {MIN_PASSWORD_LENGTH} characters"
+        )));
+    }
+    let password_hash = hash_password(credentials.password.clone()).await?;
+    let user = app_state
+        .store
+        .create_user(name, Some(&password_hash), Role::default())
+        .await?;
+    tracing::info!(user_id = user.id, "user signed up");
+    Ok(Session::User(user.id))
+}
+
+/// The argon2 hash of `password`, with a fresh salt, as it is stored
+async fn hash_password(password: String) -> QrLinkResult<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .expect("argon2 hashes passwords of any length a form can send")
+            .to_string()
+    })
+    .await
+    .map_err(Error::Task)
+}
+
+/// Sends the browser to the dashboard with a new session, or shows the form again with why it
+/// wasn't let in
+fn signed_in_or_back(
+    app_state: &AppState,
+    jar: SignedCookieJar,
+    credentials: &Credentials,
+    new_user: bool,
+    signed_in: QrLinkResult<Session>,
+) -> QrLinkResult<Response> {
+    match signed_in {
+        Ok(session) => {
+            let jar = app_state.sessions.start(jar, session);
+            Ok((jar, Redirect::to("/admin")).into_response())
+        }
+        Err(error) if error.status_code().is_client_error() => {
+            let page = pages::sign_in(&pages::SignIn {
+                name: &credentials.name,
+                new_user,
+                signup: app_state.sessions.signup,
+                oidc: app_state.oidc.is_some(),
+                error: Some(error.to_string()),
+            });
+            Ok((error.status_code(), page).into_response())
+        }
+        Err(error) => Err(error),
+    }
+}
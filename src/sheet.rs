@@ -0,0 +1,271 @@
+//! Sheets of QR codes for printing on sticker paper: many links' codes laid out in a grid on A4
+//! or Letter pages, each with a caption below it, as one PDF of as many pages as they fill.
+//! Captions are set in Helvetica, which every PDF reader has, so characters outside Latin-1
+//! print as `?`.
+
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::error::{Error, QrLinkResult};
+use crate::qr::{self, PdfPage, Rendering};
+
+/// Codes one sheet may hold, over all its pages
+pub const MAX_STICKERS: usize = 1000;
+
+/// Captions are cut short with this when they don't fit under their code
+const ELLIPSIS: &str = "...";
+
+/// Sizes of paper sheets are printed on
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Paper {
+    /// 210 × 297 mm
+    #[default]
+    A4,
+    /// 8.5 × 11 inches
+    Letter,
+}
+
+impl Paper {
+    /// Width and height in millimeters, upright
+    fn size_mm(self) -> (f64, f64) {
+        match self {
+            Paper::A4 => (210.0, 297.0),
+            Paper::Letter => (215.9, 279.4),
+        }
+    }
+}
+
+/// How the codes are laid out on each page
+#[derive(Clone, Debug, Deserialize, ToSchema, clap::Args)]
+#[serde(default)]
+pub struct Layout {
+    #[arg(long, value_enum, default_value_t)]
+    pub paper: Paper,
+    /// Codes side by side on each page
+    #[arg(long, default_value_t = 3)]
+    pub columns: u32,
+    /// Codes one above the other on each page
+    #[arg(long, default_value_t = 7)]
+    pub rows: u32,
+    /// Blank border around the grid, in millimeters
+    #[arg(long, default_value_t = 10.0)]
+    pub margin_mm: f64,
+    /// Space between neighboring codes, in millimeters
+    #[arg(long, default_value_t = 5.0)]
+    pub gap_mm: f64,
+    /// Size of the captions in points, 0 to leave them out
+    #[arg(long, default_value_t = 9.0)]
+    pub caption_size: f64,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            paper: Paper::A4,
+            columns: 3,
+            rows: 7,
+            margin_mm: 10.0,
+            gap_mm: 5.0,
+            caption_size: 9.0,
+        }
+    }
+}
+
+/// A code to put on a sheet
+pub struct Sticker {
+    /// What the code encodes
+    pub data: String,
+    pub caption: String,
+}
+
+/// Where the codes go on every page, in points from the bottom left
+struct Grid {
+    page: (f64, f64),
+    /// Width and height of each cell, which holds a code and its caption
+    cell: (f64, f64),
+    margin: f64,
+    gap: f64,
+    /// Width of the codes, which are square
+    side: f64,
+}
+
+impl Layout {
+    /// Checks that the grid fits on the paper with room for codes worth scanning
+    fn grid(&self) -> QrLinkResult<Grid> {
+        if !(1..=20).contains(&self.columns) || !(1..=40).contains(&self.rows) {
+            return Err(Error::Validation(
+                "a sheet must have 1 to 20 columns and 1 to 40 rows".into(),
+            ));
+        }
+        if !(0.0..=50.0).contains(&self.margin_mm) || !(0.0..=50.0).contains(&self.gap_mm) {
+            return Err(Error::Validation(
+                "margin_mm and gap_mm must be between 0 and 50".into(),
+            ));
+        }
+        if !(0.0..=36.0).contains(&self.caption_size) {
+            return Err(Error::Validation(
+                "caption_size must be between 0 and 36 points".into(),
+            ));
+        }
+        let (width, height) = self.paper.size_mm();
+        let (width, height) = (width * qr::POINTS_PER_MM, height * qr::POINTS_PER_MM);
+        let margin = self.margin_mm * qr::POINTS_PER_MM;
+        let gap = self.gap_mm * qr::POINTS_PER_MM;
+        let cell = |length: f64, count: u32| {
+            (length - 2.0 * margin - gap * f64::from(count - 1)) / f64::from(count)
+        };
+        let cell = (cell(width, self.columns), cell(height, self.rows));
+        let side = cell.0.min(cell.1 - self.caption_height());
+        if side < MIN_SIDE_MM * qr::POINTS_PER_MM {
+            return Err(Error::Validation(format!(
+                "codes would be under {MIN_SIDE_MM} mm wide; use fewer columns or rows, or \
+                 smaller margins, gaps or captions"
+            )));
+        }
+        Ok(Grid {
+            page: (width, height),
+            cell,
+            margin,
+            gap,
+            side,
+        })
+    }
+
+    /// Height in points each caption takes below its code
+    fn caption_height(&self) -> f64 {
+        self.caption_size * 1.5
+    }
+}
+
+/// Narrowest a code on a sheet may be, quiet zone included, to scan reliably once printed
+const MIN_SIDE_MM: f64 = 10.0;
+
+/// `stickers` as a PDF of as many pages as `layout` needs to hold them, left to right and top
+/// to bottom, each code drawn as `rendering` says
+pub fn render(
+    rendering: &Rendering,
+    stickers: &[Sticker],
+    layout: &Layout,
+) -> QrLinkResult<Vec<u8>> {
+    if stickers.is_empty() || stickers.len() > MAX_STICKERS {
+        return Err(Error::Validation(format!(
+            "a sheet must have between 1 and {MAX_STICKERS} codes"
+        )));
+    }
+    let grid = layout.grid()?;
+    let per_page = (layout.columns * layout.rows) as usize;
+    let colors = (rendering.foreground, rendering.background);
+    let mut pages = Vec::new();
+    for page_stickers in stickers.chunks(per_page) {
+        let mut content = String::new();
+        for (index, sticker) in page_stickers.iter().enumerate() {
+            let (column, row) = (index as u32 % layout.columns, index as u32 / layout.columns);
+            let left = grid.margin + f64::from(column) * (grid.cell.0 + grid.gap);
+            let top = grid.page.1 - grid.margin - f64::from(row) * (grid.cell.1 + grid.gap);
+            let center = left + grid.cell.0 / 2.0;
+            let code = rendering.encode(&sticker.data)?;
+            let origin = (center - grid.side / 2.0, top - grid.side);
+            qr::pdf_code(
+                &mut content,
+                &code,
+                origin,
+                grid.side,
+                rendering.quiet_zone,
+                colors,
+            );
+            if layout.caption_size > 0.0 {
+                let baseline = top - grid.side - layout.caption_size * 1.1;
+                caption(
+                    &mut content,
+                    &sticker.caption,
+                    layout,
+                    grid.cell.0,
+                    center,
+                    baseline,
+                );
+            }
+        }
+        pages.push(PdfPage {
+            width: grid.page.0,
+            height: grid.page.1,
+            content,
+        });
+    }
+    Ok(qr::pdf_document(&pages))
+}
+
+/// Adds `text`, in black, centered on `center` and cut short to fit in `width`
+fn caption(content: &mut String, text: &str, layout: &Layout, width: f64, center: f64, y: f64) {
+    let mut text = latin1(text);
+    let size = layout.caption_size;
+    let text_width = |text: &[u8]| text.iter().map(|&c| char_width(c)).sum::<f64>() * size;
+    if text_width(&text) > width {
+        let room = width - text_width(ELLIPSIS.as_bytes());
+        while !text.is_empty() && text_width(&text) > room {
+            text.pop();
+        }
+        text.extend_from_slice(ELLIPSIS.as_bytes());
+    }
+    let x = center - text_width(&text) / 2.0;
+    let _ = writeln!(
+        content,
+        "BT 0 g {} {size:.2} Tf {x:.4} {y:.4} Td ({}) Tj ET",
+        qr::PDF_FONT,
+        pdf_string(&text)
+    );
+}
+
+/// `text` in Latin-1, which Helvetica's WinAnsi encoding matches but for a few characters it
+/// has none of, with `?` for each character outside it
+fn latin1(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match u32::from(c) {
+            code @ (0x20..=0x7e | 0xa0..=0xff) => code as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+/// `text` escaped for a PDF string literal, bytes past ASCII as octal escapes
+fn pdf_string(text: &[u8]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for &byte in text {
+        match byte {
+            b'(' | b')' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(char::from(byte));
+            }
+            0x20..=0x7e => escaped.push(char::from(byte)),
+            _ => {
+                let _ = write!(escaped, "\\{byte:03o}");
+            }
+        }
+    }
+    escaped
+}
+
+/// Widths of Helvetica's printable ASCII characters, in thousandths of the font size
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278,
+    278, // space to /
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, // 0 to ?
+    1015, 667, 667, 722, 722, 667, 611, 778, 722, 278, 500, 667, 556, 833, 722, 778, // @ to O
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 278, 278, 278, 469, 556, // P to _
+    333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, 556, 556, // ` to o
+    556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584, // p to ~
+];
+
+/// Width of a Latin-1 character in Helvetica as a share of the font size; letters past ASCII
+/// are taken to be as wide as most lowercase ones
+fn char_width(c: u8) -> f64 {
+    let thousandths = match c {
+        0x20..=0x7e => HELVETICA_WIDTHS[usize::from(c - 0x20)],
+        _ => 556,
+    };
+    f64::from(thousandths) / 1000.0
+}
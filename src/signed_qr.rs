@@ -0,0 +1,142 @@
+//! Signed QR code URLs. With `qr.signing_key` set, `/<id>/qr` only draws codes for URLs carrying
+//! a `sig` made with it over the id and every other parameter, which API clients ask
+//! `/api/v1/links/<id>/qr-url` for, so that others can't have codes drawn in sizes, colors and
+//! frames of their choosing. Signed URLs always name their `format`, which is signed along with
+//! the rest, rather than leaving it to the `Accept` header, which the signature can't cover.
+
+use axum::extract::{RawQuery, State};
+use axum::http::HeaderMap;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, KeyInit, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use url::form_urlencoded;
+use utoipa::ToSchema;
+
+use crate::auth::{Authorized, Read};
+use crate::error::{Error, ErrorBody, QrLinkResult};
+use crate::extract::{Path, Query};
+use crate::{AppState, QrQuery};
+
+/// The query parameter the signature is sent in
+const PARAMETER: &str = "sig";
+/// The query parameter signed URLs name the format in
+const FORMAT: &str = "format";
+
+/// A URL of a link's QR code that `/<id>/qr` draws
+#[derive(Serialize, ToSchema)]
+pub struct QrUrl {
+    /// Signed when `qr.signing_key` is set
+    url: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/links/{external_id}/qr-url",
+    summary = "Return a signed URL of a link's QR code",
+    description = "Takes the query parameters of `GET /{external_id}/qr` and answers with the URL \
+        of the code they describe, signed with `qr.signing_key` if one is set, in which case \
+        `/{external_id}/qr` draws no code without a valid `sig`. The signature covers every \
+        parameter, so any change to the URL calls for a new one. Signed URLs always carry a \
+        `format`, PNG unless another is asked for, as the `Accept` header isn't signed.",
+    params(("external_id" = String, Path, description = "Code or slug"), QrQuery),
+    responses(
+        (status = 200, body = QrUrl),
+        (status = 400, description = "Invalid parameters", body = ErrorBody),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 404, description = "No such link", body = ErrorBody),
+    ),
+    security(("api_key" = [])),
+    tag = "qr"
+)]
+pub async fn get_qr_url(
+    Path(external_id): Path<String>,
+    State(app_state): State<AppState>,
+    caller: Authorized<Read>,
+    Query(params): Query<QrQuery>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> QrLinkResult<axum::Json<QrUrl>> {
+    let link = app_state
+        .store
+        .resolve_active_in(&external_id, caller.scope())
+        .await?;
+    // Turned away now rather than when the URL is followed
+    crate::qr_rendering(&app_state, &params, params.format.unwrap_or_default())?;
+    let public_id = link.public_id();
+    let query = sign(&app_state, &public_id, query.as_deref().unwrap_or_default());
+    let mut url = crate::short_url(&app_state, &headers, &format!("{public_id}/qr"));
+    if !query.is_empty() {
+        url = format!("{url}?{query}");
+    }
+    Ok(axum::Json(QrUrl { url }))
+}
+
+/// `query`, without any `sig` it has, followed by one for the code of `external_id` it
+/// describes, if `qr.signing_key` is set, along with `format=png` if it names no format
+pub fn sign(app_state: &AppState, external_id: &str, query: &str) -> String {
+    let mut pairs = parameters(query);
+    if app_state.config.qr.signing_key.is_some() && !pairs.iter().any(|(name, _)| name == FORMAT) {
+        pairs.push((FORMAT.into(), "png".into()));
+    }
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    query.extend_pairs(&pairs);
+    if let Some(mac) = mac(app_state, external_id, pairs) {
+        query.append_pair(
+            PARAMETER,
+            &URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()),
+        );
+    }
+    query.finish()
+}
+
+/// Turns away `query` for the code of `external_id` unless it carries a valid `sig` and names
+/// its format, or `qr.signing_key` isn't set
+pub fn verify(app_state: &AppState, external_id: &str, query: Option<&str>) -> QrLinkResult<()> {
+    let query = query.unwrap_or_default();
+    let pairs = parameters(query);
+    let named_format = pairs.iter().any(|(name, _)| name == FORMAT);
+    let Some(mac) = mac(app_state, external_id, pairs) else {
+        return Ok(());
+    };
+    let signature = form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == PARAMETER)
+        .and_then(|(_, signature)| URL_SAFE_NO_PAD.decode(signature.as_bytes()).ok());
+    match signature {
+        Some(_) if !named_format => Err(Error::Forbidden(
+            "signed QR code URLs need a format parameter".into(),
+        )),
+        Some(signature) if mac.verify_slice(&signature).is_ok() => Ok(()),
+        Some(_) => Err(Error::Forbidden("invalid QR code signature".into())),
+        None => Err(Error::Forbidden(format!(
+            "QR codes need a signature from /api/v1/links/{external_id}/qr-url"
+        ))),
+    }
+}
+
+/// The parameters of `query` other than `sig`, in their order
+fn parameters(query: &str) -> Vec<(String, String)> {
+    form_urlencoded::parse(query.as_bytes())
+        .filter(|(name, _)| name != PARAMETER)
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect()
+}
+
+/// The MAC of the code of `external_id` that `pairs` describe, in any order, if
+/// `qr.signing_key` is set
+fn mac(
+    app_state: &AppState,
+    external_id: &str,
+    mut pairs: Vec<(String, String)>,
+) -> Option<Hmac<Sha256>> {
+    let key = app_state.config.qr.signing_key.as_ref()?;
+    pairs.sort();
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(&pairs)
+        .finish();
+    mac.update(format!("{external_id}?{query}").as_bytes());
+    Some(mac)
+}
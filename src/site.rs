@@ -0,0 +1,123 @@
+//! The files every site is asked for whatever it serves: `/robots.txt`, which crawlers read
+//! before anything else, and `/favicon.ico`, which browsers fetch for the tab of any page.
+//! Without a `robots_txt` of their own, crawlers are kept out of the API and the dashboard only,
+//! links being left to each one's `noindex`.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::{HeaderName, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+
+use crate::AppState;
+use crate::error::{Error, QrLinkResult};
+
+/// What crawlers are told without a `robots_txt`
+const DEFAULT_ROBOTS_TXT: &str = "User-agent: *\nDisallow: /api/\nDisallow: /admin\n\
+    Disallow: /login\nDisallow: /signup\n";
+
+/// How long browsers and proxies may reuse either file
+const CACHE_CONTROL: &str = "public, max-age=86400";
+
+const X_ROBOTS_TAG: HeaderName = HeaderName::from_static("x-robots-tag");
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiteConfig {
+    /// File served as `/robots.txt` instead of the built-in one
+    pub robots_txt: Option<PathBuf>,
+    /// Icon served as `/favicon.ico`, in ICO, PNG or SVG format; without one there is none
+    pub favicon: Option<PathBuf>,
+}
+
+/// The files `site` configures, read at start
+#[derive(Debug)]
+pub struct SiteFiles {
+    robots_txt: Arc<str>,
+    /// The icon and its content type
+    favicon: Option<(Arc<[u8]>, &'static str)>,
+}
+
+impl SiteFiles {
+    pub fn load(config: &SiteConfig) -> QrLinkResult<Self> {
+        let read = |setting: &str, path: &Path| {
+            std::fs::read(path).map_err(|error| {
+                Error::Config(format!("site.{setting} {}: {error}", path.display()))
+            })
+        };
+        let robots_txt = match &config.robots_txt {
+            Some(path) => String::from_utf8(read("robots_txt", path)?)
+                .map_err(|_| {
+                    Error::Config(format!("site.robots_txt {} isn't UTF-8", path.display()))
+                })?
+                .into(),
+            None => DEFAULT_ROBOTS_TXT.into(),
+        };
+        let favicon = match &config.favicon {
+            Some(path) => Some((read("favicon", path)?.into(), icon_type(path)?)),
+            None => None,
+        };
+        Ok(SiteFiles {
+            robots_txt,
+            favicon,
+        })
+    }
+}
+
+/// The content type of an icon, going by its extension
+fn icon_type(path: &Path) -> QrLinkResult<&'static str> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("ico") => Ok("image/x-icon"),
+        Some("png") => Ok("image/png"),
+        Some("svg") => Ok("image/svg+xml"),
+        _ => Err(Error::Config(format!(
+            "site.favicon {} must be an .ico, .png or .svg file",
+            path.display()
+        ))),
+    }
+}
+
+/// GET /robots.txt
+pub async fn get_robots_txt(State(app_state): State<AppState>) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        app_state.site.robots_txt.to_string(),
+    )
+        .into_response()
+}
+
+/// GET /favicon.ico, answered with 204 No Content without a `favicon`, so that browsers asking
+/// for one on every page aren't counted as lookups of a link named `favicon.ico`
+pub async fn get_favicon(State(app_state): State<AppState>) -> Response {
+    match &app_state.site.favicon {
+        Some((icon, content_type)) => (
+            [
+                (header::CONTENT_TYPE, *content_type),
+                (header::CACHE_CONTROL, CACHE_CONTROL),
+            ],
+            icon.to_vec(),
+        )
+            .into_response(),
+        None => (
+            StatusCode::NO_CONTENT,
+            [(header::CACHE_CONTROL, CACHE_CONTROL)],
+        )
+            .into_response(),
+    }
+}
+
+/// Asks search engines not to index `response`, for links marked `noindex`
+pub fn noindex(response: &mut Response) {
+    response
+        .headers_mut()
+        .insert(X_ROBOTS_TAG, HeaderValue::from_static("noindex"));
+}
@@ -0,0 +1,45 @@
+use crate::error::{Error, QrLinkResult};
+
+/// Slugs that would be confusing next to the routes the service serves itself
+pub static RESERVED_SLUGS: &[&str] = &[
+    "qr", "meta", "stats", "info", "restore", "api", "metrics", "healthz", "readyz", "docs",
+    "preview", "app", "admin", "ws", "oembed",
+];
+
+pub const MAX_SLUG_LENGTH: usize = 64;
+
+/// Checks that a client-supplied slug can be used as a public identifier.
+///
+/// Slugs are lowercase ASCII letters, digits, `-` and `_`. Purely numeric slugs are refused
+/// so they can never be mistaken for an id.
+pub fn validate(slug: &str) -> QrLinkResult<()> {
+    if slug.is_empty() || slug.len() > MAX_SLUG_LENGTH {
+        return Err(Error::Validation(format!(
+            "slug must be between 1 and {} characters",
+            MAX_SLUG_LENGTH
+        )));
+    }
+    if !slug
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        return Err(Error::Validation(
+            "slug may only contain a-z, 0-9, '-' and '_'".into(),
+        ));
+    }
+    if slug.chars().all(|c| c.is_ascii_digit()) {
+        return Err(Error::Validation("slug cannot be purely numeric".into()));
+    }
+    if RESERVED_SLUGS.contains(&slug) {
+        return Err(Error::Validation(format!("slug '{}' is reserved", slug)));
+    }
+    Ok(())
+}
+
+/// The slug an id in capitals, as compact QR codes name slugs, can only be: slugs are lowercase,
+/// so `MENU` is `menu`. Codes are looked up first, so a code in capitals is found as itself.
+pub fn from_capitals(external_id: &str) -> Option<String> {
+    (external_id.chars().any(|c| c.is_ascii_uppercase())
+        && !external_id.chars().any(|c| c.is_ascii_lowercase()))
+    .then(|| external_id.to_ascii_lowercase())
+}
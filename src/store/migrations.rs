@@ -0,0 +1,271 @@
+//! Numbered schema migrations, applied in order and recorded in a `schema_version` table.
+//!
+//! A migration is never edited once released; schema changes get a new file instead.
+
+use crate::error::{Error, QrLinkResult};
+
+pub struct Migration {
+    pub version: u32,
+    pub sql: &'static str,
+}
+
+pub static SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        sql: include_str!("../../migrations/sqlite/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        sql: include_str!("../../migrations/sqlite/0002_click_visitors.sql"),
+    },
+    Migration {
+        version: 3,
+        sql: include_str!("../../migrations/sqlite/0003_click_sources.sql"),
+    },
+    Migration {
+        version: 4,
+        sql: include_str!("../../migrations/sqlite/0004_click_locations.sql"),
+    },
+    Migration {
+        version: 5,
+        sql: include_str!("../../migrations/sqlite/0005_click_channels.sql"),
+    },
+    Migration {
+        version: 6,
+        sql: include_str!("../../migrations/sqlite/0006_api_keys.sql"),
+    },
+    Migration {
+        version: 7,
+        sql: include_str!("../../migrations/sqlite/0007_webhooks.sql"),
+    },
+    Migration {
+        version: 8,
+        sql: include_str!("../../migrations/sqlite/0008_max_clicks.sql"),
+    },
+    Migration {
+        version: 9,
+        sql: include_str!("../../migrations/sqlite/0009_link_activation.sql"),
+    },
+    Migration {
+        version: 10,
+        sql: include_str!("../../migrations/sqlite/0010_link_targets.sql"),
+    },
+    Migration {
+        version: 11,
+        sql: include_str!("../../migrations/sqlite/0011_country_targets.sql"),
+    },
+    Migration {
+        version: 12,
+        sql: include_str!("../../migrations/sqlite/0012_language_targets.sql"),
+    },
+    Migration {
+        version: 13,
+        sql: include_str!("../../migrations/sqlite/0013_link_variants.sql"),
+    },
+    Migration {
+        version: 14,
+        sql: include_str!("../../migrations/sqlite/0014_link_forwarding.sql"),
+    },
+    Migration {
+        version: 15,
+        sql: include_str!("../../migrations/sqlite/0015_utm_templates.sql"),
+    },
+    Migration {
+        version: 16,
+        sql: include_str!("../../migrations/sqlite/0016_url_hashes.sql"),
+    },
+    Migration {
+        version: 17,
+        sql: include_str!("../../migrations/sqlite/0017_idempotency_keys.sql"),
+    },
+    Migration {
+        version: 18,
+        sql: include_str!("../../migrations/sqlite/0018_tags.sql"),
+    },
+    Migration {
+        version: 19,
+        sql: include_str!("../../migrations/sqlite/0019_link_titles.sql"),
+    },
+    Migration {
+        version: 20,
+        sql: include_str!("../../migrations/sqlite/0020_link_health.sql"),
+    },
+    Migration {
+        version: 21,
+        sql: include_str!("../../migrations/sqlite/0021_link_safety.sql"),
+    },
+    Migration {
+        version: 22,
+        sql: include_str!("../../migrations/sqlite/0022_visitor_salts.sql"),
+    },
+    Migration {
+        version: 23,
+        sql: include_str!("../../migrations/sqlite/0023_click_rollups.sql"),
+    },
+    Migration {
+        version: 24,
+        sql: include_str!("../../migrations/sqlite/0024_users.sql"),
+    },
+    Migration {
+        version: 25,
+        sql: include_str!("../../migrations/sqlite/0025_user_passwords.sql"),
+    },
+    Migration {
+        version: 26,
+        sql: include_str!("../../migrations/sqlite/0026_user_oidc_subjects.sql"),
+    },
+    Migration {
+        version: 27,
+        sql: include_str!("../../migrations/sqlite/0027_roles.sql"),
+    },
+    Migration {
+        version: 28,
+        sql: include_str!("../../migrations/sqlite/0028_link_noindex.sql"),
+    },
+    Migration {
+        version: 29,
+        sql: include_str!("../../migrations/sqlite/0029_idempotency_key_owners.sql"),
+    },
+];
+
+#[cfg(feature = "postgres")]
+pub static POSTGRES: &[Migration] = &[
+    Migration {
+        version: 1,
+        sql: include_str!("../../migrations/postgres/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        sql: include_str!("../../migrations/postgres/0002_click_visitors.sql"),
+    },
+    Migration {
+        version: 3,
+        sql: include_str!("../../migrations/postgres/0003_click_sources.sql"),
+    },
+    Migration {
+        version: 4,
+        sql: include_str!("../../migrations/postgres/0004_click_locations.sql"),
+    },
+    Migration {
+        version: 5,
+        sql: include_str!("../../migrations/postgres/0005_click_channels.sql"),
+    },
+    Migration {
+        version: 6,
+        sql: include_str!("../../migrations/postgres/0006_api_keys.sql"),
+    },
+    Migration {
+        version: 7,
+        sql: include_str!("../../migrations/postgres/0007_webhooks.sql"),
+    },
+    Migration {
+        version: 8,
+        sql: include_str!("../../migrations/postgres/0008_max_clicks.sql"),
+    },
+    Migration {
+        version: 9,
+        sql: include_str!("../../migrations/postgres/0009_link_activation.sql"),
+    },
+    Migration {
+        version: 10,
+        sql: include_str!("../../migrations/postgres/0010_link_targets.sql"),
+    },
+    Migration {
+        version: 11,
+        sql: include_str!("../../migrations/postgres/0011_country_targets.sql"),
+    },
+    Migration {
+        version: 12,
+        sql: include_str!("../../migrations/postgres/0012_language_targets.sql"),
+    },
+    Migration {
+        version: 13,
+        sql: include_str!("../../migrations/postgres/0013_link_variants.sql"),
+    },
+    Migration {
+        version: 14,
+        sql: include_str!("../../migrations/postgres/0014_link_forwarding.sql"),
+    },
+    Migration {
+        version: 15,
+        sql: include_str!("../../migrations/postgres/0015_utm_templates.sql"),
+    },
+    Migration {
+        version: 16,
+        sql: include_str!("../../migrations/postgres/0016_url_hashes.sql"),
+    },
+    Migration {
+        version: 17,
+        sql: include_str!("../../migrations/postgres/0017_idempotency_keys.sql"),
+    },
+    Migration {
+        version: 18,
+        sql: include_str!("../../migrations/postgres/0018_tags.sql"),
+    },
+    Migration {
+        version: 19,
+        sql: include_str!("../../migrations/postgres/0019_link_titles.sql"),
+    },
+    Migration {
+        version: 20,
+        sql: include_str!("../../migrations/postgres/0020_link_health.sql"),
+    },
+    Migration {
+        version: 21,
+        sql: include_str!("../../migrations/postgres/0021_link_safety.sql"),
+    },
+    Migration {
+        version: 22,
+        sql: include_str!("../../migrations/postgres/0022_visitor_salts.sql"),
+    },
+    Migration {
+        version: 23,
+        sql: include_str!("../../migrations/postgres/0023_click_rollups.sql"),
+    },
+    Migration {
+        version: 24,
+        sql: include_str!("../../migrations/postgres/0024_users.sql"),
+    },
+    Migration {
+        version: 25,
+        sql: include_str!("../../migrations/postgres/0025_user_passwords.sql"),
+    },
+    Migration {
+        version: 26,
+        sql: include_str!("../../migrations/postgres/0026_user_oidc_subjects.sql"),
+    },
+    Migration {
+        version: 27,
+        sql: include_str!("../../migrations/postgres/0027_roles.sql"),
+    },
+    Migration {
+        version: 28,
+        sql: include_str!("../../migrations/postgres/0028_link_noindex.sql"),
+    },
+    Migration {
+        version: 29,
+        sql: include_str!("../../migrations/postgres/0029_idempotency_key_owners.sql"),
+    },
+];
+
+/// The version a database is at once every migration has been applied
+pub fn latest(migrations: &[Migration]) -> u32 {
+    migrations.last().map_or(0, |migration| migration.version)
+}
+
+/// The migrations a database at version `current` still needs, refusing databases that a newer
+/// build has already migrated past what this one knows
+pub fn pending(migrations: &[Migration], current: u32) -> QrLinkResult<&[Migration]> {
+    if current > latest(migrations) {
+        return Err(Error::Migration(format!(
+            "database schema is at version {}, but this build only knows up to {}",
+            current,
+            latest(migrations)
+        )));
+    }
+    let applied = migrations
+        .iter()
+        .take_while(|migration| migration.version <= current)
+        .count();
+    Ok(&migrations[applied..])
+}
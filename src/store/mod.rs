@@ -0,0 +1,1064 @@
+//! Storage of links and their clicks, behind [LinkStore] so the backend can be swapped
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::code::CodeGenerator;
+use crate::error::{Error, QrLinkResult};
+use sqlite::SqliteConfig;
+
+pub mod migrations;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod sqlite;
+
+/// How many random codes to draw before giving up on finding an unused one
+pub const MAX_CODE_ATTEMPTS: usize = 10;
+
+/// A stored short link
+#[derive(Clone, Debug)]
+pub struct Link {
+    pub id: u64,
+    pub url: String,
+    pub slug: Option<String>,
+    pub code: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// How many clicks it is followed for before it deletes itself
+    pub max_clicks: Option<u64>,
+    /// When it starts redirecting; until then it shows a page saying it is coming soon
+    pub activate_at: Option<DateTime<Utc>>,
+    /// Whether the query string a visitor brings is appended to the destination
+    pub forward_query: bool,
+    /// Whether a path after the code or slug is appended to the destination
+    pub forward_path: bool,
+    /// Whether search engines are asked not to index it, with `X-Robots-Tag: noindex`
+    pub noindex: bool,
+    /// Query parameters appended to the destination, with placeholders for the slug and code
+    pub utm_template: Option<String>,
+    pub title: Option<String>,
+    /// Free text for whoever manages the link
+    pub notes: Option<String>,
+    /// The `<title>` of the destination page, once it has been fetched
+    pub page_title: Option<String>,
+    /// The description the destination page gives of itself, once it has been fetched
+    pub page_description: Option<String>,
+    /// Set while its destination is flagged as harmful, when it shows a warning instead of
+    /// redirecting
+    pub quarantine: Option<Quarantine>,
+    /// The user it belongs to, or `None` for links created with the admin token, a key not
+    /// issued to any user, or before there were users
+    pub owner_id: Option<u64>,
+}
+
+impl Link {
+    /// The identifier a link is published under: its slug if it has one, otherwise its code.
+    /// Links created before codes existed are published under their numeric id.
+    pub fn public_id(&self) -> String {
+        self.slug
+            .clone()
+            .or_else(|| self.code.clone())
+            .unwrap_or_else(|| self.id.to_string())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    /// Whether it has yet to start redirecting
+    pub fn is_pending(&self) -> bool {
+        self.activate_at
+            .is_some_and(|activate_at| activate_at > Utc::now())
+    }
+}
+
+/// A link about to be created, already validated
+#[derive(Clone, Debug, Default)]
+pub struct NewLink {
+    pub url: String,
+    pub slug: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_clicks: Option<u64>,
+    pub activate_at: Option<DateTime<Utc>>,
+    pub forward_query: bool,
+    pub forward_path: bool,
+    pub noindex: bool,
+    pub utm_template: Option<String>,
+    pub title: Option<String>,
+    pub notes: Option<String>,
+    /// Set for links created with `dedupe`, which no other active link may share
+    pub url_hash: Option<String>,
+    /// What a retry of the request creating the link finds it by, until [IDEMPOTENCY_TTL]
+    /// has passed
+    pub idempotency_key: Option<String>,
+    /// Already normalized, each only once
+    pub tags: Vec<String>,
+    pub targets: LinkTargets,
+    pub owner_id: Option<u64>,
+}
+
+/// Changes to a link, already validated; `None` leaves a field as it is
+#[derive(Clone, Debug, Default)]
+pub struct LinkUpdate {
+    pub url: Option<String>,
+    pub slug: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_clicks: Option<u64>,
+    pub activate_at: Option<DateTime<Utc>>,
+    pub forward_query: Option<bool>,
+    pub forward_path: Option<bool>,
+    pub noindex: Option<bool>,
+    /// `Some(None)` removes the template
+    pub utm_template: Option<Option<String>>,
+    /// `Some(None)` removes the title
+    pub title: Option<Option<String>>,
+    /// `Some(None)` removes the notes
+    pub notes: Option<Option<String>>,
+    /// Replaces every platform target the link has
+    pub platform_targets: Option<BTreeMap<Platform, String>>,
+    /// Replaces every country target the link has
+    pub country_targets: Option<Vec<CountryTarget>>,
+    /// Replaces every language target the link has
+    pub language_targets: Option<Vec<LanguageTarget>>,
+    /// Replaces every variant the link has
+    pub variants: Option<Vec<Variant>>,
+    /// Replaces every tag the link has
+    pub tags: Option<Vec<String>>,
+}
+
+/// The kinds of device a link can send to a destination of their own
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    /// iPhones, iPads and iPods
+    Ios,
+    Android,
+    /// Desktop and laptop computers
+    Desktop,
+}
+
+impl Platform {
+    pub const ALL: [Platform; 3] = [Platform::Ios, Platform::Android, Platform::Desktop];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Platform::Ios => "ios",
+            Platform::Android => "android",
+            Platform::Desktop => "desktop",
+        }
+    }
+
+    /// Reads back [Platform::as_str]
+    fn parse(platform: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|known| known.as_str() == platform)
+    }
+}
+
+/// Destinations a link sends some visitors to instead of its own `url`, which stays the
+/// destination of everyone else unless the link splits them between `variants`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LinkTargets {
+    pub platforms: BTreeMap<Platform, String>,
+    /// Tried in order, the first one for the visitor's country winning
+    pub countries: Vec<CountryTarget>,
+    /// Tried in order for each language the visitor accepts, most preferred first
+    pub languages: Vec<LanguageTarget>,
+    /// Shared by the visitors no other target is for, by weight
+    pub variants: Vec<Variant>,
+}
+
+impl LinkTargets {
+    pub fn is_empty(&self) -> bool {
+        self.platforms.is_empty()
+            && self.countries.is_empty()
+            && self.languages.is_empty()
+            && self.variants.is_empty()
+    }
+}
+
+/// A destination for visitors from one country, or from the EU
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub struct CountryTarget {
+    /// ISO 3166-1 alpha-2 code, e.g. `DK`, or `EU` for every member state
+    pub country: String,
+    pub url: String,
+}
+
+/// A destination for visitors who accept one language
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub struct LanguageTarget {
+    /// A language tag such as `da`, which also covers `da-DK`, or `en-GB`
+    pub lang: String,
+    pub url: String,
+}
+
+/// One of the destinations a split test sends visitors to
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub struct Variant {
+    pub url: String,
+    /// The variant's share of visitors, relative to the weights of the others
+    pub weight: u32,
+}
+
+/// A destination a link had before it was repointed
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct HistoryEntry {
+    pub url: String,
+    pub replaced_at: DateTime<Utc>,
+}
+
+/// Longest referrer or user agent kept for a click; anything past it is cut off
+pub const MAX_HEADER_LENGTH: usize = 512;
+
+/// A single followed redirect
+#[derive(Clone, Debug)]
+pub struct Click {
+    pub ip_addr: String,
+    /// A stable, non-reversible stand-in for `ip_addr`, for counting unique visitors
+    pub visitor: String,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    /// Browser and OS names read from `user_agent`, so clicks can be grouped by them
+    pub browser: Option<String>,
+    pub os: Option<String>,
+    /// ISO country code and city name of the client, when they could be looked up
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub source: ClickSource,
+    /// The URL of the variant the visitor was sent to, when the link splits visitors
+    pub variant: Option<String>,
+}
+
+/// What came of recording a click
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordedClick {
+    /// Counted, and the link stays active
+    Counted,
+    /// Counted as the last of the link's `max_clicks`, which deleted it
+    Last,
+    /// Not counted, as the link is deleted or has no clicks left; one that had none left is
+    /// deleted now
+    Refused,
+}
+
+impl RecordedClick {
+    /// What becomes of a click on an active link that has been clicked `clicks` times before
+    fn after(clicks: u64, max_clicks: Option<u64>) -> Self {
+        match max_clicks {
+            Some(max_clicks) if clicks >= max_clicks => RecordedClick::Refused,
+            Some(max_clicks) if clicks + 1 == max_clicks => RecordedClick::Last,
+            _ => RecordedClick::Counted,
+        }
+    }
+}
+
+/// How a visitor came by a link
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClickSource {
+    /// Followed the link itself, e.g. shared in a message
+    #[default]
+    Link,
+    /// Scanned one of our QR codes, which mark their short link with `?src=qr`
+    Qr,
+}
+
+impl ClickSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ClickSource::Link => "link",
+            ClickSource::Qr => "qr",
+        }
+    }
+}
+
+impl Click {
+    /// A click of a visitor nothing is kept about, who only counts toward the link's clicks
+    pub fn anonymous(source: ClickSource) -> Self {
+        Click {
+            ip_addr: String::new(),
+            visitor: String::new(),
+            referrer: None,
+            user_agent: None,
+            browser: None,
+            os: None,
+            country: None,
+            city: None,
+            source,
+            variant: None,
+        }
+    }
+
+    pub fn new(ip_addr: IpAddr, referrer: Option<&str>, user_agent: Option<&str>) -> Self {
+        let truncate = |value: &str| value.chars().take(MAX_HEADER_LENGTH).collect::<String>();
+        let parsed = user_agent.and_then(|agent| woothee::parser::Parser::new().parse(agent));
+        let known = |value: &str| (value != woothee::woothee::VALUE_UNKNOWN).then(|| value.into());
+        Click {
+            ip_addr: ip_addr.to_string(),
+            visitor: visitor_hash(ip_addr),
+            referrer: referrer.map(truncate),
+            user_agent: user_agent.map(truncate),
+            browser: parsed.as_ref().and_then(|parsed| known(parsed.name)),
+            os: parsed.as_ref().and_then(|parsed| known(parsed.os)),
+            country: None,
+            city: None,
+            source: ClickSource::default(),
+            variant: None,
+        }
+    }
+}
+
+/// A stable, non-reversible stand-in for a client address, so unique visitors can still be
+/// counted once the address itself is no longer kept
+fn visitor_hash(ip_addr: IpAddr) -> String {
+    Sha256::digest(ip_addr.to_string())
+        .iter()
+        .take(16)
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The width of the time buckets clicks are counted in
+#[derive(Clone, Copy, Debug, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Hour,
+    #[default]
+    Day,
+    Week,
+}
+
+/// The clicks that fell in the bucket starting at `start`
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct ClickBucket {
+    pub start: DateTime<Utc>,
+    pub clicks: u64,
+}
+
+/// How many entries each breakdown in [LinkStats] lists
+pub const TOP_BREAKDOWN: u32 = 10;
+
+/// The clicks that share one referrer, browser, operating system or country
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Breakdown {
+    pub name: String,
+    pub clicks: u64,
+}
+
+/// The clicks sent to one variant of a split test
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct VariantStats {
+    pub url: String,
+    pub clicks: u64,
+    pub unique_visitors: u64,
+}
+
+/// A link's clicks. Those rolled up into daily totals count in `clicks`, `scans` and the
+/// buckets, as made at the start of their day; everything else covers only the clicks kept.
+#[derive(Clone, Debug)]
+pub struct LinkStats {
+    pub clicks: u64,
+    /// How many of the clicks were QR code scans; the rest followed the link directly
+    pub scans: u64,
+    pub unique_visitors: u64,
+    pub first_click: Option<DateTime<Utc>>,
+    pub last_click: Option<DateTime<Utc>>,
+    /// Oldest first, leaving out buckets without clicks
+    pub buckets: Vec<ClickBucket>,
+    /// The most common referrers, browsers, operating systems and countries, most clicks first
+    /// and at most [TOP_BREAKDOWN] of each
+    pub referrers: Vec<Breakdown>,
+    pub browsers: Vec<Breakdown>,
+    pub operating_systems: Vec<Breakdown>,
+    pub countries: Vec<Breakdown>,
+    /// Every variant a click was sent to, most clicks first
+    pub variants: Vec<VariantStats>,
+}
+
+/// A key that lets its holder use the API; only a hash of the key itself is kept
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    pub id: u64,
+    pub name: String,
+    /// The start of the key, so its holder can tell which one it is
+    pub prefix: String,
+    pub created_at: DateTime<Utc>,
+    /// The user whose links the key works on, or `None` for the links of no user
+    pub user_id: Option<u64>,
+    pub role: Role,
+}
+
+/// Someone links and API keys belong to, e.g. a team
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct User {
+    pub id: u64,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// What the user may do when signed in to the dashboard, and the most their keys may
+    pub role: Role,
+}
+
+/// What a user or API key may do, each role allowing everything the ones before it do
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Reads links and their stats
+    Viewer,
+    /// Creates links, and changes and deletes them
+    #[default]
+    Editor,
+    /// Works on every link, whoever it belongs to, and manages users, keys and settings
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Viewer, Role::Editor, Role::Admin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// Reads back [Role::as_str], taking anything else for the role allowed least
+    fn parse(role: &str) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|known| known.as_str() == role)
+            .unwrap_or(Role::Viewer)
+    }
+}
+
+/// Whose links a caller sees and changes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scope {
+    /// Every link, for the admin
+    #[default]
+    All,
+    /// The links of one user, or those of no user
+    Owner(Option<u64>),
+}
+
+impl Scope {
+    /// Whether a link belonging to `owner_id` is in scope
+    pub fn allows(self, owner_id: Option<u64>) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Owner(owner) => owner == owner_id,
+        }
+    }
+}
+
+/// Something that happened to a link, which webhooks can be sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub enum WebhookEvent {
+    #[serde(rename = "click")]
+    Click,
+    #[serde(rename = "link.created")]
+    LinkCreated,
+    #[serde(rename = "link.deleted")]
+    LinkDeleted,
+}
+
+impl WebhookEvent {
+    pub const ALL: [WebhookEvent; 3] = [
+        WebhookEvent::Click,
+        WebhookEvent::LinkCreated,
+        WebhookEvent::LinkDeleted,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WebhookEvent::Click => "click",
+            WebhookEvent::LinkCreated => "link.created",
+            WebhookEvent::LinkDeleted => "link.deleted",
+        }
+    }
+
+    /// The events of a webhook as they are stored, as a comma-separated list
+    fn join(events: &[WebhookEvent]) -> String {
+        let names: Vec<&str> = events.iter().map(|event| event.as_str()).collect();
+        names.join(",")
+    }
+
+    /// Reads back [WebhookEvent::join], skipping names this build doesn't know
+    fn split(events: &str) -> Vec<WebhookEvent> {
+        events
+            .split(',')
+            .filter_map(|name| Self::ALL.into_iter().find(|event| event.as_str() == name))
+            .collect()
+    }
+}
+
+/// An endpoint that is sent events as they happen
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    pub id: u64,
+    pub url: String,
+    /// What payloads are signed with
+    pub secret: String,
+    /// The link it is limited to, or `None` for every link
+    pub link_id: Option<u64>,
+    /// That link's code or slug
+    pub link: Option<String>,
+    pub events: Vec<WebhookEvent>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    /// Whether `event` on the link `link_id` is sent to this webhook
+    pub fn wants(&self, event: WebhookEvent, link_id: u64) -> bool {
+        self.events.contains(&event) && self.link_id.is_none_or(|id| id == link_id)
+    }
+}
+
+/// A webhook about to be registered, already validated
+#[derive(Clone, Debug)]
+pub struct NewWebhook {
+    pub url: String,
+    pub secret: String,
+    pub link_id: Option<u64>,
+    pub events: Vec<WebhookEvent>,
+}
+
+/// Where a delivery stands
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Not sent yet, or to be retried
+    Pending,
+    Delivered,
+    /// Given up on after the last attempt failed
+    Failed,
+}
+
+impl DeliveryStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "delivered" => DeliveryStatus::Delivered,
+            "failed" => DeliveryStatus::Failed,
+            _ => DeliveryStatus::Pending,
+        }
+    }
+}
+
+/// One event sent, or to be sent, to one webhook
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    pub id: u64,
+    pub event: String,
+    /// The JSON body, exactly as it is signed and sent
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    /// When a pending delivery is next tried
+    pub next_attempt_at: DateTime<Utc>,
+    /// The HTTP status the endpoint last answered with, if it answered
+    pub response_status: Option<u16>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+/// A delivery claimed for sending, with where to send it
+#[derive(Clone, Debug)]
+pub struct DueDelivery {
+    pub id: u64,
+    pub event: String,
+    pub payload: String,
+    /// Attempts made before this one
+    pub attempts: u32,
+    pub url: String,
+    pub secret: String,
+}
+
+/// What came of an attempt at a delivery
+#[derive(Clone, Debug)]
+pub enum DeliveryOutcome {
+    Delivered {
+        response_status: u16,
+    },
+    /// Failed, to be tried again at `retry_at`, or never if there is none
+    Failed {
+        response_status: Option<u16>,
+        error: String,
+        retry_at: Option<DateTime<Utc>>,
+    },
+}
+
+/// What links are listed by ordered by
+#[derive(Clone, Copy, Debug, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkSort {
+    #[default]
+    CreatedAt,
+    Clicks,
+}
+
+/// Which links are listed by how their destination last answered
+#[derive(Clone, Copy, Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthFilter {
+    /// Answered with a status below 400
+    Ok,
+    /// Answered with a 4xx or 5xx status, or not at all
+    Broken,
+    /// Not checked since it was created or repointed
+    Unchecked,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortOrder {
+    fn sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+/// Which links to list and in what order; unset filters let every link through
+#[derive(Clone, Debug, Default)]
+pub struct LinkFilter {
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    /// Only deleted links, or only links that aren't
+    pub deleted: Option<bool>,
+    /// Case-insensitive substring of the destination
+    pub search: Option<String>,
+    /// Only links with this tag
+    pub tag: Option<String>,
+    /// Only links whose destination last answered like this
+    pub health: Option<HealthFilter>,
+    /// Only quarantined links, or only links that aren't
+    pub quarantined: Option<bool>,
+    /// Only the links of one user, or of no user
+    pub scope: Scope,
+    pub sort: LinkSort,
+    pub order: SortOrder,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+/// A link with its click count, as listed
+#[derive(Clone, Debug)]
+pub struct LinkSummary {
+    pub link: Link,
+    pub clicks: u64,
+    /// Alphabetically
+    pub tags: Vec<String>,
+}
+
+/// How a link's destination answered when it was last checked
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct LinkHealth {
+    pub checked_at: DateTime<Utc>,
+    /// The status it answered with, unless it didn't
+    pub status: Option<u16>,
+    /// Milliseconds until it answered or was given up on
+    pub latency_ms: u64,
+    /// Why it didn't answer, e.g. a timeout
+    pub error: Option<String>,
+    /// Whether it answered with a 4xx or 5xx status, or not at all
+    pub broken: bool,
+}
+
+/// What a destination was flagged for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Threat {
+    Malware,
+    /// Pages posing as another site to get visitors to give something away
+    Phishing,
+    UnwantedSoftware,
+    /// Apps that harm the devices they are installed on
+    HarmfulApplication,
+    /// On the configured `safety.blocklist`
+    Blocklisted,
+}
+
+impl Threat {
+    pub const ALL: [Threat; 5] = [
+        Threat::Malware,
+        Threat::Phishing,
+        Threat::UnwantedSoftware,
+        Threat::HarmfulApplication,
+        Threat::Blocklisted,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Threat::Malware => "malware",
+            Threat::Phishing => "phishing",
+            Threat::UnwantedSoftware => "unwanted_software",
+            Threat::HarmfulApplication => "harmful_application",
+            Threat::Blocklisted => "blocklisted",
+        }
+    }
+
+    /// Reads back [Threat::as_str]
+    fn parse(threat: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|known| known.as_str() == threat)
+    }
+}
+
+/// Why, and since when, a link shows a warning instead of redirecting
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Quarantine {
+    pub threat: Threat,
+    pub since: DateTime<Utc>,
+}
+
+impl Quarantine {
+    /// The quarantine the `threat` and `quarantined_at` columns of a link describe, if any
+    fn from_columns(threat: Option<String>, since: Option<DateTime<Utc>>) -> Option<Self> {
+        Some(Quarantine {
+            threat: Threat::parse(&threat?)?,
+            since: since?,
+        })
+    }
+}
+
+/// What a destination page says about itself
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PageInfo {
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+/// A tag and how many active links have it
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct TagCount {
+    pub tag: String,
+    pub links: u64,
+}
+
+/// How many links a scope has, as per-user quotas count them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkUsage {
+    /// Links not deleted
+    pub active: u64,
+    /// Links created since the time asked about, deleted or not
+    pub created_since: u64,
+}
+
+/// One page of listed links, and how many links matched in all
+#[derive(Clone, Debug)]
+pub struct LinkPage {
+    pub links: Vec<LinkSummary>,
+    pub total: u64,
+}
+
+/// `search` as a `LIKE` pattern matching it anywhere, with its own wildcards escaped by `\`
+fn like_pattern(search: &str) -> String {
+    let escaped = search
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{escaped}%")
+}
+
+/// How busy the store's connection pools are, all of them together
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PoolStats {
+    pub connections: u32,
+    pub idle: u32,
+    pub max: u32,
+}
+
+#[async_trait]
+pub trait LinkStore: Send + Sync {
+    fn pool_stats(&self) -> PoolStats;
+
+    /// Applies the migrations the database hasn't seen yet, returning the schema version it is
+    /// at afterwards
+    async fn migrate(&self) -> QrLinkResult<u32>;
+
+    /// The schema version the database is at, which also shows it can be reached
+    async fn schema_version(&self) -> QrLinkResult<u32>;
+
+    /// The schema version this build migrates databases to
+    fn latest_schema_version(&self) -> u32;
+
+    /// Writes out anything the database still holds in memory or in logs and closes the
+    /// connections; called once no more requests are being served
+    async fn close(&self) -> QrLinkResult<()>;
+
+    /// Copies the database into a new file at `path` while it stays in use. Only SQLite
+    /// databases are backed up this way; Postgres has its own tools for that.
+    async fn backup(&self, path: &Path) -> QrLinkResult<()>;
+
+    /// Stores a new link under an unused code drawn from `codes`
+    async fn create(&self, link: NewLink, codes: &CodeGenerator) -> QrLinkResult<Link>;
+
+    /// Stores new links in one transaction, answering for each in order. A link whose slug is
+    /// taken, by then also by one earlier in `links`, gets its conflict and is left out; any
+    /// other failure stores none of them.
+    async fn create_many(
+        &self,
+        links: Vec<NewLink>,
+        codes: &CodeGenerator,
+    ) -> QrLinkResult<Vec<QrLinkResult<Link>>>;
+
+    /// Finds a link by its code or slug, or by its numeric id if it predates codes.
+    /// Deleted links are found too.
+    async fn resolve(&self, external_id: &str) -> QrLinkResult<Link>;
+
+    /// The active link created with `url_hash`, if any
+    async fn find_duplicate(&self, url_hash: &str) -> QrLinkResult<Option<Link>>;
+
+    /// The link of `owner_id` created with `idempotency_key`, deleted or not, if any
+    async fn find_idempotent(
+        &self,
+        idempotency_key: &str,
+        owner_id: Option<u64>,
+    ) -> QrLinkResult<Option<Link>>;
+
+    /// Applies `update` to an active link, recording a changed destination in its history
+    async fn update(&self, id: u64, update: LinkUpdate) -> QrLinkResult<Link>;
+
+    /// Soft-deletes a link, keeping the time it was first deleted
+    async fn delete(&self, id: u64) -> QrLinkResult<()>;
+
+    /// Undoes a soft-delete
+    async fn restore(&self, id: u64) -> QrLinkResult<Link>;
+
+    /// The previous destinations of a link, newest first
+    async fn history(&self, id: u64) -> QrLinkResult<Vec<HistoryEntry>>;
+
+    /// Where a link sends visitors it doesn't send to its own `url`
+    async fn targets(&self, id: u64) -> QrLinkResult<LinkTargets>;
+
+    /// The tags of a link, alphabetically
+    async fn tags(&self, id: u64) -> QrLinkResult<Vec<String>>;
+
+    /// Stores what was fetched from a link's destination page, unless the link has since been
+    /// repointed away from `url`
+    async fn set_page_info(&self, id: u64, url: &str, page: PageInfo) -> QrLinkResult<()>;
+
+    /// Up to `limit` active links whose destination hasn't been checked since `checked_before`,
+    /// those never checked first and then the longest unchecked
+    async fn links_due_for_check(
+        &self,
+        checked_before: DateTime<Utc>,
+        limit: u32,
+    ) -> QrLinkResult<Vec<Link>>;
+
+    /// Stores how a link's destination answered, in place of its last check, unless the link
+    /// has since been repointed away from `url`
+    async fn record_health(&self, id: u64, url: &str, health: LinkHealth) -> QrLinkResult<()>;
+
+    /// How a link's destination answered when it was last checked, if it has been since the
+    /// link was created or repointed
+    async fn health(&self, id: u64) -> QrLinkResult<Option<LinkHealth>>;
+
+    /// Up to `limit` active links whose destination hasn't been checked for threats since
+    /// `checked_before`, those never checked first and then the longest unchecked
+    async fn links_due_for_safety_check(
+        &self,
+        checked_before: DateTime<Utc>,
+        limit: u32,
+    ) -> QrLinkResult<Vec<Link>>;
+
+    /// Records that a link's destination was checked for threats, quarantining the link for
+    /// `threat` or releasing it without one, unless it has since been repointed away from `url`.
+    /// A link that stays quarantined keeps the time it was first quarantined.
+    async fn record_safety(&self, id: u64, url: &str, threat: Option<Threat>) -> QrLinkResult<()>;
+
+    /// Every tag an active link in `scope` has, alphabetically, with how many do
+    async fn tag_counts(&self, scope: Scope) -> QrLinkResult<Vec<TagCount>>;
+
+    /// The salt addresses of visitors on `day` are hashed with, which is `fresh` unless another
+    /// was kept for that day first. Salts of earlier days are deleted, so addresses can't be
+    /// worked back from their hashes once the day is over.
+    async fn visitor_salt(&self, day: NaiveDate, fresh: &str) -> QrLinkResult<String>;
+
+    /// Counts a click on an active link, deleting the link once it has had its `max_clicks`
+    async fn record_click(&self, id: u64, click: Click) -> QrLinkResult<RecordedClick>;
+
+    /// Counts clicks on links without a click limit in one go, as they come out of the
+    /// buffer they waited in
+    async fn record_clicks(&self, clicks: Vec<(u64, Click)>) -> QrLinkResult<()>;
+
+    /// Click totals for a link, with the clicks counted per `granularity` bucket
+    async fn stats(&self, id: u64, granularity: Granularity) -> QrLinkResult<LinkStats>;
+
+    /// Deletes the clicks made before `before`, returning how many there were. With `roll_up`
+    /// they are first added to daily totals per link and source, which keep counting in
+    /// [LinkStats] and toward `max_clicks`.
+    async fn prune_clicks(&self, before: DateTime<Utc>, roll_up: bool) -> QrLinkResult<u64>;
+
+    /// A page of the links `filter` lets through, deleted ones included unless it says otherwise
+    async fn list(&self, filter: LinkFilter) -> QrLinkResult<LinkPage>;
+
+    /// How many links `scope` has, and how many of them were created since `since`
+    async fn link_usage(&self, scope: Scope, since: DateTime<Utc>) -> QrLinkResult<LinkUsage>;
+
+    /// Stores a new user with `role`, whose name no other user may have, signing in to the
+    /// dashboard with the password hashing to `password_hash` if there is one
+    async fn create_user(
+        &self,
+        name: &str,
+        password_hash: Option<&str>,
+        role: Role,
+    ) -> QrLinkResult<User>;
+
+    /// The user with `id`, if there is one
+    async fn user(&self, id: u64) -> QrLinkResult<Option<User>>;
+
+    /// Gives the user with `id` another role, which also caps that of their keys
+    async fn set_user_role(&self, id: u64, role: Role) -> QrLinkResult<User>;
+
+    /// The user named `name` with the hash of their password, unless there is no such user or
+    /// they have no password
+    async fn user_credentials(&self, name: &str) -> QrLinkResult<Option<(User, String)>>;
+
+    /// The user signing in through OpenID Connect as `subject`, made on their first sign-in
+    /// with `name`, which no other user may have
+    async fn oidc_user(&self, subject: &str, name: &str) -> QrLinkResult<User>;
+
+    /// Every user, oldest first
+    async fn users(&self) -> QrLinkResult<Vec<User>>;
+
+    /// Stores a newly issued key with `role` under `key_hash`, for the links of `user_id` if
+    /// there is one
+    async fn create_api_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        prefix: &str,
+        user_id: Option<u64>,
+        role: Role,
+    ) -> QrLinkResult<ApiKey>;
+
+    /// The key hashing to `key_hash`, unless there is none or it has been revoked. Its role is
+    /// lowered to its user's, should theirs be the lower one.
+    async fn find_api_key(&self, key_hash: &str) -> QrLinkResult<Option<ApiKey>>;
+
+    /// Stops a key from being accepted, keeping the time it was first revoked
+    async fn revoke_api_key(&self, id: u64) -> QrLinkResult<()>;
+
+    async fn create_webhook(&self, webhook: NewWebhook) -> QrLinkResult<Webhook>;
+
+    /// Every registered webhook, oldest first
+    async fn webhooks(&self) -> QrLinkResult<Vec<Webhook>>;
+
+    /// Unregisters a webhook, dropping its deliveries with it
+    async fn delete_webhook(&self, id: u64) -> QrLinkResult<()>;
+
+    /// Queues `payload` to be sent to each of `webhook_ids` as `event`
+    async fn enqueue_deliveries(
+        &self,
+        webhook_ids: Vec<u64>,
+        event: WebhookEvent,
+        payload: String,
+    ) -> QrLinkResult<()>;
+
+    /// Takes up to `limit` pending deliveries that are due, oldest first, and puts them off
+    /// until `lease_until`, so no one else sends them meanwhile
+    async fn claim_deliveries(
+        &self,
+        limit: u32,
+        lease_until: DateTime<Utc>,
+    ) -> QrLinkResult<Vec<DueDelivery>>;
+
+    /// Records the attempt at a claimed delivery
+    async fn finish_delivery(&self, id: u64, outcome: DeliveryOutcome) -> QrLinkResult<()>;
+
+    /// The latest `limit` deliveries to a webhook, newest first
+    async fn deliveries(&self, webhook_id: u64, limit: u32) -> QrLinkResult<Vec<Delivery>>;
+
+    /// Like [LinkStore::resolve], but deleted links are not found
+    async fn resolve_active(&self, external_id: &str) -> QrLinkResult<Link> {
+        let link = self.resolve(external_id).await?;
+        if link.deleted_at.is_some() {
+            return Err(Error::NotFound(external_id.to_owned()));
+        }
+        Ok(link)
+    }
+
+    /// Like [LinkStore::resolve], but links out of `scope` are not found, so callers can't
+    /// tell whether another user's link exists
+    async fn resolve_in(&self, external_id: &str, scope: Scope) -> QrLinkResult<Link> {
+        let link = self.resolve(external_id).await?;
+        if !scope.allows(link.owner_id) {
+            return Err(Error::NotFound(external_id.to_owned()));
+        }
+        Ok(link)
+    }
+
+    /// Like [LinkStore::resolve_in], but deleted links are not found either
+    async fn resolve_active_in(&self, external_id: &str, scope: Scope) -> QrLinkResult<Link> {
+        let link = self.resolve_in(external_id, scope).await?;
+        if link.deleted_at.is_some() {
+            return Err(Error::NotFound(external_id.to_owned()));
+        }
+        Ok(link)
+    }
+}
+
+/// How long a link can be found by the idempotency key it was created with
+pub const IDEMPOTENCY_TTL: chrono::TimeDelta = chrono::TimeDelta::hours(24);
+
+/// The `database` setting for a SQLite database that only lives as long as the process
+pub static IN_MEMORY: &str = ":memory:";
+
+/// Whether `database` is a Postgres URL rather than a SQLite database
+pub fn is_postgres(database: &str) -> bool {
+    database.starts_with("postgres://") || database.starts_with("postgresql://")
+}
+
+/// Opens the store `database` names: a `postgres://` URL when built with the `postgres`
+/// feature, [IN_MEMORY], and otherwise the path of a SQLite file, opened with `sqlite`
+pub async fn open(database: &str, sqlite: &SqliteConfig) -> QrLinkResult<Arc<dyn LinkStore>> {
+    if is_postgres(database) {
+        #[cfg(feature = "postgres")]
+        return Ok(Arc::new(postgres::PostgresStore::connect(database).await?));
+        #[cfg(not(feature = "postgres"))]
+        return Err(Error::Validation(
+            "this build has no Postgres support, enable the `postgres` feature".into(),
+        ));
+    }
+    if database == IN_MEMORY {
+        return Ok(Arc::new(sqlite::SqliteStore::open_in_memory(sqlite)?));
+    }
+    Ok(Arc::new(sqlite::SqliteStore::open(database, sqlite)?))
+}
+
+pub fn slug_taken(slug: &str) -> Error {
+    Error::Conflict(format!("slug '{}' is already taken", slug))
+}
+
+pub fn user_taken(name: &str) -> Error {
+    Error::Conflict(format!("user '{}' already exists", name))
+}
@@ -0,0 +1,1772 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use deadpool_postgres::{GenericClient, Runtime};
+use tokio_postgres::NoTls;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::ToSql;
+
+use super::{
+    ApiKey, Breakdown, Click, ClickBucket, CountryTarget, Delivery, DeliveryOutcome,
+    DeliveryStatus, DueDelivery, Granularity, HealthFilter, HistoryEntry, LanguageTarget, Link,
+    LinkFilter, LinkHealth, LinkPage, LinkSort, LinkStats, LinkStore, LinkSummary, LinkTargets,
+    LinkUpdate, LinkUsage, NewLink, NewWebhook, PageInfo, Platform, PoolStats, Quarantine,
+    RecordedClick, Role, Scope, TOP_BREAKDOWN, TagCount, Threat, User, Variant, VariantStats,
+    Webhook, WebhookEvent, like_pattern, migrations,
+};
+use crate::code::CodeGenerator;
+use crate::error::{Error, QrLinkResult};
+
+/// Advisory lock key held while migrating
+const MIGRATION_LOCK: i64 = 0x71726c696e6b;
+
+static LINK_COLUMNS: &str = "id, external_id, slug, code, created_at, deleted_at, expires_at,
+    max_clicks, activate_at, forward_query, forward_path, utm_template, title, notes, page_title,
+    page_description, threat, quarantined_at, owner_id, noindex";
+
+/// Clicks per link, those rolled up into daily totals included
+static CLICK_COUNTS: &str = "SELECT url_id, SUM(clicks)::BIGINT AS clicks FROM (
+        SELECT url_id, COUNT(*) AS clicks FROM stats GROUP BY url_id
+        UNION ALL SELECT url_id, SUM(clicks)::BIGINT FROM click_rollups GROUP BY url_id
+    ) AS counted GROUP BY url_id";
+
+static API_KEY_COLUMNS: &str = "id, name, prefix, created_at, user_id, role";
+
+static USER_COLUMNS: &str = "id, name, created_at, role";
+
+/// Webhooks are selected joined with the link they are limited to, if any, for its code or slug
+static WEBHOOK_COLUMNS: &str = "webhooks.id, webhooks.url, webhooks.secret, webhooks.link_id,
+    COALESCE(urls.slug, urls.code, urls.id::text), webhooks.events, webhooks.created_at";
+
+static DELIVERY_COLUMNS: &str = "id, event, payload, status, attempts, next_attempt_at,
+    response_status, last_error, created_at, delivered_at";
+
+/// Links in a Postgres database, so several instances of the service can share them
+#[derive(Clone)]
+pub struct PostgresStore {
+    pool: deadpool_postgres::Pool,
+}
+
+impl PostgresStore {
+    /// Connects to the database at `url`; the schema is brought up to date by
+    /// [LinkStore::migrate]
+    pub async fn connect(url: &str) -> QrLinkResult<Self> {
+        let config = deadpool_postgres::Config {
+            url: Some(url.to_owned()),
+            ..Default::default()
+        };
+        let pool = config
+            .create_pool(Some(Runtime::Tokio1), NoTls)
+            .map_err(|error| Error::PostgresPool(error.to_string()))?;
+        Ok(PostgresStore { pool })
+    }
+
+    async fn client(&self) -> QrLinkResult<deadpool_postgres::Object> {
+        self.pool
+            .get()
+            .await
+            .map_err(|error| Error::PostgresPool(error.to_string()))
+    }
+}
+
+#[async_trait]
+impl LinkStore for PostgresStore {
+    fn pool_stats(&self) -> PoolStats {
+        let status = self.pool.status();
+        PoolStats {
+            connections: status.size as u32,
+            idle: status.available as u32,
+            max: status.max_size as u32,
+        }
+    }
+
+    async fn migrate(&self) -> QrLinkResult<u32> {
+        let mut client = self.client().await?;
+        // One transaction under an advisory lock, so instances starting together don't race to
+        // apply the same migration
+        let tx = client.transaction().await.map_err(Error::Postgres)?;
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+            .await
+            .map_err(Error::Postgres)?;
+        tx.batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+                version INTEGER NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .await
+        .map_err(Error::Postgres)?;
+        let current: i32 = tx
+            .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])
+            .await
+            .map_err(Error::Postgres)?
+            .get(0);
+
+        for migration in migrations::pending(migrations::POSTGRES, current as u32)? {
+            tx.batch_execute(migration.sql)
+                .await
+                .map_err(Error::Postgres)?;
+            tx.execute(
+                "INSERT INTO schema_version (version) VALUES ($1)",
+                &[&(migration.version as i32)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        }
+        tx.commit().await.map_err(Error::Postgres)?;
+        Ok(migrations::latest(migrations::POSTGRES))
+    }
+
+    async fn schema_version(&self) -> QrLinkResult<u32> {
+        let client = self.client().await?;
+        let version: i32 = client
+            .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])
+            .await
+            .map_err(Error::Postgres)?
+            .get(0);
+        Ok(version as u32)
+    }
+
+    fn latest_schema_version(&self) -> u32 {
+        migrations::latest(migrations::POSTGRES)
+    }
+
+    async fn close(&self) -> QrLinkResult<()> {
+        self.pool.close();
+        Ok(())
+    }
+
+    async fn backup(&self, _path: &Path) -> QrLinkResult<()> {
+        Err(Error::Config(
+            "backups are only made of SQLite databases, use pg_dump for Postgres".into(),
+        ))
+    }
+
+    async fn create(&self, link: NewLink, codes: &CodeGenerator) -> QrLinkResult<Link> {
+        let client = self.client().await?;
+        insert_link(&client, &link, codes).await
+    }
+
+    async fn create_many(
+        &self,
+        links: Vec<NewLink>,
+        codes: &CodeGenerator,
+    ) -> QrLinkResult<Vec<QrLinkResult<Link>>> {
+        let mut client = self.client().await?;
+        let mut tx = client.transaction().await.map_err(Error::Postgres)?;
+        let mut created = Vec::with_capacity(links.len());
+        for link in &links {
+            // A failed statement spoils the whole transaction, unless it ran in a savepoint
+            let savepoint = tx.savepoint("link").await.map_err(Error::Postgres)?;
+            match insert_link(&savepoint, link, codes).await {
+                Err(error @ Error::Conflict(_)) => {
+                    savepoint.rollback().await.map_err(Error::Postgres)?;
+                    created.push(Err(error));
+                }
+                result => {
+                    let link = result?;
+                    savepoint.commit().await.map_err(Error::Postgres)?;
+                    created.push(Ok(link));
+                }
+            }
+        }
+        tx.commit().await.map_err(Error::Postgres)?;
+        Ok(created)
+    }
+
+    async fn resolve(&self, external_id: &str) -> QrLinkResult<Link> {
+        let client = self.client().await?;
+        let id = resolve_id(&client, external_id).await?;
+        get_link(&client, id).await
+    }
+
+    async fn find_duplicate(&self, url_hash: &str) -> QrLinkResult<Option<Link>> {
+        let client = self.client().await?;
+        let row = client
+            .query_opt(
+                &format!(
+                    "SELECT {LINK_COLUMNS} FROM urls WHERE url_hash = $1 AND deleted_at IS NULL"
+                ),
+                &[&url_hash],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(row.as_ref().map(link_from_row))
+    }
+
+    async fn find_idempotent(
+        &self,
+        idempotency_key: &str,
+        owner_id: Option<u64>,
+    ) -> QrLinkResult<Option<Link>> {
+        let client = self.client().await?;
+        let row = client
+            .query_opt(
+                &format!(
+                    "SELECT {LINK_COLUMNS} FROM urls
+                     WHERE idempotency_key = $1 AND owner_id IS NOT DISTINCT FROM $2"
+                ),
+                &[&idempotency_key, &owner_id.map(|id| id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(row.as_ref().map(link_from_row))
+    }
+
+    async fn update(&self, id: u64, update: LinkUpdate) -> QrLinkResult<Link> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(Error::Postgres)?;
+
+        let current_url: String = tx
+            .query_opt(
+                "SELECT external_id FROM urls WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .ok_or_else(|| Error::NotFound(id.to_string()))?
+            .get(0);
+
+        if let Some(url) = update.url.filter(|url| *url != current_url) {
+            tx.execute(
+                "INSERT INTO url_history (url_id, external_id) VALUES ($1, $2)",
+                &[&(id as i64), &current_url],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+            tx.execute(
+                "UPDATE urls SET external_id = $1, url_hash = NULL, page_title = NULL,
+                    page_description = NULL, threat = NULL, quarantined_at = NULL,
+                    safety_checked_at = NULL
+                 WHERE id = $2",
+                &[&url, &(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+            tx.execute("DELETE FROM link_health WHERE url_id = $1", &[&(id as i64)])
+                .await
+                .map_err(Error::Postgres)?;
+        }
+        if let Some(slug) = &update.slug {
+            ensure_slug_available(&tx, slug, Some(id)).await?;
+            tx.execute(
+                "UPDATE urls SET slug = $1 WHERE id = $2",
+                &[slug, &(id as i64)],
+            )
+            .await
+            .map_err(slug_conflict(slug))?;
+        }
+        if let Some(expires_at) = update.expires_at {
+            tx.execute(
+                "UPDATE urls SET expires_at = $1 WHERE id = $2",
+                &[&expires_at, &(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        }
+        if let Some(max_clicks) = update.max_clicks {
+            tx.execute(
+                "UPDATE urls SET max_clicks = $1 WHERE id = $2",
+                &[&(max_clicks as i64), &(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        }
+        if let Some(activate_at) = update.activate_at {
+            tx.execute(
+                "UPDATE urls SET activate_at = $1 WHERE id = $2",
+                &[&activate_at, &(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        }
+        if let Some(forward_query) = update.forward_query {
+            tx.execute(
+                "UPDATE urls SET forward_query = $1 WHERE id = $2",
+                &[&forward_query, &(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        }
+        if let Some(forward_path) = update.forward_path {
+            tx.execute(
+                "UPDATE urls SET forward_path = $1 WHERE id = $2",
+                &[&forward_path, &(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        }
+        if let Some(noindex) = update.noindex {
+            tx.execute(
+                "UPDATE urls SET noindex = $1 WHERE id = $2",
+                &[&noindex, &(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        }
+        if let Some(utm_template) = &update.utm_template {
+            tx.execute(
+                "UPDATE urls SET utm_template = $1 WHERE id = $2",
+                &[utm_template, &(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        }
+        if let Some(title) = &update.title {
+            tx.execute(
+                "UPDATE urls SET title = $1 WHERE id = $2",
+                &[title, &(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        }
+        if let Some(notes) = &update.notes {
+            tx.execute(
+                "UPDATE urls SET notes = $1 WHERE id = $2",
+                &[notes, &(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        }
+        if let Some(platforms) = &update.platform_targets {
+            replace_platform_targets(&tx, id, platforms).await?;
+        }
+        if let Some(countries) = &update.country_targets {
+            replace_country_targets(&tx, id, countries).await?;
+        }
+        if let Some(languages) = &update.language_targets {
+            replace_language_targets(&tx, id, languages).await?;
+        }
+        if let Some(variants) = &update.variants {
+            replace_variants(&tx, id, variants).await?;
+        }
+        if let Some(tags) = &update.tags {
+            replace_tags(&tx, id, tags).await?;
+        }
+
+        tx.commit().await.map_err(Error::Postgres)?;
+        get_link(&client, id).await
+    }
+
+    async fn delete(&self, id: u64) -> QrLinkResult<()> {
+        self.client()
+            .await?
+            .execute(
+                "UPDATE urls SET deleted_at = COALESCE(deleted_at, now()) WHERE id = $1",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(())
+    }
+
+    async fn restore(&self, id: u64) -> QrLinkResult<Link> {
+        let client = self.client().await?;
+        // A duplicate created while the link was deleted keeps their destination's hash
+        client
+            .execute(
+                "UPDATE urls SET deleted_at = NULL,
+                    url_hash = CASE WHEN EXISTS (
+                        SELECT 1 FROM urls other
+                        WHERE other.url_hash = urls.url_hash AND other.deleted_at IS NULL
+                    ) THEN NULL ELSE url_hash END
+                WHERE id = $1",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        get_link(&client, id).await
+    }
+
+    async fn history(&self, id: u64) -> QrLinkResult<Vec<HistoryEntry>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT external_id, replaced_at FROM url_history
+                 WHERE url_id = $1 ORDER BY replaced_at DESC, id DESC",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(rows
+            .iter()
+            .map(|row| HistoryEntry {
+                url: row.get(0),
+                replaced_at: row.get(1),
+            })
+            .collect())
+    }
+
+    async fn targets(&self, id: u64) -> QrLinkResult<LinkTargets> {
+        let client = self.client().await?;
+        let platforms = client
+            .query(
+                "SELECT platform, url FROM link_targets WHERE url_id = $1",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .iter()
+            .filter_map(|row| Some((Platform::parse(row.get(0))?, row.get(1))))
+            .collect();
+        let countries = client
+            .query(
+                "SELECT country, url FROM link_country_targets
+                 WHERE url_id = $1 ORDER BY position",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .iter()
+            .map(|row| CountryTarget {
+                country: row.get(0),
+                url: row.get(1),
+            })
+            .collect();
+        let languages = client
+            .query(
+                "SELECT lang, url FROM link_language_targets
+                 WHERE url_id = $1 ORDER BY position",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .iter()
+            .map(|row| LanguageTarget {
+                lang: row.get(0),
+                url: row.get(1),
+            })
+            .collect();
+        let variants = client
+            .query(
+                "SELECT url, weight FROM link_variants WHERE url_id = $1 ORDER BY position",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .iter()
+            .map(|row| Variant {
+                url: row.get(0),
+                weight: row.get::<_, i32>(1) as u32,
+            })
+            .collect();
+        Ok(LinkTargets {
+            platforms,
+            countries,
+            languages,
+            variants,
+        })
+    }
+
+    async fn tags(&self, id: u64) -> QrLinkResult<Vec<String>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT tags.name FROM link_tags JOIN tags ON tags.id = link_tags.tag_id
+                 WHERE link_tags.url_id = $1 ORDER BY tags.name",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn set_page_info(&self, id: u64, url: &str, page: PageInfo) -> QrLinkResult<()> {
+        self.client()
+            .await?
+            .execute(
+                "UPDATE urls SET page_title = $1, page_description = $2
+                 WHERE id = $3 AND external_id = $4",
+                &[&page.title, &page.description, &(id as i64), &url],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(())
+    }
+
+    async fn links_due_for_check(
+        &self,
+        checked_before: DateTime<Utc>,
+        limit: u32,
+    ) -> QrLinkResult<Vec<Link>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                &format!(
+                    "SELECT {LINK_COLUMNS} FROM urls
+                     LEFT JOIN link_health ON link_health.url_id = urls.id
+                     WHERE deleted_at IS NULL AND (expires_at IS NULL OR expires_at > now())
+                        AND (checked_at IS NULL OR checked_at < $1)
+                     ORDER BY checked_at NULLS FIRST, id LIMIT $2"
+                ),
+                &[&checked_before, &i64::from(limit)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(rows.iter().map(link_from_row).collect())
+    }
+
+    async fn record_health(&self, id: u64, url: &str, health: LinkHealth) -> QrLinkResult<()> {
+        self.client()
+            .await?
+            .execute(
+                "INSERT INTO link_health (url_id, checked_at, status, latency_ms, error, broken)
+                 SELECT id, $1, $2, $3, $4, $5 FROM urls WHERE id = $6 AND external_id = $7
+                 ON CONFLICT (url_id) DO UPDATE SET checked_at = excluded.checked_at,
+                    status = excluded.status, latency_ms = excluded.latency_ms,
+                    error = excluded.error, broken = excluded.broken",
+                &[
+                    &health.checked_at,
+                    &health.status.map(i32::from),
+                    &(health.latency_ms as i64),
+                    &health.error,
+                    &health.broken,
+                    &(id as i64),
+                    &url,
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(())
+    }
+
+    async fn health(&self, id: u64) -> QrLinkResult<Option<LinkHealth>> {
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                "SELECT checked_at, status, latency_ms, error, broken FROM link_health
+                 WHERE url_id = $1",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(row.map(|row| LinkHealth {
+            checked_at: row.get(0),
+            status: row.get::<_, Option<i32>>(1).map(|status| status as u16),
+            latency_ms: row.get::<_, i64>(2) as u64,
+            error: row.get(3),
+            broken: row.get(4),
+        }))
+    }
+
+    async fn links_due_for_safety_check(
+        &self,
+        checked_before: DateTime<Utc>,
+        limit: u32,
+    ) -> QrLinkResult<Vec<Link>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                &format!(
+                    "SELECT {LINK_COLUMNS} FROM urls
+                     WHERE deleted_at IS NULL AND (expires_at IS NULL OR expires_at > now())
+                        AND (safety_checked_at IS NULL OR safety_checked_at < $1)
+                     ORDER BY safety_checked_at NULLS FIRST, id LIMIT $2"
+                ),
+                &[&checked_before, &i64::from(limit)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(rows.iter().map(link_from_row).collect())
+    }
+
+    async fn record_safety(&self, id: u64, url: &str, threat: Option<Threat>) -> QrLinkResult<()> {
+        self.client()
+            .await?
+            .execute(
+                "UPDATE urls SET safety_checked_at = now(), threat = $1::TEXT,
+                    quarantined_at = CASE WHEN $1::TEXT IS NULL THEN NULL
+                        ELSE COALESCE(quarantined_at, now()) END
+                 WHERE id = $2 AND external_id = $3",
+                &[&threat.map(Threat::as_str), &(id as i64), &url],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(())
+    }
+
+    async fn tag_counts(&self, scope: Scope) -> QrLinkResult<Vec<TagCount>> {
+        let (any_owner, owner_id) = match scope {
+            Scope::All => (true, None),
+            Scope::Owner(owner_id) => (false, owner_id.map(|id| id as i64)),
+        };
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT tags.name, COUNT(*) FROM tags
+                 JOIN link_tags ON link_tags.tag_id = tags.id
+                 JOIN urls ON urls.id = link_tags.url_id
+                 WHERE urls.deleted_at IS NULL
+                    AND ($1 OR urls.owner_id IS NOT DISTINCT FROM $2)
+                 GROUP BY tags.name ORDER BY tags.name",
+                &[&any_owner, &owner_id],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(rows
+            .iter()
+            .map(|row| TagCount {
+                tag: row.get(0),
+                links: row.get::<_, i64>(1) as u64,
+            })
+            .collect())
+    }
+
+    async fn link_usage(&self, scope: Scope, since: DateTime<Utc>) -> QrLinkResult<LinkUsage> {
+        let (any_owner, owner_id) = match scope {
+            Scope::All => (true, None),
+            Scope::Owner(owner_id) => (false, owner_id.map(|id| id as i64)),
+        };
+        let row = self
+            .client()
+            .await?
+            .query_one(
+                "SELECT
+                    COUNT(*) FILTER (WHERE deleted_at IS NULL),
+                    COUNT(*) FILTER (WHERE created_at >= $3)
+                 FROM urls WHERE $1 OR owner_id IS NOT DISTINCT FROM $2",
+                &[&any_owner, &owner_id, &since],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(LinkUsage {
+            active: row.get::<_, i64>(0) as u64,
+            created_since: row.get::<_, i64>(1) as u64,
+        })
+    }
+
+    async fn list(&self, filter: LinkFilter) -> QrLinkResult<LinkPage> {
+        let mut conditions = Vec::new();
+        let mut params: Vec<Box<dyn ToSql + Sync + Send>> = Vec::new();
+        if let Some(after) = filter.created_after {
+            params.push(Box::new(after));
+            conditions.push(format!("created_at >= ${}", params.len()));
+        }
+        if let Some(before) = filter.created_before {
+            params.push(Box::new(before));
+            conditions.push(format!("created_at < ${}", params.len()));
+        }
+        match filter.deleted {
+            Some(true) => conditions.push("deleted_at IS NOT NULL".into()),
+            Some(false) => conditions.push("deleted_at IS NULL".into()),
+            None => {}
+        }
+        if let Some(search) = &filter.search {
+            params.push(Box::new(like_pattern(search)));
+            conditions.push(format!("external_id ILIKE ${} ESCAPE '\\'", params.len()));
+        }
+        if let Some(tag) = &filter.tag {
+            params.push(Box::new(tag.clone()));
+            conditions.push(format!(
+                "id IN (SELECT url_id FROM link_tags
+                 JOIN tags ON tags.id = link_tags.tag_id WHERE tags.name = ${})",
+                params.len()
+            ));
+        }
+        match filter.health {
+            Some(HealthFilter::Ok) => {
+                conditions.push("id IN (SELECT url_id FROM link_health WHERE NOT broken)".into())
+            }
+            Some(HealthFilter::Broken) => {
+                conditions.push("id IN (SELECT url_id FROM link_health WHERE broken)".into())
+            }
+            Some(HealthFilter::Unchecked) => {
+                conditions.push("id NOT IN (SELECT url_id FROM link_health)".into())
+            }
+            None => {}
+        }
+        match filter.quarantined {
+            Some(true) => conditions.push("quarantined_at IS NOT NULL".into()),
+            Some(false) => conditions.push("quarantined_at IS NULL".into()),
+            None => {}
+        }
+        if let Scope::Owner(owner_id) = filter.scope {
+            params.push(Box::new(owner_id.map(|id| id as i64)));
+            conditions.push(format!("owner_id IS NOT DISTINCT FROM ${}", params.len()));
+        }
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        let client = self.client().await?;
+        let param_refs: Vec<_> = params.iter().map(|param| param.as_ref() as _).collect();
+        let total: i64 = client
+            .query_one(
+                &format!("SELECT COUNT(*) FROM urls {where_clause}"),
+                &param_refs,
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .get(0);
+
+        let sort = match filter.sort {
+            LinkSort::CreatedAt => "created_at",
+            LinkSort::Clicks => "clicks",
+        };
+        let order = filter.order.sql();
+        params.push(Box::new(i64::from(filter.limit)));
+        params.push(Box::new(i64::from(filter.offset)));
+        let (limit, offset) = (params.len() - 1, params.len());
+        let param_refs: Vec<_> = params.iter().map(|param| param.as_ref() as _).collect();
+        let links = client
+            .query(
+                &format!(
+                    "SELECT {LINK_COLUMNS}, COALESCE(counts.clicks, 0) AS clicks,
+                        ARRAY(
+                            SELECT tags.name FROM link_tags
+                            JOIN tags ON tags.id = link_tags.tag_id
+                            WHERE link_tags.url_id = urls.id ORDER BY tags.name
+                        ) AS tags
+                    FROM urls
+                    LEFT JOIN ({CLICK_COUNTS}) counts
+                        ON counts.url_id = urls.id
+                    {where_clause}
+                    ORDER BY {sort} {order}, id {order} LIMIT ${limit} OFFSET ${offset}"
+                ),
+                &param_refs,
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .iter()
+            .map(|row| LinkSummary {
+                link: link_from_row(row),
+                clicks: row.get::<_, i64>("clicks") as u64,
+                tags: row.get("tags"),
+            })
+            .collect();
+
+        Ok(LinkPage {
+            links,
+            total: total as u64,
+        })
+    }
+
+    async fn create_user(
+        &self,
+        name: &str,
+        password_hash: Option<&str>,
+        role: Role,
+    ) -> QrLinkResult<User> {
+        let row = self
+            .client()
+            .await?
+            .query_one(
+                &format!(
+                    "INSERT INTO users (name, password_hash, role) VALUES ($1, $2, $3)
+                    RETURNING {USER_COLUMNS}"
+                ),
+                &[&name, &password_hash, &role.as_str()],
+            )
+            .await
+            .map_err(|error| match error.code() {
+                Some(&SqlState::UNIQUE_VIOLATION) => super::user_taken(name),
+                _ => Error::Postgres(error),
+            })?;
+        Ok(user_from_row(&row))
+    }
+
+    async fn user(&self, id: u64) -> QrLinkResult<Option<User>> {
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                &format!("SELECT {USER_COLUMNS} FROM users WHERE id = $1"),
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    async fn set_user_role(&self, id: u64, role: Role) -> QrLinkResult<User> {
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                &format!("UPDATE users SET role = $1 WHERE id = $2 RETURNING {USER_COLUMNS}"),
+                &[&role.as_str(), &(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        row.as_ref()
+            .map(user_from_row)
+            .ok_or(Error::UnknownUser(id))
+    }
+
+    async fn user_credentials(&self, name: &str) -> QrLinkResult<Option<(User, String)>> {
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                &format!(
+                    "SELECT {USER_COLUMNS}, password_hash FROM users
+                    WHERE name = $1 AND password_hash IS NOT NULL"
+                ),
+                &[&name],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(row.map(|row| (user_from_row(&row), row.get(4))))
+    }
+
+    async fn oidc_user(&self, subject: &str, name: &str) -> QrLinkResult<User> {
+        // Updated to itself on a conflict, so the user already there is returned
+        let row = self
+            .client()
+            .await?
+            .query_one(
+                &format!(
+                    "INSERT INTO users (name, oidc_subject) VALUES ($1, $2)
+                    ON CONFLICT (oidc_subject) DO UPDATE SET oidc_subject = EXCLUDED.oidc_subject
+                    RETURNING {USER_COLUMNS}"
+                ),
+                &[&name, &subject],
+            )
+            .await
+            .map_err(|error| match error.code() {
+                Some(&SqlState::UNIQUE_VIOLATION) => super::user_taken(name),
+                _ => Error::Postgres(error),
+            })?;
+        Ok(user_from_row(&row))
+    }
+
+    async fn users(&self) -> QrLinkResult<Vec<User>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                &format!("SELECT {USER_COLUMNS} FROM users ORDER BY id"),
+                &[],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(rows.iter().map(user_from_row).collect())
+    }
+
+    async fn create_api_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        prefix: &str,
+        user_id: Option<u64>,
+        role: Role,
+    ) -> QrLinkResult<ApiKey> {
+        let row = self
+            .client()
+            .await?
+            .query_one(
+                &format!(
+                    "INSERT INTO api_keys (name, key_hash, prefix, user_id, role)
+                    VALUES ($1, $2, $3, $4, $5)
+                    RETURNING {API_KEY_COLUMNS}"
+                ),
+                &[
+                    &name,
+                    &key_hash,
+                    &prefix,
+                    &user_id.map(|id| id as i64),
+                    &role.as_str(),
+                ],
+            )
+            .await
+            .map_err(|error| match (error.code(), user_id) {
+                (Some(&SqlState::FOREIGN_KEY_VIOLATION), Some(user_id)) => {
+                    Error::UnknownUser(user_id)
+                }
+                _ => Error::Postgres(error),
+            })?;
+        Ok(api_key_from_row(&row))
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> QrLinkResult<Option<ApiKey>> {
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                &format!(
+                    "SELECT {API_KEY_COLUMNS},
+                        (SELECT role FROM users WHERE users.id = api_keys.user_id)
+                    FROM api_keys WHERE key_hash = $1 AND revoked_at IS NULL"
+                ),
+                &[&key_hash],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(row.map(|row| {
+            let mut key = api_key_from_row(&row);
+            if let Some(user_role) = row.get::<_, Option<String>>(6) {
+                key.role = key.role.min(Role::parse(&user_role));
+            }
+            key
+        }))
+    }
+
+    async fn revoke_api_key(&self, id: u64) -> QrLinkResult<()> {
+        let found = self
+            .client()
+            .await?
+            .execute(
+                "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, now()) WHERE id = $1",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        if found == 0 {
+            return Err(Error::UnknownApiKey(id));
+        }
+        Ok(())
+    }
+
+    async fn create_webhook(&self, webhook: NewWebhook) -> QrLinkResult<Webhook> {
+        let client = self.client().await?;
+        let id: i64 = client
+            .query_one(
+                "INSERT INTO webhooks (url, secret, link_id, events) VALUES ($1, $2, $3, $4)
+                RETURNING id",
+                &[
+                    &webhook.url,
+                    &webhook.secret,
+                    &webhook.link_id.map(|id| id as i64),
+                    &WebhookEvent::join(&webhook.events),
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .get(0);
+        let row = client
+            .query_one(
+                &format!(
+                    "SELECT {WEBHOOK_COLUMNS} FROM webhooks
+                    LEFT JOIN urls ON urls.id = webhooks.link_id
+                    WHERE webhooks.id = $1"
+                ),
+                &[&id],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(webhook_from_row(&row))
+    }
+
+    async fn webhooks(&self) -> QrLinkResult<Vec<Webhook>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                &format!(
+                    "SELECT {WEBHOOK_COLUMNS} FROM webhooks
+                    LEFT JOIN urls ON urls.id = webhooks.link_id
+                    ORDER BY webhooks.id"
+                ),
+                &[],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(rows.iter().map(webhook_from_row).collect())
+    }
+
+    async fn delete_webhook(&self, id: u64) -> QrLinkResult<()> {
+        let found = self
+            .client()
+            .await?
+            .execute("DELETE FROM webhooks WHERE id = $1", &[&(id as i64)])
+            .await
+            .map_err(Error::Postgres)?;
+        if found == 0 {
+            return Err(Error::UnknownWebhook(id));
+        }
+        Ok(())
+    }
+
+    async fn enqueue_deliveries(
+        &self,
+        webhook_ids: Vec<u64>,
+        event: WebhookEvent,
+        payload: String,
+    ) -> QrLinkResult<()> {
+        let webhook_ids: Vec<i64> = webhook_ids.into_iter().map(|id| id as i64).collect();
+        self.client()
+            .await?
+            .execute(
+                "INSERT INTO webhook_deliveries (webhook_id, event, payload)
+                SELECT webhook_id, $2, $3 FROM UNNEST($1::BIGINT[]) AS webhook_id",
+                &[&webhook_ids, &event.as_str(), &payload],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(())
+    }
+
+    async fn claim_deliveries(
+        &self,
+        limit: u32,
+        lease_until: DateTime<Utc>,
+    ) -> QrLinkResult<Vec<DueDelivery>> {
+        // SKIP LOCKED lets instances sharing the database claim deliveries side by side
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "UPDATE webhook_deliveries d SET next_attempt_at = $1
+                FROM webhooks w
+                WHERE w.id = d.webhook_id AND d.id IN (
+                    SELECT id FROM webhook_deliveries
+                    WHERE status = 'pending' AND next_attempt_at <= now()
+                    ORDER BY next_attempt_at, id LIMIT $2
+                    FOR UPDATE SKIP LOCKED
+                )
+                RETURNING d.id, d.event, d.payload, d.attempts, w.url, w.secret",
+                &[&lease_until, &i64::from(limit)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(rows
+            .iter()
+            .map(|row| DueDelivery {
+                id: row.get::<_, i64>(0) as u64,
+                event: row.get(1),
+                payload: row.get(2),
+                attempts: row.get::<_, i32>(3) as u32,
+                url: row.get(4),
+                secret: row.get(5),
+            })
+            .collect())
+    }
+
+    async fn finish_delivery(&self, id: u64, outcome: DeliveryOutcome) -> QrLinkResult<()> {
+        let client = self.client().await?;
+        match outcome {
+            DeliveryOutcome::Delivered { response_status } => {
+                client
+                    .execute(
+                        "UPDATE webhook_deliveries
+                        SET status = $1, attempts = attempts + 1, response_status = $2,
+                            last_error = NULL, delivered_at = now()
+                        WHERE id = $3",
+                        &[
+                            &DeliveryStatus::Delivered.as_str(),
+                            &i32::from(response_status),
+                            &(id as i64),
+                        ],
+                    )
+                    .await
+            }
+            DeliveryOutcome::Failed {
+                response_status,
+                error,
+                retry_at,
+            } => {
+                let status = match retry_at {
+                    Some(_) => DeliveryStatus::Pending,
+                    None => DeliveryStatus::Failed,
+                };
+                client
+                    .execute(
+                        "UPDATE webhook_deliveries
+                        SET status = $1, attempts = attempts + 1, response_status = $2,
+                            last_error = $3, next_attempt_at = COALESCE($4, next_attempt_at)
+                        WHERE id = $5",
+                        &[
+                            &status.as_str(),
+                            &response_status.map(i32::from),
+                            &error,
+                            &retry_at,
+                            &(id as i64),
+                        ],
+                    )
+                    .await
+            }
+        }
+        .map_err(Error::Postgres)?;
+        Ok(())
+    }
+
+    async fn deliveries(&self, webhook_id: u64, limit: u32) -> QrLinkResult<Vec<Delivery>> {
+        let client = self.client().await?;
+        let found: bool = client
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM webhooks WHERE id = $1)",
+                &[&(webhook_id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .get(0);
+        if !found {
+            return Err(Error::UnknownWebhook(webhook_id));
+        }
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {DELIVERY_COLUMNS} FROM webhook_deliveries
+                    WHERE webhook_id = $1 ORDER BY id DESC LIMIT $2"
+                ),
+                &[&(webhook_id as i64), &i64::from(limit)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(rows.iter().map(delivery_from_row).collect())
+    }
+
+    async fn visitor_salt(&self, day: NaiveDate, fresh: &str) -> QrLinkResult<String> {
+        let client = self.client().await?;
+        client
+            .execute("DELETE FROM visitor_salts WHERE day < $1", &[&day])
+            .await
+            .map_err(Error::Postgres)?;
+        let row = client
+            .query_one(
+                "INSERT INTO visitor_salts (day, salt) VALUES ($1, $2)
+                 ON CONFLICT (day) DO UPDATE SET salt = visitor_salts.salt
+                 RETURNING salt",
+                &[&day, &fresh],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        Ok(row.get(0))
+    }
+
+    async fn prune_clicks(&self, before: DateTime<Utc>, roll_up: bool) -> QrLinkResult<u64> {
+        let client = self.client().await?;
+        let pruned = if roll_up {
+            // Rolling up what was deleted, rather than what is about to be, leaves out no click
+            // made in between
+            client
+                .query_one(
+                    "WITH pruned AS (
+                        DELETE FROM stats WHERE clicked_at < $1 RETURNING url_id, clicked_at, source
+                    ), rolled_up AS (
+                        INSERT INTO click_rollups (url_id, day, source, clicks)
+                        SELECT url_id, (clicked_at AT TIME ZONE 'UTC')::date AS day, source,
+                            COUNT(*)
+                        FROM pruned GROUP BY url_id, day, source
+                        ON CONFLICT (url_id, day, source) DO UPDATE
+                            SET clicks = click_rollups.clicks + EXCLUDED.clicks
+                    )
+                    SELECT COUNT(*) FROM pruned",
+                    &[&before],
+                )
+                .await
+                .map_err(Error::Postgres)?
+                .get::<_, i64>(0) as u64
+        } else {
+            client
+                .execute("DELETE FROM stats WHERE clicked_at < $1", &[&before])
+                .await
+                .map_err(Error::Postgres)?
+        };
+        Ok(pruned)
+    }
+
+    async fn record_click(&self, id: u64, click: Click) -> QrLinkResult<RecordedClick> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(Error::Postgres)?;
+        // Only links with a limit are locked, which keeps two clicks from both getting the last
+        // one without making every other click wait its turn
+        let (mut deleted, mut max_clicks) = click_limit(&tx, id, "").await?;
+        if max_clicks.is_some() {
+            (deleted, max_clicks) = click_limit(&tx, id, "FOR UPDATE").await?;
+        }
+        if deleted {
+            return Ok(RecordedClick::Refused);
+        }
+        let clicks = match max_clicks {
+            Some(_) => tx
+                .query_one(
+                    "SELECT (SELECT COUNT(*) FROM stats WHERE url_id = $1)
+                        + (SELECT COALESCE(SUM(clicks), 0)::BIGINT FROM click_rollups
+                            WHERE url_id = $1)",
+                    &[&(id as i64)],
+                )
+                .await
+                .map_err(Error::Postgres)?
+                .get::<_, i64>(0) as u64,
+            None => 0,
+        };
+        let recorded = RecordedClick::after(clicks, max_clicks);
+        if recorded == RecordedClick::Refused {
+            tx.execute(
+                "UPDATE urls SET deleted_at = now() WHERE id = $1",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+            tx.commit().await.map_err(Error::Postgres)?;
+            return Ok(recorded);
+        }
+        insert_click(&tx, id, &click).await?;
+        if recorded == RecordedClick::Last {
+            tx.execute(
+                "UPDATE urls SET deleted_at = now() WHERE id = $1",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        }
+        tx.commit().await.map_err(Error::Postgres)?;
+        Ok(recorded)
+    }
+
+    async fn record_clicks(&self, clicks: Vec<(u64, Click)>) -> QrLinkResult<()> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(Error::Postgres)?;
+        for (id, click) in &clicks {
+            insert_click(&tx, *id, click).await?;
+        }
+        tx.commit().await.map_err(Error::Postgres)
+    }
+
+    async fn stats(&self, id: u64, granularity: Granularity) -> QrLinkResult<LinkStats> {
+        let client = self.client().await?;
+        // Clicks from before visitors were hashed are told apart by their address instead, and
+        // clicks kept without either, of visitors asking not to be tracked, count as no one
+        let totals = client
+            .query_one(
+                "SELECT COUNT(*), COUNT(*) FILTER (WHERE source = 'qr'),
+                    COUNT(DISTINCT NULLIF(COALESCE(visitor, ip_addr), '')),
+                    MIN(clicked_at), MAX(clicked_at)
+                FROM stats WHERE url_id = $1",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        let rolled_up = client
+            .query_one(
+                "SELECT COALESCE(SUM(clicks), 0)::BIGINT,
+                    COALESCE(SUM(clicks) FILTER (WHERE source = 'qr'), 0)::BIGINT,
+                    MIN(day)::timestamp AT TIME ZONE 'UTC', MAX(day)::timestamp AT TIME ZONE 'UTC'
+                FROM click_rollups WHERE url_id = $1",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        let field = match granularity {
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+            Granularity::Week => "week",
+        };
+        let buckets = client
+            .query(
+                "SELECT date_trunc($2, clicked_at AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' AS start,
+                    SUM(clicks)::BIGINT
+                FROM (
+                    SELECT clicked_at, 1 AS clicks FROM stats WHERE url_id = $1
+                    UNION ALL SELECT day::timestamp AT TIME ZONE 'UTC', clicks
+                    FROM click_rollups WHERE url_id = $1
+                ) AS counted
+                GROUP BY start ORDER BY start",
+                &[&(id as i64), &field],
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .iter()
+            .map(|row| ClickBucket {
+                start: row.get(0),
+                clicks: row.get::<_, i64>(1) as u64,
+            })
+            .collect();
+
+        let variants = client
+            .query(
+                "SELECT variant, COUNT(*) AS clicks,
+                    COUNT(DISTINCT NULLIF(COALESCE(visitor, ip_addr), ''))
+                FROM stats WHERE url_id = $1 AND variant IS NOT NULL
+                GROUP BY variant ORDER BY clicks DESC, variant",
+                &[&(id as i64)],
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .iter()
+            .map(|row| VariantStats {
+                url: row.get(0),
+                clicks: row.get::<_, i64>(1) as u64,
+                unique_visitors: row.get::<_, i64>(2) as u64,
+            })
+            .collect();
+
+        Ok(LinkStats {
+            clicks: (totals.get::<_, i64>(0) + rolled_up.get::<_, i64>(0)) as u64,
+            scans: (totals.get::<_, i64>(1) + rolled_up.get::<_, i64>(1)) as u64,
+            unique_visitors: totals.get::<_, i64>(2) as u64,
+            // Rolled up clicks are all older than the ones kept
+            first_click: rolled_up.get::<_, Option<_>>(2).or(totals.get(3)),
+            last_click: totals.get::<_, Option<_>>(4).or(rolled_up.get(3)),
+            buckets,
+            referrers: breakdown(&client, id, "referrer").await?,
+            browsers: breakdown(&client, id, "browser").await?,
+            operating_systems: breakdown(&client, id, "os").await?,
+            countries: breakdown(&client, id, "country").await?,
+            variants,
+        })
+    }
+}
+
+async fn insert_click(client: &impl GenericClient, id: u64, click: &Click) -> QrLinkResult<()> {
+    client
+        .execute(
+            "INSERT INTO stats (
+                url_id, ip_addr, visitor, referrer, user_agent, browser, os, country, city,
+                source, variant
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+            &[
+                &(id as i64),
+                &click.ip_addr,
+                &click.visitor,
+                &click.referrer,
+                &click.user_agent,
+                &click.browser,
+                &click.os,
+                &click.country,
+                &click.city,
+                &click.source.as_str(),
+                &click.variant,
+            ],
+        )
+        .await
+        .map_err(Error::Postgres)?;
+    Ok(())
+}
+
+/// The most common values of `column` among a link's clicks, leaving out clicks without one
+async fn breakdown(
+    client: &impl GenericClient,
+    id: u64,
+    column: &str,
+) -> QrLinkResult<Vec<Breakdown>> {
+    Ok(client
+        .query(
+            &format!(
+                "SELECT {column}, COUNT(*) AS clicks FROM stats
+                WHERE url_id = $1 AND {column} IS NOT NULL
+                GROUP BY {column} ORDER BY clicks DESC, {column} LIMIT $2"
+            ),
+            &[&(id as i64), &i64::from(TOP_BREAKDOWN)],
+        )
+        .await
+        .map_err(Error::Postgres)?
+        .iter()
+        .map(|row| Breakdown {
+            name: row.get(0),
+            clicks: row.get::<_, i64>(1) as u64,
+        })
+        .collect())
+}
+
+fn api_key_from_row(row: &tokio_postgres::Row) -> ApiKey {
+    ApiKey {
+        id: row.get::<_, i64>(0) as u64,
+        name: row.get(1),
+        prefix: row.get(2),
+        created_at: row.get(3),
+        user_id: row.get::<_, Option<i64>>(4).map(|id| id as u64),
+        role: Role::parse(row.get(5)),
+    }
+}
+
+fn user_from_row(row: &tokio_postgres::Row) -> User {
+    User {
+        id: row.get::<_, i64>(0) as u64,
+        name: row.get(1),
+        created_at: row.get(2),
+        role: Role::parse(row.get(3)),
+    }
+}
+
+fn webhook_from_row(row: &tokio_postgres::Row) -> Webhook {
+    Webhook {
+        id: row.get::<_, i64>(0) as u64,
+        url: row.get(1),
+        secret: row.get(2),
+        link_id: row.get::<_, Option<i64>>(3).map(|id| id as u64),
+        link: row.get(4),
+        events: WebhookEvent::split(row.get(5)),
+        created_at: row.get(6),
+    }
+}
+
+fn delivery_from_row(row: &tokio_postgres::Row) -> Delivery {
+    Delivery {
+        id: row.get::<_, i64>(0) as u64,
+        event: row.get(1),
+        payload: row.get(2),
+        status: DeliveryStatus::parse(row.get(3)),
+        attempts: row.get::<_, i32>(4) as u32,
+        next_attempt_at: row.get(5),
+        response_status: row.get::<_, Option<i32>>(6).map(|status| status as u16),
+        last_error: row.get(7),
+        created_at: row.get(8),
+        delivered_at: row.get(9),
+    }
+}
+
+fn link_from_row(row: &tokio_postgres::Row) -> Link {
+    Link {
+        id: row.get::<_, i64>(0) as u64,
+        url: row.get(1),
+        slug: row.get(2),
+        code: row.get(3),
+        created_at: row.get(4),
+        deleted_at: row.get(5),
+        expires_at: row.get(6),
+        max_clicks: row
+            .get::<_, Option<i64>>(7)
+            .map(|max_clicks| max_clicks as u64),
+        activate_at: row.get(8),
+        forward_query: row.get(9),
+        forward_path: row.get(10),
+        utm_template: row.get(11),
+        title: row.get(12),
+        notes: row.get(13),
+        page_title: row.get(14),
+        page_description: row.get(15),
+        quarantine: Quarantine::from_columns(row.get(16), row.get(17)),
+        owner_id: row.get::<_, Option<i64>>(18).map(|id| id as u64),
+        noindex: row.get(19),
+    }
+}
+
+/// Whether a link is deleted, and how many clicks it allows, read with `lock` as its locking
+/// clause
+async fn click_limit(
+    client: &impl GenericClient,
+    id: u64,
+    lock: &str,
+) -> QrLinkResult<(bool, Option<u64>)> {
+    let row = client
+        .query_opt(
+            &format!("SELECT deleted_at IS NOT NULL, max_clicks FROM urls WHERE id = $1 {lock}"),
+            &[&(id as i64)],
+        )
+        .await
+        .map_err(Error::Postgres)?
+        .ok_or_else(|| Error::NotFound(id.to_string()))?;
+    let max_clicks = row
+        .get::<_, Option<i64>>(1)
+        .map(|max_clicks| max_clicks as u64);
+    Ok((row.get(0), max_clicks))
+}
+
+async fn get_link(client: &impl GenericClient, id: u64) -> QrLinkResult<Link> {
+    client
+        .query_opt(
+            &format!("SELECT {} FROM urls WHERE id = $1", LINK_COLUMNS),
+            &[&(id as i64)],
+        )
+        .await
+        .map_err(Error::Postgres)?
+        .map(|row| link_from_row(&row))
+        .ok_or_else(|| Error::NotFound(id.to_string()))
+}
+
+/// Finds the internal id of a link from its code or slug, or from its numeric id if it
+/// predates codes
+async fn resolve_id(client: &impl GenericClient, external_id: &str) -> QrLinkResult<u64> {
+    let found = client
+        .query_opt(
+            "SELECT id FROM urls WHERE code = $1 OR slug = $1",
+            &[&external_id],
+        )
+        .await
+        .map_err(Error::Postgres)?;
+    if let Some(row) = found {
+        return Ok(row.get::<_, i64>(0) as u64);
+    }
+    let Ok(legacy_id) = external_id.parse::<i64>() else {
+        return Err(Error::NotFound(external_id.to_owned()));
+    };
+    client
+        .query_opt(
+            "SELECT id FROM urls WHERE id = $1 AND code IS NULL",
+            &[&legacy_id],
+        )
+        .await
+        .map_err(Error::Postgres)?
+        .map(|row| row.get::<_, i64>(0) as u64)
+        .ok_or_else(|| Error::NotFound(external_id.to_owned()))
+}
+
+/// Draws codes until one is free, both as a code and as a slug
+async fn unused_code(client: &impl GenericClient, codes: &CodeGenerator) -> QrLinkResult<String> {
+    for _ in 0..super::MAX_CODE_ATTEMPTS {
+        let code = codes.generate();
+        let taken: bool = client
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM urls WHERE code = $1 OR slug = $1)",
+                &[&code],
+            )
+            .await
+            .map_err(Error::Postgres)?
+            .get(0);
+        if !taken {
+            return Ok(code);
+        }
+    }
+    Err(Error::Conflict(
+        "could not find an unused code, consider a longer code length".into(),
+    ))
+}
+
+/// Stores `link` under an unused code and returns it as stored
+async fn insert_link(
+    client: &impl GenericClient,
+    link: &NewLink,
+    codes: &CodeGenerator,
+) -> QrLinkResult<Link> {
+    if let Some(slug) = &link.slug {
+        ensure_slug_available(client, slug, None).await?;
+    }
+    let code = unused_code(client, codes).await?;
+    if let Some(url_hash) = &link.url_hash {
+        // An expired link is no duplicate to return, so it gives up the hash
+        client
+            .execute(
+                "UPDATE urls SET url_hash = NULL WHERE url_hash = $1 AND expires_at <= now()",
+                &[url_hash],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+    }
+    if let Some(idempotency_key) = &link.idempotency_key {
+        client
+            .execute(
+                "UPDATE urls SET idempotency_key = NULL
+                 WHERE idempotency_key = $1 AND owner_id IS NOT DISTINCT FROM $2
+                    AND created_at <= $3",
+                &[
+                    idempotency_key,
+                    &link.owner_id.map(|id| id as i64),
+                    &(Utc::now() - super::IDEMPOTENCY_TTL),
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+    }
+
+    let row = client
+        .query_one(
+            &format!(
+                "INSERT INTO urls (
+                    external_id, slug, code, expires_at, max_clicks, activate_at, forward_query,
+                    forward_path, utm_template, title, notes, url_hash, idempotency_key, owner_id,
+                    noindex
+                 )
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+                 RETURNING {}",
+                LINK_COLUMNS
+            ),
+            &[
+                &link.url,
+                &link.slug,
+                &code,
+                &link.expires_at,
+                &link.max_clicks.map(|max_clicks| max_clicks as i64),
+                &link.activate_at,
+                &link.forward_query,
+                &link.forward_path,
+                &link.utm_template,
+                &link.title,
+                &link.notes,
+                &link.url_hash,
+                &link.idempotency_key,
+                &link.owner_id.map(|id| id as i64),
+                &link.noindex,
+            ],
+        )
+        .await
+        .map_err(slug_conflict(link.slug.as_deref().unwrap_or_default()))?;
+    let created = link_from_row(&row);
+    if !link.targets.platforms.is_empty() {
+        replace_platform_targets(client, created.id, &link.targets.platforms).await?;
+    }
+    if !link.targets.countries.is_empty() {
+        replace_country_targets(client, created.id, &link.targets.countries).await?;
+    }
+    if !link.targets.languages.is_empty() {
+        replace_language_targets(client, created.id, &link.targets.languages).await?;
+    }
+    if !link.targets.variants.is_empty() {
+        replace_variants(client, created.id, &link.targets.variants).await?;
+    }
+    if !link.tags.is_empty() {
+        replace_tags(client, created.id, &link.tags).await?;
+    }
+    Ok(created)
+}
+
+/// Makes `tags` the only tags of the link `id`, adding any that are new
+async fn replace_tags(client: &impl GenericClient, id: u64, tags: &[String]) -> QrLinkResult<()> {
+    client
+        .execute("DELETE FROM link_tags WHERE url_id = $1", &[&(id as i64)])
+        .await
+        .map_err(Error::Postgres)?;
+    for tag in tags {
+        client
+            .execute(
+                "INSERT INTO tags (name) VALUES ($1) ON CONFLICT (name) DO NOTHING",
+                &[tag],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+        client
+            .execute(
+                "INSERT INTO link_tags (url_id, tag_id) SELECT $1, id FROM tags WHERE name = $2",
+                &[&(id as i64), tag],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+    }
+    Ok(())
+}
+
+/// Makes `platforms` the only platform targets of the link `id`
+async fn replace_platform_targets(
+    client: &impl GenericClient,
+    id: u64,
+    platforms: &BTreeMap<Platform, String>,
+) -> QrLinkResult<()> {
+    client
+        .execute(
+            "DELETE FROM link_targets WHERE url_id = $1",
+            &[&(id as i64)],
+        )
+        .await
+        .map_err(Error::Postgres)?;
+    for (platform, url) in platforms {
+        client
+            .execute(
+                "INSERT INTO link_targets (url_id, platform, url) VALUES ($1, $2, $3)",
+                &[&(id as i64), &platform.as_str(), url],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+    }
+    Ok(())
+}
+
+/// Makes `countries` the only country targets of the link `id`, in their order
+async fn replace_country_targets(
+    client: &impl GenericClient,
+    id: u64,
+    countries: &[CountryTarget],
+) -> QrLinkResult<()> {
+    client
+        .execute(
+            "DELETE FROM link_country_targets WHERE url_id = $1",
+            &[&(id as i64)],
+        )
+        .await
+        .map_err(Error::Postgres)?;
+    for (position, target) in countries.iter().enumerate() {
+        client
+            .execute(
+                "INSERT INTO link_country_targets (url_id, position, country, url)
+                 VALUES ($1, $2, $3, $4)",
+                &[
+                    &(id as i64),
+                    &(position as i32),
+                    &target.country,
+                    &target.url,
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+    }
+    Ok(())
+}
+
+/// Makes `languages` the only language targets of the link `id`, in their order
+async fn replace_language_targets(
+    client: &impl GenericClient,
+    id: u64,
+    languages: &[LanguageTarget],
+) -> QrLinkResult<()> {
+    client
+        .execute(
+            "DELETE FROM link_language_targets WHERE url_id = $1",
+            &[&(id as i64)],
+        )
+        .await
+        .map_err(Error::Postgres)?;
+    for (position, target) in languages.iter().enumerate() {
+        client
+            .execute(
+                "INSERT INTO link_language_targets (url_id, position, lang, url)
+                 VALUES ($1, $2, $3, $4)",
+                &[&(id as i64), &(position as i32), &target.lang, &target.url],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+    }
+    Ok(())
+}
+
+/// Makes `variants` the only variants of the link `id`, in their order
+async fn replace_variants(
+    client: &impl GenericClient,
+    id: u64,
+    variants: &[Variant],
+) -> QrLinkResult<()> {
+    client
+        .execute(
+            "DELETE FROM link_variants WHERE url_id = $1",
+            &[&(id as i64)],
+        )
+        .await
+        .map_err(Error::Postgres)?;
+    for (position, variant) in variants.iter().enumerate() {
+        client
+            .execute(
+                "INSERT INTO link_variants (url_id, position, url, weight) VALUES ($1, $2, $3, $4)",
+                &[
+                    &(id as i64),
+                    &(position as i32),
+                    &variant.url,
+                    &(variant.weight as i32),
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+    }
+    Ok(())
+}
+
+/// Refuses a slug that is already some other link's slug or code
+async fn ensure_slug_available(
+    client: &impl GenericClient,
+    slug: &str,
+    for_id: Option<u64>,
+) -> QrLinkResult<()> {
+    let taken: bool = client
+        .query_one(
+            "SELECT EXISTS (
+                SELECT 1 FROM urls WHERE (code = $1 OR slug = $1) AND id IS DISTINCT FROM $2
+            )",
+            &[&slug, &for_id.map(|id| id as i64)],
+        )
+        .await
+        .map_err(Error::Postgres)?
+        .get(0);
+    if taken {
+        return Err(super::slug_taken(slug));
+    }
+    Ok(())
+}
+
+/// Maps a unique constraint on `urls` being violated to a conflict
+fn slug_conflict(slug: &str) -> impl FnOnce(tokio_postgres::Error) -> Error + '_ {
+    move |error| match error.code() {
+        Some(&SqlState::UNIQUE_VIOLATION) => super::slug_taken(slug),
+        _ => Error::Postgres(error),
+    }
+}
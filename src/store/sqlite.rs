@@ -0,0 +1,1918 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::OptionalExtension;
+use serde::Deserialize;
+
+use super::{
+    ApiKey, Breakdown, Click, ClickBucket, CountryTarget, Delivery, DeliveryOutcome,
+    DeliveryStatus, DueDelivery, Granularity, HealthFilter, HistoryEntry, LanguageTarget, Link,
+    LinkFilter, LinkHealth, LinkPage, LinkSort, LinkStats, LinkStore, LinkSummary, LinkTargets,
+    LinkUpdate, LinkUsage, NewLink, NewWebhook, PageInfo, Platform, PoolStats, Quarantine,
+    RecordedClick, Role, Scope, TOP_BREAKDOWN, TagCount, Threat, User, Variant, VariantStats,
+    Webhook, WebhookEvent, like_pattern, migrations,
+};
+use crate::code::CodeGenerator;
+use crate::error::{Error, QrLinkResult};
+
+/// Connections reading at once; writes all go through a connection of their own
+pub const POOL_SIZE: u32 = 8;
+
+/// How SQLite keeps its journal, as `PRAGMA journal_mode`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalMode {
+    /// Readers and a writer don't block each other, at the cost of `-wal` and `-shm` files
+    /// next to the database
+    #[default]
+    Wal,
+    Delete,
+    Truncate,
+    Persist,
+}
+
+/// How often SQLite waits for writes to reach the disk, as `PRAGMA synchronous`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    /// Enough in WAL mode for a power loss to only lose the latest commits, never to corrupt
+    #[default]
+    Normal,
+    Full,
+    Extra,
+}
+
+/// The `sqlite` settings, applied to every connection as it is opened
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqliteConfig {
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    /// Milliseconds a connection waits for another's write lock before giving up with
+    /// `database is locked`
+    pub busy_timeout_ms: u64,
+    /// Whether the foreign keys the schema declares are enforced, which SQLite doesn't do
+    /// unless asked to
+    pub foreign_keys: bool,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        SqliteConfig {
+            journal_mode: JournalMode::default(),
+            synchronous: Synchronous::default(),
+            busy_timeout_ms: 5000,
+            foreign_keys: true,
+        }
+    }
+}
+
+impl SqliteConfig {
+    /// Applies the settings to a newly opened read-only connection, which can't change the
+    /// journal mode and doesn't write anything to check or sync
+    fn init_reader(&self, conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+        conn.busy_timeout(Duration::from_millis(self.busy_timeout_ms))
+    }
+
+    /// Applies the settings to a newly opened connection
+    fn init(&self, conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+        conn.busy_timeout(Duration::from_millis(self.busy_timeout_ms))?;
+        let journal_mode = match self.journal_mode {
+            JournalMode::Wal => "WAL",
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+        };
+        let synchronous = match self.synchronous {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        };
+        // In-memory databases keep their journal in memory whatever they're asked
+        conn.pragma_update_and_check(None, "journal_mode", journal_mode, |_| Ok(()))?;
+        conn.pragma_update(None, "synchronous", synchronous)?;
+        conn.pragma_update(None, "foreign_keys", self.foreign_keys)
+    }
+}
+
+/// Pages a backup copies before letting other connections write for [BACKUP_STEP_PAUSE]
+const BACKUP_STEP_PAGES: std::ffi::c_int = 1000;
+const BACKUP_STEP_PAUSE: Duration = Duration::from_millis(10);
+
+static LINK_COLUMNS: &str = "id, external_id, slug, code, created_at, deleted_at, expires_at,
+    max_clicks, activate_at, forward_query, forward_path, utm_template, title, notes, page_title,
+    page_description, threat, quarantined_at, owner_id, noindex";
+
+/// Clicks per link, those rolled up into daily totals included
+static CLICK_COUNTS: &str = "SELECT url_id, SUM(clicks) AS clicks FROM (
+        SELECT url_id, COUNT(*) AS clicks FROM stats GROUP BY url_id
+        UNION ALL SELECT url_id, SUM(clicks) FROM click_rollups GROUP BY url_id
+    ) GROUP BY url_id";
+
+static API_KEY_COLUMNS: &str = "id, name, prefix, created_at, user_id, role";
+
+static USER_COLUMNS: &str = "id, name, created_at, role";
+
+/// Webhooks are selected joined with the link they are limited to, if any, for its code or slug
+static WEBHOOK_COLUMNS: &str = "webhooks.id, webhooks.url, webhooks.secret, webhooks.link_id,
+    COALESCE(urls.slug, urls.code, CAST(urls.id AS TEXT)), webhooks.events, webhooks.created_at";
+
+static DELIVERY_COLUMNS: &str = "id, event, payload, status, attempts, next_attempt_at,
+    response_status, last_error, created_at, delivered_at";
+
+/// Links in a SQLite file, behind connections that are used from blocking tasks so queries
+/// never stall the runtime. Writes take turns on a single connection, rather than failing on
+/// each other's locks, while a pool of read-only connections reads alongside them, which WAL
+/// mode keeps from waiting on the writer.
+#[derive(Clone)]
+pub struct SqliteStore {
+    writer: r2d2::Pool<SqliteConnectionManager>,
+    /// Without it, reads share the writer's connection
+    readers: Option<r2d2::Pool<SqliteConnectionManager>>,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, with `config` applied to each connection; the schema is
+    /// brought up to date by [LinkStore::migrate]
+    pub fn open(path: impl AsRef<Path>, config: &SqliteConfig) -> QrLinkResult<Self> {
+        let path = path.as_ref();
+        let writer_config = config.clone();
+        // Opened first, creating the file if it isn't there yet for the readers to open
+        let writer = r2d2::Pool::builder()
+            .max_size(1)
+            .build(
+                SqliteConnectionManager::file(path).with_init(move |conn| writer_config.init(conn)),
+            )
+            .map_err(Error::Pool)?;
+        let reader_config = config.clone();
+        let readers = SqliteConnectionManager::file(path)
+            .with_flags(
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX
+                    | rusqlite::OpenFlags::SQLITE_OPEN_URI,
+            )
+            .with_init(move |conn| reader_config.init_reader(conn));
+        let readers = r2d2::Pool::builder()
+            .max_size(POOL_SIZE)
+            .build(readers)
+            .map_err(Error::Pool)?;
+        Ok(SqliteStore {
+            writer,
+            readers: Some(readers),
+        })
+    }
+
+    /// Opens a private database that lives in memory for as long as the store does, for tests
+    /// and throwaway instances. The pool holds a single connection that is never recycled,
+    /// since every in-memory connection is a database of its own.
+    pub fn open_in_memory(config: &SqliteConfig) -> QrLinkResult<Self> {
+        let config = config.clone();
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .build(SqliteConnectionManager::memory().with_init(move |conn| config.init(conn)))
+            .map_err(Error::Pool)?;
+        Ok(SqliteStore {
+            writer: pool,
+            readers: None,
+        })
+    }
+
+    /// Runs `f`, which only reads, with a read-only connection on the blocking thread pool
+    pub async fn read<T, F>(&self, f: F) -> QrLinkResult<T>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> QrLinkResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        run(self.readers.as_ref().unwrap_or(&self.writer), f).await
+    }
+
+    /// Runs `f` with the connection that writes, on the blocking thread pool, once the writes
+    /// before it are done
+    pub async fn write<T, F>(&self, f: F) -> QrLinkResult<T>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> QrLinkResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        run(&self.writer, f).await
+    }
+}
+
+/// Runs `f` with a connection from `pool` on the blocking thread pool
+async fn run<T, F>(pool: &r2d2::Pool<SqliteConnectionManager>, f: F) -> QrLinkResult<T>
+where
+    F: FnOnce(&mut rusqlite::Connection) -> QrLinkResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool.get().map_err(Error::Pool)?;
+        f(&mut conn)
+    })
+    .await
+    .map_err(Error::Task)?
+}
+
+#[async_trait]
+impl LinkStore for SqliteStore {
+    fn pool_stats(&self) -> PoolStats {
+        self.readers
+            .iter()
+            .chain([&self.writer])
+            .map(|pool| {
+                let state = pool.state();
+                PoolStats {
+                    connections: state.connections,
+                    idle: state.idle_connections,
+                    max: pool.max_size(),
+                }
+            })
+            .fold(PoolStats::default(), |total, pool| PoolStats {
+                connections: total.connections + pool.connections,
+                idle: total.idle + pool.idle,
+                max: total.max + pool.max,
+            })
+    }
+
+    async fn migrate(&self) -> QrLinkResult<u32> {
+        self.write(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS schema_version (
+                    version INTEGER NOT NULL,
+                    applied_at DATETIME DEFAULT CURRENT_TIMESTAMP
+                )",
+            )
+            .map_err(Error::Database)?;
+            let current: u32 = conn
+                .query_row(
+                    "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+                    [],
+                    |row| row.get(0),
+                )
+                .map_err(Error::Database)?;
+
+            for migration in migrations::pending(migrations::SQLITE, current)? {
+                let tx = conn.transaction().map_err(Error::Database)?;
+                tx.execute_batch(migration.sql).map_err(Error::Database)?;
+                tx.execute(
+                    "INSERT INTO schema_version (version) VALUES (?)",
+                    [migration.version],
+                )
+                .map_err(Error::Database)?;
+                tx.commit().map_err(Error::Database)?;
+            }
+            Ok(migrations::latest(migrations::SQLITE))
+        })
+        .await
+    }
+
+    async fn schema_version(&self) -> QrLinkResult<u32> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    fn latest_schema_version(&self) -> u32 {
+        migrations::latest(migrations::SQLITE)
+    }
+
+    async fn close(&self) -> QrLinkResult<()> {
+        // Folds the write-ahead log back into the database file, if it is in WAL mode, so the
+        // file is complete on its own. Pooled connections close as the pool is dropped.
+        self.write(|conn| {
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+                .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn backup(&self, path: &Path) -> QrLinkResult<()> {
+        let path = path.to_owned();
+        // Copied a few pages at a time, so writes from other connections aren't held up for
+        // long; the copy starts over should one of them change a page already copied
+        self.read(move |conn| {
+            let mut copy = rusqlite::Connection::open(&path).map_err(Error::Database)?;
+            rusqlite::backup::Backup::new(conn, &mut copy)
+                .and_then(|backup| {
+                    backup.run_to_completion(BACKUP_STEP_PAGES, BACKUP_STEP_PAUSE, None)
+                })
+                .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn create(&self, link: NewLink, codes: &CodeGenerator) -> QrLinkResult<Link> {
+        let codes = codes.clone();
+        self.write(move |conn| insert_link(conn, &link, &codes))
+            .await
+    }
+
+    async fn create_many(
+        &self,
+        links: Vec<NewLink>,
+        codes: &CodeGenerator,
+    ) -> QrLinkResult<Vec<QrLinkResult<Link>>> {
+        let codes = codes.clone();
+        self.write(move |conn| {
+            let tx = conn.transaction().map_err(Error::Database)?;
+            let mut created = Vec::with_capacity(links.len());
+            for link in &links {
+                match insert_link(&tx, link, &codes) {
+                    Err(error @ Error::Conflict(_)) => created.push(Err(error)),
+                    result => created.push(Ok(result?)),
+                }
+            }
+            tx.commit().map_err(Error::Database)?;
+            Ok(created)
+        })
+        .await
+    }
+
+    async fn resolve(&self, external_id: &str) -> QrLinkResult<Link> {
+        let external_id = external_id.to_owned();
+        self.read(move |conn| {
+            let id = resolve_id(conn, &external_id)?;
+            get_link(conn, id)
+        })
+        .await
+    }
+
+    async fn find_duplicate(&self, url_hash: &str) -> QrLinkResult<Option<Link>> {
+        let url_hash = url_hash.to_owned();
+        self.read(move |conn| {
+            let id: Option<u64> = conn
+                .query_row(
+                    "SELECT id FROM urls WHERE url_hash = ? AND deleted_at IS NULL",
+                    [url_hash],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(Error::Database)?;
+            id.map(|id| get_link(conn, id)).transpose()
+        })
+        .await
+    }
+
+    async fn find_idempotent(
+        &self,
+        idempotency_key: &str,
+        owner_id: Option<u64>,
+    ) -> QrLinkResult<Option<Link>> {
+        let idempotency_key = idempotency_key.to_owned();
+        self.read(move |conn| {
+            let id: Option<u64> = conn
+                .query_row(
+                    "SELECT id FROM urls WHERE idempotency_key = ? AND owner_id IS ?",
+                    rusqlite::params![idempotency_key, owner_id],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(Error::Database)?;
+            id.map(|id| get_link(conn, id)).transpose()
+        })
+        .await
+    }
+
+    async fn update(&self, id: u64, update: LinkUpdate) -> QrLinkResult<Link> {
+        self.write(move |conn| {
+            let tx = conn.transaction().map_err(Error::Database)?;
+
+            let current_url: String = tx
+                .query_row(
+                    "SELECT external_id FROM urls WHERE id = ? AND deleted_at IS NULL",
+                    [id],
+                    |row| row.get(0),
+                )
+                .map_err(Error::lookup(&id.to_string()))?;
+
+            if let Some(url) = update.url.filter(|url| *url != current_url) {
+                tx.execute(
+                    "INSERT INTO url_history (url_id, external_id) VALUES (?, ?)",
+                    rusqlite::params![id, current_url],
+                )
+                .map_err(Error::Database)?;
+                tx.execute(
+                    "UPDATE urls SET external_id = ?, url_hash = NULL, page_title = NULL,
+                        page_description = NULL, threat = NULL, quarantined_at = NULL,
+                        safety_checked_at = NULL
+                    WHERE id = ?",
+                    rusqlite::params![url, id],
+                )
+                .map_err(Error::Database)?;
+                tx.execute("DELETE FROM link_health WHERE url_id = ?", [id])
+                    .map_err(Error::Database)?;
+            }
+            if let Some(slug) = &update.slug {
+                ensure_slug_available(&tx, slug, Some(id))?;
+                tx.execute(
+                    "UPDATE urls SET slug = ? WHERE id = ?",
+                    rusqlite::params![slug, id],
+                )
+                .map_err(slug_conflict(slug))?;
+            }
+            if let Some(expires_at) = update.expires_at {
+                tx.execute(
+                    "UPDATE urls SET expires_at = ? WHERE id = ?",
+                    rusqlite::params![expires_at, id],
+                )
+                .map_err(Error::Database)?;
+            }
+            if let Some(max_clicks) = update.max_clicks {
+                tx.execute(
+                    "UPDATE urls SET max_clicks = ? WHERE id = ?",
+                    rusqlite::params![max_clicks, id],
+                )
+                .map_err(Error::Database)?;
+            }
+            if let Some(activate_at) = update.activate_at {
+                tx.execute(
+                    "UPDATE urls SET activate_at = ? WHERE id = ?",
+                    rusqlite::params![activate_at, id],
+                )
+                .map_err(Error::Database)?;
+            }
+            if let Some(forward_query) = update.forward_query {
+                tx.execute(
+                    "UPDATE urls SET forward_query = ? WHERE id = ?",
+                    rusqlite::params![forward_query, id],
+                )
+                .map_err(Error::Database)?;
+            }
+            if let Some(forward_path) = update.forward_path {
+                tx.execute(
+                    "UPDATE urls SET forward_path = ? WHERE id = ?",
+                    rusqlite::params![forward_path, id],
+                )
+                .map_err(Error::Database)?;
+            }
+            if let Some(noindex) = update.noindex {
+                tx.execute(
+                    "UPDATE urls SET noindex = ? WHERE id = ?",
+                    rusqlite::params![noindex, id],
+                )
+                .map_err(Error::Database)?;
+            }
+            if let Some(utm_template) = &update.utm_template {
+                tx.execute(
+                    "UPDATE urls SET utm_template = ? WHERE id = ?",
+                    rusqlite::params![utm_template, id],
+                )
+                .map_err(Error::Database)?;
+            }
+            if let Some(title) = &update.title {
+                tx.execute(
+                    "UPDATE urls SET title = ? WHERE id = ?",
+                    rusqlite::params![title, id],
+                )
+                .map_err(Error::Database)?;
+            }
+            if let Some(notes) = &update.notes {
+                tx.execute(
+                    "UPDATE urls SET notes = ? WHERE id = ?",
+                    rusqlite::params![notes, id],
+                )
+                .map_err(Error::Database)?;
+            }
+            if let Some(platforms) = &update.platform_targets {
+                replace_platform_targets(&tx, id, platforms)?;
+            }
+            if let Some(countries) = &update.country_targets {
+                replace_country_targets(&tx, id, countries)?;
+            }
+            if let Some(languages) = &update.language_targets {
+                replace_language_targets(&tx, id, languages)?;
+            }
+            if let Some(variants) = &update.variants {
+                replace_variants(&tx, id, variants)?;
+            }
+            if let Some(tags) = &update.tags {
+                replace_tags(&tx, id, tags)?;
+            }
+
+            tx.commit().map_err(Error::Database)?;
+            get_link(conn, id)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: u64) -> QrLinkResult<()> {
+        self.write(move |conn| {
+            conn.execute(
+                "UPDATE urls SET deleted_at = COALESCE(deleted_at, CURRENT_TIMESTAMP) WHERE id = ?",
+                [id],
+            )
+            .map_err(Error::Database)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn restore(&self, id: u64) -> QrLinkResult<Link> {
+        self.write(move |conn| {
+            // A duplicate created while the link was deleted keeps their destination's hash
+            conn.execute(
+                "UPDATE urls SET deleted_at = NULL,
+                    url_hash = CASE WHEN EXISTS (
+                        SELECT 1 FROM urls other
+                        WHERE other.url_hash = urls.url_hash AND other.deleted_at IS NULL
+                    ) THEN NULL ELSE url_hash END
+                WHERE id = ?",
+                [id],
+            )
+            .map_err(Error::Database)?;
+            get_link(conn, id)
+        })
+        .await
+    }
+
+    async fn history(&self, id: u64) -> QrLinkResult<Vec<HistoryEntry>> {
+        self.read(move |conn| {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT external_id, replaced_at FROM url_history
+                     WHERE url_id = ? ORDER BY replaced_at DESC, rowid DESC",
+                )
+                .map_err(Error::Database)?;
+            stmt.query_map([id], |row| {
+                Ok(HistoryEntry {
+                    url: row.get(0)?,
+                    replaced_at: row.get(1)?,
+                })
+            })
+            .and_then(Iterator::collect)
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn targets(&self, id: u64) -> QrLinkResult<LinkTargets> {
+        self.read(move |conn| {
+            let mut stmt = conn
+                .prepare("SELECT platform, url FROM link_targets WHERE url_id = ?")
+                .map_err(Error::Database)?;
+            let rows: Vec<(String, String)> = stmt
+                .query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))
+                .and_then(Iterator::collect)
+                .map_err(Error::Database)?;
+            let platforms = rows
+                .into_iter()
+                .filter_map(|(platform, url)| Some((Platform::parse(&platform)?, url)))
+                .collect();
+            let countries = conn
+                .prepare(
+                    "SELECT country, url FROM link_country_targets
+                    WHERE url_id = ? ORDER BY position",
+                )
+                .and_then(|mut stmt| {
+                    stmt.query_map([id], |row| {
+                        Ok(CountryTarget {
+                            country: row.get(0)?,
+                            url: row.get(1)?,
+                        })
+                    })?
+                    .collect()
+                })
+                .map_err(Error::Database)?;
+            let languages = conn
+                .prepare(
+                    "SELECT lang, url FROM link_language_targets
+                    WHERE url_id = ? ORDER BY position",
+                )
+                .and_then(|mut stmt| {
+                    stmt.query_map([id], |row| {
+                        Ok(LanguageTarget {
+                            lang: row.get(0)?,
+                            url: row.get(1)?,
+                        })
+                    })?
+                    .collect()
+                })
+                .map_err(Error::Database)?;
+            let variants = conn
+                .prepare("SELECT url, weight FROM link_variants WHERE url_id = ? ORDER BY position")
+                .and_then(|mut stmt| {
+                    stmt.query_map([id], |row| {
+                        Ok(Variant {
+                            url: row.get(0)?,
+                            weight: row.get(1)?,
+                        })
+                    })?
+                    .collect()
+                })
+                .map_err(Error::Database)?;
+            Ok(LinkTargets {
+                platforms,
+                countries,
+                languages,
+                variants,
+            })
+        })
+        .await
+    }
+
+    async fn tags(&self, id: u64) -> QrLinkResult<Vec<String>> {
+        self.read(move |conn| {
+            conn.prepare(
+                "SELECT tags.name FROM link_tags JOIN tags ON tags.id = link_tags.tag_id
+                WHERE link_tags.url_id = ? ORDER BY tags.name",
+            )
+            .and_then(|mut stmt| stmt.query_map([id], |row| row.get(0))?.collect())
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn set_page_info(&self, id: u64, url: &str, page: PageInfo) -> QrLinkResult<()> {
+        let url = url.to_owned();
+        self.write(move |conn| {
+            conn.execute(
+                "UPDATE urls SET page_title = ?, page_description = ?
+                WHERE id = ? AND external_id = ?",
+                rusqlite::params![page.title, page.description, id, url],
+            )
+            .map_err(Error::Database)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn links_due_for_check(
+        &self,
+        checked_before: DateTime<Utc>,
+        limit: u32,
+    ) -> QrLinkResult<Vec<Link>> {
+        self.read(move |conn| {
+            conn.prepare(&format!(
+                "SELECT {LINK_COLUMNS} FROM urls
+                LEFT JOIN link_health ON link_health.url_id = urls.id
+                WHERE deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)
+                    AND (checked_at IS NULL OR checked_at < ?)
+                ORDER BY checked_at IS NOT NULL, checked_at, id LIMIT ?"
+            ))
+            .and_then(|mut stmt| {
+                stmt.query_map(
+                    rusqlite::params![Utc::now(), checked_before, limit],
+                    link_from_row,
+                )?
+                .collect()
+            })
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn record_health(&self, id: u64, url: &str, health: LinkHealth) -> QrLinkResult<()> {
+        let url = url.to_owned();
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT INTO link_health (url_id, checked_at, status, latency_ms, error, broken)
+                SELECT id, ?, ?, ?, ?, ? FROM urls WHERE id = ? AND external_id = ?
+                ON CONFLICT (url_id) DO UPDATE SET checked_at = excluded.checked_at,
+                    status = excluded.status, latency_ms = excluded.latency_ms,
+                    error = excluded.error, broken = excluded.broken",
+                rusqlite::params![
+                    health.checked_at,
+                    health.status,
+                    health.latency_ms,
+                    health.error,
+                    health.broken,
+                    id,
+                    url
+                ],
+            )
+            .map_err(Error::Database)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn health(&self, id: u64) -> QrLinkResult<Option<LinkHealth>> {
+        self.read(move |conn| {
+            conn.query_row(
+                "SELECT checked_at, status, latency_ms, error, broken FROM link_health
+                WHERE url_id = ?",
+                [id],
+                |row| {
+                    Ok(LinkHealth {
+                        checked_at: row.get(0)?,
+                        status: row.get(1)?,
+                        latency_ms: row.get(2)?,
+                        error: row.get(3)?,
+                        broken: row.get(4)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn links_due_for_safety_check(
+        &self,
+        checked_before: DateTime<Utc>,
+        limit: u32,
+    ) -> QrLinkResult<Vec<Link>> {
+        self.read(move |conn| {
+            conn.prepare(&format!(
+                "SELECT {LINK_COLUMNS} FROM urls
+                WHERE deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?)
+                    AND (safety_checked_at IS NULL OR safety_checked_at < ?)
+                ORDER BY safety_checked_at IS NOT NULL, safety_checked_at, id LIMIT ?"
+            ))
+            .and_then(|mut stmt| {
+                stmt.query_map(
+                    rusqlite::params![Utc::now(), checked_before, limit],
+                    link_from_row,
+                )?
+                .collect()
+            })
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn record_safety(&self, id: u64, url: &str, threat: Option<Threat>) -> QrLinkResult<()> {
+        let url = url.to_owned();
+        self.write(move |conn| {
+            let now = Utc::now();
+            conn.execute(
+                "UPDATE urls SET safety_checked_at = ?1, threat = ?2,
+                    quarantined_at = CASE WHEN ?2 IS NULL THEN NULL
+                        ELSE COALESCE(quarantined_at, ?1) END
+                WHERE id = ?3 AND external_id = ?4",
+                rusqlite::params![now, threat.map(Threat::as_str), id, url],
+            )
+            .map_err(Error::Database)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn tag_counts(&self, scope: Scope) -> QrLinkResult<Vec<TagCount>> {
+        self.read(move |conn| {
+            // Every link matches a NULL owner argument
+            let (any_owner, owner_id) = match scope {
+                Scope::All => (true, None),
+                Scope::Owner(owner_id) => (false, owner_id),
+            };
+            conn.prepare(
+                "SELECT tags.name, COUNT(*) FROM tags
+                JOIN link_tags ON link_tags.tag_id = tags.id
+                JOIN urls ON urls.id = link_tags.url_id
+                WHERE urls.deleted_at IS NULL AND (?1 OR urls.owner_id IS ?2)
+                GROUP BY tags.name ORDER BY tags.name",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map(rusqlite::params![any_owner, owner_id], |row| {
+                    Ok(TagCount {
+                        tag: row.get(0)?,
+                        links: row.get(1)?,
+                    })
+                })?
+                .collect()
+            })
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn link_usage(&self, scope: Scope, since: DateTime<Utc>) -> QrLinkResult<LinkUsage> {
+        self.read(move |conn| {
+            let (any_owner, owner_id) = match scope {
+                Scope::All => (true, None),
+                Scope::Owner(owner_id) => (false, owner_id),
+            };
+            conn.query_row(
+                "SELECT
+                    COALESCE(SUM(deleted_at IS NULL), 0),
+                    COALESCE(SUM(datetime(created_at) >= datetime(?3)), 0)
+                FROM urls WHERE ?1 OR owner_id IS ?2",
+                rusqlite::params![any_owner, owner_id, since],
+                |row| {
+                    Ok(LinkUsage {
+                        active: row.get(0)?,
+                        created_since: row.get(1)?,
+                    })
+                },
+            )
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn list(&self, filter: LinkFilter) -> QrLinkResult<LinkPage> {
+        self.read(move |conn| {
+            let mut conditions = Vec::new();
+            let mut params: Vec<Box<dyn rusqlite::ToSql + Send>> = Vec::new();
+            if let Some(after) = filter.created_after {
+                conditions.push("datetime(created_at) >= datetime(?)");
+                params.push(Box::new(after));
+            }
+            if let Some(before) = filter.created_before {
+                conditions.push("datetime(created_at) < datetime(?)");
+                params.push(Box::new(before));
+            }
+            match filter.deleted {
+                Some(true) => conditions.push("deleted_at IS NOT NULL"),
+                Some(false) => conditions.push("deleted_at IS NULL"),
+                None => {}
+            }
+            if let Some(search) = &filter.search {
+                conditions.push("external_id LIKE ? ESCAPE '\\'");
+                params.push(Box::new(like_pattern(search)));
+            }
+            if let Some(tag) = &filter.tag {
+                conditions.push(
+                    "id IN (SELECT url_id FROM link_tags
+                    JOIN tags ON tags.id = link_tags.tag_id WHERE tags.name = ?)",
+                );
+                params.push(Box::new(tag.clone()));
+            }
+            match filter.health {
+                Some(HealthFilter::Ok) => {
+                    conditions.push("id IN (SELECT url_id FROM link_health WHERE NOT broken)");
+                }
+                Some(HealthFilter::Broken) => {
+                    conditions.push("id IN (SELECT url_id FROM link_health WHERE broken)");
+                }
+                Some(HealthFilter::Unchecked) => {
+                    conditions.push("id NOT IN (SELECT url_id FROM link_health)");
+                }
+                None => {}
+            }
+            match filter.quarantined {
+                Some(true) => conditions.push("quarantined_at IS NOT NULL"),
+                Some(false) => conditions.push("quarantined_at IS NULL"),
+                None => {}
+            }
+            if let Scope::Owner(owner_id) = filter.scope {
+                conditions.push("owner_id IS ?");
+                params.push(Box::new(owner_id));
+            }
+            let where_clause = if conditions.is_empty() {
+                String::new()
+            } else {
+                format!("WHERE {}", conditions.join(" AND "))
+            };
+
+            let total = conn
+                .query_row(
+                    &format!("SELECT COUNT(*) FROM urls {where_clause}"),
+                    rusqlite::params_from_iter(&params),
+                    |row| row.get(0),
+                )
+                .map_err(Error::Database)?;
+
+            let sort = match filter.sort {
+                LinkSort::CreatedAt => "created_at",
+                LinkSort::Clicks => "clicks",
+            };
+            let order = filter.order.sql();
+            params.push(Box::new(filter.limit));
+            params.push(Box::new(filter.offset));
+            let links = conn
+                .prepare(&format!(
+                    "SELECT {LINK_COLUMNS}, COALESCE(counts.clicks, 0) AS clicks,
+                        (
+                            SELECT group_concat(tags.name, ',') FROM link_tags
+                            JOIN tags ON tags.id = link_tags.tag_id
+                            WHERE link_tags.url_id = urls.id
+                        ) AS tags
+                    FROM urls
+                    LEFT JOIN ({CLICK_COUNTS}) counts
+                        ON counts.url_id = urls.id
+                    {where_clause}
+                    ORDER BY {sort} {order}, id {order} LIMIT ? OFFSET ?"
+                ))
+                .and_then(|mut statement| {
+                    statement
+                        .query_map(rusqlite::params_from_iter(&params), |row| {
+                            // Comma-separated, which no tag contains
+                            let tags: Option<String> = row.get("tags")?;
+                            let mut tags: Vec<String> = tags
+                                .iter()
+                                .flat_map(|tags| tags.split(','))
+                                .map(str::to_owned)
+                                .collect();
+                            tags.sort();
+                            Ok(LinkSummary {
+                                link: link_from_row(row)?,
+                                clicks: row.get("clicks")?,
+                                tags,
+                            })
+                        })?
+                        .collect()
+                })
+                .map_err(Error::Database)?;
+
+            Ok(LinkPage { links, total })
+        })
+        .await
+    }
+
+    async fn create_user(
+        &self,
+        name: &str,
+        password_hash: Option<&str>,
+        role: Role,
+    ) -> QrLinkResult<User> {
+        let (name, password_hash) = (name.to_owned(), password_hash.map(str::to_owned));
+        self.write(move |conn| {
+            conn.query_row(
+                &format!(
+                    "INSERT INTO users (name, password_hash, role) VALUES (?, ?, ?)
+                    RETURNING {USER_COLUMNS}"
+                ),
+                rusqlite::params![name, password_hash, role.as_str()],
+                user_from_row,
+            )
+            .map_err(|error| match error {
+                rusqlite::Error::SqliteFailure(failure, _)
+                    if failure.code == rusqlite::ErrorCode::ConstraintViolation =>
+                {
+                    super::user_taken(&name)
+                }
+                error => Error::Database(error),
+            })
+        })
+        .await
+    }
+
+    async fn user(&self, id: u64) -> QrLinkResult<Option<User>> {
+        self.read(move |conn| {
+            conn.query_row(
+                &format!("SELECT {USER_COLUMNS} FROM users WHERE id = ?"),
+                [id],
+                user_from_row,
+            )
+            .optional()
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn set_user_role(&self, id: u64, role: Role) -> QrLinkResult<User> {
+        self.write(move |conn| {
+            conn.query_row(
+                &format!("UPDATE users SET role = ? WHERE id = ? RETURNING {USER_COLUMNS}"),
+                rusqlite::params![role.as_str(), id],
+                user_from_row,
+            )
+            .optional()
+            .map_err(Error::Database)?
+            .ok_or(Error::UnknownUser(id))
+        })
+        .await
+    }
+
+    async fn user_credentials(&self, name: &str) -> QrLinkResult<Option<(User, String)>> {
+        let name = name.to_owned();
+        self.read(move |conn| {
+            conn.query_row(
+                &format!(
+                    "SELECT {USER_COLUMNS}, password_hash FROM users
+                    WHERE name = ? AND password_hash IS NOT NULL"
+                ),
+                [name],
+                |row| Ok((user_from_row(row)?, row.get(4)?)),
+            )
+            .optional()
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn oidc_user(&self, subject: &str, name: &str) -> QrLinkResult<User> {
+        let (subject, name) = (subject.to_owned(), name.to_owned());
+        self.write(move |conn| {
+            // Updated to itself on a conflict, so the user already there is returned
+            conn.query_row(
+                &format!(
+                    "INSERT INTO users (name, oidc_subject) VALUES (?, ?)
+                    ON CONFLICT (oidc_subject) DO UPDATE SET oidc_subject = excluded.oidc_subject
+                    RETURNING {USER_COLUMNS}"
+                ),
+                [&name, &subject],
+                user_from_row,
+            )
+            .map_err(|error| match error {
+                rusqlite::Error::SqliteFailure(failure, _)
+                    if failure.code == rusqlite::ErrorCode::ConstraintViolation =>
+                {
+                    super::user_taken(&name)
+                }
+                error => Error::Database(error),
+            })
+        })
+        .await
+    }
+
+    async fn users(&self) -> QrLinkResult<Vec<User>> {
+        self.read(|conn| {
+            conn.prepare(&format!("SELECT {USER_COLUMNS} FROM users ORDER BY id"))
+                .and_then(|mut stmt| stmt.query_map([], user_from_row)?.collect())
+                .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn create_api_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        prefix: &str,
+        user_id: Option<u64>,
+        role: Role,
+    ) -> QrLinkResult<ApiKey> {
+        let (name, key_hash, prefix) = (name.to_owned(), key_hash.to_owned(), prefix.to_owned());
+        self.write(move |conn| {
+            if let Some(user_id) = user_id {
+                let known: bool = conn
+                    .query_row(
+                        "SELECT EXISTS (SELECT 1 FROM users WHERE id = ?)",
+                        [user_id],
+                        |row| row.get(0),
+                    )
+                    .map_err(Error::Database)?;
+                if !known {
+                    return Err(Error::UnknownUser(user_id));
+                }
+            }
+            conn.query_row(
+                &format!(
+                    "INSERT INTO api_keys (name, key_hash, prefix, user_id, role)
+                    VALUES (?, ?, ?, ?, ?)
+                    RETURNING {API_KEY_COLUMNS}"
+                ),
+                rusqlite::params![name, key_hash, prefix, user_id, role.as_str()],
+                api_key_from_row,
+            )
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> QrLinkResult<Option<ApiKey>> {
+        let key_hash = key_hash.to_owned();
+        self.read(move |conn| {
+            conn.query_row(
+                &format!(
+                    "SELECT {API_KEY_COLUMNS},
+                        (SELECT role FROM users WHERE users.id = api_keys.user_id)
+                    FROM api_keys WHERE key_hash = ? AND revoked_at IS NULL"
+                ),
+                [key_hash],
+                |row| {
+                    let mut key = api_key_from_row(row)?;
+                    if let Some(user_role) = row.get::<_, Option<String>>(6)? {
+                        key.role = key.role.min(Role::parse(&user_role));
+                    }
+                    Ok(key)
+                },
+            )
+            .optional()
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn revoke_api_key(&self, id: u64) -> QrLinkResult<()> {
+        self.write(move |conn| {
+            let found = conn
+                .execute(
+                    "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, CURRENT_TIMESTAMP)
+                    WHERE id = ?",
+                    [id],
+                )
+                .map_err(Error::Database)?;
+            if found == 0 {
+                return Err(Error::UnknownApiKey(id));
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn create_webhook(&self, webhook: NewWebhook) -> QrLinkResult<Webhook> {
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT INTO webhooks (url, secret, link_id, events) VALUES (?, ?, ?, ?)",
+                rusqlite::params![
+                    webhook.url,
+                    webhook.secret,
+                    webhook.link_id,
+                    WebhookEvent::join(&webhook.events)
+                ],
+            )
+            .map_err(Error::Database)?;
+            get_webhook(conn, conn.last_insert_rowid() as u64)
+        })
+        .await
+    }
+
+    async fn webhooks(&self) -> QrLinkResult<Vec<Webhook>> {
+        self.read(|conn| {
+            conn.prepare(&format!(
+                "SELECT {WEBHOOK_COLUMNS} FROM webhooks
+                LEFT JOIN urls ON urls.id = webhooks.link_id
+                ORDER BY webhooks.id"
+            ))
+            .and_then(|mut statement| statement.query_map([], webhook_from_row)?.collect())
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn delete_webhook(&self, id: u64) -> QrLinkResult<()> {
+        self.write(move |conn| {
+            // Foreign keys aren't enforced, so the deliveries don't cascade by themselves
+            let tx = conn.transaction().map_err(Error::Database)?;
+            tx.execute("DELETE FROM webhook_deliveries WHERE webhook_id = ?", [id])
+                .map_err(Error::Database)?;
+            let found = tx
+                .execute("DELETE FROM webhooks WHERE id = ?", [id])
+                .map_err(Error::Database)?;
+            if found == 0 {
+                return Err(Error::UnknownWebhook(id));
+            }
+            tx.commit().map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn enqueue_deliveries(
+        &self,
+        webhook_ids: Vec<u64>,
+        event: WebhookEvent,
+        payload: String,
+    ) -> QrLinkResult<()> {
+        self.write(move |conn| {
+            let tx = conn.transaction().map_err(Error::Database)?;
+            {
+                let mut statement = tx
+                    .prepare(
+                        "INSERT INTO webhook_deliveries
+                            (webhook_id, event, payload, next_attempt_at)
+                        VALUES (?, ?, ?, ?)",
+                    )
+                    .map_err(Error::Database)?;
+                let now = Utc::now();
+                for webhook_id in webhook_ids {
+                    statement
+                        .execute(rusqlite::params![webhook_id, event.as_str(), payload, now])
+                        .map_err(Error::Database)?;
+                }
+            }
+            tx.commit().map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn claim_deliveries(
+        &self,
+        limit: u32,
+        lease_until: DateTime<Utc>,
+    ) -> QrLinkResult<Vec<DueDelivery>> {
+        self.write(move |conn| {
+            let tx = conn
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                .map_err(Error::Database)?;
+            let due: Vec<DueDelivery> = tx
+                .prepare(
+                    "SELECT d.id, d.event, d.payload, d.attempts, w.url, w.secret
+                    FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+                    WHERE d.status = 'pending' AND datetime(d.next_attempt_at) <= datetime(?)
+                    ORDER BY d.next_attempt_at, d.id LIMIT ?",
+                )
+                .and_then(|mut statement| {
+                    statement
+                        .query_map(rusqlite::params![Utc::now(), limit], |row| {
+                            Ok(DueDelivery {
+                                id: row.get(0)?,
+                                event: row.get(1)?,
+                                payload: row.get(2)?,
+                                attempts: row.get(3)?,
+                                url: row.get(4)?,
+                                secret: row.get(5)?,
+                            })
+                        })?
+                        .collect()
+                })
+                .map_err(Error::Database)?;
+            for delivery in &due {
+                tx.execute(
+                    "UPDATE webhook_deliveries SET next_attempt_at = ? WHERE id = ?",
+                    rusqlite::params![lease_until, delivery.id],
+                )
+                .map_err(Error::Database)?;
+            }
+            tx.commit().map_err(Error::Database)?;
+            Ok(due)
+        })
+        .await
+    }
+
+    async fn finish_delivery(&self, id: u64, outcome: DeliveryOutcome) -> QrLinkResult<()> {
+        self.write(move |conn| {
+            match outcome {
+                DeliveryOutcome::Delivered { response_status } => conn.execute(
+                    "UPDATE webhook_deliveries
+                    SET status = ?, attempts = attempts + 1, response_status = ?,
+                        last_error = NULL, delivered_at = ?
+                    WHERE id = ?",
+                    rusqlite::params![
+                        DeliveryStatus::Delivered.as_str(),
+                        response_status,
+                        Utc::now(),
+                        id
+                    ],
+                ),
+                DeliveryOutcome::Failed {
+                    response_status,
+                    error,
+                    retry_at,
+                } => {
+                    let status = match retry_at {
+                        Some(_) => DeliveryStatus::Pending,
+                        None => DeliveryStatus::Failed,
+                    };
+                    conn.execute(
+                        "UPDATE webhook_deliveries
+                        SET status = ?, attempts = attempts + 1, response_status = ?,
+                            last_error = ?, next_attempt_at = COALESCE(?, next_attempt_at)
+                        WHERE id = ?",
+                        rusqlite::params![status.as_str(), response_status, error, retry_at, id],
+                    )
+                }
+            }
+            .map_err(Error::Database)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn deliveries(&self, webhook_id: u64, limit: u32) -> QrLinkResult<Vec<Delivery>> {
+        self.read(move |conn| {
+            let found: bool = conn
+                .query_row(
+                    "SELECT EXISTS (SELECT 1 FROM webhooks WHERE id = ?)",
+                    [webhook_id],
+                    |row| row.get(0),
+                )
+                .map_err(Error::Database)?;
+            if !found {
+                return Err(Error::UnknownWebhook(webhook_id));
+            }
+            conn.prepare(&format!(
+                "SELECT {DELIVERY_COLUMNS} FROM webhook_deliveries
+                WHERE webhook_id = ? ORDER BY id DESC LIMIT ?"
+            ))
+            .and_then(|mut statement| {
+                statement
+                    .query_map(rusqlite::params![webhook_id, limit], delivery_from_row)?
+                    .collect()
+            })
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn visitor_salt(&self, day: NaiveDate, fresh: &str) -> QrLinkResult<String> {
+        let fresh = fresh.to_owned();
+        self.write(move |conn| {
+            conn.execute("DELETE FROM visitor_salts WHERE day < ?", [day])
+                .map_err(Error::Database)?;
+            conn.query_row(
+                "INSERT INTO visitor_salts (day, salt) VALUES (?, ?)
+                ON CONFLICT (day) DO UPDATE SET salt = visitor_salts.salt
+                RETURNING salt",
+                rusqlite::params![day, fresh],
+                |row| row.get(0),
+            )
+            .map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn prune_clicks(&self, before: DateTime<Utc>, roll_up: bool) -> QrLinkResult<u64> {
+        self.write(move |conn| {
+            let tx = conn
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                .map_err(Error::Database)?;
+            if roll_up {
+                tx.execute(
+                    "INSERT INTO click_rollups (url_id, day, source, clicks)
+                    SELECT url_id, date(clicked_at), source, COUNT(*) FROM stats
+                    WHERE clicked_at < ?
+                    GROUP BY url_id, date(clicked_at), source
+                    ON CONFLICT (url_id, day, source) DO UPDATE
+                        SET clicks = click_rollups.clicks + excluded.clicks",
+                    [before],
+                )
+                .map_err(Error::Database)?;
+            }
+            let pruned = tx
+                .execute("DELETE FROM stats WHERE clicked_at < ?", [before])
+                .map_err(Error::Database)?;
+            tx.commit().map_err(Error::Database)?;
+            Ok(pruned as u64)
+        })
+        .await
+    }
+
+    async fn record_click(&self, id: u64, click: Click) -> QrLinkResult<RecordedClick> {
+        self.write(move |conn| {
+            // Taking the write lock up front keeps two clicks from both getting the last one
+            let tx = conn
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                .map_err(Error::Database)?;
+            let (deleted, max_clicks): (bool, Option<u64>) = tx
+                .query_row(
+                    "SELECT deleted_at IS NOT NULL, max_clicks FROM urls WHERE id = ?",
+                    [id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .map_err(Error::lookup(&id.to_string()))?;
+            if deleted {
+                return Ok(RecordedClick::Refused);
+            }
+            let clicks = match max_clicks {
+                Some(_) => tx
+                    .query_row(
+                        "SELECT (SELECT COUNT(*) FROM stats WHERE url_id = ?1)
+                            + (SELECT COALESCE(SUM(clicks), 0) FROM click_rollups
+                                WHERE url_id = ?1)",
+                        [id],
+                        |row| row.get(0),
+                    )
+                    .map_err(Error::Database)?,
+                None => 0,
+            };
+            let recorded = RecordedClick::after(clicks, max_clicks);
+            if recorded == RecordedClick::Refused {
+                tx.execute(
+                    "UPDATE urls SET deleted_at = CURRENT_TIMESTAMP WHERE id = ?",
+                    [id],
+                )
+                .map_err(Error::Database)?;
+                tx.commit().map_err(Error::Database)?;
+                return Ok(recorded);
+            }
+            insert_click(&tx, id, &click)?;
+            if recorded == RecordedClick::Last {
+                tx.execute(
+                    "UPDATE urls SET deleted_at = CURRENT_TIMESTAMP WHERE id = ?",
+                    [id],
+                )
+                .map_err(Error::Database)?;
+            }
+            tx.commit().map_err(Error::Database)?;
+            Ok(recorded)
+        })
+        .await
+    }
+
+    async fn record_clicks(&self, clicks: Vec<(u64, Click)>) -> QrLinkResult<()> {
+        self.write(move |conn| {
+            let tx = conn.transaction().map_err(Error::Database)?;
+            for (id, click) in &clicks {
+                insert_click(&tx, *id, click)?;
+            }
+            tx.commit().map_err(Error::Database)
+        })
+        .await
+    }
+
+    async fn stats(&self, id: u64, granularity: Granularity) -> QrLinkResult<LinkStats> {
+        // Clicks from before visitors were hashed are told apart by their address instead, and
+        // clicks kept without either, of visitors asking not to be tracked, count as no one
+        let (clicks, scans, unique_visitors, first_click, last_click): (
+            u64,
+            u64,
+            u64,
+            Option<DateTime<Utc>>,
+            Option<DateTime<Utc>>,
+        ) = self
+            .read(move |conn| {
+                conn.query_row(
+                    "SELECT COUNT(*), COUNT(*) FILTER (WHERE source = 'qr'),
+                        COUNT(DISTINCT NULLIF(COALESCE(visitor, ip_addr), '')),
+                        MIN(clicked_at), MAX(clicked_at)
+                    FROM stats WHERE url_id = ?",
+                    [id],
+                    |row| {
+                        Ok((
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                        ))
+                    },
+                )
+                .map_err(Error::Database)
+            })
+            .await?;
+
+        let rolled_up: (u64, u64, Option<DateTime<Utc>>, Option<DateTime<Utc>>) = self
+            .read(move |conn| {
+                conn.query_row(
+                    "SELECT COALESCE(SUM(clicks), 0),
+                        COALESCE(SUM(clicks) FILTER (WHERE source = 'qr'), 0),
+                        datetime(MIN(day)), datetime(MAX(day))
+                    FROM click_rollups WHERE url_id = ?",
+                    [id],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )
+                .map_err(Error::Database)
+            })
+            .await?;
+
+        let bucket = match granularity {
+            Granularity::Hour => "strftime('%Y-%m-%d %H:00:00', clicked_at)",
+            Granularity::Day => "strftime('%Y-%m-%d 00:00:00', clicked_at)",
+            // Weeks start on Monday: forward to Sunday, then back six days
+            Granularity::Week => {
+                "strftime('%Y-%m-%d 00:00:00', clicked_at, 'weekday 0', '-6 days')"
+            }
+        };
+        let buckets = self
+            .read(move |conn| {
+                conn.prepare(&format!(
+                    "SELECT {bucket} AS start, SUM(clicks) FROM (
+                        SELECT clicked_at, 1 AS clicks FROM stats WHERE url_id = ?1
+                        UNION ALL SELECT day, clicks FROM click_rollups WHERE url_id = ?1
+                    )
+                    GROUP BY start ORDER BY start"
+                ))
+                .and_then(|mut statement| {
+                    statement
+                        .query_map([id], |row| {
+                            Ok(ClickBucket {
+                                start: row.get(0)?,
+                                clicks: row.get(1)?,
+                            })
+                        })?
+                        .collect()
+                })
+                .map_err(Error::Database)
+            })
+            .await?;
+
+        let (referrers, browsers, operating_systems, countries) = self
+            .read(move |conn| {
+                Ok((
+                    breakdown(conn, id, "referrer")?,
+                    breakdown(conn, id, "browser")?,
+                    breakdown(conn, id, "os")?,
+                    breakdown(conn, id, "country")?,
+                ))
+            })
+            .await?;
+
+        let variants = self
+            .read(move |conn| {
+                conn.prepare(
+                    "SELECT variant, COUNT(*) AS clicks,
+                        COUNT(DISTINCT NULLIF(COALESCE(visitor, ip_addr), ''))
+                    FROM stats WHERE url_id = ? AND variant IS NOT NULL
+                    GROUP BY variant ORDER BY clicks DESC, variant",
+                )
+                .and_then(|mut statement| {
+                    statement
+                        .query_map([id], |row| {
+                            Ok(VariantStats {
+                                url: row.get(0)?,
+                                clicks: row.get(1)?,
+                                unique_visitors: row.get(2)?,
+                            })
+                        })?
+                        .collect()
+                })
+                .map_err(Error::Database)
+            })
+            .await?;
+
+        let (rolled_up_clicks, rolled_up_scans, first_day, last_day) = rolled_up;
+        Ok(LinkStats {
+            clicks: clicks + rolled_up_clicks,
+            scans: scans + rolled_up_scans,
+            unique_visitors,
+            // Rolled up clicks are all older than the ones kept
+            first_click: first_day.or(first_click),
+            last_click: last_click.or(last_day),
+            buckets,
+            referrers,
+            browsers,
+            operating_systems,
+            countries,
+            variants,
+        })
+    }
+}
+
+fn insert_click(conn: &rusqlite::Connection, id: u64, click: &Click) -> QrLinkResult<()> {
+    conn.prepare_cached(
+        "INSERT INTO stats (
+            url_id, ip_addr, visitor, referrer, user_agent, browser, os, country, city, source,
+            variant
+        )
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .and_then(|mut statement| {
+        statement.execute(rusqlite::params![
+            id,
+            click.ip_addr,
+            click.visitor,
+            click.referrer,
+            click.user_agent,
+            click.browser,
+            click.os,
+            click.country,
+            click.city,
+            click.source.as_str(),
+            click.variant
+        ])
+    })
+    .map_err(Error::Database)?;
+    Ok(())
+}
+
+/// The most common values of `column` among a link's clicks, leaving out clicks without one
+fn breakdown(conn: &rusqlite::Connection, id: u64, column: &str) -> QrLinkResult<Vec<Breakdown>> {
+    conn.prepare(&format!(
+        "SELECT {column}, COUNT(*) AS clicks FROM stats
+        WHERE url_id = ? AND {column} IS NOT NULL
+        GROUP BY {column} ORDER BY clicks DESC, {column} LIMIT ?"
+    ))
+    .and_then(|mut statement| {
+        statement
+            .query_map(rusqlite::params![id, TOP_BREAKDOWN], |row| {
+                Ok(Breakdown {
+                    name: row.get(0)?,
+                    clicks: row.get(1)?,
+                })
+            })?
+            .collect()
+    })
+    .map_err(Error::Database)
+}
+
+fn api_key_from_row(row: &rusqlite::Row) -> rusqlite::Result<ApiKey> {
+    Ok(ApiKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        prefix: row.get(2)?,
+        created_at: row.get(3)?,
+        user_id: row.get(4)?,
+        role: Role::parse(&row.get::<_, String>(5)?),
+    })
+}
+
+fn user_from_row(row: &rusqlite::Row) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        created_at: row.get(2)?,
+        role: Role::parse(&row.get::<_, String>(3)?),
+    })
+}
+
+fn webhook_from_row(row: &rusqlite::Row) -> rusqlite::Result<Webhook> {
+    Ok(Webhook {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        secret: row.get(2)?,
+        link_id: row.get(3)?,
+        link: row.get(4)?,
+        events: WebhookEvent::split(&row.get::<_, String>(5)?),
+        created_at: row.get(6)?,
+    })
+}
+
+fn get_webhook(conn: &rusqlite::Connection, id: u64) -> QrLinkResult<Webhook> {
+    conn.query_row(
+        &format!(
+            "SELECT {WEBHOOK_COLUMNS} FROM webhooks
+            LEFT JOIN urls ON urls.id = webhooks.link_id
+            WHERE webhooks.id = ?"
+        ),
+        [id],
+        webhook_from_row,
+    )
+    .map_err(Error::Database)
+}
+
+fn delivery_from_row(row: &rusqlite::Row) -> rusqlite::Result<Delivery> {
+    Ok(Delivery {
+        id: row.get(0)?,
+        event: row.get(1)?,
+        payload: row.get(2)?,
+        status: DeliveryStatus::parse(&row.get::<_, String>(3)?),
+        attempts: row.get(4)?,
+        next_attempt_at: row.get(5)?,
+        response_status: row.get(6)?,
+        last_error: row.get(7)?,
+        created_at: row.get(8)?,
+        delivered_at: row.get(9)?,
+    })
+}
+
+fn link_from_row(row: &rusqlite::Row) -> rusqlite::Result<Link> {
+    Ok(Link {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        slug: row.get(2)?,
+        code: row.get(3)?,
+        created_at: row.get(4)?,
+        deleted_at: row.get(5)?,
+        expires_at: row.get(6)?,
+        max_clicks: row.get(7)?,
+        activate_at: row.get(8)?,
+        forward_query: row.get(9)?,
+        forward_path: row.get(10)?,
+        utm_template: row.get(11)?,
+        title: row.get(12)?,
+        notes: row.get(13)?,
+        page_title: row.get(14)?,
+        page_description: row.get(15)?,
+        quarantine: Quarantine::from_columns(row.get(16)?, row.get(17)?),
+        owner_id: row.get(18)?,
+        noindex: row.get(19)?,
+    })
+}
+
+fn get_link(conn: &rusqlite::Connection, id: u64) -> QrLinkResult<Link> {
+    conn.query_row(
+        &format!("SELECT {} FROM urls WHERE id = ?", LINK_COLUMNS),
+        [id],
+        link_from_row,
+    )
+    .map_err(Error::lookup(&id.to_string()))
+}
+
+/// Finds the internal id of a link from the public identifier used in its path, which is its
+/// random code or a custom slug. Links created before codes existed are still reachable by
+/// their numeric id.
+fn resolve_id(conn: &rusqlite::Connection, external_id: &str) -> QrLinkResult<u64> {
+    let found = conn
+        .query_row(
+            "SELECT id FROM urls WHERE code = ?1 OR slug = ?1",
+            [external_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(Error::Database)?;
+    match (found, external_id.parse::<u64>()) {
+        (Some(id), _) => Ok(id),
+        (None, Ok(legacy_id)) => conn
+            .query_row(
+                "SELECT id FROM urls WHERE id = ? AND code IS NULL",
+                [legacy_id],
+                |row| row.get(0),
+            )
+            .map_err(Error::lookup(external_id)),
+        (None, Err(_)) => Err(Error::NotFound(external_id.to_owned())),
+    }
+}
+
+/// Draws codes until one is free, both as a code and as a slug
+fn unused_code(conn: &rusqlite::Connection, codes: &CodeGenerator) -> QrLinkResult<String> {
+    let mut stmt = conn
+        .prepare("SELECT EXISTS (SELECT 1 FROM urls WHERE code = ?1 OR slug = ?1)")
+        .map_err(Error::Database)?;
+    for _ in 0..super::MAX_CODE_ATTEMPTS {
+        let code = codes.generate();
+        let taken: bool = stmt
+            .query_row([&code], |row| row.get(0))
+            .map_err(Error::Database)?;
+        if !taken {
+            return Ok(code);
+        }
+    }
+    Err(Error::Conflict(
+        "could not find an unused code, consider a longer code length".into(),
+    ))
+}
+
+/// Stores `link` under an unused code and returns it as stored
+fn insert_link(
+    conn: &rusqlite::Connection,
+    link: &NewLink,
+    codes: &CodeGenerator,
+) -> QrLinkResult<Link> {
+    if let Some(slug) = &link.slug {
+        ensure_slug_available(conn, slug, None)?;
+    }
+    let code = unused_code(conn, codes)?;
+    if let Some(url_hash) = &link.url_hash {
+        // An expired link is no duplicate to return, so it gives up the hash
+        conn.execute(
+            "UPDATE urls SET url_hash = NULL WHERE url_hash = ? AND expires_at <= ?",
+            rusqlite::params![url_hash, Utc::now()],
+        )
+        .map_err(Error::Database)?;
+    }
+    if let Some(idempotency_key) = &link.idempotency_key {
+        conn.execute(
+            "UPDATE urls SET idempotency_key = NULL
+            WHERE idempotency_key = ? AND owner_id IS ? AND created_at <= ?",
+            rusqlite::params![
+                idempotency_key,
+                link.owner_id,
+                Utc::now() - super::IDEMPOTENCY_TTL
+            ],
+        )
+        .map_err(Error::Database)?;
+    }
+
+    conn.execute(
+        "INSERT INTO urls (
+            external_id, slug, code, expires_at, max_clicks, activate_at, forward_query,
+            forward_path, utm_template, title, notes, url_hash, idempotency_key, owner_id, noindex
+        )
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        rusqlite::params![
+            link.url,
+            link.slug,
+            code,
+            link.expires_at,
+            link.max_clicks,
+            link.activate_at,
+            link.forward_query,
+            link.forward_path,
+            link.utm_template,
+            link.title,
+            link.notes,
+            link.url_hash,
+            link.idempotency_key,
+            link.owner_id,
+            link.noindex
+        ],
+    )
+    .map_err(slug_conflict(link.slug.as_deref().unwrap_or_default()))?;
+
+    let id = conn.last_insert_rowid() as u64;
+    if !link.targets.platforms.is_empty() {
+        replace_platform_targets(conn, id, &link.targets.platforms)?;
+    }
+    if !link.targets.countries.is_empty() {
+        replace_country_targets(conn, id, &link.targets.countries)?;
+    }
+    if !link.targets.languages.is_empty() {
+        replace_language_targets(conn, id, &link.targets.languages)?;
+    }
+    if !link.targets.variants.is_empty() {
+        replace_variants(conn, id, &link.targets.variants)?;
+    }
+    if !link.tags.is_empty() {
+        replace_tags(conn, id, &link.tags)?;
+    }
+    get_link(conn, id)
+}
+
+/// Makes `tags` the only tags of the link `id`, adding any that are new
+fn replace_tags(conn: &rusqlite::Connection, id: u64, tags: &[String]) -> QrLinkResult<()> {
+    conn.execute("DELETE FROM link_tags WHERE url_id = ?", [id])
+        .map_err(Error::Database)?;
+    let mut insert_tag = conn
+        .prepare("INSERT INTO tags (name) VALUES (?) ON CONFLICT (name) DO NOTHING")
+        .map_err(Error::Database)?;
+    let mut insert = conn
+        .prepare("INSERT INTO link_tags (url_id, tag_id) SELECT ?, id FROM tags WHERE name = ?")
+        .map_err(Error::Database)?;
+    for tag in tags {
+        insert_tag.execute([tag]).map_err(Error::Database)?;
+        insert
+            .execute(rusqlite::params![id, tag])
+            .map_err(Error::Database)?;
+    }
+    Ok(())
+}
+
+/// Makes `platforms` the only platform targets of the link `id`
+fn replace_platform_targets(
+    conn: &rusqlite::Connection,
+    id: u64,
+    platforms: &BTreeMap<Platform, String>,
+) -> QrLinkResult<()> {
+    conn.execute("DELETE FROM link_targets WHERE url_id = ?", [id])
+        .map_err(Error::Database)?;
+    let mut insert = conn
+        .prepare("INSERT INTO link_targets (url_id, platform, url) VALUES (?, ?, ?)")
+        .map_err(Error::Database)?;
+    for (platform, url) in platforms {
+        insert
+            .execute(rusqlite::params![id, platform.as_str(), url])
+            .map_err(Error::Database)?;
+    }
+    Ok(())
+}
+
+/// Makes `countries` the only country targets of the link `id`, in their order
+fn replace_country_targets(
+    conn: &rusqlite::Connection,
+    id: u64,
+    countries: &[CountryTarget],
+) -> QrLinkResult<()> {
+    conn.execute("DELETE FROM link_country_targets WHERE url_id = ?", [id])
+        .map_err(Error::Database)?;
+    let mut insert = conn
+        .prepare(
+            "INSERT INTO link_country_targets (url_id, position, country, url)
+            VALUES (?, ?, ?, ?)",
+        )
+        .map_err(Error::Database)?;
+    for (position, target) in countries.iter().enumerate() {
+        insert
+            .execute(rusqlite::params![id, position, target.country, target.url])
+            .map_err(Error::Database)?;
+    }
+    Ok(())
+}
+
+/// Makes `languages` the only language targets of the link `id`, in their order
+fn replace_language_targets(
+    conn: &rusqlite::Connection,
+    id: u64,
+    languages: &[LanguageTarget],
+) -> QrLinkResult<()> {
+    conn.execute("DELETE FROM link_language_targets WHERE url_id = ?", [id])
+        .map_err(Error::Database)?;
+    let mut insert = conn
+        .prepare(
+            "INSERT INTO link_language_targets (url_id, position, lang, url)
+            VALUES (?, ?, ?, ?)",
+        )
+        .map_err(Error::Database)?;
+    for (position, target) in languages.iter().enumerate() {
+        insert
+            .execute(rusqlite::params![id, position, target.lang, target.url])
+            .map_err(Error::Database)?;
+    }
+    Ok(())
+}
+
+/// Makes `variants` the only variants of the link `id`, in their order
+fn replace_variants(
+    conn: &rusqlite::Connection,
+    id: u64,
+    variants: &[Variant],
+) -> QrLinkResult<()> {
+    conn.execute("DELETE FROM link_variants WHERE url_id = ?", [id])
+        .map_err(Error::Database)?;
+    let mut insert = conn
+        .prepare("INSERT INTO link_variants (url_id, position, url, weight) VALUES (?, ?, ?, ?)")
+        .map_err(Error::Database)?;
+    for (position, variant) in variants.iter().enumerate() {
+        insert
+            .execute(rusqlite::params![id, position, variant.url, variant.weight])
+            .map_err(Error::Database)?;
+    }
+    Ok(())
+}
+
+/// Refuses a slug that is already some other link's slug or code
+fn ensure_slug_available(
+    conn: &rusqlite::Connection,
+    slug: &str,
+    for_id: Option<u64>,
+) -> QrLinkResult<()> {
+    let taken: bool = conn
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM urls WHERE (code = ?1 OR slug = ?1) AND id IS NOT ?2)",
+            rusqlite::params![slug, for_id],
+            |row| row.get(0),
+        )
+        .map_err(Error::Database)?;
+    if taken {
+        return Err(super::slug_taken(slug));
+    }
+    Ok(())
+}
+
+/// Maps the unique index on `urls.slug` being violated to a conflict
+fn slug_conflict(slug: &str) -> impl FnOnce(rusqlite::Error) -> Error + '_ {
+    move |error| match error {
+        rusqlite::Error::SqliteFailure(failure, _)
+            if failure.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            super::slug_taken(slug)
+        }
+        error => Error::Database(error),
+    }
+}
@@ -0,0 +1,165 @@
+//! Picking where a redirect goes among a link's targets, from what the request tells of the
+//! visitor
+
+use std::net::IpAddr;
+
+use chrono::NaiveDate;
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, QrLinkResult};
+use crate::store::{LinkTargets, Platform, Variant};
+
+/// What a country target names to match every member state of the EU
+const EU: &str = "EU";
+
+/// ISO 3166-1 alpha-2 codes of the EU member states
+const EU_MEMBERS: [&str; 27] = [
+    "AT", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "ES", "FI", "FR", "GR", "HR", "HU", "IE", "IT",
+    "LT", "LU", "LV", "MT", "NL", "PL", "PT", "RO", "SE", "SI", "SK",
+];
+
+/// What is known of the visitor a redirect is for
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Visitor<'a> {
+    pub user_agent: Option<&'a str>,
+    /// ISO 3166-1 alpha-2 code, when the address could be located
+    pub country: Option<&'a str>,
+    /// The `Accept-Language` header, as sent
+    pub accept_language: Option<&'a str>,
+}
+
+/// The platform a user agent runs on, if it is one links can target
+pub fn platform(user_agent: &str) -> Option<Platform> {
+    let parsed = woothee::parser::Parser::new().parse(user_agent)?;
+    match parsed.os {
+        "iPhone" | "iPad" | "iPod" | "iOS" => Some(Platform::Ios),
+        "Android" => Some(Platform::Android),
+        _ if parsed.category == "pc" => Some(Platform::Desktop),
+        _ => None,
+    }
+}
+
+/// `country` the way country targets keep it, uppercase, refusing anything but a two-letter
+/// code
+pub fn country_code(country: &str) -> QrLinkResult<String> {
+    let code = country.trim().to_ascii_uppercase();
+    if code.len() != 2 || !code.bytes().all(|byte| byte.is_ascii_uppercase()) {
+        return Err(Error::Validation(format!(
+            "country '{country}' must be a two-letter ISO 3166-1 code, or {EU}"
+        )));
+    }
+    Ok(code)
+}
+
+/// `lang` the way language targets keep it, lowercase, refusing anything but a language tag
+/// such as `da` or `en-gb`
+pub fn language_tag(lang: &str) -> QrLinkResult<String> {
+    let tag = lang.trim().to_ascii_lowercase();
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or_default();
+    let valid = (2..=8).contains(&primary.len())
+        && primary.bytes().all(|byte| byte.is_ascii_lowercase())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len())
+                && subtag.bytes().all(|byte| byte.is_ascii_alphanumeric())
+        });
+    if !valid {
+        return Err(Error::Validation(format!(
+            "language '{lang}' must be a language tag such as 'da' or 'en-GB'"
+        )));
+    }
+    Ok(tag)
+}
+
+/// The languages an `Accept-Language` header asks for, most preferred first, leaving out the
+/// wildcard and any the visitor refuses with `q=0`
+fn accepted_languages(accept_language: &str) -> Vec<&str> {
+    let mut ranges: Vec<(&str, f32)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let tag = parts.next().filter(|tag| !tag.is_empty() && *tag != "*")?;
+            let quality = match parts.find_map(|param| param.strip_prefix("q=")) {
+                Some(quality) => quality.parse().ok()?,
+                None => 1.0,
+            };
+            (quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    // Stable, so languages of equal weight keep the order the visitor listed them in
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges.into_iter().map(|(tag, _)| tag).collect()
+}
+
+/// Whether a language target for `target` covers visitors asking for `lang`, either exactly or
+/// as a more specific tag such as `da-DK` for `da`
+fn speaks(target: &str, lang: &str) -> bool {
+    lang.eq_ignore_ascii_case(target)
+        || lang.get(..target.len()).is_some_and(|prefix| {
+            prefix.eq_ignore_ascii_case(target) && lang[target.len()..].starts_with('-')
+        })
+}
+
+/// Whether a country target for `target` covers visitors from `country`
+fn covers(target: &str, country: &str) -> bool {
+    target == country || (target == EU && EU_MEMBERS.contains(&country))
+}
+
+/// Where `targets` send `visitor`, if any of them does. A target for the visitor's platform
+/// comes before one for a language they accept, which comes before one for their country.
+pub fn target<'a>(targets: &'a LinkTargets, visitor: Visitor) -> Option<&'a str> {
+    let by_platform = visitor
+        .user_agent
+        .and_then(platform)
+        .and_then(|platform| targets.platforms.get(&platform));
+    let by_language = || {
+        accepted_languages(visitor.accept_language?)
+            .into_iter()
+            .find_map(|lang| {
+                targets
+                    .languages
+                    .iter()
+                    .find(|target| speaks(&target.lang, lang))
+            })
+            .map(|target| &target.url)
+    };
+    let by_country = || {
+        let country = visitor.country?;
+        targets
+            .countries
+            .iter()
+            .find(|target| covers(&target.country, country))
+            .map(|target| &target.url)
+    };
+    by_platform
+        .or_else(by_language)
+        .or_else(by_country)
+        .map(String::as_str)
+}
+
+/// The variant of the link `link_id` that the visitor from `ip_addr` gets on `day`, each
+/// getting a share of visitors by its weight. The same visitor gets the same variant all day.
+pub fn variant(
+    variants: &[Variant],
+    link_id: u64,
+    ip_addr: IpAddr,
+    day: NaiveDate,
+) -> Option<&Variant> {
+    let total: u64 = variants
+        .iter()
+        .map(|variant| u64::from(variant.weight))
+        .sum();
+    if total == 0 {
+        return None;
+    }
+    let digest = Sha256::digest(format!("{link_id}/{ip_addr}/{day}"));
+    let mut point = u64::from_be_bytes(digest[..8].try_into().ok()?) % total;
+    variants.iter().find(|variant| {
+        let weight = u64::from(variant.weight);
+        if point < weight {
+            return true;
+        }
+        point -= weight;
+        false
+    })
+}
@@ -0,0 +1,73 @@
+//! Text drawn into images, for social cards and the labels of framed QR codes: measured and
+//! laid out with the font's own advances and kerning, and painted with smoothed edges
+
+use ab_glyph::{Font, FontArc, PxScale, ScaleFont, point};
+use image::RgbaImage;
+
+use crate::qr::{self, Color};
+
+/// The width of `text` at `size` pixels
+pub fn width(font: &FontArc, text: &str, size: f32) -> f32 {
+    let scaled = font.as_scaled(PxScale::from(size));
+    let mut width = 0.0;
+    let mut previous = None;
+    for ch in text.chars() {
+        let glyph = scaled.glyph_id(ch);
+        if let Some(previous) = previous {
+            width += scaled.kern(previous, glyph);
+        }
+        width += scaled.h_advance(glyph);
+        previous = Some(glyph);
+    }
+    width
+}
+
+/// `text`, or as much of it as fits in `max_width` followed by an ellipsis
+pub fn fit(font: &FontArc, text: &str, size: f32, max_width: f32) -> String {
+    if width(font, text, size) <= max_width {
+        return text.to_owned();
+    }
+    let mut fitted: String = text.trim_end_matches('…').to_owned();
+    while !fitted.is_empty() && width(font, &format!("{fitted}…"), size) > max_width {
+        fitted.pop();
+    }
+    format!("{}…", fitted.trim_end())
+}
+
+/// Paints `text` in `color` from `left`, sitting on `baseline`, leaving out what falls outside
+/// `image`
+pub fn draw(
+    image: &mut RgbaImage,
+    font: &FontArc,
+    text: &str,
+    size: f32,
+    (left, baseline): (f32, f32),
+    color: Color,
+) {
+    let scaled = font.as_scaled(PxScale::from(size));
+    let mut caret = left;
+    let mut previous = None;
+    for ch in text.chars() {
+        let id = scaled.glyph_id(ch);
+        if let Some(previous) = previous {
+            caret += scaled.kern(previous, id);
+        }
+        let glyph = id.with_scale_and_position(size, point(caret, baseline));
+        caret += scaled.h_advance(id);
+        previous = Some(id);
+        let Some(outline) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outline.px_bounds();
+        outline.draw(|x, y, coverage| {
+            let x = bounds.min.x as i64 + i64::from(x);
+            let y = bounds.min.y as i64 + i64::from(y);
+            if let (Ok(x), Ok(y)) = (u32::try_from(x), u32::try_from(y))
+                && x < image.width()
+                && y < image.height()
+            {
+                qr::blend(image.get_pixel_mut(x, y), color, coverage);
+            }
+        });
+    }
+}
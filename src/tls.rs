@@ -0,0 +1,229 @@
+//! Serving HTTPS without a proxy in front, either from a certificate and private key in PEM
+//! files, or with certificates an ACME certificate authority such as Let's Encrypt issues.
+//!
+//! Certificate files are checked every `tls.reload_interval_secs` and read again once either
+//! changes, e.g. after a renewal, without dropping the connections open at the time. ACME
+//! certificates are requested through TLS-ALPN-01 challenges answered on `listen`, which the
+//! authority must reach on port 443, and are renewed before they expire. The account and
+//! certificates are kept in `tls.acme.directory`, so restarts don't request them again.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use axum_server::tls_rustls::RustlsConfig;
+use futures_util::StreamExt;
+use rustls_acme::axum::AxumAcceptor;
+use rustls_acme::caches::DirCache;
+use serde::Deserialize;
+use url::Url;
+
+use crate::error::{Error, QrLinkResult};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate, followed by any intermediates; without it, plain HTTP is
+    /// served
+    pub certificate: Option<PathBuf>,
+    /// PEM file with the certificate's private key
+    pub private_key: Option<PathBuf>,
+    /// Seconds between two checks for a changed certificate or key; 0 to read them at start
+    /// only
+    pub reload_interval_secs: u64,
+    pub acme: AcmeConfig,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            certificate: None,
+            private_key: None,
+            reload_interval_secs: 60,
+            acme: AcmeConfig::default(),
+        }
+    }
+}
+
+/// Certificates from an ACME certificate authority, in place of certificate files
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcmeConfig {
+    pub enabled: bool,
+    /// Domains the certificate is for; the host of `base_url` if empty
+    pub domains: Vec<String>,
+    /// Email addresses the authority may write to about the certificates, e.g. before they
+    /// expire
+    pub contact: Vec<String>,
+    /// The authority's directory URL
+    pub directory_url: String,
+    /// Where the account and certificates are kept between restarts
+    pub directory: PathBuf,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        AcmeConfig {
+            enabled: false,
+            domains: Vec::new(),
+            contact: Vec::new(),
+            directory_url: rustls_acme::acme::LETS_ENCRYPT_PRODUCTION_DIRECTORY.into(),
+            directory: "acme".into(),
+        }
+    }
+}
+
+impl AcmeConfig {
+    /// The domains to request a certificate for
+    fn domains(&self, base_url: Option<&Url>) -> QrLinkResult<Vec<String>> {
+        if !self.domains.is_empty() {
+            return Ok(self.domains.clone());
+        }
+        match base_url.and_then(Url::domain) {
+            Some(domain) => Ok(vec![domain.to_owned()]),
+            None => Err(Error::Config(
+                "tls.acme needs tls.acme.domains, or a base_url with a domain".into(),
+            )),
+        }
+    }
+}
+
+/// How HTTPS is served
+#[derive(Clone)]
+pub enum Tls {
+    /// From certificate files
+    Files(RustlsConfig),
+    /// With certificates from an ACME certificate authority
+    Acme(AxumAcceptor),
+}
+
+impl TlsConfig {
+    /// Checks the settings that can be wrong without failing to parse
+    pub fn validate(&self, base_url: Option<&Url>) -> QrLinkResult<()> {
+        let files = self.files()?;
+        if !self.acme.enabled {
+            return Ok(());
+        }
+        if files.is_some() {
+            return Err(Error::Config(
+                "tls.acme can't be enabled along with tls.certificate".into(),
+            ));
+        }
+        self.acme.domains(base_url)?;
+        Url::parse(&self.acme.directory_url).map_err(|error| {
+            Error::Config(format!(
+                "tls.acme.directory_url '{}': {error}",
+                self.acme.directory_url
+            ))
+        })?;
+        Ok(())
+    }
+
+    /// The certificate and key files, if HTTPS is to be served from them
+    fn files(&self) -> QrLinkResult<Option<(&Path, &Path)>> {
+        match (&self.certificate, &self.private_key) {
+            (Some(certificate), Some(private_key)) => Ok(Some((certificate, private_key))),
+            (None, None) => Ok(None),
+            _ => Err(Error::Config(
+                "tls.certificate and tls.private_key must be given together".into(),
+            )),
+        }
+    }
+}
+
+/// HTTPS as `tls` configures it, or `None` to serve plain HTTP. Certificate files are watched
+/// for changes, and ACME certificates requested and renewed, from here on.
+pub async fn start(config: &TlsConfig, base_url: Option<&Url>) -> QrLinkResult<Option<Tls>> {
+    // Whichever provider another dependency installed first is as good
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    if config.acme.enabled {
+        return Ok(Some(Tls::Acme(start_acme(&config.acme, base_url)?)));
+    }
+    let Some(rustls) = load(config).await? else {
+        return Ok(None);
+    };
+    start_reloading(config, rustls.clone());
+    Ok(Some(Tls::Files(rustls)))
+}
+
+/// Requests a certificate from the authority, or takes the one kept from before, and renews it
+/// before it expires
+fn start_acme(config: &AcmeConfig, base_url: Option<&Url>) -> QrLinkResult<AxumAcceptor> {
+    let domains = config.domains(base_url)?;
+    let mut state = rustls_acme::AcmeConfig::new(&domains)
+        .contact(config.contact.iter().map(|email| format!("mailto:{email}")))
+        .cache(DirCache::new(config.directory.clone()))
+        .directory(&config.directory_url)
+        .state();
+    let acceptor = state.axum_acceptor(state.default_rustls_config());
+    tokio::spawn(async move {
+        // The state retries failed orders itself, backing off, for as long as it is polled
+        while let Some(event) = state.next().await {
+            match event {
+                Ok(event) => tracing::info!(?event, ?domains, "acme"),
+                Err(error) => tracing::warn!(%error, ?domains, "acme certificate not obtained"),
+            }
+        }
+    });
+    Ok(acceptor)
+}
+
+/// The certificate and key `tls` points to, ready to serve HTTPS with, or `None` if it points to
+/// none
+async fn load(config: &TlsConfig) -> QrLinkResult<Option<RustlsConfig>> {
+    let Some((certificate, private_key)) = config.files()? else {
+        return Ok(None);
+    };
+    let rustls = RustlsConfig::from_pem_file(certificate, private_key)
+        .await
+        .map_err(|error| {
+            Error::Config(format!(
+                "tls: can't use {} and {}: {error}",
+                certificate.display(),
+                private_key.display()
+            ))
+        })?;
+    Ok(Some(rustls))
+}
+
+/// When `path` was last changed, if it can be told
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reads the certificate and key again whenever either changes, keeping the ones read before
+/// if they can't be used
+fn start_reloading(config: &TlsConfig, rustls: RustlsConfig) {
+    let Ok(Some((certificate, private_key))) = config.files() else {
+        return;
+    };
+    if config.reload_interval_secs == 0 {
+        return;
+    }
+    let (certificate, private_key) = (certificate.to_owned(), private_key.to_owned());
+    let period = Duration::from_secs(config.reload_interval_secs);
+    tokio::spawn(async move {
+        let mut last = (modified(&certificate), modified(&private_key));
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            ticker.tick().await;
+            let current = (modified(&certificate), modified(&private_key));
+            if current == last {
+                continue;
+            }
+            // Retried on the next tick should only one of the files have been replaced so far
+            match rustls
+                .reload_from_pem_file(&certificate, &private_key)
+                .await
+            {
+                Ok(()) => {
+                    last = current;
+                    tracing::info!(certificate = %certificate.display(), "certificate reloaded");
+                }
+                Err(error) => tracing::warn!(certificate = %certificate.display(), %error,
+                    "certificate could not be reloaded, keeping the one read before"),
+            }
+        }
+    });
+}
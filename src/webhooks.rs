@@ -0,0 +1,601 @@
+//! Webhooks: endpoints, registered by admins, that are sent a signed JSON payload
+//! whenever a link is clicked, created or deleted.
+//!
+//! Events are queued in the database as one delivery per webhook and sent by a background
+//! worker, which retries failed deliveries with exponential backoff. Every request carries
+//! `X-Webhook-Signature: t=<unix time>,v1=<hex>`, the HMAC-SHA256 of `<unix time>.<body>` under
+//! the webhook's secret.
+
+use std::sync::{Arc, Once, RwLock};
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::AppState;
+use crate::auth::{Authorized, Manage};
+use crate::code::{BASE62, CodeGenerator};
+use crate::config::WebhookConfig;
+use crate::error::{Error, ErrorBody, QrLinkResult};
+use crate::extract::{Json, Path, Query};
+use crate::store::{
+    Click, Delivery, DeliveryOutcome, DeliveryStatus, DueDelivery, Link, LinkStore, NewWebhook,
+    Webhook, WebhookEvent,
+};
+
+/// Marks webhook secrets, so they are easy to recognise, e.g. by secret scanners
+pub static SECRET_PREFIX: &str = "whsec_";
+
+/// Random characters in a secret after [SECRET_PREFIX]
+pub const SECRET_LENGTH: usize = 32;
+
+/// Longest endpoint URL that can be registered
+pub const MAX_URL_LENGTH: usize = 2048;
+
+/// Deliveries the worker sends at once
+const BATCH_SIZE: u32 = 20;
+
+/// How often the worker looks for due retries when nothing wakes it sooner
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the worker reloads the registered webhooks, picking up changes made through
+/// other instances on the same database
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest wait between two attempts at a delivery
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// How long past the timeout a claimed delivery is left alone, after which it is sent again
+/// by whoever finds it, in case its sender died
+const LEASE_MARGIN: Duration = Duration::from_secs(30);
+
+/// Longest error message kept for a failed attempt
+const MAX_ERROR_LENGTH: usize = 512;
+
+/// The registered webhooks, and the worker sending them their deliveries
+pub struct Webhooks {
+    /// Kept in memory, so events no webhook wants cost no database write
+    registered: RwLock<Vec<Webhook>>,
+    /// Wakes the worker when deliveries are queued
+    queued: Notify,
+    worker: Once,
+    client: reqwest::Client,
+    config: WebhookConfig,
+}
+
+impl Webhooks {
+    pub fn new(config: &WebhookConfig) -> QrLinkResult<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .redirect(reqwest::redirect::Policy::none())
+            .user_agent(concat!("qr-link-service/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|error| Error::Config(format!("can't set up the webhook client: {error}")))?;
+        Ok(Webhooks {
+            registered: RwLock::default(),
+            queued: Notify::new(),
+            worker: Once::new(),
+            client,
+            config: config.clone(),
+        })
+    }
+
+    /// Reads the registered webhooks from `store` again
+    pub async fn reload(&self, store: &dyn LinkStore) -> QrLinkResult<()> {
+        let webhooks = store.webhooks().await?;
+        *self.registered.write().expect("webhook registry poisoned") = webhooks;
+        Ok(())
+    }
+
+    /// Starts the worker sending deliveries from `store`, unless it is already running
+    pub fn start(self: &Arc<Self>, store: Arc<dyn LinkStore>) {
+        self.worker.call_once(|| {
+            tokio::spawn(self.clone().deliver_forever(store));
+        });
+    }
+
+    /// The ids of the webhooks that want `event` on the link `link_id`
+    fn subscribers(&self, event: WebhookEvent, link_id: u64) -> Vec<u64> {
+        self.registered
+            .read()
+            .expect("webhook registry poisoned")
+            .iter()
+            .filter(|webhook| webhook.wants(event, link_id))
+            .map(|webhook| webhook.id)
+            .collect()
+    }
+
+    async fn deliver_forever(self: Arc<Self>, store: Arc<dyn LinkStore>) {
+        let mut reloaded = Instant::now();
+        loop {
+            if reloaded.elapsed() >= RELOAD_INTERVAL {
+                if let Err(error) = self.reload(&*store).await {
+                    tracing::warn!(%error, "webhooks could not be reloaded");
+                }
+                reloaded = Instant::now();
+            }
+
+            let lease = Duration::from_secs(self.config.timeout_secs) + LEASE_MARGIN;
+            let due = match store.claim_deliveries(BATCH_SIZE, Utc::now() + lease).await {
+                Ok(due) => due,
+                Err(error) => {
+                    tracing::warn!(%error, "webhook deliveries could not be claimed");
+                    Vec::new()
+                }
+            };
+            let more = due.len() == BATCH_SIZE as usize;
+            futures_util::future::join_all(
+                due.into_iter()
+                    .map(|delivery| self.deliver(&*store, delivery)),
+            )
+            .await;
+
+            if !more {
+                tokio::select! {
+                    () = self.queued.notified() => {}
+                    () = tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+            }
+        }
+    }
+
+    /// Sends one delivery and records how it went
+    async fn deliver(&self, store: &dyn LinkStore, delivery: DueDelivery) {
+        let outcome = self.attempt(&delivery).await;
+        match &outcome {
+            DeliveryOutcome::Delivered { .. } => {
+                tracing::debug!(delivery = delivery.id, url = %delivery.url, "webhook delivered");
+            }
+            DeliveryOutcome::Failed {
+                error, retry_at, ..
+            } => tracing::warn!(
+                delivery = delivery.id,
+                url = %delivery.url,
+                error,
+                retry_at = retry_at.map(|at| at.to_rfc3339()),
+                "webhook delivery failed"
+            ),
+        }
+        if let Err(error) = store.finish_delivery(delivery.id, outcome).await {
+            tracing::warn!(delivery = delivery.id, %error, "webhook delivery not recorded");
+        }
+    }
+
+    async fn attempt(&self, delivery: &DueDelivery) -> DeliveryOutcome {
+        let timestamp = Utc::now().timestamp();
+        let sent = self
+            .client
+            .post(&delivery.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Event", &delivery.event)
+            .header("X-Webhook-Delivery", delivery.id)
+            .header(
+                "X-Webhook-Signature",
+                signature(&delivery.secret, timestamp, &delivery.payload),
+            )
+            .body(delivery.payload.clone())
+            .send()
+            .await;
+        let (response_status, error) = match sent {
+            Ok(response) if response.status().is_success() => {
+                return DeliveryOutcome::Delivered {
+                    response_status: response.status().as_u16(),
+                };
+            }
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                format!("endpoint answered {}", response.status()),
+            ),
+            Err(error) => (None, error_chain(&error)),
+        };
+        let attempts = delivery.attempts + 1;
+        DeliveryOutcome::Failed {
+            response_status,
+            error: error.chars().take(MAX_ERROR_LENGTH).collect(),
+            retry_at: (attempts < self.config.max_attempts)
+                .then(|| Utc::now() + self.retry_delay(attempts)),
+        }
+    }
+
+    /// How long to wait after the `attempts`th failed attempt
+    fn retry_delay(&self, attempts: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempts - 1).unwrap_or(u32::MAX);
+        Duration::from_secs(self.config.retry_base_secs)
+            .saturating_mul(factor)
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+/// `error` with the errors that caused it, which is where reqwest says what went wrong
+pub fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+/// The `X-Webhook-Signature` of `payload` sent at `timestamp`
+pub fn signature(secret: &str, timestamp: i64, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{timestamp}.{payload}").as_bytes());
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("t={timestamp},v1={digest}")
+}
+
+/// The body of every delivery
+#[derive(Serialize, ToSchema)]
+#[schema(as = WebhookPayload)]
+pub struct Event {
+    event: WebhookEvent,
+    occurred_at: DateTime<Utc>,
+    link: EventLink,
+    /// Only on `click` events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    click: Option<EventClick>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct EventLink {
+    /// The code or slug, or for clicks the one that was followed
+    id: String,
+    url: String,
+}
+
+/// What is known about a click, short of the client address
+#[derive(Serialize, ToSchema)]
+struct EventClick {
+    /// `link`, or `qr` for QR code scans
+    source: &'static str,
+    referrer: Option<String>,
+    browser: Option<String>,
+    os: Option<String>,
+    country: Option<String>,
+    city: Option<String>,
+}
+
+impl Event {
+    /// `event` for `link` as it is now
+    pub fn link(event: WebhookEvent, link: &Link) -> Self {
+        Event {
+            event,
+            occurred_at: Utc::now(),
+            link: EventLink {
+                id: link.public_id(),
+                url: link.url.clone(),
+            },
+            click: None,
+        }
+    }
+
+    /// A click on the link `external_id`, which led to `url`
+    pub fn click(external_id: &str, url: &str, click: &Click) -> Self {
+        Event {
+            event: WebhookEvent::Click,
+            occurred_at: Utc::now(),
+            link: EventLink {
+                id: external_id.to_owned(),
+                url: url.to_owned(),
+            },
+            click: Some(EventClick {
+                source: click.source.as_str(),
+                referrer: click.referrer.clone(),
+                browser: click.browser.clone(),
+                os: click.os.clone(),
+                country: click.country.clone(),
+                city: click.city.clone(),
+            }),
+        }
+    }
+}
+
+/// Queues `event` on the link `link_id` for every webhook that wants it. Failing to queue it is
+/// logged rather than failing the request it happened in.
+pub async fn publish(app_state: &AppState, link_id: u64, event: Event) {
+    let subscribers = app_state.webhooks.subscribers(event.event, link_id);
+    if subscribers.is_empty() {
+        return;
+    }
+    let payload = serde_json::to_string(&event).expect("events serialize");
+    match app_state
+        .store
+        .enqueue_deliveries(subscribers, event.event, payload)
+        .await
+    {
+        Ok(()) => app_state.webhooks.queued.notify_one(),
+        Err(error) => {
+            tracing::warn!(%error, event = event.event.as_str(), "webhook event not queued");
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RegisterWebhookParams {
+    /// The endpoint, which must be https unless `webhooks.allow_http` is on
+    url: String,
+    /// What to send; every event when left out
+    events: Option<Vec<WebhookEvent>>,
+    /// The code or slug of the only link to send events about; every link when left out
+    link: Option<String>,
+}
+
+/// A registered webhook, without its secret
+#[derive(Serialize, ToSchema)]
+pub struct WebhookInfo {
+    id: u64,
+    url: String,
+    events: Vec<WebhookEvent>,
+    /// The code or slug of the only link it is sent events about
+    link: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<Webhook> for WebhookInfo {
+    fn from(webhook: Webhook) -> Self {
+        WebhookInfo {
+            id: webhook.id,
+            url: webhook.url,
+            events: webhook.events,
+            link: webhook.link,
+            created_at: webhook.created_at,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RegisteredWebhook {
+    id: u64,
+    url: String,
+    events: Vec<WebhookEvent>,
+    link: Option<String>,
+    created_at: DateTime<Utc>,
+    /// What payloads are signed with, to check `X-Webhook-Signature` against
+    secret: String,
+}
+
+/// The endpoint of a webhook, if it is one deliveries may be sent to
+fn endpoint(url: &str, config: &WebhookConfig) -> QrLinkResult<String> {
+    if url.len() > MAX_URL_LENGTH {
+        return Err(Error::Validation(format!(
+            "webhook URL must be at most {MAX_URL_LENGTH} characters"
+        )));
+    }
+    let parsed = Url::parse(url)
+        .map_err(|error| Error::Validation(format!("invalid webhook URL: {error}")))?;
+    let scheme_allowed = match parsed.scheme() {
+        "https" => true,
+        "http" => config.allow_http,
+        _ => false,
+    };
+    if !scheme_allowed || parsed.host().is_none() {
+        let schemes = if config.allow_http {
+            "http or https"
+        } else {
+            "https"
+        };
+        return Err(Error::Validation(format!(
+            "webhook URL must be an {schemes} URL with a host"
+        )));
+    }
+    Ok(parsed.into())
+}
+
+/// POST /api/v1/webhooks registers an endpoint to send events to; its secret is only ever shown
+/// in this response
+#[utoipa::path(
+    post,
+    path = "/api/v1/webhooks",
+    summary = "Register a webhook (admin)",
+    description = "Every event is POSTed as a JSON `WebhookPayload`, with its kind in \
+        `X-Webhook-Event` and `X-Webhook-Signature: t=<unix time>,v1=<hex>`, the HMAC-SHA256 \
+        of `<unix time>.<body>` under the secret. Deliveries that don't get a 2xx answer are \
+        retried with exponential backoff.",
+    request_body = RegisterWebhookParams,
+    responses(
+        (status = 201, body = RegisteredWebhook),
+        (status = 400, description = "Invalid URL or no events", body = ErrorBody),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+        (status = 404, description = "No such link", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "webhooks"
+)]
+pub async fn register_webhook(
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+    Json(params): Json<RegisterWebhookParams>,
+) -> QrLinkResult<(StatusCode, axum::Json<RegisteredWebhook>)> {
+    let url = endpoint(params.url.trim(), &app_state.config.webhooks)?;
+    let mut events = params.events.unwrap_or_else(|| WebhookEvent::ALL.to_vec());
+    events.sort_by_key(|event| WebhookEvent::ALL.iter().position(|known| known == event));
+    events.dedup();
+    if events.is_empty() {
+        return Err(Error::Validation(
+            "a webhook needs at least one event".into(),
+        ));
+    }
+    let link_id = match &params.link {
+        Some(link) => Some(app_state.store.resolve_active(link).await?.id),
+        None => None,
+    };
+    let codes = CodeGenerator::new(BASE62, SECRET_LENGTH).expect("base62 is a valid alphabet");
+    let secret = format!("{SECRET_PREFIX}{}", codes.generate());
+
+    let webhook = app_state
+        .store
+        .create_webhook(NewWebhook {
+            url,
+            secret,
+            link_id,
+            events,
+        })
+        .await?;
+    app_state.webhooks.reload(&*app_state.store).await?;
+    app_state.webhooks.start(app_state.store.clone());
+
+    Ok((
+        StatusCode::CREATED,
+        axum::Json(RegisteredWebhook {
+            id: webhook.id,
+            url: webhook.url,
+            events: webhook.events,
+            link: webhook.link,
+            created_at: webhook.created_at,
+            secret: webhook.secret,
+        }),
+    ))
+}
+
+/// GET /api/v1/webhooks lists the registered webhooks, oldest first
+#[utoipa::path(
+    get,
+    path = "/api/v1/webhooks",
+    summary = "List webhooks (admin)",
+    responses(
+        (status = 200, body = Vec<WebhookInfo>),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "webhooks"
+)]
+pub async fn list_webhooks(
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+) -> QrLinkResult<axum::Json<Vec<WebhookInfo>>> {
+    let webhooks = app_state.store.webhooks().await?;
+    Ok(axum::Json(webhooks.into_iter().map(Into::into).collect()))
+}
+
+/// DELETE /api/v1/webhooks/<id> unregisters a webhook; deliveries still queued for it are dropped
+#[utoipa::path(
+    delete,
+    path = "/api/v1/webhooks/{id}",
+    summary = "Delete a webhook (admin)",
+    params(("id" = u64, Path, description = "The webhook's id")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+        (status = 404, description = "No such webhook", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "webhooks"
+)]
+pub async fn delete_webhook(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+) -> QrLinkResult<StatusCode> {
+    app_state.store.delete_webhook(id).await?;
+    app_state.webhooks.reload(&*app_state.store).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Most deliveries one request to the delivery log lists
+pub const MAX_DELIVERIES: u32 = 100;
+
+/// GET /api/v1/webhooks/<id>/deliveries?limit=20 lists the latest deliveries to a webhook, newest
+/// first, with how their last attempt went
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeliveriesQuery {
+    /// At most [MAX_DELIVERIES]; 20 when left out
+    limit: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DeliveryInfo {
+    id: u64,
+    event: String,
+    status: DeliveryStatus,
+    attempts: u32,
+    /// When a pending delivery is next tried
+    next_attempt_at: Option<DateTime<Utc>>,
+    /// What the endpoint last answered with, if it answered
+    response_status: Option<u16>,
+    last_error: Option<String>,
+    created_at: DateTime<Utc>,
+    delivered_at: Option<DateTime<Utc>>,
+    /// The body that was sent
+    #[schema(value_type = Object)]
+    payload: serde_json::Value,
+}
+
+impl From<Delivery> for DeliveryInfo {
+    fn from(delivery: Delivery) -> Self {
+        DeliveryInfo {
+            id: delivery.id,
+            event: delivery.event,
+            status: delivery.status,
+            attempts: delivery.attempts,
+            next_attempt_at: (delivery.status == DeliveryStatus::Pending)
+                .then_some(delivery.next_attempt_at),
+            response_status: delivery.response_status,
+            last_error: delivery.last_error,
+            created_at: delivery.created_at,
+            delivered_at: delivery.delivered_at,
+            payload: serde_json::from_str(&delivery.payload)
+                .unwrap_or(serde_json::Value::String(delivery.payload)),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/webhooks/{id}/deliveries",
+    summary = "List a webhook's deliveries (admin)",
+    params(("id" = u64, Path, description = "The webhook's id"), DeliveriesQuery),
+    responses(
+        (status = 200, body = Vec<DeliveryInfo>),
+        (
+            status = 401,
+            description = "Missing bearer token, or an unknown or revoked API key",
+            body = ErrorBody
+        ),
+        (status = 403, description = "The caller isn't an admin", body = ErrorBody),
+        (status = 404, description = "No such webhook", body = ErrorBody),
+    ),
+    security(("admin_token" = []), ("api_key" = [])),
+    tag = "webhooks"
+)]
+pub async fn webhook_deliveries(
+    Path(id): Path<u64>,
+    Query(query): Query<DeliveriesQuery>,
+    State(app_state): State<AppState>,
+    _: Authorized<Manage>,
+) -> QrLinkResult<axum::Json<Vec<DeliveryInfo>>> {
+    let limit = query.limit.unwrap_or(20).clamp(1, MAX_DELIVERIES);
+    let deliveries = app_state.store.deliveries(id, limit).await?;
+    Ok(axum::Json(deliveries.into_iter().map(Into::into).collect()))
+}